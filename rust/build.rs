@@ -54,6 +54,10 @@ fn main () {
   println!("cargo:rerun-if-changed={}", &wrapper_file);
   println!("cargo:rerun-if-changed={}/{}", "wrapper", GEN_HVM_SAVE_VARIABLES_BIN);
   println!("cargo:rustc-link-lib={}={}", "dylib", "xenctrl");
+  println!("cargo:rustc-link-lib={}={}", "dylib", "xenevtchn");
+  println!("cargo:rustc-link-lib={}={}", "dylib", "xenforeignmemory");
+  println!("cargo:rustc-link-lib={}={}", "dylib", "xengnttab");
+  println!("cargo:rustc-link-lib={}={}", "dylib", "xenguest");
   println!("cargo:rustc-link-lib={}={}", "dylib", "xenstore");
 
   let bindings = bindgen::Builder::default()
@@ -61,6 +65,8 @@ fn main () {
     .default_enum_style(bindgen::EnumVariation::Rust {
       non_exhaustive: false,
     })
+    // Permissions of xenstore nodes are combined: READ | WRITE.
+    .bitfield_enum("xs_perm_type")
     .layout_tests(false)
     .rustfmt_bindings(true)
     .derive_default(true)
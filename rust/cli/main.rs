@@ -1,4 +1,9 @@
+use serde_json::{json, Map, Value};
 use std::env;
+use std::fs;
+use std::io::{BufReader, Cursor, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
 
 use xenops::*;
 
@@ -9,7 +14,650 @@ fn help () {
 xenops-cli {{pause|unpause|shutdown}} <integer>
   pause/unpause or shutdown a vm if the integer is a valid domain id.
 xenops-cli domain-list`
-  List all domains ids.")
+  List all domains ids.
+xenops-cli hvm-context dump <integer> [--json]
+  Print the decoded HVM context records of a domain.
+xenops-cli hvm-context save <integer> <file>
+  Save the raw HVM context of a domain in a file.
+xenops-cli hvm-context diff <file> <file>
+  Compare two saved HVM contexts field by field.
+xenops-cli mem dump <integer> <address> <length> [--vcpu <integer>]
+  Print a hexdump of guest memory. The address is physical, or virtual if a vCPU is given.
+xenops-cli gdbserver <integer> [--listen <address:port>]
+  Wait for a gdb connection to debug a HVM domain, on 127.0.0.1:9999 by default.
+xenops-cli dmesg [-f] [--clear]
+  Print the console ring of Xen, and follow it with -f. --clear clears what is read.
+xenops-cli debug-keys <keys>
+  Trigger the given debug keys of Xen and print their output.
+xenops-cli migrate <integer> <host[:port]> [--downtime <ms>] [--bandwidth <bytes/s>] [--simulated]
+  Live migrate a domain to a host running migrate-receive or xenopsd.
+xenops-cli migrate-receive [--listen <address:port>] [--simulated]
+  Wait for one incoming migration, on 0.0.0.0:3031 by default.
+xenops-cli console <integer> [--host <host[:port]>] [--read-only]
+  Attach the terminal to the console of a domain through xenopsd, Ctrl-] to detach.
+xenops-cli image-info <file>
+  Check a save file or a raw migration stream and print its content. Does not need Xen.")
+}
+
+// -----------------------------------------------------------------------------
+
+fn to_hex (data: &[u8]) -> String {
+  data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn has_raw_fields (record: &hvm_context::Record) -> bool {
+  matches!(
+    record.data,
+    hvm_context::RecordData::PciIrq(_) | hvm_context::RecordData::IsaIrq(_) | hvm_context::RecordData::Raw(..)
+  )
+}
+
+fn print_hvm_context_json (context: &hvm_context::HvmContext) {
+  let records: Vec<Value> = context.records.iter().map(|record| {
+    let mut object = json!({
+      "type": record.data.name(),
+      "code": record.code(),
+      "instance": record.instance
+    });
+    if has_raw_fields(record) {
+      object["data"] = Value::from(to_hex(record.as_bytes()));
+    } else {
+      let fields: Map<String, Value> = record.data.fields().into_iter()
+        .map(|field| (field.name, Value::from(field.value)))
+        .collect();
+      object["fields"] = Value::Object(fields);
+    }
+    object
+  }).collect();
+
+  println!("{}", serde_json::to_string_pretty(&records).unwrap());
+}
+
+fn print_hvm_context_table (context: &hvm_context::HvmContext) {
+  for record in &context.records {
+    println!("{} #{} (code {})", record.data.name(), record.instance, record.code());
+    if has_raw_fields(record) {
+      println!("  {}", to_hex(record.as_bytes()));
+    } else {
+      for field in record.data.fields() {
+        println!("  {:<24} {:#018x}", field.name, field.value);
+      }
+    }
+  }
+}
+
+fn load_hvm_context (path: &str) -> Option<hvm_context::HvmContext> {
+  let blob = match fs::read(path) {
+    Ok(blob) => blob,
+    Err(e) => {
+      eprintln!("Failed to read `{}`: {}", path, e);
+      return None
+    }
+  };
+
+  match hvm_context::HvmContext::parse(&blob) {
+    Ok(context) => Some(context),
+    Err(e) => {
+      eprintln!("Failed to parse `{}`: {}", path, e);
+      None
+    }
+  }
+}
+
+fn hvm_context_command (xc: &xenctrl::Xenctrl, args: &[String]) {
+  let parse_dom_id = |arg: &str| match arg.parse::<u32>() {
+    Ok(dom_id) => Some(dom_id),
+    Err(_) => {
+      eprintln!("error: domain id not an integer");
+      help();
+      None
+    }
+  };
+
+  match args {
+    [cmd, dom_id] | [cmd, dom_id, _] if cmd == "dump" => {
+      let json = match args.get(2) {
+        None => false,
+        Some(flag) if flag == "--json" => true,
+        Some(_) => return help()
+      };
+      let dom_id = match parse_dom_id(dom_id) {
+        Some(dom_id) => dom_id,
+        None => return
+      };
+
+      let blob = match xc.get_hvm_context(dom_id) {
+        Ok(blob) => blob,
+        Err(e) => return eprintln!("Failed to get HVM context: {}", e)
+      };
+      match hvm_context::HvmContext::parse(&blob) {
+        Ok(context) if json => print_hvm_context_json(&context),
+        Ok(context) => print_hvm_context_table(&context),
+        Err(e) => eprintln!("Failed to parse HVM context: {}", e)
+      }
+    },
+    [cmd, dom_id, path] if cmd == "save" => {
+      let dom_id = match parse_dom_id(dom_id) {
+        Some(dom_id) => dom_id,
+        None => return
+      };
+
+      match xc.get_hvm_context(dom_id) {
+        Ok(blob) => if let Err(e) = fs::write(path, blob) {
+          eprintln!("Failed to write `{}`: {}", path, e)
+        },
+        Err(e) => eprintln!("Failed to get HVM context: {}", e)
+      }
+    },
+    _ => {
+      eprintln!("Error: invalid hvm-context command");
+      help()
+    }
+  }
+}
+
+// Offline: compares two saved contexts.
+fn hvm_context_diff_command (args: &[String]) {
+  let (first, second) = match args {
+    [first, second] => match (load_hvm_context(first), load_hvm_context(second)) {
+      (Some(first), Some(second)) => (first, second),
+      _ => return
+    },
+    _ => {
+      eprintln!("Error: invalid hvm-context command");
+      return help()
+    }
+  };
+
+  let differences = hvm_context::diff(&first, &second);
+  if differences.is_empty() {
+    println!("HVM contexts are identical.");
+  }
+  for difference in differences {
+    println!("{}", difference);
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn parse_number (arg: &str) -> Option<u64> {
+  match arg.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => arg.parse().ok()
+  }
+}
+
+fn print_hexdump (addr: u64, data: &[u8]) {
+  for (i, line) in data.chunks(16).enumerate() {
+    let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let ascii: String = line.iter()
+      .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+      .collect();
+    println!("{:016x}  {:<47}  |{}|", addr + (i * 16) as u64, hex.join(" "), ascii);
+  }
+}
+
+fn mem_command (xc: &xenctrl::Xenctrl, args: &[String]) {
+  let (dom_id, addr, len, vcpu) = match args {
+    [cmd, dom_id, addr, len] if cmd == "dump" => (dom_id, addr, len, None),
+    [cmd, dom_id, addr, len, flag, vcpu] if cmd == "dump" && flag == "--vcpu" => (dom_id, addr, len, Some(vcpu)),
+    _ => {
+      eprintln!("Error: invalid mem command");
+      return help()
+    }
+  };
+
+  let (dom_id, addr, len, vcpu) = match (
+    dom_id.parse::<u32>().ok(),
+    parse_number(addr),
+    parse_number(len),
+    vcpu.map(|vcpu| vcpu.parse::<u32>().ok())
+  ) {
+    (Some(dom_id), Some(addr), Some(len), None) => (dom_id, addr, len as usize, None),
+    (Some(dom_id), Some(addr), Some(len), Some(Some(vcpu))) => (dom_id, addr, len as usize, Some(vcpu)),
+    _ => {
+      eprintln!("error: invalid integer argument");
+      return help()
+    }
+  };
+
+  let fmem = match foreignmemory::ForeignMemory::new() {
+    Ok(fmem) => fmem,
+    Err(e) => return eprintln!("Could not execute command: {}", e)
+  };
+
+  let guest = guest::Guest::new(xc, &fmem, dom_id);
+  let memory = match vcpu {
+    Some(vcpu) => guest.read_virt(vcpu, addr, len),
+    None => guest.read_phys(addr, len)
+  };
+  match memory {
+    Ok(memory) => print_hexdump(addr, &memory),
+    Err(e) => eprintln!("Failed to read guest memory: {}", e)
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn gdbserver_command (xc: &xenctrl::Xenctrl, args: &[String]) {
+  let (dom_id, address) = match args {
+    [dom_id] => (dom_id, "127.0.0.1:9999"),
+    [dom_id, flag, address] if flag == "--listen" => (dom_id, &address[..]),
+    _ => {
+      eprintln!("Error: invalid gdbserver command");
+      return help()
+    }
+  };
+  let dom_id: u32 = match dom_id.parse() {
+    Ok(dom_id) => dom_id,
+    Err(_) => {
+      eprintln!("error: domain id not an integer");
+      return help()
+    }
+  };
+
+  let fmem = match foreignmemory::ForeignMemory::new() {
+    Ok(fmem) => fmem,
+    Err(e) => return eprintln!("Could not execute command: {}", e)
+  };
+
+  let listener = match TcpListener::bind(address) {
+    Ok(listener) => listener,
+    Err(e) => return eprintln!("Failed to listen on {}: {}", address, e)
+  };
+  println!("Listening for gdb on {}...", address);
+
+  let stream = match listener.accept() {
+    Ok((stream, peer)) => {
+      println!("Connection from {}.", peer);
+      stream
+    },
+    Err(e) => return eprintln!("Failed to accept gdb connection: {}", e)
+  };
+
+  let target = match gdbstub::XenTarget::attach(xc, &fmem, dom_id) {
+    Ok(target) => target,
+    Err(e) => return eprintln!("Failed to attach to domain {}: {}", dom_id, e)
+  };
+  let mut stub = gdbstub::GdbStub::new(target);
+  match gdbstub::serve(&mut stub, stream) {
+    Ok(_) => println!("Detached from domain {}.", dom_id),
+    Err(e) => eprintln!("gdb connection error: {}", e)
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Interval between two reads of the console ring of Xen with `dmesg -f`.
+const DMESG_FOLLOW_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+fn print_console_ring (data: &[u8]) {
+  let mut stdout = std::io::stdout();
+  let _ = stdout.write_all(data).and_then(|_| stdout.flush());
+}
+
+fn dmesg_command (xc: &xenctrl::Xenctrl, args: &[String]) {
+  let mut follow = false;
+  let mut clear = false;
+  for option in args {
+    match &option[..] {
+      "-f" => follow = true,
+      "--clear" => clear = true,
+      _ => {
+        eprintln!("Error: invalid dmesg option {}", option);
+        return help()
+      }
+    }
+  }
+
+  let mut cursor = None;
+  loop {
+    match xc.read_console_ring(cursor, clear) {
+      Ok((data, next)) => {
+        print_console_ring(&data);
+        cursor = Some(next);
+      },
+      Err(e) => return eprintln!("Failed to read the console ring: {}", e)
+    }
+    if !follow {
+      return
+    }
+    std::thread::sleep(DMESG_FOLLOW_INTERVAL);
+  }
+}
+
+fn debug_keys_command (xc: &xenctrl::Xenctrl, args: &[String]) {
+  let keys = match args {
+    [keys] => keys,
+    _ => {
+      eprintln!("Error: invalid debug-keys command");
+      return help()
+    }
+  };
+
+  // Print what the handlers of the keys write.
+  let cursor = match xc.read_console_ring(None, false) {
+    Ok((_, cursor)) => cursor,
+    Err(e) => return eprintln!("Failed to read the console ring: {}", e)
+  };
+  if let Err(e) = xc.send_debug_keys(keys) {
+    return eprintln!("Failed to send debug keys: {}", e)
+  }
+  match xc.read_console_ring(Some(cursor), false) {
+    Ok((data, _)) => print_console_ring(&data),
+    Err(e) => eprintln!("Failed to read the console ring: {}", e)
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Domain created by `migrate --simulated`: 64 MiB, 20000 pages written per second.
+const SIMULATED_MEMKB: u64 = 64 * 1024;
+const SIMULATED_DIRTY_RATE: u64 = 20000;
+
+fn open_xen () -> Option<(xenctrl::Xenctrl, xenstore::Xenstore)> {
+  match (xenctrl::Xenctrl::new(), xenstore::Xenstore::new()) {
+    (Ok(xc), Ok(xs)) => Some((xc, xs)),
+    (Err(e), _) | (_, Err(e)) => {
+      eprintln!("Could not execute command: {}", e);
+      None
+    }
+  }
+}
+
+fn migrate_command (args: &[String]) {
+  let (dom_id, destination) = match args {
+    [dom_id, destination, ..] => (dom_id, destination),
+    _ => {
+      eprintln!("Error: invalid migrate command");
+      return help()
+    }
+  };
+  let dom_id: u32 = match dom_id.parse() {
+    Ok(dom_id) => dom_id,
+    Err(_) => {
+      eprintln!("error: domain id not an integer");
+      return help()
+    }
+  };
+
+  let mut config = migration::MigrationConfig::default();
+  let mut simulated = false;
+  let mut options = args[2..].iter();
+  while let Some(option) = options.next() {
+    match (&option[..], options.as_slice().first().and_then(|value| parse_number(value))) {
+      ("--simulated", _) => simulated = true,
+      ("--downtime", Some(value)) => { config.max_downtime_ms = value; options.next(); },
+      ("--bandwidth", Some(value)) => { config.bandwidth_limit = Some(value); options.next(); },
+      _ => {
+        eprintln!("Error: invalid migrate option {}", option);
+        return help()
+      }
+    }
+  }
+
+  let mut progress = |progress: &migration::Progress| {
+    println!(
+      "iteration {}: {} dirty pages, {} bytes sent", progress.iteration, progress.dirty_pages, progress.bytes_sent
+    );
+  };
+
+  if simulated {
+    let host = migration::SimulatedHost::new();
+    host.create_domain(dom_id, SIMULATED_MEMKB, SIMULATED_DIRTY_RATE);
+    match migration::send(&host, dom_id, destination, &config, &mut progress) {
+      Ok((remote_dom_id, _)) => println!(
+        "Migrated to domain {} (checksum {:016x}).", remote_dom_id, host.suspended_checksum(dom_id).unwrap_or(0)
+      ),
+      Err(e) => eprintln!("Failed to migrate domain {}: {}", dom_id, e)
+    }
+  } else if let Some((xc, xs)) = open_xen() {
+    match migration::send(&migration::XenHost::new(&xc, &xs), dom_id, destination, &config, &mut progress) {
+      Ok((remote_dom_id, None)) => println!("Migrated to domain {}.", remote_dom_id),
+      Ok((remote_dom_id, Some(devices_state))) => println!(
+        "Migrated to domain {}, its devices state is in {} on the receiver.", remote_dom_id, devices_state.display()
+      ),
+      Err(e) => eprintln!("Failed to migrate domain {}: {}", dom_id, e)
+    }
+  }
+}
+
+fn migrate_receive_command (args: &[String]) {
+  let default_address = SocketAddr::from(([0, 0, 0, 0], migration::MIGRATION_PORT)).to_string();
+  let (address, simulated) = match args {
+    [] => (&default_address[..], false),
+    [flag] if flag == "--simulated" => (&default_address[..], true),
+    [flag, address] if flag == "--listen" => (&address[..], false),
+    [flag, address, simulated] if flag == "--listen" && simulated == "--simulated" => (&address[..], true),
+    _ => {
+      eprintln!("Error: invalid migrate-receive command");
+      return help()
+    }
+  };
+
+  let listener = match TcpListener::bind(address) {
+    Ok(listener) => listener,
+    Err(e) => return eprintln!("Failed to listen on {}: {}", address, e)
+  };
+  println!("Waiting for a migration on {}...", address);
+
+  let stream = match listener.accept() {
+    Ok((stream, peer)) => {
+      println!("Migration from {}.", peer);
+      stream
+    },
+    Err(e) => return eprintln!("Failed to accept migration: {}", e)
+  };
+
+  if simulated {
+    let host = migration::SimulatedHost::new();
+    match migration::receive(&host, &stream) {
+      Ok((dom_id, _)) => {
+        println!("Received domain {} (checksum {:016x}).", dom_id, host.checksum(dom_id).unwrap_or(0));
+        if let Some(size) = host.devices_state_size(dom_id) {
+          println!("Devices state of {} bytes.", size);
+        }
+      },
+      Err(e) => eprintln!("Failed to receive domain: {}", e)
+    }
+  } else if let Some((xc, xs)) = open_xen() {
+    match migration::receive(&migration::XenHost::new(&xc, &xs), &stream) {
+      Ok((dom_id, None)) => println!("Received domain {}.", dom_id),
+      Ok((dom_id, Some(devices_state))) => println!("Received domain {}, its devices state is in {}.", dom_id, devices_state.display()),
+      Err(e) => eprintln!("Failed to receive domain: {}", e)
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Terminal of the CLI in raw mode until `restore`.
+struct RawTerminal {
+  saved: libc::termios
+}
+
+// Received by the main thread of `console_command`.
+enum ConsoleEvent {
+  Input(Vec<u8>),
+  InputClosed,
+  OutputClosed
+}
+
+impl RawTerminal {
+  fn enable () -> Option<Self> {
+    unsafe {
+      let mut termios: libc::termios = std::mem::zeroed();
+      if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+        return None
+      }
+      let saved = termios;
+      libc::cfmakeraw(&mut termios);
+      if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+        return None
+      }
+      Some(Self { saved })
+    }
+  }
+
+  fn restore (&self) {
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved); }
+  }
+}
+
+fn console_command (args: &[String]) {
+  let dom_id: u32 = match args.first().map(|dom_id| dom_id.parse()) {
+    Some(Ok(dom_id)) => dom_id,
+    _ => {
+      eprintln!("error: domain id not an integer");
+      return help()
+    }
+  };
+
+  let mut host = String::from("127.0.0.1");
+  let mut write = true;
+  let mut options = args[1..].iter();
+  while let Some(option) = options.next() {
+    match (&option[..], options.as_slice().first()) {
+      ("--read-only", _) => write = false,
+      ("--host", Some(value)) => { host = value.clone(); options.next(); },
+      _ => {
+        eprintln!("Error: invalid console option {}", option);
+        return help()
+      }
+    }
+  }
+  let host = migration::address_with_port(&host, console::CONSOLE_PORT);
+
+  let mut stream = match TcpStream::connect(&host) {
+    Ok(stream) => stream,
+    Err(e) => return eprintln!("Failed to connect to {}: {}", host, e)
+  };
+  let mut request = serde_json::to_vec(&console::AttachRequest { dom_id, write }).unwrap();
+  request.push(b'\n');
+  let reply = stream.write_all(&request)
+    .and_then(|_| console::read_line(&mut stream))
+    .and_then(|line| serde_json::from_str::<console::AttachReply>(&line).map_err(std::io::Error::from));
+  let writer = match reply {
+    Ok(console::AttachReply { error: Some(e), .. }) => return eprintln!("Failed to attach console: {}", e),
+    Ok(reply) => reply.writer,
+    Err(e) => return eprintln!("Failed to attach console: {}", e)
+  };
+  if write && !writer {
+    eprintln!("Another client writes in this console, attached read-only.");
+  }
+  eprintln!("Attached to domain {}, Ctrl-] to detach.", dom_id);
+
+  // The output ends when the console is closed.
+  let mut output = match stream.try_clone() {
+    Ok(output) => output,
+    Err(e) => return eprintln!("Failed to attach console: {}", e)
+  };
+  let terminal = RawTerminal::enable();
+
+  // The input and the output are read by their own threads, the main one
+  // waits for either to end.
+  let (events, received) = mpsc::channel();
+  let output_events = events.clone();
+  let output_thread = std::thread::spawn(move || {
+    let _ = std::io::copy(&mut output, &mut std::io::stdout());
+    let _ = output_events.send(ConsoleEvent::OutputClosed);
+  });
+  std::thread::spawn(move || {
+    let mut input = [0u8; 1024];
+    let stdin = std::io::stdin();
+    let mut stdin = stdin.lock();
+    loop {
+      let event = match stdin.read(&mut input) {
+        Ok(0) | Err(_) => ConsoleEvent::InputClosed,
+        Ok(count) => ConsoleEvent::Input(input[..count].to_vec())
+      };
+      let closed = matches!(event, ConsoleEvent::InputClosed);
+      if events.send(event).is_err() || closed {
+        return
+      }
+    }
+  });
+
+  let console_closed = loop {
+    match received.recv() {
+      Ok(ConsoleEvent::Input(input)) => {
+        let (data, detach) = match input.iter().position(|byte| *byte == console::DETACH_KEY) {
+          Some(position) => (&input[..position], true),
+          None => (&input[..], false)
+        };
+        if writer && stream.write_all(data).is_err() {
+          break true
+        }
+        if detach {
+          break false
+        }
+      },
+      Ok(ConsoleEvent::InputClosed) => break false,
+      Ok(ConsoleEvent::OutputClosed) | Err(_) => break true
+    }
+  };
+
+  // The input thread may still wait for stdin, it ends with the process.
+  let _ = stream.shutdown(Shutdown::Both);
+  let _ = output_thread.join();
+  if let Some(terminal) = &terminal {
+    terminal.restore();
+  }
+  if console_closed {
+    eprintln!("\r\nConsole of domain {} closed.", dom_id);
+  } else {
+    eprintln!("\r\nDetached from domain {}.", dom_id);
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn image_info_command (args: &[String]) {
+  if args.len() != 1 {
+    return help()
+  }
+
+  let mut file = match fs::File::open(&args[0]) {
+    Ok(file) => BufReader::new(file),
+    Err(e) => return eprintln!("Failed to open {}: {}", args[0], e)
+  };
+
+  // Save files start with a header written by xenops, raw streams don't.
+  let mut magic = [0u8; 16];
+  if let Err(e) = file.read_exact(&mut magic) {
+    return eprintln!("Failed to read {}: {}", args[0], e)
+  }
+  let result = if &magic == save::SAVE_MAGIC {
+    let mut reader = Cursor::new(magic).chain(file);
+    match save::read_header(&mut reader) {
+      Ok(config) => {
+        println!("name: {}", config.name);
+        println!("uuid: {}", config.uuid);
+        println!("vcpus: {}", config.max_vcpus);
+        println!("memory: {} KiB", config.max_memkb);
+      },
+      Err(e) => return eprintln!("Invalid save file: {}", e)
+    }
+    migration_stream::validate(reader)
+  } else {
+    migration_stream::validate(Cursor::new(magic).chain(file))
+  };
+
+  match result {
+    Ok(summary) => {
+      println!("stream version: {}", summary.image_header.version);
+      println!("domain type: {}", summary.domain_header.domain_type);
+      println!("page shift: {}", summary.domain_header.page_shift);
+      println!("xen version: {}.{}", summary.domain_header.xen_major, summary.domain_header.xen_minor);
+      println!("pages: {}", summary.pages);
+      println!("vcpus: {}", summary.vcpus);
+      println!("records:");
+      for (record_type, count) in &summary.records {
+        println!("  {:<24} {}", migration_stream::record_type_name(*record_type), count);
+      }
+    },
+    Err(e) => eprintln!("Invalid migration stream: {}", e)
+  }
 }
 
 // -----------------------------------------------------------------------------
@@ -17,6 +665,20 @@ xenops-cli domain-list`
 fn main () {
   let args: Vec<String> = env::args().collect();
 
+  // Commands which open the Xen interfaces only if needed.
+  if args.len() > 1 {
+    match &args[1][..] {
+      "console" => return console_command(&args[2..]),
+      "hvm-context" if args.get(2).map_or(false, |cmd| cmd == "diff") => {
+        return hvm_context_diff_command(&args[3..])
+      },
+      "image-info" => return image_info_command(&args[2..]),
+      "migrate" => return migrate_command(&args[2..]),
+      "migrate-receive" => return migrate_receive_command(&args[2..]),
+      _ => ()
+    }
+  }
+
   let xs = match xenstore::Xenstore::new() {
     Ok(xs) => xs,
     Err(e) => {
@@ -32,6 +694,18 @@ fn main () {
     }
   };
 
+  // Commands with sub-commands or options.
+  if args.len() > 1 {
+    match &args[1][..] {
+      "hvm-context" => return hvm_context_command(&xc, &args[2..]),
+      "mem" => return mem_command(&xc, &args[2..]),
+      "gdbserver" => return gdbserver_command(&xc, &args[2..]),
+      "dmesg" => return dmesg_command(&xc, &args[2..]),
+      "debug-keys" => return debug_keys_command(&xc, &args[2..]),
+      _ => ()
+    }
+  }
+
   match args.len() {
     // one command passed
     2 => {
@@ -1,10 +1,20 @@
 use enclose::enclose;
-use jsonrpc_core::{Error, ErrorCode, IoHandler, Params, Value, serde_json::json};
+use jsonrpc_core::{Error, ErrorCode, IoHandler, Params, Value, serde_json, serde_json::json};
 use jsonrpc_http_server::{AccessControlAllowOrigin, DomainsValidation, RestApi, ServerBuilder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::iter::FromIterator;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use xenops::{vm, xenctrl, xenstore};
+use std::time::Duration;
+use xenops::{
+  blkback, checkpoint, console, coredump, device, devicemodel, evtchn, foreignmemory, hotplug, logdirty, migration, pci, save,
+  snapshot, stats, varstore, vcpu_context, vm, vtpm, xenctrl, xenstore
+};
+use xenops::device::{vbd, vif};
+use xenops::devicemodel::{qmp::Qmp, supervisor};
 
 // =============================================================================
 
@@ -12,6 +22,204 @@ fn make_error (error: &str) -> Error {
   Error { code: ErrorCode::ServerError(0), message: error.to_string(), data: None }
 }
 
+// Recursively overwrite the fields of `target` with the ones of `patch`.
+fn merge_json (target: &mut Value, patch: Value) {
+  match (target, patch) {
+    (Value::Object(target), Value::Object(patch)) => {
+      for (key, value) in patch {
+        match target.get_mut(&key) {
+          Some(field) => merge_json(field, value),
+          None => { target.insert(key, value); }
+        }
+      }
+    },
+    (target, patch) => *target = patch
+  }
+}
+
+fn decode_hex (hex: &str) -> Option<Vec<u8>> {
+  if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+    return None
+  }
+  (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok()).collect()
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum MigrationState {
+  Running { progress: Option<migration::Progress> },
+  // The device model of the remote domain is started with its devices state.
+  Completed { remote_dom_id: u32, devices_state: Option<PathBuf> },
+  Failed { error: String }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum CheckpointState {
+  Running,
+  Stopped,
+  Failed { error: String }
+}
+
+struct Checkpoint {
+  control: checkpoint::CheckpointControl,
+  state: CheckpointState
+}
+
+// Migrations are long: each one uses its own Xen handles to not block the
+// other requests.
+fn open_xen () -> Result<(xenctrl::Xenctrl, xenstore::Xenstore), &'static str> {
+  Ok((xenctrl::Xenctrl::new()?, xenstore::Xenstore::new()?))
+}
+
+// The device model of a domain to save with its devices state, if any.
+fn device_model_qmp (device_models: &supervisor::Supervisor, dom_id: u32) -> Result<Option<Qmp<UnixStream>>, Error> {
+  match device_models.qmp(dom_id, devicemodel::DEFAULT_TIMEOUT) {
+    Ok(qmp) => Ok(Some(qmp)),
+    Err(devicemodel::Error::NoSuchDeviceModel(_)) => Ok(None),
+    Err(e) => Err(make_error(&e.to_string()))
+  }
+}
+
+// Receive the migrations and the checkpoints of the peers.
+fn receive_migrations (listener: TcpListener, standby: Arc<checkpoint::Standby>) {
+  for stream in listener.incoming() {
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        eprintln!("Failed to accept migration: {}", e);
+        continue
+      }
+    };
+
+    std::thread::spawn(enclose! { (standby) move || {
+      let result = open_xen().map_err(String::from).and_then(|(xc, xs)| {
+        let mut reader = &stream;
+        match save::read_header(&mut reader) {
+          Ok(config) if config.checkpointed => {
+            standby.receive(&xc, &xs, &stream, &config).map(|dom_id| (dom_id, None)).map_err(|e| e.to_string())
+          },
+          config => {
+            let host = migration::XenHost::new(&xc, &xs);
+            migration::receive_with_config(&host, &stream, config.map_err(migration::Error::from))
+              .map_err(|e| e.to_string())
+          }
+        }
+      });
+      match result {
+        Ok((dom_id, None)) => eprintln!("Received domain {} from {:?}.", dom_id, stream.peer_addr()),
+        Ok((dom_id, Some(devices_state))) => eprintln!(
+          "Received domain {} from {:?}, devices state in {}.", dom_id, stream.peer_addr(), devices_state.display()
+        ),
+        Err(e) => eprintln!("Failed to receive migration from {:?}: {}", stream.peer_addr(), e)
+      }
+    } });
+  }
+}
+
+// Set up the backends of this domain when they wait for the hotplug.
+fn run_hotplug (config: hotplug::HotplugConfig) -> Result<(), String> {
+  let xs = xenstore::Xenstore::new().map_err(String::from)?;
+  let path = format!("{}/backend", xenstore::get_domain_path(0));
+  xs.watch(&path, "hotplug").map_err(|e| e.to_string())?;
+
+  let hotplug = hotplug::Hotplug::new(config, hotplug::SystemRunner);
+  loop {
+    let (path, _) = xs.read_watch().map_err(|e| e.to_string())?;
+    if let Some((action, backend, Err(e))) = hotplug.backend_changed(&xs, &path) {
+      eprintln!("Failed to {} backend {}: {}", action, backend, e);
+    }
+  }
+}
+
+// Log the output of the consoles of the domains and relay it to the clients.
+fn run_console_backend (sessions: Arc<console::Sessions>) -> Result<(), String> {
+  let (xc, xs) = open_xen()?;
+  let fmem = foreignmemory::ForeignMemory::new()?;
+  let evtchn = evtchn::EventChannel::new()?;
+  let mut backend = console::Backend::new(&xc, &xs, &fmem, &evtchn, &sessions, console::LogConfig::default());
+  loop {
+    match backend.poll() {
+      Err(e) if !e.is_transient() => return Err(e.to_string()),
+      _ => std::thread::sleep(console::POLL_INTERVAL)
+    }
+  }
+}
+
+// Serve the disks of the native block backend.
+fn run_block_backend () -> Result<(), String> {
+  let xs = xenstore::Xenstore::new().map_err(String::from)?;
+  let mut backend = blkback::Backend::new(&xs);
+  loop {
+    backend.poll();
+    std::thread::sleep(blkback::POLL_INTERVAL);
+  }
+}
+
+// Apply the exit policies of the device models, and stop the ones of the
+// destroyed domains.
+fn run_device_model_supervisor (device_models: Arc<supervisor::Supervisor>) -> Result<(), String> {
+  let (xc, xs) = open_xen()?;
+  loop {
+    for event in device_models.poll(&xc, &xs) {
+      match event {
+        supervisor::Event::Exited(dom_id, record) => eprintln!(
+          "Device model of domain {} exited (code: {:?}, signal: {:?}), {}:\n{}",
+          dom_id, record.code, record.signal, record.action, record.log_tail
+        ),
+        supervisor::Event::Stopped(dom_id) => eprintln!("Stopped the device model of destroyed domain {}.", dom_id)
+      }
+    }
+    std::thread::sleep(supervisor::POLL_INTERVAL);
+  }
+}
+
+// Apply the crash actions of the crashed domains.
+fn run_crash_handler () -> Result<(), String> {
+  let (xc, xs) = open_xen()?;
+  let fmem = foreignmemory::ForeignMemory::new()?;
+  let mut handler = vm::CrashHandler::new();
+  loop {
+    for (dom_id, result) in handler.poll(&xc, &fmem, &xs, Path::new(vm::CORE_DIR)).map_err(|e| e.to_string())? {
+      match result {
+        Ok((action, Some(core))) => eprintln!("Domain {} crashed: core dumped in {}, {}.", dom_id, core.display(), action),
+        Ok((action, None)) => eprintln!("Domain {} crashed: {}.", dom_id, action),
+        Err(e) => eprintln!("Domain {} crashed, preserved: failed to apply its crash action: {}", dom_id, e)
+      }
+    }
+    std::thread::sleep(vm::CRASH_POLL_INTERVAL);
+  }
+}
+
+// Sample the statistics of the host and of the domains.
+fn run_stats_collector (collector: Arc<stats::Collector>) -> Result<(), String> {
+  let (xc, xs) = open_xen()?;
+  loop {
+    std::thread::sleep(stats::until_next_sample());
+    if let Err(e) = collector.sample(&xc, &xs) {
+      eprintln!("Failed to sample statistics: {}", e);
+    }
+  }
+}
+
+// Attach the clients of the console port.
+fn receive_consoles (listener: TcpListener, sessions: Arc<console::Sessions>) {
+  for stream in listener.incoming() {
+    match stream {
+      Ok(stream) => {
+        std::thread::spawn(enclose! { (sessions) move || {
+          if let Err(e) = console::serve(&sessions, stream) {
+            eprintln!("Console session failed: {}", e);
+          }
+        } });
+      },
+      Err(e) => eprintln!("Failed to accept console client: {}", e)
+    }
+  }
+}
+
 // =============================================================================
 
 fn main () {
@@ -35,6 +243,84 @@ fn main () {
     }
   ));
 
+  let fmem = Arc::new(Mutex::new(
+    match foreignmemory::ForeignMemory::new() {
+      Ok(fmem) => fmem,
+      Err(e) => {
+        eprintln!("Could not start daemon: {}", e);
+        return
+      }
+    }
+  ));
+
+  let migrations: Arc<Mutex<HashMap<u32, MigrationState>>> = Arc::new(Mutex::new(HashMap::new()));
+  let checkpoints: Arc<Mutex<HashMap<u32, Checkpoint>>> = Arc::new(Mutex::new(HashMap::new()));
+  let device_models = Arc::new(supervisor::Supervisor::new());
+  let standby = Arc::new(checkpoint::Standby::new());
+  let collector = Arc::new(stats::Collector::default());
+
+  match TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], migration::MIGRATION_PORT))) {
+    Ok(listener) => { std::thread::spawn(enclose! { (standby) move || receive_migrations(listener, standby) }); },
+    Err(e) => {
+      eprintln!("Could not start daemon: failed to listen for migrations: {}", e);
+      return
+    }
+  }
+
+  match hotplug::HotplugConfig::load(Path::new(hotplug::DEFAULT_CONFIG_PATH)) {
+    Ok(config) => {
+      std::thread::spawn(move || {
+        if let Err(e) = run_hotplug(config) {
+          eprintln!("Hotplug stopped: {}", e);
+        }
+      });
+    },
+    Err(e) => {
+      eprintln!("Could not start daemon: {}", e);
+      return
+    }
+  }
+
+  let sessions = Arc::new(console::Sessions::new());
+  std::thread::spawn(enclose! { (sessions) move || {
+    if let Err(e) = run_console_backend(sessions) {
+      eprintln!("Console backend stopped: {}", e);
+    }
+  } });
+
+  std::thread::spawn(|| {
+    if let Err(e) = run_block_backend() {
+      eprintln!("Block backend stopped: {}", e);
+    }
+  });
+
+  // The consoles are not authenticated: only the local clients are accepted.
+  match TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], console::CONSOLE_PORT))) {
+    Ok(listener) => { std::thread::spawn(move || receive_consoles(listener, sessions)); },
+    Err(e) => {
+      eprintln!("Could not start daemon: failed to listen for consoles: {}", e);
+      return
+    }
+  }
+
+  std::thread::spawn(enclose! { (device_models) move || {
+    if let Err(e) = run_device_model_supervisor(device_models) {
+      eprintln!("Device model supervisor stopped: {}", e);
+    }
+  } });
+
+  std::thread::spawn(|| {
+    if let Err(e) = run_crash_handler() {
+      eprintln!("Crash handler stopped: {}", e);
+    }
+  });
+
+  std::thread::spawn(enclose! { (collector) move || {
+    if let Err(e) = run_stats_collector(collector) {
+      eprintln!("Statistics collector stopped: {}", e);
+    }
+  } });
+
   let mut io = IoHandler::new();
 
   io.add_method("host.domain-list", enclose! { (xc, xs) move |_: Params| {
@@ -54,6 +340,54 @@ fn main () {
     }
   } } );
 
+  io.add_method("host.dmesg", enclose! { (xc) move |params: Params| {
+    #[derive(Default, Deserialize)]
+    struct HostDmesgParams {
+      cursor: Option<u32>,
+      clear: Option<bool>
+    }
+
+    let parsed: HostDmesgParams = match params {
+      Params::None => HostDmesgParams::default(),
+      params => params.parse()?
+    };
+    match xc.lock().unwrap().read_console_ring(parsed.cursor, parsed.clear.unwrap_or(false)) {
+      Ok((data, cursor)) => Ok(json!({ "text": String::from_utf8_lossy(&data), "cursor": cursor })),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("host.debug-keys", enclose! { (xc) move |params: Params| {
+    #[derive(Deserialize)]
+    struct HostDebugKeysParams {
+      keys: String
+    }
+
+    let parsed: HostDebugKeysParams = params.parse()?;
+    match xc.lock().unwrap().send_debug_keys(&parsed.keys) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("host.stats", enclose! { (collector) move |params: Params| {
+    #[derive(Default, Deserialize)]
+    struct HostStatsParams {
+      start: Option<u64>,
+      end: Option<u64>,
+      step: Option<u64>
+    }
+
+    let parsed: HostStatsParams = match params {
+      Params::None => HostStatsParams::default(),
+      params => params.parse()?
+    };
+    match collector.host_stats(parsed.start, parsed.end, parsed.step) {
+      Ok(stats) => Ok(json!(stats)),
+      Err(e) => Err(make_error(&e))
+    }
+  } } );
+
   // See: https://stackoverflow.com/questions/31360003/is-there-another-option-to-share-an-arc-in-multiple-closures-besides-cloning-it
   io.add_method("vm.pause", enclose! { (xc) move |params: Params| {
     #[derive(Deserialize)]
@@ -94,6 +428,881 @@ fn main () {
     }
   } } );
 
+  io.add_method("vm.vcpu-context-get", enclose! { (xc) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVcpuContextGetParams {
+      dom_id: u32,
+      vcpu: u32
+    }
+
+    let parsed: VmVcpuContextGetParams = params.parse()?;
+    match vcpu_context::get(&xc.lock().unwrap(), parsed.dom_id, parsed.vcpu) {
+      Ok(registers) => Ok(json!(registers)),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.vcpu-context-set", enclose! { (xc) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVcpuContextSetParams {
+      dom_id: u32,
+      vcpu: u32,
+      registers: Value
+    }
+
+    let parsed: VmVcpuContextSetParams = params.parse()?;
+    let xc = xc.lock().unwrap();
+
+    // Only the given registers are modified, the others keep their current value.
+    let mut registers = match vcpu_context::get(&xc, parsed.dom_id, parsed.vcpu) {
+      Ok(registers) => json!(registers),
+      Err(e) => return Err(make_error(&e.to_string()))
+    };
+    merge_json(&mut registers, parsed.registers);
+    let registers: vcpu_context::X86Registers = match serde_json::from_value(registers) {
+      Ok(registers) => registers,
+      Err(e) => return Err(Error::invalid_params(e.to_string()))
+    };
+
+    match vcpu_context::set(&xc, parsed.dom_id, parsed.vcpu, &registers) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.coredump", enclose! { (xc, fmem) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmCoredumpParams {
+      dom_id: u32,
+      path: String
+    }
+
+    let parsed: VmCoredumpParams = params.parse()?;
+    match coredump::dump(&xc.lock().unwrap(), &fmem.lock().unwrap(), parsed.dom_id, Path::new(&parsed.path)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.crash-action-set", enclose! { (xs) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmCrashActionSetParams {
+      dom_id: u32,
+      action: String
+    }
+
+    let parsed: VmCrashActionSetParams = params.parse()?;
+    let action: vm::CrashAction = parsed.action.parse().map_err(|e: String| make_error(&e))?;
+    match vm::set_crash_action(&*xs.lock().unwrap(), parsed.dom_id, action) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  // Long operations: they use their own Xen handles to not block the other
+  // requests.
+  io.add_method("vm.save", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmSaveParams {
+      dom_id: u32,
+      path: String
+    }
+
+    let parsed: VmSaveParams = params.parse()?;
+    let mut qmp = device_model_qmp(&device_models, parsed.dom_id)?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    match save::save(&xc, &xs, parsed.dom_id, &parsed.path, qmp.as_mut()) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.restore", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmRestoreParams {
+      path: String
+    }
+
+    let parsed: VmRestoreParams = params.parse()?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    match save::restore(&xc, &xs, &parsed.path) {
+      Ok((dom_id, devices_state)) => Ok(json!({ "dom_id": dom_id, "devices_state": devices_state })),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.snapshot-memory", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmSnapshotMemoryParams {
+      dom_id: u32,
+      path: String
+    }
+
+    let parsed: VmSnapshotMemoryParams = params.parse()?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    let fmem = foreignmemory::ForeignMemory::new().map_err(make_error)?;
+    match snapshot::snapshot_memory(&xc, &xs, &fmem, parsed.dom_id, &parsed.path) {
+      Ok(stats) => Ok(json!(stats)),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.console-log", enclose! { (xc) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmConsoleLogParams {
+      dom_id: u32,
+      lines: Option<usize>
+    }
+
+    let parsed: VmConsoleLogParams = params.parse()?;
+    let info = xc.lock().unwrap().get_domain_info(parsed.dom_id).map_err(|e| make_error(&e.to_string()))?;
+    let uuid = xenctrl::get_uuid_from_domain_handle(&info.handle);
+    match console::tail(&console::log_path(&uuid), parsed.lines.unwrap_or(100)) {
+      Ok(output) => Ok(Value::String(output)),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.migrate", enclose! { (migrations, device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmMigrateParams {
+      dom_id: u32,
+      destination: String,
+      max_downtime_ms: Option<u64>,
+      bandwidth_limit: Option<u64>,
+      max_iterations: Option<u32>
+    }
+
+    let parsed: VmMigrateParams = params.parse()?;
+    let default = migration::MigrationConfig::default();
+    let config = migration::MigrationConfig {
+      max_downtime_ms: parsed.max_downtime_ms.unwrap_or(default.max_downtime_ms),
+      bandwidth_limit: parsed.bandwidth_limit.or(default.bandwidth_limit),
+      max_iterations: parsed.max_iterations.unwrap_or(default.max_iterations)
+    };
+
+    let dom_id = parsed.dom_id;
+    let qmp = device_model_qmp(&device_models, dom_id)?;
+    {
+      let mut migrations = migrations.lock().unwrap();
+      if let Some(MigrationState::Running { .. }) = migrations.get(&dom_id) {
+        return Err(make_error(&format!("domain {} is already migrating", dom_id)))
+      }
+      migrations.insert(dom_id, MigrationState::Running { progress: None });
+    }
+
+    std::thread::spawn(enclose! { (migrations) move || {
+      let set_state = |state| { migrations.lock().unwrap().insert(dom_id, state); };
+      let result = open_xen().map_err(String::from).and_then(|(xc, xs)| {
+        let host = match qmp {
+          Some(qmp) => migration::XenHost::new(&xc, &xs).with_device_model(qmp),
+          None => migration::XenHost::new(&xc, &xs)
+        };
+        migration::send(&host, dom_id, &parsed.destination, &config, &mut |progress| {
+          println!(
+            "Migration of domain {}: iteration {}, {} dirty pages, {} bytes sent",
+            dom_id, progress.iteration, progress.dirty_pages, progress.bytes_sent
+          );
+          set_state(MigrationState::Running { progress: Some(*progress) });
+        }).map_err(|e| e.to_string())
+      });
+      match result {
+        Ok((remote_dom_id, devices_state)) => set_state(MigrationState::Completed { remote_dom_id, devices_state }),
+        Err(error) => {
+          eprintln!("Failed to migrate domain {}: {}", dom_id, error);
+          set_state(MigrationState::Failed { error })
+        }
+      }
+    } });
+
+    Ok(Value::String(String::from("started")))
+  } } );
+
+  io.add_method("vm.migrate-status", enclose! { (migrations) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmMigrateStatusParams {
+      dom_id: u32
+    }
+
+    let parsed: VmMigrateStatusParams = params.parse()?;
+    match migrations.lock().unwrap().get(&parsed.dom_id) {
+      Some(state) => Ok(json!(state)),
+      None => Err(make_error(&format!("no migration of domain {}", parsed.dom_id)))
+    }
+  } } );
+
+  // The measure lasts the whole window: it uses its own handle.
+  io.add_method("vm.dirty-rate", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDirtyRateParams {
+      dom_id: u32,
+      window_ms: Option<u64>
+    }
+
+    let parsed: VmDirtyRateParams = params.parse()?;
+    let window = Duration::from_millis(parsed.window_ms.unwrap_or(1000));
+    let xc = xenctrl::Xenctrl::new().map_err(make_error)?;
+    match logdirty::measure_dirty_rate(&xc, parsed.dom_id, window) {
+      Ok(rate) => Ok(json!(rate)),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.stats", enclose! { (collector) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmStatsParams {
+      dom_id: u32,
+      start: Option<u64>,
+      end: Option<u64>,
+      step: Option<u64>
+    }
+
+    let parsed: VmStatsParams = params.parse()?;
+    match collector.domain_stats(parsed.dom_id, parsed.start, parsed.end, parsed.step) {
+      Ok(stats) => Ok(json!(stats)),
+      Err(e) => Err(make_error(&e))
+    }
+  } } );
+
+  io.add_method("vm.checkpoint-start", enclose! { (checkpoints, device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmCheckpointStartParams {
+      dom_id: u32,
+      path: Option<String>,
+      destination: Option<String>,
+      interval_ms: Option<u64>
+    }
+
+    let parsed: VmCheckpointStartParams = params.parse()?;
+    let destination = match (parsed.path, parsed.destination) {
+      (Some(path), None) => checkpoint::Destination::File(path),
+      (None, Some(destination)) => checkpoint::Destination::Peer(destination),
+      _ => return Err(make_error("expected one of path or destination"))
+    };
+    let config = checkpoint::CheckpointConfig {
+      interval_ms: parsed.interval_ms.unwrap_or(checkpoint::CheckpointConfig::default().interval_ms)
+    };
+
+    let dom_id = parsed.dom_id;
+    let mut qmp = device_model_qmp(&device_models, dom_id)?;
+    let control = checkpoint::CheckpointControl::new();
+    {
+      let mut checkpoints = checkpoints.lock().unwrap();
+      if let Some(Checkpoint { state: CheckpointState::Running, .. }) = checkpoints.get(&dom_id) {
+        return Err(make_error(&format!("domain {} is already checkpointed", dom_id)))
+      }
+      checkpoints.insert(dom_id, Checkpoint { control: control.clone(), state: CheckpointState::Running });
+    }
+
+    std::thread::spawn(enclose! { (checkpoints) move || {
+      let result = open_xen().map_err(String::from).and_then(|(xc, xs)| {
+        checkpoint::run(&xc, &xs, dom_id, &destination, &config, &control, qmp.as_mut()).map_err(|e| e.to_string())
+      });
+      let state = match result {
+        Ok(_) => CheckpointState::Stopped,
+        Err(error) => {
+          eprintln!("Failed to checkpoint domain {}: {}", dom_id, error);
+          CheckpointState::Failed { error }
+        }
+      };
+      if let Some(checkpoint) = checkpoints.lock().unwrap().get_mut(&dom_id) {
+        checkpoint.state = state;
+      }
+    } });
+
+    Ok(Value::String(String::from("started")))
+  } } );
+
+  io.add_method("vm.checkpoint-stop", enclose! { (checkpoints) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmCheckpointStopParams {
+      dom_id: u32
+    }
+
+    let parsed: VmCheckpointStopParams = params.parse()?;
+    match checkpoints.lock().unwrap().get(&parsed.dom_id) {
+      Some(checkpoint) => {
+        checkpoint.control.stop();
+        Ok(Value::String(String::from("success")))
+      },
+      None => Err(make_error(&format!("no checkpoint of domain {}", parsed.dom_id)))
+    }
+  } } );
+
+  io.add_method("vm.checkpoint-status", enclose! { (checkpoints) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmCheckpointStatusParams {
+      dom_id: u32
+    }
+
+    let parsed: VmCheckpointStatusParams = params.parse()?;
+    match checkpoints.lock().unwrap().get(&parsed.dom_id) {
+      Some(checkpoint) => {
+        let mut status = json!(checkpoint.state);
+        merge_json(&mut status, json!(checkpoint.control.stats()));
+        Ok(status)
+      },
+      None => Err(make_error(&format!("no checkpoint of domain {}", parsed.dom_id)))
+    }
+  } } );
+
+  io.add_method("host.standby-list", enclose! { (standby) move |_: Params| {
+    Ok(json!(standby.list()))
+  } } );
+
+  // Activating a domain restores it: own Xen handles.
+  io.add_method("vm.standby-activate", enclose! { (standby) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmStandbyActivateParams {
+      dom_id: u32
+    }
+
+    let parsed: VmStandbyActivateParams = params.parse()?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    match standby.activate(&xc, &xs, parsed.dom_id) {
+      Ok(checkpoint::Activation::Started(dom_id, devices_state)) => {
+        Ok(json!({ "dom_id": dom_id, "state": "started", "devices_state": devices_state }))
+      },
+      Ok(checkpoint::Activation::Pending) => Ok(json!({ "dom_id": parsed.dom_id, "state": "pending" })),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.standby-discard", enclose! { (standby) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmStandbyDiscardParams {
+      dom_id: u32
+    }
+
+    let parsed: VmStandbyDiscardParams = params.parse()?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    match standby.discard(&xc, &xs, parsed.dom_id) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  // Devices wait for their backend with their own xenstore handle, to not block the other requests.
+  io.add_method("vm.vbd-attach", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVbdAttachParams {
+      dom_id: u32,
+      vdev: String,
+      target: String,
+      backend: Option<String>,
+      backend_type: Option<String>,
+      mode: Option<String>,
+      device_type: Option<String>,
+      backend_dom_id: Option<u32>,
+      script: Option<String>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmVbdAttachParams = params.parse()?;
+    let mut config = vbd::VbdConfig::new(&parsed.vdev, &parsed.target);
+    if let Some(backend) = parsed.backend {
+      config.backend = backend.parse().map_err(|e: String| make_error(&e))?;
+      if config.backend == vbd::Backend::Native {
+        config.backend_type = String::from("raw");
+      }
+    }
+    if let Some(backend_type) = parsed.backend_type {
+      config.backend_type = backend_type;
+    }
+    if let Some(mode) = parsed.mode {
+      config.mode = mode.parse().map_err(|e: String| make_error(&e))?;
+    }
+    if let Some(device_type) = parsed.device_type {
+      config.device_type = device_type.parse().map_err(|e: String| make_error(&e))?;
+    }
+    config.backend_dom_id = parsed.backend_dom_id.unwrap_or(0);
+    config.script = parsed.script;
+    let timeout = parsed.timeout_ms.map_or(device::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let xs = xenstore::Xenstore::new().map_err(make_error)?;
+    match vbd::attach(&xs, parsed.dom_id, &config, timeout) {
+      Ok(devid) => Ok(json!({ "devid": devid })),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.vbd-detach", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVbdDetachParams {
+      dom_id: u32,
+      vdev: String,
+      force: Option<bool>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmVbdDetachParams = params.parse()?;
+    let devid = match vbd::parse_vdev(&parsed.vdev) {
+      Some(devid) => devid,
+      None => return Err(make_error(&format!("invalid virtual device `{}`", parsed.vdev)))
+    };
+    let timeout = parsed.timeout_ms.map_or(device::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let xs = xenstore::Xenstore::new().map_err(make_error)?;
+    match vbd::detach(&xs, parsed.dom_id, devid, parsed.force.unwrap_or(false), timeout) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.vbd-list", enclose! { (xs) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVbdListParams {
+      dom_id: u32
+    }
+
+    let parsed: VmVbdListParams = params.parse()?;
+    Ok(json!(vbd::list(&*xs.lock().unwrap(), parsed.dom_id)))
+  } } );
+
+  io.add_method("vm.vif-attach", enclose! { (xc) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVifAttachParams {
+      dom_id: u32,
+      devid: Option<u32>,
+      mac: Option<String>,
+      bridge: Option<String>,
+      script: Option<String>,
+      rate: Option<u64>,
+      mtu: Option<u32>,
+      ip: Option<Vec<String>>,
+      backend_dom_id: Option<u32>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmVifAttachParams = params.parse()?;
+    let default = vif::VifConfig::default();
+    let config = vif::VifConfig {
+      devid: parsed.devid,
+      mac: match parsed.mac {
+        Some(mac) => Some(mac.parse().map_err(|e: String| make_error(&e))?),
+        None => None
+      },
+      bridge: parsed.bridge.unwrap_or(default.bridge),
+      script: parsed.script.unwrap_or(default.script),
+      rate: parsed.rate,
+      mtu: parsed.mtu,
+      ip: parsed.ip.unwrap_or_default(),
+      backend_dom_id: parsed.backend_dom_id.unwrap_or(default.backend_dom_id)
+    };
+    let timeout = parsed.timeout_ms.map_or(device::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let uuid = match xc.lock().unwrap().get_domain_info(parsed.dom_id) {
+      Ok(info) => xenctrl::get_uuid_from_domain_handle(&info.handle),
+      Err(e) => return Err(make_error(&e.to_string()))
+    };
+    let xs = xenstore::Xenstore::new().map_err(make_error)?;
+    match vif::attach(&xs, parsed.dom_id, &uuid, &config, timeout) {
+      Ok((devid, mac)) => Ok(json!({ "devid": devid, "mac": mac.to_string() })),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.vif-detach", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVifDetachParams {
+      dom_id: u32,
+      devid: u32,
+      force: Option<bool>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmVifDetachParams = params.parse()?;
+    let timeout = parsed.timeout_ms.map_or(device::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let xs = xenstore::Xenstore::new().map_err(make_error)?;
+    match vif::detach(&xs, parsed.dom_id, parsed.devid, parsed.force.unwrap_or(false), timeout) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.vif-list", enclose! { (xs) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVifListParams {
+      dom_id: u32
+    }
+
+    let parsed: VmVifListParams = params.parse()?;
+    Ok(json!(vif::list(&*xs.lock().unwrap(), parsed.dom_id)))
+  } } );
+
+  // The device models of the HVM guests plug their PCI devices.
+  io.add_method("vm.pci-attach", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmPciAttachParams {
+      dom_id: u32,
+      bdf: String,
+      permissive: Option<bool>,
+      rdm_relaxed: Option<bool>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmPciAttachParams = params.parse()?;
+    let mut config = pci::PciConfig::new(parsed.bdf.parse().map_err(|e: pci::Error| make_error(&e.to_string()))?);
+    config.permissive = parsed.permissive.unwrap_or(false);
+    config.rdm_relaxed = parsed.rdm_relaxed.unwrap_or(true);
+    let timeout = parsed.timeout_ms.map_or(device::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    let mut qmp = device_models.qmp(parsed.dom_id, timeout).ok();
+    match pci::attach(&xc, &xs, &pci::Sysfs::default(), qmp.as_mut(), parsed.dom_id, &config, timeout) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.pci-detach", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmPciDetachParams {
+      dom_id: u32,
+      bdf: String,
+      force: Option<bool>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmPciDetachParams = params.parse()?;
+    let bdf: pci::Bdf = parsed.bdf.parse().map_err(|e: pci::Error| make_error(&e.to_string()))?;
+    let timeout = parsed.timeout_ms.map_or(device::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    let mut qmp = device_models.qmp(parsed.dom_id, timeout).ok();
+    let sysfs = pci::Sysfs::default();
+    let force = parsed.force.unwrap_or(false);
+    match pci::detach(&xc, &xs, &sysfs, qmp.as_mut(), parsed.dom_id, &bdf, force, timeout) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.pci-list", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmPciListParams {
+      dom_id: u32
+    }
+
+    let parsed: VmPciListParams = params.parse()?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    pci::release_destroyed_domains(&xc, &xs).map_err(|e| make_error(&e.to_string()))?;
+    let devices: Vec<String> = pci::list(&xs, parsed.dom_id).iter().map(pci::Bdf::to_string).collect();
+    Ok(json!(devices))
+  });
+
+  io.add_method("host.pci-assignable-list", |_| {
+    let devices: Vec<String> = pci::list_assignable(&pci::Sysfs::default()).iter().map(pci::Bdf::to_string).collect();
+    Ok(json!(devices))
+  });
+
+  io.add_method("host.pci-assignable-add", enclose! { (xs) move |params: Params| {
+    #[derive(Deserialize)]
+    struct HostPciAssignableAddParams {
+      bdf: String
+    }
+
+    let parsed: HostPciAssignableAddParams = params.parse()?;
+    let bdf: pci::Bdf = parsed.bdf.parse().map_err(|e: pci::Error| make_error(&e.to_string()))?;
+    match pci::make_assignable(&pci::Sysfs::default(), &*xs.lock().unwrap(), &bdf) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("host.pci-assignable-remove", |params: Params| {
+    #[derive(Deserialize)]
+    struct HostPciAssignableRemoveParams {
+      bdf: String,
+      rebind: Option<bool>
+    }
+
+    let parsed: HostPciAssignableRemoveParams = params.parse()?;
+    let bdf: pci::Bdf = parsed.bdf.parse().map_err(|e: pci::Error| make_error(&e.to_string()))?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    let removed = pci::release_destroyed_domains(&xc, &xs)
+      .and_then(|_| pci::remove_assignable(&pci::Sysfs::default(), &xs, &bdf, parsed.rebind.unwrap_or(true)));
+    match removed {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.device-model-start", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDeviceModelStartParams {
+      dom_id: u32,
+      config: devicemodel::DeviceModelConfig,
+      stubdomain_dom_id: Option<u32>,
+      on_exit: Option<supervisor::ExitPolicy>,
+      timeout_ms: Option<u64>
+    }
+
+    let parsed: VmDeviceModelStartParams = params.parse()?;
+    let dom_id = parsed.dom_id;
+    let on_exit = parsed.on_exit.unwrap_or_default();
+    if on_exit == supervisor::ExitPolicy::Restart && parsed.stubdomain_dom_id.is_some() {
+      return Err(make_error("a device model in a stubdomain can't be restarted"))
+    }
+    if device_models.contains(dom_id) {
+      return Err(make_error(&format!("device model of domain {} already started", dom_id)))
+    }
+    let timeout = parsed.timeout_ms.map_or(devicemodel::DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let xs = xenstore::Xenstore::new().map_err(make_error)?;
+    let result = match parsed.stubdomain_dom_id {
+      Some(stubdomain_dom_id) => {
+        devicemodel::DeviceModel::start_in_stubdomain(&xs, dom_id, stubdomain_dom_id, &parsed.config, timeout)
+      },
+      None => devicemodel::DeviceModel::start(&xs, dom_id, &parsed.config, timeout)
+    };
+    match result {
+      Ok(device_model) => {
+        let reply = json!({ "pid": device_model.pid(), "stubdomain_dom_id": device_model.stubdomain() });
+        device_models.add(device_model, &parsed.config, on_exit);
+        Ok(reply)
+      },
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.device-model-stop", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDeviceModelStopParams {
+      dom_id: u32
+    }
+
+    let parsed: VmDeviceModelStopParams = params.parse()?;
+    let (xc, xs) = open_xen().map_err(make_error)?;
+    let device_model = device_models.remove(parsed.dom_id).map_err(|e| make_error(&e.to_string()))?;
+    match device_model.stop(&xc, &xs) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  // Open a QMP session with the device model of a domain.
+  let open_qmp = enclose! { (device_models) move |dom_id: u32| {
+    device_models.qmp(dom_id, devicemodel::DEFAULT_TIMEOUT).map_err(|e| make_error(&e.to_string()))
+  } };
+
+  // The run state is only given by a QEMU in dom0.
+  io.add_method("vm.device-model-status", enclose! { (device_models, open_qmp) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDeviceModelStatusParams {
+      dom_id: u32
+    }
+
+    let parsed: VmDeviceModelStatusParams = params.parse()?;
+    let info = match device_models.info(parsed.dom_id) {
+      Some(info) => info,
+      None => return Err(make_error(&format!("no device model for domain {}", parsed.dom_id)))
+    };
+    let has_qmp = info.pid.is_some();
+    let mut status = json!(info);
+    if has_qmp {
+      match open_qmp(parsed.dom_id)?.query_status() {
+        Ok(qmp_status) => {
+          status["running"] = json!(qmp_status.running);
+          status["status"] = json!(qmp_status.status);
+        },
+        Err(e) => return Err(make_error(&e.to_string()))
+      }
+    }
+    Ok(status)
+  } } );
+
+  io.add_method("vm.device-model-device-add", enclose! { (open_qmp) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDeviceModelDeviceAddParams {
+      dom_id: u32,
+      driver: String,
+      id: String,
+      properties: Option<Value>
+    }
+
+    let parsed: VmDeviceModelDeviceAddParams = params.parse()?;
+    let properties = parsed.properties.unwrap_or(Value::Null);
+    match open_qmp(parsed.dom_id)?.device_add(&parsed.driver, &parsed.id, properties) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.device-model-device-del", enclose! { (open_qmp) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDeviceModelDeviceDelParams {
+      dom_id: u32,
+      id: String
+    }
+
+    let parsed: VmDeviceModelDeviceDelParams = params.parse()?;
+    match open_qmp(parsed.dom_id)?.device_del(&parsed.id) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.device-model-save", enclose! { (open_qmp) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmDeviceModelSaveParams {
+      dom_id: u32,
+      path: String,
+      live: Option<bool>
+    }
+
+    let parsed: VmDeviceModelSaveParams = params.parse()?;
+    match open_qmp(parsed.dom_id)?.save_devices_state(Path::new(&parsed.path), parsed.live.unwrap_or(false)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.vtpm-create", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVtpmCreateParams {
+      uuid: String,
+      overwrite: Option<bool>
+    }
+
+    let parsed: VmVtpmCreateParams = params.parse()?;
+    if device_models.uses_vtpm(&parsed.uuid) {
+      return Err(make_error(&format!("vTPM of VM {} is running", parsed.uuid)))
+    }
+    match vtpm::Swtpm::default().create(&parsed.uuid, parsed.overwrite.unwrap_or(false)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.vtpm-backup", |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVtpmBackupParams {
+      uuid: String,
+      path: String
+    }
+
+    let parsed: VmVtpmBackupParams = params.parse()?;
+    match vtpm::backup(&parsed.uuid, Path::new(&parsed.path)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  });
+
+  io.add_method("vm.vtpm-restore", enclose! { (device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmVtpmRestoreParams {
+      uuid: String,
+      path: String
+    }
+
+    let parsed: VmVtpmRestoreParams = params.parse()?;
+    if device_models.uses_vtpm(&parsed.uuid) {
+      return Err(make_error(&format!("vTPM of VM {} is running", parsed.uuid)))
+    }
+    match vtpm::restore(&parsed.uuid, Path::new(&parsed.path)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  // The variables are read and written by one call at a time, and only
+  // changed while QEMU does not use them.
+  let uefi_vars = Arc::new(Mutex::new(()));
+
+  io.add_method("vm.uefi-vars-create", enclose! { (uefi_vars, device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmUefiVarsCreateParams {
+      uuid: String,
+      template: Option<String>,
+      overwrite: Option<bool>
+    }
+
+    let parsed: VmUefiVarsCreateParams = params.parse()?;
+    if device_models.uses_uefi_vars(&parsed.uuid) {
+      return Err(make_error(&format!("UEFI variables of VM {} are in use", parsed.uuid)))
+    }
+    let _lock = uefi_vars.lock().unwrap();
+    match varstore::create(&parsed.uuid, parsed.template.as_deref(), parsed.overwrite.unwrap_or(false)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.uefi-vars-list", enclose! { (uefi_vars) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmUefiVarsListParams {
+      uuid: String
+    }
+
+    let parsed: VmUefiVarsListParams = params.parse()?;
+    let _lock = uefi_vars.lock().unwrap();
+    match varstore::load(&parsed.uuid) {
+      Ok(store) => Ok(json!(store.variables())),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  // The vendor is EFI_GLOBAL_VARIABLE if no `guid` is given.
+  let parse_guid = |guid: Option<String>| match guid {
+    Some(guid) => guid.parse::<varstore::Guid>().map_err(|e| make_error(&e.to_string())),
+    None => Ok(varstore::EFI_GLOBAL_VARIABLE)
+  };
+
+  io.add_method("vm.uefi-vars-set", enclose! { (uefi_vars, device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmUefiVarsSetParams {
+      uuid: String,
+      guid: Option<String>,
+      name: String,
+      attributes: Option<u32>,
+      data: String
+    }
+
+    let parsed: VmUefiVarsSetParams = params.parse()?;
+    if parsed.name.is_empty() {
+      return Err(make_error("empty variable name"))
+    }
+    if device_models.uses_uefi_vars(&parsed.uuid) {
+      return Err(make_error(&format!("UEFI variables of VM {} are in use", parsed.uuid)))
+    }
+    let variable = varstore::Variable {
+      guid: parse_guid(parsed.guid)?,
+      name: parsed.name,
+      attributes: parsed.attributes.unwrap_or(varstore::DEFAULT_ATTRIBUTES),
+      timestamp: None,
+      data: decode_hex(&parsed.data).ok_or_else(|| make_error("data must be hexadecimal"))?
+    };
+    let _lock = uefi_vars.lock().unwrap();
+    match varstore::update(&parsed.uuid, |store| store.set(variable)) {
+      Ok(_) => Ok(Value::String(String::from("success"))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
+  io.add_method("vm.uefi-vars-delete", enclose! { (uefi_vars, device_models) move |params: Params| {
+    #[derive(Deserialize)]
+    struct VmUefiVarsDeleteParams {
+      uuid: String,
+      guid: Option<String>,
+      name: String
+    }
+
+    let parsed: VmUefiVarsDeleteParams = params.parse()?;
+    if device_models.uses_uefi_vars(&parsed.uuid) {
+      return Err(make_error(&format!("UEFI variables of VM {} are in use", parsed.uuid)))
+    }
+    let guid = parse_guid(parsed.guid)?;
+    let name = parsed.name;
+    let _lock = uefi_vars.lock().unwrap();
+    match varstore::update(&parsed.uuid, |store| store.delete(&guid, &name)) {
+      Ok(true) => Ok(Value::String(String::from("success"))),
+      Ok(false) => Err(make_error(&format!("no UEFI variable {}:{}", guid, name))),
+      Err(e) => Err(make_error(&e.to_string()))
+    }
+  } } );
+
   let server = ServerBuilder::new(io)
     .threads(2)
     .rest_api(RestApi::Unsecure)
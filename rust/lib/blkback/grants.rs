@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use crate::xenctrl;
+
+// =============================================================================
+// Pages granted by the frontend for the data of the requests.
+//
+// With persistent grants (`feature-persistent`), the frontend reuses a pool of
+// pages granted once in read-write mode: they are kept mapped, up to
+// `MAX_PERSISTENT_GRANTS`. The other pages are mapped for one segment.
+// =============================================================================
+
+// Like blkback: 3 times the segments of a full ring.
+pub const MAX_PERSISTENT_GRANTS: usize = 1056;
+
+// A granted page mapped in our address space.
+pub trait GrantedPage {
+  fn as_slice (&self) -> &[u8];
+
+  fn as_mut_slice (&mut self) -> &mut [u8];
+}
+
+pub trait GrantMapper {
+  type Page: GrantedPage;
+
+  fn map (&self, gref: u32, writable: bool) -> xenctrl::Result<Self::Page>;
+}
+
+pub struct Grants<M: GrantMapper> {
+  mapper: M,
+  persistent: bool,
+  mapped: HashMap<u32, M::Page>
+}
+
+impl<M: GrantMapper> Grants<M> {
+  pub fn new (mapper: M, persistent: bool) -> Self {
+    Self { mapper, persistent, mapped: HashMap::new() }
+  }
+
+  // Count of the persistent grants mapped.
+  pub fn persistent_count (&self) -> usize {
+    self.mapped.len()
+  }
+
+  fn with_page<T, F: FnOnce(&mut M::Page) -> T> (&mut self, gref: u32, writable: bool, f: F) -> xenctrl::Result<T> {
+    if self.persistent {
+      if !self.mapped.contains_key(&gref) && self.mapped.len() < MAX_PERSISTENT_GRANTS {
+        // A page granted read-only is not persistent.
+        if let Ok(page) = self.mapper.map(gref, true) {
+          self.mapped.insert(gref, page);
+        }
+      }
+      if let Some(page) = self.mapped.get_mut(&gref) {
+        return Ok(f(page))
+      }
+    }
+    let mut page = self.mapper.map(gref, writable)?;
+    Ok(f(&mut page))
+  }
+
+  // Read a page, to write its data on the disk.
+  pub fn read<T, F: FnOnce(&[u8]) -> T> (&mut self, gref: u32, f: F) -> xenctrl::Result<T> {
+    self.with_page(gref, false, |page| f(page.as_slice()))
+  }
+
+  // Write a page, with data read from the disk.
+  pub fn write<T, F: FnOnce(&mut [u8]) -> T> (&mut self, gref: u32, f: F) -> xenctrl::Result<T> {
+    self.with_page(gref, true, |page| f(page.as_mut_slice()))
+  }
+}
@@ -0,0 +1,190 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use super::qcow2::Qcow2;
+use super::ring::SECTOR_SIZE;
+
+// =============================================================================
+// Disk images served by the backend.
+// =============================================================================
+
+pub enum Error {
+  Io(std::io::Error),
+  // The image is corrupted.
+  Invalid(String),
+  // Valid, but uses a feature we do not implement.
+  Unsupported(String)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Invalid(details) => write!(f, "invalid image: {}", details),
+      Error::Unsupported(details) => write!(f, "unsupported image: {}", details)
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+  Raw,
+  Qcow2
+}
+
+impl std::str::FromStr for Format {
+  type Err = String;
+
+  fn from_str (value: &str) -> std::result::Result<Self, Self::Err> {
+    match value {
+      "raw" => Ok(Format::Raw),
+      "qcow2" => Ok(Format::Qcow2),
+      _ => Err(format!("invalid image format: `{}`", value))
+    }
+  }
+}
+
+impl std::fmt::Display for Format {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Format::Raw => write!(f, "raw"),
+      Format::Qcow2 => write!(f, "qcow2")
+    }
+  }
+}
+
+// A disk, accessed at byte offsets. The backend only reads and writes whole
+// sectors within its size.
+pub trait Image: Send {
+  // Size of the disk in bytes, a multiple of the sector size.
+  fn size (&self) -> u64;
+
+  fn read_at (&mut self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+  fn write_at (&mut self, data: &[u8], offset: u64) -> Result<()>;
+
+  // Make the written data durable.
+  fn flush (&mut self) -> Result<()>;
+
+  // Alignment of the discarded ranges, None if discard is not supported.
+  fn discard_granularity (&self) -> Option<u64>;
+
+  // Give back the space of a range, its data is then undefined. Parts of the
+  // range smaller than the granularity may be kept.
+  fn discard (&mut self, offset: u64, length: u64) -> Result<()>;
+}
+
+pub fn open (path: &Path, format: Format, read_only: bool) -> Result<Box<dyn Image>> {
+  let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+  Ok(match format {
+    Format::Raw => Box::new(Raw::new(file)?),
+    Format::Qcow2 => Box::new(Qcow2::open(file, read_only)?)
+  })
+}
+
+// Punch a hole in a file, it then reads as zeroes.
+pub(super) fn punch_hole (file: &File, offset: u64, length: u64) -> Result<()> {
+  let ret = unsafe {
+    libc::fallocate(
+      file.as_raw_fd(), libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+      offset as libc::off_t, length as libc::off_t
+    )
+  };
+  match ret {
+    0 => Ok(()),
+    _ => Err(std::io::Error::last_os_error().into())
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// A file or a block device holding the disk as is.
+pub struct Raw {
+  file: File,
+  size: u64,
+  // Holes can be punched in regular files only.
+  is_file: bool
+}
+
+impl Raw {
+  pub fn new (mut file: File) -> Result<Self> {
+    let is_file = file.metadata()?.is_file();
+    // The length of a block device is only given by seeking its end.
+    let size = file.seek(SeekFrom::End(0))? / SECTOR_SIZE * SECTOR_SIZE;
+    Ok(Self { file, size, is_file })
+  }
+}
+
+impl Image for Raw {
+  fn size (&self) -> u64 {
+    self.size
+  }
+
+  fn read_at (&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+    Ok(self.file.read_exact_at(buf, offset)?)
+  }
+
+  fn write_at (&mut self, data: &[u8], offset: u64) -> Result<()> {
+    Ok(self.file.write_all_at(data, offset)?)
+  }
+
+  fn flush (&mut self) -> Result<()> {
+    Ok(self.file.sync_data()?)
+  }
+
+  fn discard_granularity (&self) -> Option<u64> {
+    if self.is_file { Some(SECTOR_SIZE) } else { None }
+  }
+
+  fn discard (&mut self, offset: u64, length: u64) -> Result<()> {
+    punch_hole(&self.file, offset, length)
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::TempDir;
+
+  #[test]
+  fn raw () {
+    let dir = TempDir::new("raw");
+    let path = dir.join("image.raw");
+    std::fs::write(&path, vec![0x5au8; 3 * SECTOR_SIZE as usize + 100]).unwrap();
+    let mut image = open(&path, Format::Raw, false).ok().unwrap();
+    assert_eq!(image.size(), 3 * SECTOR_SIZE);
+    assert_eq!(image.discard_granularity(), Some(SECTOR_SIZE));
+
+    image.write_at(&[1u8; 512], SECTOR_SIZE).ok().unwrap();
+    image.discard(2 * SECTOR_SIZE, SECTOR_SIZE).ok().unwrap();
+    image.flush().ok().unwrap();
+    let mut buf = vec![0u8; 3 * SECTOR_SIZE as usize];
+    image.read_at(&mut buf, 0).ok().unwrap();
+    assert!(buf[..512].iter().all(|byte| *byte == 0x5a));
+    assert!(buf[512..1024].iter().all(|byte| *byte == 1));
+    assert!(buf[1024..].iter().all(|byte| *byte == 0));
+
+    let mut image = open(&path, Format::Raw, true).ok().unwrap();
+    assert!(image.write_at(&[0u8; 512], 0).is_err());
+  }
+
+  #[test]
+  fn formats () {
+    assert!("raw".parse::<Format>().ok().unwrap() == Format::Raw);
+    assert_eq!("qcow2".parse::<Format>().ok().unwrap().to_string(), "qcow2");
+    assert_eq!("vhd".parse::<Format>().err().unwrap(), "invalid image format: `vhd`");
+  }
+}
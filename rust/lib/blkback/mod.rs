@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::device::vbd::{self, DeviceType, Mode};
+use super::device::{self, XenbusState};
+use super::evtchn::EventChannel;
+use super::gnttab::{self, GrantTable};
+use super::xenctrl;
+use super::xenstore::{self, Store};
+
+pub mod grants;
+pub mod image;
+pub mod qcow2;
+pub mod ring;
+
+use grants::{GrantMapper, GrantedPage, Grants};
+use image::{Format, Image};
+use ring::{BackRing, Operation, Request, Response, Segment, SECTOR_SIZE};
+
+// =============================================================================
+// Native block backend, serving the vbds from raw files or qcow2 images.
+//
+// Its devices have their own backend kind (`vbd::NATIVE_BACKEND_KIND`), so the
+// kernel blkback and QEMU leave them alone, and no hotplug script is run.
+// `Backend::poll` follows their backend directories in dom0: the image is
+// opened when the toolstack creates a device, and its ring is served by a
+// thread once the frontend is connected, until either side closes. The I/O
+// counters of a served disk are written in its `statistics` directory.
+//
+// Flush, discard and persistent grants are supported; indirect descriptors
+// and multi-page rings are not.
+// =============================================================================
+
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The serving threads check if they must stop at this interval.
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+const STATISTICS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Flags of the `info` node.
+const VDISK_CDROM: u32 = 1;
+const VDISK_REMOVABLE: u32 = 2;
+const VDISK_READONLY: u32 = 4;
+
+pub enum Error {
+  Xen(xenctrl::Error),
+  Xenstore(&'static str),
+  // A Xen interface could not be opened.
+  Open(&'static str),
+  Image(image::Error),
+  Ring(ring::Error),
+  InvalidConfig(String)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Xen(e) => write!(f, "{}", e),
+      Error::Xenstore(details) => write!(f, "xenstore error: {}", details),
+      Error::Open(details) => write!(f, "{}", details),
+      Error::Image(e) => write!(f, "{}", e),
+      Error::Ring(e) => write!(f, "{}", e),
+      Error::InvalidConfig(details) => write!(f, "invalid device: {}", details)
+    }
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Xen(e)
+  }
+}
+
+impl From<image::Error> for Error {
+  fn from (e: image::Error) -> Self {
+    Error::Image(e)
+  }
+}
+
+impl From<ring::Error> for Error {
+  fn from (e: ring::Error) -> Self {
+    Error::Ring(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+fn status<T, E> (result: std::result::Result<T, E>) -> i16 {
+  match result {
+    Ok(_) => ring::STATUS_OKAY,
+    Err(_) => ring::STATUS_ERROR
+  }
+}
+
+// I/O counters of a disk, named like the `statistics` of blkback in sysfs.
+// The sectors are counted once the request succeeded.
+#[derive(Default)]
+pub struct Statistics {
+  pub rd_req: AtomicU64,
+  pub wr_req: AtomicU64,
+  pub f_req: AtomicU64,
+  pub ds_req: AtomicU64,
+  pub rd_sect: AtomicU64,
+  pub wr_sect: AtomicU64
+}
+
+impl Statistics {
+  pub fn nodes (&self) -> Vec<(&'static str, u64)> {
+    let counters = [
+      ("rd_req", &self.rd_req),
+      ("wr_req", &self.wr_req),
+      ("f_req", &self.f_req),
+      ("ds_req", &self.ds_req),
+      ("rd_sect", &self.rd_sect),
+      ("wr_sect", &self.wr_sect)
+    ];
+    counters.iter().map(|(name, counter)| (*name, counter.load(Ordering::Relaxed))).collect()
+  }
+
+  fn account (&self, request: &Request, status: i16) {
+    let add = |counter: &AtomicU64, value: u64| { counter.fetch_add(value, Ordering::Relaxed); };
+    let sectors = |segments: &[Segment]| {
+      if status != ring::STATUS_OKAY {
+        return 0
+      }
+      segments.iter().map(|segment| segment.length() as u64).sum::<u64>() / SECTOR_SIZE
+    };
+    match &request.operation {
+      Operation::Read(segments) => {
+        add(&self.rd_req, 1);
+        add(&self.rd_sect, sectors(segments));
+      },
+      Operation::Write(segments) | Operation::WriteBarrier(segments) => {
+        add(&self.wr_req, 1);
+        add(&self.wr_sect, sectors(segments));
+      },
+      Operation::FlushDiskCache(segments) => {
+        add(&self.f_req, 1);
+        add(&self.wr_sect, sectors(segments));
+      },
+      Operation::Discard { .. } => add(&self.ds_req, 1),
+      Operation::Invalid | Operation::Unsupported => ()
+    }
+  }
+}
+
+// The disk of a connected frontend: serves the requests of its ring.
+pub struct Disk<M: GrantMapper> {
+  image: Box<dyn Image>,
+  read_only: bool,
+  ring: BackRing,
+  grants: Grants<M>,
+  statistics: Arc<Statistics>
+}
+
+impl<M: GrantMapper> Disk<M> {
+  pub fn new (
+    image: Box<dyn Image>, read_only: bool, ring: BackRing, grants: Grants<M>, statistics: Arc<Statistics>
+  ) -> Self {
+    Self { image, read_only, ring, grants, statistics }
+  }
+
+  // Answer the requests of the ring until it is empty. Returns true if the
+  // frontend must be notified.
+  pub fn process (&mut self, page: &mut [u8]) -> ring::Result<bool> {
+    let mut notify = false;
+    loop {
+      let requests = self.ring.take_requests(page)?;
+      if requests.is_empty() {
+        if self.ring.final_check(page) {
+          continue
+        }
+        return Ok(notify)
+      }
+      let responses: Vec<Response> = requests.iter()
+        .map(|request| {
+          let status = self.handle(request);
+          self.statistics.account(request, status);
+          Response::new(request, status)
+        })
+        .collect();
+      notify |= self.ring.push_responses(page, &responses);
+    }
+  }
+
+  fn handle (&mut self, request: &Request) -> i16 {
+    let writable = !self.read_only;
+    match &request.operation {
+      Operation::Read(segments) => self.transfer(request.sector, segments, false),
+      Operation::Write(segments) if writable => self.transfer(request.sector, segments, true),
+      Operation::FlushDiskCache(segments) if writable => {
+        // The frontend can write data with the flush.
+        if !segments.is_empty() {
+          let status = self.transfer(request.sector, segments, true);
+          if status != ring::STATUS_OKAY {
+            return status
+          }
+        }
+        status(self.image.flush())
+      },
+      Operation::Discard { sectors, secure: false } if writable && self.image.discard_granularity().is_some() => {
+        self.discard(request.sector, *sectors)
+      },
+      Operation::Write(_) | Operation::FlushDiskCache(_) | Operation::Invalid => ring::STATUS_ERROR,
+      Operation::WriteBarrier(_) | Operation::Discard { .. } | Operation::Unsupported => ring::STATUS_NOT_SUPPORTED
+    }
+  }
+
+  // Offset of a range of the disk, None if it is not on the disk.
+  fn range (&self, sector: u64, length: u64) -> Option<u64> {
+    let offset = sector.checked_mul(SECTOR_SIZE)?;
+    if offset.checked_add(length)? > self.image.size() {
+      return None
+    }
+    Some(offset)
+  }
+
+  fn discard (&mut self, sector: u64, sectors: u64) -> i16 {
+    let length = match sectors.checked_mul(SECTOR_SIZE) {
+      Some(length) => length,
+      None => return ring::STATUS_ERROR
+    };
+    match self.range(sector, length) {
+      Some(offset) => status(self.image.discard(offset, length)),
+      None => ring::STATUS_ERROR
+    }
+  }
+
+  // Copy the segments from (`write`) or to the disk, they are contiguous on
+  // the disk.
+  fn transfer (&mut self, sector: u64, segments: &[Segment], write: bool) -> i16 {
+    if segments.is_empty() || !segments.iter().all(Segment::is_valid) {
+      return ring::STATUS_ERROR
+    }
+    let length = segments.iter().map(|segment| segment.length() as u64).sum();
+    let mut offset = match self.range(sector, length) {
+      Some(offset) => offset,
+      None => return ring::STATUS_ERROR
+    };
+
+    let Self { image, grants, .. } = self;
+    for segment in segments {
+      let data = segment.offset()..segment.offset() + segment.length();
+      let result = if write {
+        grants.read(segment.gref, |page| image.write_at(&page[data], offset))
+      } else {
+        grants.write(segment.gref, |page| image.read_at(&mut page[data], offset))
+      };
+      match result {
+        Ok(Ok(())) => offset += segment.length() as u64,
+        _ => return ring::STATUS_ERROR
+      }
+    }
+    ring::STATUS_OKAY
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+impl GrantedPage for gnttab::Mapping<'_> {
+  fn as_slice (&self) -> &[u8] {
+    gnttab::Mapping::as_slice(self)
+  }
+
+  fn as_mut_slice (&mut self) -> &mut [u8] {
+    gnttab::Mapping::as_mut_slice(self)
+  }
+}
+
+// The pages granted by a frontend.
+struct DomainGrants<'a> {
+  xgt: &'a GrantTable,
+  dom_id: u32
+}
+
+impl<'a> GrantMapper for DomainGrants<'a> {
+  type Page = gnttab::Mapping<'a>;
+
+  fn map (&self, gref: u32, writable: bool) -> xenctrl::Result<Self::Page> {
+    self.xgt.map(self.dom_id, &[gref], writable)
+  }
+}
+
+// What the frontend gives to connect.
+struct Connection {
+  dom_id: u32,
+  ring_ref: u32,
+  port: u32,
+  abi: ring::Abi,
+  persistent: bool
+}
+
+// Serve the ring of a frontend until `stop` is set.
+fn serve (
+  connection: Connection, image: Box<dyn Image>, read_only: bool, statistics: Arc<Statistics>, stop: &AtomicBool
+) -> Result<()> {
+  let xgt = GrantTable::new().map_err(Error::Open)?;
+  let evtchn = EventChannel::new().map_err(Error::Open)?;
+  let mut ring_page = xgt.map(connection.dom_id, &[connection.ring_ref], true)?;
+  let port = evtchn.bind_interdomain(connection.dom_id, connection.port)?;
+
+  let grants = Grants::new(DomainGrants { xgt: &xgt, dom_id: connection.dom_id }, connection.persistent);
+  let ring = BackRing::new(ring_page.as_slice(), connection.abi);
+  let mut disk = Disk::new(image, read_only, ring, grants, statistics);
+  while !stop.load(Ordering::SeqCst) {
+    if disk.process(ring_page.as_mut_slice())? {
+      evtchn.notify(port)?;
+    }
+    if let Some(port) = evtchn.wait(Some(WAIT_INTERVAL))? {
+      evtchn.unmask(port)?;
+    }
+  }
+  Ok(())
+}
+
+struct Worker {
+  stop: Arc<AtomicBool>,
+  done: Arc<AtomicBool>,
+  thread: JoinHandle<Result<()>>,
+  statistics: Arc<Statistics>,
+  // Last write of the statistics.
+  published: Option<Instant>
+}
+
+impl Worker {
+  fn stop (self) -> Result<()> {
+    self.stop.store(true, Ordering::SeqCst);
+    self.thread.join().unwrap_or_else(|_| Err(Error::InvalidConfig(String::from("serving thread panicked"))))
+  }
+}
+
+// A device of the backend.
+struct Device {
+  path: String,
+  frontend_path: String,
+  frontend_dom_id: u32,
+  // Opened when the device is created, until the frontend connects.
+  image: Option<Box<dyn Image>>,
+  // The image could not be opened, the device stays closed.
+  failed: bool,
+  worker: Option<Worker>
+}
+
+impl Device {
+  fn read (&self, store: &dyn Store, key: &str) -> Result<String> {
+    store.read(&format!("{}/{}", self.path, key)).map_err(|_| Error::Xenstore("missing backend node"))
+  }
+
+  fn read_frontend (&self, store: &dyn Store, key: &str) -> Option<String> {
+    store.read(&format!("{}/{}", self.frontend_path, key)).ok()
+  }
+
+  fn write_nodes (&self, store: &dyn Store, nodes: &[(&str, String)]) -> Result<()> {
+    store.atomically(&mut |store| {
+      for (key, value) in nodes {
+        store.write(&format!("{}/{}", self.path, key), value)?;
+      }
+      Ok(())
+    }).map_err(|_| Error::Xenstore("failed to write backend nodes"))
+  }
+
+  fn set_state (&self, store: &dyn Store, state: XenbusState) {
+    let _ = store.write(&format!("{}/state", self.path), &state.value().to_string());
+  }
+
+  fn is_read_only (&self, store: &dyn Store) -> Result<bool> {
+    let mode: Mode = self.read(store, "mode")?.parse().map_err(Error::InvalidConfig)?;
+    Ok(mode == Mode::ReadOnly)
+  }
+
+  fn open_image (&self, store: &dyn Store) -> Result<Box<dyn Image>> {
+    let format: Format = self.read(store, "type")?.parse().map_err(Error::InvalidConfig)?;
+    let path = self.read(store, "params")?;
+    Ok(image::open(Path::new(&path), format, self.is_read_only(store)?)?)
+  }
+
+  // Open the image and publish the features, the toolstack then waits for
+  // the frontend.
+  fn init (&mut self, store: &dyn Store) -> Result<()> {
+    let image = self.open_image(store)?;
+    let mut nodes = vec![
+      ("feature-flush-cache", String::from("1")),
+      ("feature-persistent", String::from("1")),
+      // Not run, and the hotplug handlers skip the device.
+      ("hotplug-status", String::from("connected")),
+      ("state", XenbusState::InitWait.value().to_string())
+    ];
+    if let (false, Some(granularity)) = (self.is_read_only(store)?, image.discard_granularity()) {
+      nodes.push(("feature-discard", String::from("1")));
+      nodes.push(("discard-granularity", granularity.to_string()));
+      nodes.push(("discard-alignment", String::from("0")));
+    }
+    self.write_nodes(store, &nodes)?;
+    self.image = Some(image);
+    Ok(())
+  }
+
+  // Serve the ring given by the frontend.
+  fn connect (&mut self, store: &dyn Store) -> Result<()> {
+    let read_u32 = |key: &str| -> Result<u32> {
+      self.read_frontend(store, key).and_then(|value| value.parse().ok())
+        .ok_or_else(|| Error::InvalidConfig(format!("invalid frontend node {}", key)))
+    };
+    let connection = Connection {
+      dom_id: self.frontend_dom_id,
+      ring_ref: read_u32("ring-ref")?,
+      port: read_u32("event-channel")?,
+      abi: ring::Abi::from_protocol(self.read_frontend(store, "protocol").as_deref())?,
+      persistent: self.read_frontend(store, "feature-persistent").as_deref() == Some("1")
+    };
+
+    let read_only = self.is_read_only(store)?;
+    let image = match self.image.take() {
+      Some(image) => image,
+      None => self.open_image(store)?
+    };
+    let mut info = if read_only { VDISK_READONLY } else { 0 };
+    if self.read(store, "device-type").ok().and_then(|value| value.parse().ok()) == Some(DeviceType::Cdrom) {
+      info |= VDISK_CDROM;
+    }
+    if self.read(store, "removable").ok().as_deref() == Some("1") {
+      info |= VDISK_REMOVABLE;
+    }
+    let nodes = [
+      ("sectors", (image.size() / SECTOR_SIZE).to_string()),
+      ("sector-size", SECTOR_SIZE.to_string()),
+      ("info", info.to_string())
+    ];
+    self.write_nodes(store, &nodes)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let statistics = Arc::new(Statistics::default());
+    let thread = std::thread::spawn({
+      let (stop, done, statistics) = (stop.clone(), done.clone(), statistics.clone());
+      move || {
+        let result = serve(connection, image, read_only, statistics, &stop);
+        done.store(true, Ordering::SeqCst);
+        result
+      }
+    });
+    self.worker = Some(Worker { stop, done, thread, statistics, published: None });
+    self.set_state(store, XenbusState::Connected);
+    Ok(())
+  }
+
+  fn is_serving (&self) -> bool {
+    match &self.worker {
+      Some(worker) => !worker.done.load(Ordering::SeqCst),
+      None => false
+    }
+  }
+
+  fn publish_statistics (&mut self, store: &dyn Store) {
+    let Self { path, worker, .. } = self;
+    let worker = match worker {
+      Some(worker) => worker,
+      None => return
+    };
+    if worker.published.map_or(false, |time| time.elapsed() < STATISTICS_INTERVAL) {
+      return
+    }
+    worker.published = Some(Instant::now());
+    let nodes = worker.statistics.nodes();
+    let result = store.atomically(&mut |store| {
+      for (name, value) in &nodes {
+        store.write(&format!("{}/statistics/{}", path, name), &value.to_string())?;
+      }
+      Ok(())
+    });
+    if result.is_err() {
+      eprintln!("Failed to write the statistics of block device {}.", path);
+    }
+  }
+
+  fn disconnect (&mut self) {
+    if let Some(worker) = self.worker.take() {
+      if let Err(e) = worker.stop() {
+        eprintln!("Failed to serve block device {}: {}", self.path, e);
+      }
+    }
+  }
+
+  // Follow the states of both sides.
+  fn update (&mut self, store: &dyn Store) {
+    let state = device::read_state(store, &self.path);
+    let frontend_state = device::read_state(store, &self.frontend_path);
+    let online = self.read(store, "online").ok().as_deref() == Some("1");
+    if self.is_serving() {
+      self.publish_statistics(store);
+    }
+
+    match (state, frontend_state) {
+      (XenbusState::Initialising, _) => {
+        if let Err(e) = self.init(store) {
+          self.fail(store, &e);
+        }
+      },
+      (XenbusState::InitWait, XenbusState::Initialised) | (XenbusState::InitWait, XenbusState::Connected) => {
+        if let Err(e) = self.connect(store) {
+          eprintln!("Failed to connect block device {}: {}", self.path, e);
+          self.set_state(store, XenbusState::Closing);
+        }
+      },
+      (XenbusState::Connected, XenbusState::Closing) => {
+        self.disconnect();
+        self.set_state(store, XenbusState::Closing);
+      },
+      (XenbusState::Connected, XenbusState::Closed) | (XenbusState::Connected, XenbusState::Unknown) => {
+        self.disconnect();
+        self.set_state(store, XenbusState::Closed);
+      },
+      // Connected before the daemon started.
+      (XenbusState::Connected, _) if self.worker.is_none() => {
+        if let Err(e) = self.connect(store) {
+          eprintln!("Failed to reconnect block device {}: {}", self.path, e);
+          self.set_state(store, XenbusState::Closing);
+        }
+      },
+      // The ring was corrupted by the frontend, or a Xen call failed.
+      (XenbusState::Connected, _) if !self.is_serving() => {
+        self.disconnect();
+        self.set_state(store, XenbusState::Closing);
+      },
+      // Asked by the toolstack, or the frontend closes: done once it is gone.
+      (XenbusState::Closing, XenbusState::Connected) => self.disconnect(),
+      (XenbusState::Closing, _) => {
+        self.disconnect();
+        self.set_state(store, XenbusState::Closed);
+      },
+      // The frontend reconnects, after a kexec for instance.
+      (XenbusState::Closed, XenbusState::Initialising) if online && !self.failed => {
+        self.set_state(store, XenbusState::Initialising);
+      },
+      _ => ()
+    }
+  }
+
+  // The error is reported to the toolstack like the hotplug scripts do.
+  fn fail (&mut self, store: &dyn Store, error: &Error) {
+    eprintln!("Failed to open block device {}: {}", self.path, error);
+    self.failed = true;
+    let _ = self.write_nodes(store, &[
+      ("hotplug-error", error.to_string()),
+      ("hotplug-status", String::from("error")),
+      ("state", XenbusState::Closed.value().to_string())
+    ]);
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// The devices of the native backend in dom0.
+pub struct Backend<'a> {
+  store: &'a dyn Store,
+  devices: HashMap<String, Device>
+}
+
+impl<'a> Backend<'a> {
+  pub fn new (store: &'a dyn Store) -> Self {
+    Self { store, devices: HashMap::new() }
+  }
+
+  fn open_device (&self, path: &str) -> Option<Device> {
+    let read = |key: &str| self.store.read(&format!("{}/{}", path, key)).ok();
+    Some(Device {
+      path: path.to_string(),
+      frontend_path: read("frontend")?,
+      frontend_dom_id: read("frontend-id")?.parse().ok()?,
+      image: None,
+      failed: false,
+      worker: None
+    })
+  }
+
+  pub fn poll (&mut self) {
+    let root = format!("{}/backend/{}", xenstore::get_domain_path(0), vbd::NATIVE_BACKEND_KIND);
+    let paths: Vec<String> = self.store.directory(&root).unwrap_or_default().iter().flat_map(|dom_id| {
+      let domain_path = format!("{}/{}", root, dom_id);
+      self.store.directory(&domain_path).unwrap_or_default().into_iter()
+        .map(move |devid| format!("{}/{}", domain_path, devid))
+    }).collect();
+
+    // Removed by the toolstack.
+    let removed: Vec<String> = self.devices.keys().filter(|path| !paths.contains(path)).cloned().collect();
+    for path in removed {
+      if let Some(mut device) = self.devices.remove(&path) {
+        device.disconnect();
+      }
+    }
+
+    for path in paths {
+      if !self.devices.contains_key(&path) {
+        // Not fully written yet.
+        match self.open_device(&path) {
+          Some(device) => { self.devices.insert(path.clone(), device); },
+          None => continue
+        }
+      }
+      if let Some(device) = self.devices.get_mut(&path) {
+        device.update(self.store);
+      }
+    }
+  }
+}
@@ -0,0 +1,628 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use super::image::{self, Error, Image, Result};
+use super::ring::SECTOR_SIZE;
+
+// =============================================================================
+// qcow2 images (see docs/interop/qcow2.txt in QEMU).
+//
+// Versions 2 and 3 are supported, without backing file, encryption, compressed
+// clusters or incompatible features, with 16-bit refcounts. Images with
+// internal snapshots can only be read, their clusters may be shared.
+//
+// The L1 and refcount tables are kept in memory, the L2 tables and refcount
+// blocks are read and written in place. Clusters are allocated at the end of
+// the file, and are not reused once discarded: their space is given back by
+// punching holes. What metadata points to is always written before it, so a
+// crash can only leak clusters.
+// =============================================================================
+
+const MAGIC: u32 = 0x5146_49fb;
+
+const V2_HEADER_LENGTH: usize = 72;
+const V3_HEADER_LENGTH: usize = 104;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+const REFCOUNT_ORDER: u32 = 4;
+
+// Tables kept in memory: 32 MiB each, an L1 table of this size maps 256 TiB
+// with 64 KiB clusters.
+const MAX_TABLE_SIZE: u64 = 32 << 20;
+
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+// Entries of the L1 and L2 tables.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+
+fn be_u32 (header: &[u8], offset: usize) -> u32 {
+  let mut value = [0u8; 4];
+  value.copy_from_slice(&header[offset..offset + 4]);
+  u32::from_be_bytes(value)
+}
+
+fn be_u64 (header: &[u8], offset: usize) -> u64 {
+  let mut value = [0u8; 8];
+  value.copy_from_slice(&header[offset..offset + 8]);
+  u64::from_be_bytes(value)
+}
+
+fn invalid (details: &str) -> Error {
+  Error::Invalid(details.to_string())
+}
+
+fn read_only_error () -> Error {
+  Error::Io(std::io::Error::from_raw_os_error(libc::EROFS))
+}
+
+// Iterate over the parts of a guest range in each cluster.
+fn chunks (cluster_size: u64, offset: u64, length: usize) -> impl Iterator<Item = (u64, std::ops::Range<usize>)> {
+  let mut done = 0;
+  std::iter::from_fn(move || {
+    if done == length {
+      return None
+    }
+    let guest_offset = offset + done as u64;
+    let count = (cluster_size - (guest_offset & (cluster_size - 1))).min((length - done) as u64) as usize;
+    let chunk = (guest_offset, done..done + count);
+    done += count;
+    Some(chunk)
+  })
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct Qcow2 {
+  file: File,
+  read_only: bool,
+  cluster_bits: u32,
+  size: u64,
+  l1_table_offset: u64,
+  l1_table: Vec<u64>,
+  refcount_table_offset: u64,
+  refcount_table: Vec<u64>,
+  // Where the next cluster is allocated.
+  end: u64
+}
+
+impl Qcow2 {
+  pub fn open (file: File, read_only: bool) -> Result<Self> {
+    let mut header = vec![0u8; V3_HEADER_LENGTH];
+    file.read_exact_at(&mut header[..V2_HEADER_LENGTH], 0)?;
+    if be_u32(&header, 0) != MAGIC {
+      return Err(invalid("bad magic"))
+    }
+    let version = be_u32(&header, 4);
+    match version {
+      2 => (),
+      3 => file.read_exact_at(&mut header[V2_HEADER_LENGTH..], V2_HEADER_LENGTH as u64)?,
+      _ => return Err(Error::Unsupported(format!("version {}", version)))
+    }
+
+    if be_u64(&header, 8) != 0 {
+      return Err(Error::Unsupported(String::from("backing file")))
+    }
+    let cluster_bits = be_u32(&header, 20);
+    if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+      return Err(invalid("bad cluster size"))
+    }
+    if be_u32(&header, 32) != 0 {
+      return Err(Error::Unsupported(String::from("encryption")))
+    }
+    if version == 3 {
+      let incompatible_features = be_u64(&header, 72);
+      if incompatible_features != 0 {
+        return Err(Error::Unsupported(format!("incompatible features {:#x}", incompatible_features)))
+      }
+      if be_u32(&header, 96) != REFCOUNT_ORDER {
+        return Err(Error::Unsupported(format!("refcount order {}", be_u32(&header, 96))))
+      }
+    }
+    if be_u32(&header, 60) != 0 && !read_only {
+      return Err(Error::Unsupported(String::from("internal snapshots, the image can only be read")))
+    }
+
+    let cluster_size = 1u64 << cluster_bits;
+    let aligned = |offset: u64| offset & (cluster_size - 1) == 0;
+    let read_table = |offset: u64, entries: u64, name: &str| -> Result<Vec<u64>> {
+      if !aligned(offset) {
+        return Err(Error::Invalid(format!("unaligned {}", name)))
+      }
+      if entries * 8 > MAX_TABLE_SIZE {
+        return Err(Error::Unsupported(format!("{} of {} entries", name, entries)))
+      }
+      let mut table = vec![0u8; entries as usize * 8];
+      file.read_exact_at(&mut table, offset)?;
+      Ok(table.chunks(8).map(|entry| be_u64(entry, 0)).collect())
+    };
+
+    let size = be_u64(&header, 24);
+    let l1_size = u64::from(be_u32(&header, 36));
+    let l1_table_offset = be_u64(&header, 40);
+    let bytes_per_l1_entry = 1u128 << (2 * cluster_bits - 3);
+    if u128::from(l1_size) * bytes_per_l1_entry < u128::from(size) {
+      return Err(invalid("L1 table too small"))
+    }
+    let l1_table = read_table(l1_table_offset, l1_size, "L1 table")?;
+
+    let refcount_table_offset = be_u64(&header, 48);
+    let refcount_table_clusters = u64::from(be_u32(&header, 56));
+    let refcount_table = read_table(
+      refcount_table_offset, refcount_table_clusters * cluster_size / 8, "refcount table"
+    )?;
+
+    let length = file.metadata()?.len();
+    let end = (length + cluster_size - 1) & !(cluster_size - 1);
+
+    // Extensions we do not know are no longer valid once we write.
+    if version == 3 && !read_only && be_u64(&header, AUTOCLEAR_FEATURES_OFFSET as usize) != 0 {
+      file.write_all_at(&0u64.to_be_bytes(), AUTOCLEAR_FEATURES_OFFSET)?;
+    }
+
+    Ok(Self {
+      file,
+      read_only,
+      cluster_bits,
+      size,
+      l1_table_offset,
+      l1_table,
+      refcount_table_offset,
+      refcount_table,
+      end
+    })
+  }
+
+  fn cluster_size (&self) -> u64 {
+    1 << self.cluster_bits
+  }
+
+  fn is_aligned (&self, offset: u64) -> bool {
+    offset & (self.cluster_size() - 1) == 0
+  }
+
+  fn read_u64 (&self, offset: u64) -> Result<u64> {
+    let mut value = [0u8; 8];
+    self.file.read_exact_at(&mut value, offset)?;
+    Ok(u64::from_be_bytes(value))
+  }
+
+  fn write_u64 (&self, offset: u64, value: u64) -> Result<()> {
+    Ok(self.file.write_all_at(&value.to_be_bytes(), offset)?)
+  }
+
+  fn write_zeroes (&self, offset: u64) -> Result<()> {
+    Ok(self.file.write_all_at(&vec![0u8; self.cluster_size() as usize], offset)?)
+  }
+
+  fn l1_index (&self, guest_offset: u64) -> usize {
+    (guest_offset >> (2 * self.cluster_bits - 3)) as usize
+  }
+
+  // Where the L2 entry of a guest cluster is, if its L2 table exists.
+  fn l2_entry_offset (&self, guest_offset: u64) -> Result<Option<u64>> {
+    let l2_table = self.l1_table.get(self.l1_index(guest_offset))
+      .ok_or_else(|| invalid("offset beyond the L1 table"))? & OFFSET_MASK;
+    if l2_table == 0 {
+      return Ok(None)
+    }
+    if !self.is_aligned(l2_table) {
+      return Err(invalid("unaligned L2 table"))
+    }
+    let l2_index = (guest_offset >> self.cluster_bits) & (self.cluster_size() / 8 - 1);
+    Ok(Some(l2_table + l2_index * 8))
+  }
+
+  fn read_l2_entry (&self, entry_offset: u64) -> Result<u64> {
+    let entry = self.read_u64(entry_offset)?;
+    if entry & COMPRESSED != 0 {
+      return Err(Error::Unsupported(String::from("compressed clusters")))
+    }
+    if !self.is_aligned(entry & OFFSET_MASK) {
+      return Err(invalid("unaligned data cluster"))
+    }
+    Ok(entry)
+  }
+
+  // Host cluster holding a guest cluster, None if it reads as zeroes.
+  fn data_cluster (&self, guest_offset: u64) -> Result<Option<u64>> {
+    let entry = match self.l2_entry_offset(guest_offset)? {
+      Some(entry_offset) => self.read_l2_entry(entry_offset)?,
+      None => return Ok(None)
+    };
+    match entry & OFFSET_MASK {
+      0 => Ok(None),
+      _ if entry & ZERO != 0 => Ok(None),
+      host_offset => Ok(Some(host_offset))
+    }
+  }
+
+  // Read host data, zeroes past the end of the file: the last clusters are
+  // only written up to their data.
+  fn read_data (&self, buf: &mut [u8], offset: u64) -> Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+      match self.file.read_at(&mut buf[done..], offset + done as u64) {
+        Ok(0) => {
+          for byte in buf[done..].iter_mut() {
+            *byte = 0;
+          }
+          break
+        },
+        Ok(count) => done += count,
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+        Err(e) => return Err(e.into())
+      }
+    }
+    Ok(())
+  }
+
+  // Set the refcount of a host cluster, with a new refcount block if needed.
+  fn set_refcount (&mut self, host_offset: u64, refcount: u16) -> Result<()> {
+    let refcounts_per_block = self.cluster_size() / 2;
+    let cluster = host_offset >> self.cluster_bits;
+    let block_index = (cluster / refcounts_per_block) as usize;
+    let mut block = *self.refcount_table.get(block_index)
+      .ok_or_else(|| Error::Unsupported(String::from("refcount table is full")))? & OFFSET_MASK;
+
+    if block == 0 {
+      block = self.end;
+      self.end += self.cluster_size();
+      self.write_zeroes(block)?;
+      let block_cluster = block >> self.cluster_bits;
+      if (block_cluster / refcounts_per_block) as usize == block_index {
+        // The block counts itself.
+        let offset = block + (block_cluster % refcounts_per_block) * 2;
+        self.file.write_all_at(&1u16.to_be_bytes(), offset)?;
+      } else {
+        self.set_refcount(block, 1)?;
+      }
+      self.write_u64(self.refcount_table_offset + block_index as u64 * 8, block)?;
+      self.refcount_table[block_index] = block;
+    } else if !self.is_aligned(block) {
+      return Err(invalid("unaligned refcount block"))
+    }
+
+    let offset = block + (cluster % refcounts_per_block) * 2;
+    Ok(self.file.write_all_at(&refcount.to_be_bytes(), offset)?)
+  }
+
+  fn allocate_cluster (&mut self) -> Result<u64> {
+    let host_offset = self.end;
+    self.end += self.cluster_size();
+    self.set_refcount(host_offset, 1)?;
+    Ok(host_offset)
+  }
+
+  // The L2 table of a guest cluster, allocated if needed.
+  fn l2_entry_offset_for_write (&mut self, guest_offset: u64) -> Result<u64> {
+    if let Some(entry_offset) = self.l2_entry_offset(guest_offset)? {
+      return Ok(entry_offset)
+    }
+    let l1_index = self.l1_index(guest_offset);
+    let l2_table = self.allocate_cluster()?;
+    self.write_zeroes(l2_table)?;
+    self.write_u64(self.l1_table_offset + l1_index as u64 * 8, l2_table | COPIED)?;
+    self.l1_table[l1_index] = l2_table | COPIED;
+    Ok(self.l2_entry_offset(guest_offset)?.expect("allocated L2 table"))
+  }
+
+  // Host cluster where a guest cluster is written, with the L2 entry to
+  // update once the data is written if the cluster is new.
+  fn data_cluster_for_write (&mut self, guest_offset: u64) -> Result<(u64, Option<u64>)> {
+    let entry_offset = self.l2_entry_offset_for_write(guest_offset)?;
+    let entry = self.read_l2_entry(entry_offset)?;
+    match entry & OFFSET_MASK {
+      0 => Ok((self.allocate_cluster()?, Some(entry_offset))),
+      host_offset if entry & ZERO != 0 => {
+        // Preallocated, but its content must read as zeroes.
+        self.write_zeroes(host_offset)?;
+        Ok((host_offset, Some(entry_offset)))
+      },
+      host_offset => Ok((host_offset, None))
+    }
+  }
+}
+
+impl Image for Qcow2 {
+  fn size (&self) -> u64 {
+    self.size / SECTOR_SIZE * SECTOR_SIZE
+  }
+
+  fn read_at (&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+    let cluster_mask = self.cluster_size() - 1;
+    for (guest_offset, range) in chunks(self.cluster_size(), offset, buf.len()) {
+      let chunk = &mut buf[range];
+      match self.data_cluster(guest_offset)? {
+        Some(host_offset) => self.read_data(chunk, host_offset + (guest_offset & cluster_mask))?,
+        None => for byte in chunk.iter_mut() {
+          *byte = 0;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn write_at (&mut self, data: &[u8], offset: u64) -> Result<()> {
+    if self.read_only {
+      return Err(read_only_error())
+    }
+    let cluster_mask = self.cluster_size() - 1;
+    for (guest_offset, range) in chunks(self.cluster_size(), offset, data.len()) {
+      let (host_offset, new_entry) = self.data_cluster_for_write(guest_offset)?;
+      self.file.write_all_at(&data[range], host_offset + (guest_offset & cluster_mask))?;
+      if let Some(entry_offset) = new_entry {
+        self.write_u64(entry_offset, host_offset | COPIED)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn flush (&mut self) -> Result<()> {
+    Ok(self.file.sync_data()?)
+  }
+
+  fn discard_granularity (&self) -> Option<u64> {
+    Some(self.cluster_size())
+  }
+
+  // Free the whole clusters of the range, they then read as zeroes.
+  fn discard (&mut self, offset: u64, length: u64) -> Result<()> {
+    if self.read_only {
+      return Err(read_only_error())
+    }
+    let cluster_size = self.cluster_size();
+    let start = (offset + cluster_size - 1) & !(cluster_size - 1);
+    let stop = (offset + length) & !(cluster_size - 1);
+    let mut guest_offset = start;
+    while guest_offset < stop {
+      if let Some(entry_offset) = self.l2_entry_offset(guest_offset)? {
+        let entry = self.read_l2_entry(entry_offset)?;
+        if entry != 0 {
+          self.write_u64(entry_offset, 0)?;
+          let host_offset = entry & OFFSET_MASK;
+          if host_offset != 0 {
+            self.set_refcount(host_offset, 0)?;
+            // Freed even if the filesystem cannot give its space back.
+            let _ = image::punch_hole(&self.file, host_offset, cluster_size);
+          }
+        }
+      }
+      guest_offset += cluster_size;
+    }
+    Ok(())
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{error, TempDir};
+  use std::fs::OpenOptions;
+  use std::path::PathBuf;
+
+  // 512-byte clusters: an L2 table maps 32 KiB, a refcount block counts 256
+  // clusters.
+  const CLUSTER_BITS: u32 = 9;
+  const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+  const L2_COVERAGE: u64 = CLUSTER_SIZE * CLUSTER_SIZE / 8;
+
+  // Clusters of a new image.
+  const REFCOUNT_TABLE: u64 = CLUSTER_SIZE;
+  const REFCOUNT_BLOCK: u64 = 2 * CLUSTER_SIZE;
+  const L1_TABLE: u64 = 3 * CLUSTER_SIZE;
+  const FIRST_FREE: u64 = 4 * CLUSTER_SIZE;
+
+  // A version 3 image, like `qemu-img create`: header, refcount table, one
+  // refcount block and L1 table, without data.
+  struct TestImage {
+    path: PathBuf,
+    _dir: TempDir
+  }
+
+  impl TestImage {
+    fn new (name: &str, size: u64) -> Self {
+      let dir = TempDir::new(&format!("qcow2-{}", name));
+      let path = dir.join("image.qcow2");
+      let mut data = vec![0u8; FIRST_FREE as usize];
+      let mut put = |offset: usize, value: &[u8]| data[offset..offset + value.len()].copy_from_slice(value);
+      put(0, &MAGIC.to_be_bytes());
+      put(4, &3u32.to_be_bytes());
+      put(20, &CLUSTER_BITS.to_be_bytes());
+      put(24, &size.to_be_bytes());
+      put(36, &(((size - 1) / L2_COVERAGE + 1) as u32).to_be_bytes());
+      put(40, &L1_TABLE.to_be_bytes());
+      put(48, &REFCOUNT_TABLE.to_be_bytes());
+      put(56, &1u32.to_be_bytes());
+      put(96, &REFCOUNT_ORDER.to_be_bytes());
+      put(100, &(V3_HEADER_LENGTH as u32).to_be_bytes());
+      put(REFCOUNT_TABLE as usize, &REFCOUNT_BLOCK.to_be_bytes());
+      for cluster in 0..4 {
+        put(REFCOUNT_BLOCK as usize + cluster * 2, &1u16.to_be_bytes());
+      }
+      std::fs::write(&path, &data).unwrap();
+      Self { path, _dir: dir }
+    }
+
+    fn open (&self, read_only: bool) -> Result<Qcow2> {
+      Qcow2::open(OpenOptions::new().read(true).write(!read_only).open(&self.path).unwrap(), read_only)
+    }
+
+    fn read_u64 (&self, offset: u64) -> u64 {
+      be_u64(&std::fs::read(&self.path).unwrap(), offset as usize)
+    }
+
+    fn write (&self, offset: u64, value: &[u8]) {
+      OpenOptions::new().write(true).open(&self.path).unwrap().write_all_at(value, offset).unwrap();
+    }
+
+    fn refcount (&self, host_offset: u64) -> u16 {
+      let data = std::fs::read(&self.path).unwrap();
+      let cluster = host_offset / CLUSTER_SIZE;
+      let block = be_u64(&data, (REFCOUNT_TABLE + cluster / 256 * 8) as usize);
+      let offset = (block + cluster % 256 * 2) as usize;
+      u16::from_be_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn length (&self) -> u64 {
+      std::fs::metadata(&self.path).unwrap().len()
+    }
+  }
+
+  fn pattern (length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|index| (index as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+  }
+
+  #[test]
+  fn open_errors () {
+    let image = TestImage::new("open", 1 << 20);
+    assert!(image.open(false).is_ok());
+
+    for (offset, value, message) in &[
+      (0, &[0u8; 4][..], "invalid image: bad magic"),
+      (4, &4u32.to_be_bytes()[..], "unsupported image: version 4"),
+      (8, &512u64.to_be_bytes()[..], "unsupported image: backing file"),
+      (20, &8u32.to_be_bytes()[..], "invalid image: bad cluster size"),
+      (72, &1u64.to_be_bytes()[..], "unsupported image: incompatible features 0x1"),
+      (96, &5u32.to_be_bytes()[..], "unsupported image: refcount order 5"),
+      (36, &1u32.to_be_bytes()[..], "invalid image: L1 table too small")
+    ] {
+      let image = TestImage::new("open-error", 1 << 20);
+      image.write(*offset, value);
+      assert_eq!(error(image.open(true)), *message);
+    }
+
+    // Internal snapshots: the image can only be read.
+    let image = TestImage::new("snapshots", 1 << 20);
+    image.write(60, &1u32.to_be_bytes());
+    assert_eq!(error(image.open(false)), "unsupported image: internal snapshots, the image can only be read");
+    assert!(image.open(true).is_ok());
+  }
+
+  #[test]
+  fn unallocated () {
+    let image = TestImage::new("unallocated", 1 << 20);
+    let mut qcow2 = image.open(true).ok().unwrap();
+    assert_eq!(qcow2.size(), 1 << 20);
+    let mut buf = vec![0xffu8; 1 << 20];
+    qcow2.read_at(&mut buf, 0).ok().unwrap();
+    assert!(buf.iter().all(|byte| *byte == 0));
+    assert_eq!(error(qcow2.write_at(&buf[..512], 0)), std::io::Error::from_raw_os_error(libc::EROFS).to_string());
+  }
+
+  #[test]
+  fn lookup () {
+    let image = TestImage::new("lookup", 1 << 20);
+    // Guest clusters 3, 4 and 5 of the second L2 table: data, zero flag and
+    // compressed.
+    let l2_table = FIRST_FREE;
+    let data_cluster = FIRST_FREE + CLUSTER_SIZE;
+    image.write(L1_TABLE + 8, &(l2_table | COPIED).to_be_bytes());
+    image.write(l2_table + 3 * 8, &(data_cluster | COPIED).to_be_bytes());
+    image.write(l2_table + 4 * 8, &(data_cluster | ZERO).to_be_bytes());
+    image.write(l2_table + 5 * 8, &(data_cluster | COMPRESSED).to_be_bytes());
+    image.write(data_cluster, &pattern(CLUSTER_SIZE as usize, 1));
+
+    let mut qcow2 = image.open(true).ok().unwrap();
+    let guest_offset = L2_COVERAGE + 3 * CLUSTER_SIZE;
+    let mut buf = vec![0xffu8; 3 * CLUSTER_SIZE as usize];
+    qcow2.read_at(&mut buf, guest_offset - CLUSTER_SIZE).ok().unwrap();
+    let mut expected = vec![0u8; CLUSTER_SIZE as usize];
+    expected.extend(pattern(CLUSTER_SIZE as usize, 1));
+    expected.extend(vec![0u8; CLUSTER_SIZE as usize]);
+    assert_eq!(buf, expected);
+
+    // Within a cluster.
+    let mut buf = vec![0u8; 100];
+    qcow2.read_at(&mut buf, guest_offset + 10).ok().unwrap();
+    assert_eq!(buf[..], pattern(CLUSTER_SIZE as usize, 1)[10..110]);
+
+    assert_eq!(error(qcow2.read_at(&mut buf, guest_offset + 2 * CLUSTER_SIZE)), "unsupported image: compressed clusters");
+  }
+
+  #[test]
+  fn allocating_writes () {
+    let image = TestImage::new("write", 1 << 20);
+    let mut qcow2 = image.open(false).ok().unwrap();
+
+    // Across two L2 tables: two L2 tables and two data clusters.
+    let data = pattern(2 * CLUSTER_SIZE as usize, 7);
+    qcow2.write_at(&data, L2_COVERAGE - CLUSTER_SIZE).ok().unwrap();
+    assert_eq!(image.length(), FIRST_FREE + 4 * CLUSTER_SIZE);
+    let mut buf = vec![0u8; data.len()];
+    qcow2.read_at(&mut buf, L2_COVERAGE - CLUSTER_SIZE).ok().unwrap();
+    assert_eq!(buf, data);
+
+    for l1_index in 0..2 {
+      let l2_table = image.read_u64(L1_TABLE + l1_index * 8);
+      assert_eq!(l2_table & COPIED, COPIED);
+      assert_eq!(image.refcount(l2_table & OFFSET_MASK), 1);
+      let l2_index = if l1_index == 0 { CLUSTER_SIZE / 8 - 1 } else { 0 };
+      let entry = image.read_u64((l2_table & OFFSET_MASK) + l2_index * 8);
+      assert_eq!(entry & COPIED, COPIED);
+      assert_eq!(image.refcount(entry & OFFSET_MASK), 1);
+    }
+
+    // Allocated clusters are written in place.
+    qcow2.write_at(&data[..512], L2_COVERAGE).ok().unwrap();
+    assert_eq!(image.length(), FIRST_FREE + 4 * CLUSTER_SIZE);
+
+    // The rest of a new cluster reads as zeroes.
+    qcow2.write_at(&[9u8; 100], 10 * CLUSTER_SIZE + 200).ok().unwrap();
+    let mut buf = vec![0xffu8; CLUSTER_SIZE as usize];
+    qcow2.read_at(&mut buf, 10 * CLUSTER_SIZE).ok().unwrap();
+    assert!(buf[..200].iter().all(|byte| *byte == 0) && buf[200..300].iter().all(|byte| *byte == 9));
+    assert!(buf[300..].iter().all(|byte| *byte == 0));
+    qcow2.flush().ok().unwrap();
+    drop(qcow2);
+
+    let mut qcow2 = image.open(true).ok().unwrap();
+    let mut buf = vec![0u8; data.len()];
+    qcow2.read_at(&mut buf, L2_COVERAGE - CLUSTER_SIZE).ok().unwrap();
+    assert_eq!(buf, data);
+  }
+
+  #[test]
+  fn refcount_blocks () {
+    // More clusters than the first refcount block counts.
+    let image = TestImage::new("refcounts", 1 << 20);
+    let mut qcow2 = image.open(false).ok().unwrap();
+    let data = pattern(300 * CLUSTER_SIZE as usize, 3);
+    qcow2.write_at(&data, 0).ok().unwrap();
+    let block = image.read_u64(REFCOUNT_TABLE + 8);
+    assert!(block != 0);
+    assert_eq!(image.refcount(block), 1);
+    assert_eq!(image.refcount(image.length() - CLUSTER_SIZE), 1);
+
+    let mut buf = vec![0u8; data.len()];
+    qcow2.read_at(&mut buf, 0).ok().unwrap();
+    assert!(buf == data);
+  }
+
+  #[test]
+  fn discard () {
+    let image = TestImage::new("discard", 1 << 20);
+    let mut qcow2 = image.open(false).ok().unwrap();
+    qcow2.write_at(&pattern(4 * CLUSTER_SIZE as usize, 5), 0).ok().unwrap();
+    let l2_table = image.read_u64(L1_TABLE) & OFFSET_MASK;
+    let freed = image.read_u64(l2_table + 8) & OFFSET_MASK;
+
+    // Only the whole clusters are freed.
+    qcow2.discard(CLUSTER_SIZE / 2, 2 * CLUSTER_SIZE).ok().unwrap();
+    assert_eq!(image.read_u64(l2_table + 8), 0);
+    assert_eq!(image.refcount(freed), 0);
+    let mut buf = vec![0u8; 4 * CLUSTER_SIZE as usize];
+    qcow2.read_at(&mut buf, 0).ok().unwrap();
+    let mut expected = pattern(4 * CLUSTER_SIZE as usize, 5);
+    for byte in expected[CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize].iter_mut() {
+      *byte = 0;
+    }
+    assert_eq!(buf, expected);
+  }
+}
@@ -0,0 +1,522 @@
+use std::sync::atomic::{fence, Ordering};
+
+// =============================================================================
+// Block ring protocol (see xen/include/public/io/blkif.h and ring.h).
+//
+// The shared ring starts with the indexes of the producers and the events,
+// then holds a power of two count of entries: a request written by the
+// frontend, replaced by its response written by the backend. The layout of a
+// request depends on the ABI of the frontend, given by its `protocol` node.
+//
+// The frontend can write anything in the ring at any time: each request is
+// copied once, and the indexes are checked.
+// =============================================================================
+
+pub const SECTOR_SIZE: u64 = 512;
+pub const PAGE_SIZE: usize = 4096;
+
+pub const SECTORS_PER_PAGE: u8 = (PAGE_SIZE as u64 / SECTOR_SIZE) as u8;
+
+pub const MAX_SEGMENTS: usize = 11;
+
+const REQ_PROD_OFFSET: usize = 0;
+const REQ_EVENT_OFFSET: usize = 4;
+const RSP_PROD_OFFSET: usize = 8;
+const RSP_EVENT_OFFSET: usize = 12;
+const ENTRIES_OFFSET: usize = 64;
+
+const SEGMENT_SIZE: usize = 8;
+
+pub const OP_READ: u8 = 0;
+pub const OP_WRITE: u8 = 1;
+pub const OP_WRITE_BARRIER: u8 = 2;
+pub const OP_FLUSH_DISKCACHE: u8 = 3;
+pub const OP_DISCARD: u8 = 5;
+
+pub const STATUS_OKAY: i16 = 0;
+pub const STATUS_ERROR: i16 = -1;
+pub const STATUS_NOT_SUPPORTED: i16 = -2;
+
+// Flag of a discard request: the data must be erased.
+pub const DISCARD_SECURE: u8 = 1;
+
+pub enum Error {
+  UnknownProtocol(String),
+  // The frontend produced more requests than the ring can hold.
+  Overflow(u32)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::UnknownProtocol(protocol) => write!(f, "unknown ring protocol: `{}`", protocol),
+      Error::Overflow(prod) => write!(f, "request producer overflow: {}", prod)
+    }
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+// Layout of the requests.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Abi {
+  // x86_64 and Arm: 64-bit fields are aligned.
+  Native,
+  // x86_32: 64-bit fields are packed on 4 bytes.
+  X86_32
+}
+
+impl Abi {
+  // No protocol is the native one.
+  pub fn from_protocol (protocol: Option<&str>) -> Result<Self> {
+    match protocol {
+      None | Some("x86_64-abi") | Some("arm-abi") => Ok(Abi::Native),
+      Some("x86_32-abi") => Ok(Abi::X86_32),
+      Some(protocol) => Err(Error::UnknownProtocol(protocol.to_string()))
+    }
+  }
+
+  fn entry_size (self) -> usize {
+    match self {
+      Abi::Native => 112,
+      Abi::X86_32 => 108
+    }
+  }
+
+  // Offsets of `id` and `sector_number`, followed by the segments or the
+  // count of sectors to discard.
+  fn id_offset (self) -> usize {
+    match self {
+      Abi::Native => 8,
+      Abi::X86_32 => 4
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+  pub gref: u32,
+  pub first_sect: u8,
+  pub last_sect: u8
+}
+
+impl Segment {
+  pub fn is_valid (&self) -> bool {
+    self.first_sect <= self.last_sect && self.last_sect < SECTORS_PER_PAGE
+  }
+
+  // Data of the segment in its page.
+  pub fn offset (&self) -> usize {
+    self.first_sect as usize * SECTOR_SIZE as usize
+  }
+
+  pub fn length (&self) -> usize {
+    (self.last_sect - self.first_sect + 1) as usize * SECTOR_SIZE as usize
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+  Read(Vec<Segment>),
+  Write(Vec<Segment>),
+  WriteBarrier(Vec<Segment>),
+  FlushDiskCache(Vec<Segment>),
+  Discard { sectors: u64, secure: bool },
+  // Too many segments.
+  Invalid,
+  Unsupported
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+  // Operation code of the frontend, given back in the response.
+  pub code: u8,
+  pub id: u64,
+  pub sector: u64,
+  pub operation: Operation
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Response {
+  pub id: u64,
+  pub code: u8,
+  pub status: i16
+}
+
+impl Response {
+  pub fn new (request: &Request, status: i16) -> Self {
+    Self { id: request.id, code: request.code, status }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn read_u32 (page: &[u8], offset: usize) -> u32 {
+  unsafe { std::ptr::read_volatile(page[offset..offset + 4].as_ptr() as *const u32) }
+}
+
+fn write_u32 (page: &mut [u8], offset: usize, value: u32) {
+  unsafe { std::ptr::write_volatile(page[offset..offset + 4].as_mut_ptr() as *mut u32, value) }
+}
+
+fn u32_at (entry: &[u8], offset: usize) -> u32 {
+  let mut value = [0u8; 4];
+  value.copy_from_slice(&entry[offset..offset + 4]);
+  u32::from_ne_bytes(value)
+}
+
+fn u64_at (entry: &[u8], offset: usize) -> u64 {
+  let mut value = [0u8; 8];
+  value.copy_from_slice(&entry[offset..offset + 8]);
+  u64::from_ne_bytes(value)
+}
+
+// Parse a request copied from the ring.
+fn parse_request (entry: &[u8], abi: Abi) -> Request {
+  let code = entry[0];
+  let id_offset = abi.id_offset();
+  let id = u64_at(entry, id_offset);
+  let sector = u64_at(entry, id_offset + 8);
+  let data_offset = id_offset + 16;
+
+  let segments = || -> Option<Vec<Segment>> {
+    let count = entry[1] as usize;
+    if count > MAX_SEGMENTS {
+      return None
+    }
+    Some((0..count).map(|index| {
+      let offset = data_offset + index * SEGMENT_SIZE;
+      Segment { gref: u32_at(entry, offset), first_sect: entry[offset + 4], last_sect: entry[offset + 5] }
+    }).collect())
+  };
+
+  let operation = match code {
+    OP_READ => segments().map_or(Operation::Invalid, Operation::Read),
+    OP_WRITE => segments().map_or(Operation::Invalid, Operation::Write),
+    OP_WRITE_BARRIER => segments().map_or(Operation::Invalid, Operation::WriteBarrier),
+    OP_FLUSH_DISKCACHE => segments().map_or(Operation::Invalid, Operation::FlushDiskCache),
+    OP_DISCARD => Operation::Discard {
+      sectors: u64_at(entry, data_offset),
+      secure: entry[1] & DISCARD_SECURE != 0
+    },
+    _ => Operation::Unsupported
+  };
+  Request { code, id, sector, operation }
+}
+
+// Count of entries of a ring of `length` bytes.
+pub fn ring_size (length: usize, abi: Abi) -> u32 {
+  let entries = (length.saturating_sub(ENTRIES_OFFSET) / abi.entry_size()) as u32;
+  match entries {
+    0 => 0,
+    entries => 1 << (31 - entries.leading_zeros())
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Backend side of a ring: its private indexes. The ring is given to each
+// call, it is shared with the frontend.
+pub struct BackRing {
+  abi: Abi,
+  size: u32,
+  req_cons: u32,
+  rsp_prod_pvt: u32
+}
+
+impl BackRing {
+  // Serve a ring from its last response: the requests that were not answered
+  // yet, if the ring was served before, are taken again.
+  pub fn new (page: &[u8], abi: Abi) -> Self {
+    let rsp_prod = read_u32(page, RSP_PROD_OFFSET);
+    Self { abi, size: ring_size(page.len(), abi), req_cons: rsp_prod, rsp_prod_pvt: rsp_prod }
+  }
+
+  pub fn size (&self) -> u32 {
+    self.size
+  }
+
+  fn unconsumed (&self, page: &[u8]) -> u32 {
+    let requests = read_u32(page, REQ_PROD_OFFSET).wrapping_sub(self.req_cons);
+    let free = self.size.wrapping_sub(self.req_cons.wrapping_sub(self.rsp_prod_pvt));
+    requests.min(free)
+  }
+
+  // Copy the new requests. A response must be pushed for each one.
+  pub fn take_requests (&mut self, page: &[u8]) -> Result<Vec<Request>> {
+    let prod = read_u32(page, REQ_PROD_OFFSET);
+    fence(Ordering::SeqCst);
+    if prod.wrapping_sub(self.rsp_prod_pvt) > self.size {
+      return Err(Error::Overflow(prod))
+    }
+
+    let entry_size = self.abi.entry_size();
+    let mut requests = Vec::new();
+    while self.req_cons != prod {
+      let offset = ENTRIES_OFFSET + (self.req_cons & (self.size - 1)) as usize * entry_size;
+      let mut entry = vec![0u8; entry_size];
+      entry.copy_from_slice(&page[offset..offset + entry_size]);
+      requests.push(parse_request(&entry, self.abi));
+      self.req_cons = self.req_cons.wrapping_add(1);
+    }
+    Ok(requests)
+  }
+
+  // Ask to be notified of the next request. Returns true if requests came in
+  // the meantime, they must be taken.
+  pub fn final_check (&mut self, page: &mut [u8]) -> bool {
+    if self.unconsumed(page) > 0 {
+      return true
+    }
+    write_u32(page, REQ_EVENT_OFFSET, self.req_cons.wrapping_add(1));
+    fence(Ordering::SeqCst);
+    self.unconsumed(page) > 0
+  }
+
+  // Write responses and publish them. Returns true if the frontend waits
+  // for them and must be notified.
+  pub fn push_responses (&mut self, page: &mut [u8], responses: &[Response]) -> bool {
+    if responses.is_empty() {
+      return false
+    }
+    let entry_size = self.abi.entry_size();
+    for response in responses {
+      let offset = ENTRIES_OFFSET + (self.rsp_prod_pvt & (self.size - 1)) as usize * entry_size;
+      let entry = &mut page[offset..offset + entry_size];
+      entry[0..8].copy_from_slice(&response.id.to_ne_bytes());
+      entry[8] = response.code;
+      entry[10..12].copy_from_slice(&response.status.to_ne_bytes());
+      self.rsp_prod_pvt = self.rsp_prod_pvt.wrapping_add(1);
+    }
+
+    let old = read_u32(page, RSP_PROD_OFFSET);
+    let new = self.rsp_prod_pvt;
+    fence(Ordering::SeqCst);
+    write_u32(page, RSP_PROD_OFFSET, new);
+    fence(Ordering::SeqCst);
+    let event = read_u32(page, RSP_EVENT_OFFSET);
+    new.wrapping_sub(event) < new.wrapping_sub(old)
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A shared ring, aligned like a granted page.
+  #[repr(C, align(4096))]
+  struct Page([u8; PAGE_SIZE]);
+
+  // Frontend side of a ring, like SHARED_RING_INIT and the RING_* macros.
+  struct Front {
+    page: Box<Page>,
+    abi: Abi,
+    req_prod: u32,
+    rsp_cons: u32
+  }
+
+  impl Front {
+    fn new (abi: Abi, start: u32) -> Self {
+      let mut front = Self { page: Box::new(Page([0u8; PAGE_SIZE])), abi, req_prod: start, rsp_cons: start };
+      let page = front.ring();
+      write_u32(page, REQ_PROD_OFFSET, start);
+      write_u32(page, REQ_EVENT_OFFSET, start.wrapping_add(1));
+      write_u32(page, RSP_PROD_OFFSET, start);
+      write_u32(page, RSP_EVENT_OFFSET, start.wrapping_add(1));
+      front
+    }
+
+    fn ring (&mut self) -> &mut [u8] {
+      &mut self.page.0
+    }
+
+    fn entry (&mut self, index: u32) -> &mut [u8] {
+      let entry_size = self.abi.entry_size();
+      let offset = ENTRIES_OFFSET + (index & (ring_size(PAGE_SIZE, self.abi) - 1)) as usize * entry_size;
+      &mut self.page.0[offset..offset + entry_size]
+    }
+
+    // Write a request in the next entry, it is published by `publish`.
+    fn push (&mut self, code: u8, id: u64, sector: u64, segments: &[Segment]) -> &mut [u8] {
+      let id_offset = self.abi.id_offset();
+      let index = self.req_prod;
+      self.req_prod = self.req_prod.wrapping_add(1);
+      let entry = self.entry(index);
+      for byte in entry.iter_mut() {
+        *byte = 0;
+      }
+      entry[0] = code;
+      entry[1] = segments.len() as u8;
+      entry[id_offset..id_offset + 8].copy_from_slice(&id.to_ne_bytes());
+      entry[id_offset + 8..id_offset + 16].copy_from_slice(&sector.to_ne_bytes());
+      for (index, segment) in segments.iter().enumerate() {
+        let offset = id_offset + 16 + index * SEGMENT_SIZE;
+        entry[offset..offset + 4].copy_from_slice(&segment.gref.to_ne_bytes());
+        entry[offset + 4] = segment.first_sect;
+        entry[offset + 5] = segment.last_sect;
+      }
+      entry
+    }
+
+    fn publish (&mut self) {
+      let req_prod = self.req_prod;
+      write_u32(self.ring(), REQ_PROD_OFFSET, req_prod);
+    }
+
+    // Take the published responses and wait for the next one.
+    fn responses (&mut self) -> Vec<Response> {
+      let rsp_prod = read_u32(self.ring(), RSP_PROD_OFFSET);
+      let mut responses = Vec::new();
+      while self.rsp_cons != rsp_prod {
+        let index = self.rsp_cons;
+        let entry = self.entry(index);
+        responses.push(Response { id: u64_at(entry, 0), code: entry[8], status: i16::from_ne_bytes([entry[10], entry[11]]) });
+        self.rsp_cons = self.rsp_cons.wrapping_add(1);
+      }
+      let rsp_cons = self.rsp_cons;
+      write_u32(self.ring(), RSP_EVENT_OFFSET, rsp_cons.wrapping_add(1));
+      responses
+    }
+  }
+
+  fn segment (gref: u32, first_sect: u8, last_sect: u8) -> Segment {
+    Segment { gref, first_sect, last_sect }
+  }
+
+  #[test]
+  fn ring_sizes () {
+    assert_eq!(ring_size(PAGE_SIZE, Abi::Native), 32);
+    assert_eq!(ring_size(PAGE_SIZE, Abi::X86_32), 32);
+    assert_eq!(ring_size(4 * PAGE_SIZE, Abi::Native), 128);
+    assert_eq!(ring_size(ENTRIES_OFFSET, Abi::Native), 0);
+
+    assert!(Abi::from_protocol(None).ok().unwrap() == Abi::Native);
+    assert!(Abi::from_protocol(Some("arm-abi")).ok().unwrap() == Abi::Native);
+    assert!(Abi::from_protocol(Some("x86_32-abi")).ok().unwrap() == Abi::X86_32);
+    match Abi::from_protocol(Some("sparc-abi")) {
+      Err(e) => assert_eq!(e.to_string(), "unknown ring protocol: `sparc-abi`"),
+      Ok(_) => panic!("no error")
+    }
+  }
+
+  #[test]
+  fn requests () {
+    for abi in &[Abi::Native, Abi::X86_32] {
+      let mut front = Front::new(*abi, 0);
+      let segments: Vec<Segment> = (0..MAX_SEGMENTS as u32).map(|gref| segment(gref + 100, 0, 7)).collect();
+      front.push(OP_READ, 0x1122_3344_5566_7788, 0xaabb_ccdd_0011, &[segment(7, 0, 7), segment(9, 2, 5)]);
+      front.push(OP_WRITE, 2, 8, &segments);
+      front.push(OP_FLUSH_DISKCACHE, 3, 0, &[]);
+      let entry = front.push(OP_DISCARD, 4, 1 << 40, &[]);
+      entry[1] = DISCARD_SECURE;
+      let offset = abi.id_offset() + 16;
+      entry[offset..offset + 8].copy_from_slice(&(1u64 << 33).to_ne_bytes());
+      front.push(42, 5, 0, &[]);
+      front.publish();
+
+      let mut ring = BackRing::new(front.ring(), *abi);
+      let requests = ring.take_requests(front.ring()).ok().unwrap();
+      assert_eq!(requests, [
+        Request {
+          code: OP_READ,
+          id: 0x1122_3344_5566_7788,
+          sector: 0xaabb_ccdd_0011,
+          operation: Operation::Read(vec![segment(7, 0, 7), segment(9, 2, 5)])
+        },
+        Request { code: OP_WRITE, id: 2, sector: 8, operation: Operation::Write(segments) },
+        Request { code: OP_FLUSH_DISKCACHE, id: 3, sector: 0, operation: Operation::FlushDiskCache(vec![]) },
+        Request { code: OP_DISCARD, id: 4, sector: 1 << 40, operation: Operation::Discard { sectors: 1 << 33, secure: true } },
+        Request { code: 42, id: 5, sector: 0, operation: Operation::Unsupported }
+      ]);
+      assert!(ring.take_requests(front.ring()).ok().unwrap().is_empty());
+    }
+  }
+
+  #[test]
+  fn invalid_requests () {
+    let mut front = Front::new(Abi::Native, 0);
+    front.push(OP_READ, 1, 0, &[segment(1, 0, 7)])[1] = MAX_SEGMENTS as u8 + 1;
+    front.publish();
+    let mut ring = BackRing::new(front.ring(), Abi::Native);
+    let requests = ring.take_requests(front.ring()).ok().unwrap();
+    assert_eq!(requests[0].operation, Operation::Invalid);
+
+    // More requests than the ring holds.
+    write_u32(front.ring(), REQ_PROD_OFFSET, 34);
+    match ring.take_requests(front.ring()) {
+      Err(e) => assert_eq!(e.to_string(), "request producer overflow: 34"),
+      Ok(_) => panic!("no error")
+    }
+
+    assert!(segment(1, 0, 7).is_valid());
+    assert!(!segment(1, 3, 2).is_valid());
+    assert!(!segment(1, 0, SECTORS_PER_PAGE).is_valid());
+    assert_eq!((segment(1, 2, 5).offset(), segment(1, 2, 5).length()), (1024, 2048));
+  }
+
+  #[test]
+  fn responses () {
+    let mut front = Front::new(Abi::Native, 0);
+    let mut ring = BackRing::new(front.ring(), Abi::Native);
+    assert!(!ring.final_check(front.ring()));
+    assert_eq!(read_u32(front.ring(), REQ_EVENT_OFFSET), 1);
+
+    front.push(OP_READ, 1, 0, &[segment(1, 0, 0)]);
+    front.push(OP_WRITE, 2, 0, &[segment(2, 0, 0)]);
+    front.publish();
+    // The requests came after the event was set.
+    assert!(ring.final_check(front.ring()));
+    let requests = ring.take_requests(front.ring()).ok().unwrap();
+
+    // The frontend waits for the first response.
+    assert!(ring.push_responses(front.ring(), &[Response::new(&requests[0], STATUS_OKAY)]));
+    // It did not take it yet: no notification.
+    assert!(!ring.push_responses(front.ring(), &[Response::new(&requests[1], STATUS_ERROR)]));
+    assert!(!ring.push_responses(front.ring(), &[]));
+    assert_eq!(front.responses(), [
+      Response { id: 1, code: OP_READ, status: STATUS_OKAY },
+      Response { id: 2, code: OP_WRITE, status: STATUS_ERROR }
+    ]);
+  }
+
+  #[test]
+  fn wraparound () {
+    let mut front = Front::new(Abi::X86_32, u32::MAX - 40);
+    let mut ring = BackRing::new(front.ring(), Abi::X86_32);
+    for round in 0..4 {
+      for id in 0..ring.size() {
+        front.push(OP_READ, u64::from(id), round, &[segment(1, 0, 0)]);
+      }
+      front.publish();
+      let requests = ring.take_requests(front.ring()).ok().unwrap();
+      assert_eq!(requests.len(), 32);
+      // No room for more requests until the responses are pushed.
+      assert!(!ring.final_check(front.ring()));
+
+      let responses: Vec<Response> = requests.iter().map(|request| Response::new(request, STATUS_NOT_SUPPORTED)).collect();
+      ring.push_responses(front.ring(), &responses);
+      assert!(front.responses() == responses);
+    }
+  }
+
+  #[test]
+  fn resume () {
+    let mut front = Front::new(Abi::Native, 0);
+    let mut ring = BackRing::new(front.ring(), Abi::Native);
+    for id in 0..4 {
+      front.push(OP_READ, id, 0, &[segment(1, 0, 0)]);
+    }
+    front.publish();
+    let requests = ring.take_requests(front.ring()).ok().unwrap();
+    ring.push_responses(front.ring(), &[Response::new(&requests[0], STATUS_OKAY)]);
+
+    // The requests without response are taken again.
+    let mut ring = BackRing::new(front.ring(), Abi::Native);
+    let requests = ring.take_requests(front.ring()).ok().unwrap();
+    assert_eq!(requests.iter().map(|request| request.id).collect::<Vec<u64>>(), [1, 2, 3]);
+  }
+}
@@ -0,0 +1,423 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::devicemodel::qmp::Qmp;
+use super::migration;
+use super::save::{self, RestoredDomain, SaveConfig, SaveMode};
+use super::vm;
+use super::xenctrl::Xenctrl;
+use super::xenstore::Xenstore;
+
+// =============================================================================
+// Periodic checkpoints of a running domain (Remus).
+//
+// After a first live copy, the domain is suspended at each interval, its dirty
+// pages and its state are sent, then it is resumed. The stream is written in a
+// file or sent to a peer. The peer keeps the domain paused: when the stream
+// stops (the primary host failed or the checkpoints were stopped), libxc
+// restores the last complete checkpoint and the domain waits to be activated.
+// =============================================================================
+
+// Step of the sleep between two checkpoints, to see a stop request.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct CheckpointConfig {
+  pub interval_ms: u64
+}
+
+impl Default for CheckpointConfig {
+  fn default () -> Self {
+    Self { interval_ms: 200 }
+  }
+}
+
+pub enum Destination {
+  // The file grows with each checkpoint.
+  File(String),
+  // Address of a peer daemon.
+  Peer(String)
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct CheckpointStats {
+  pub checkpoints: u64,
+  // Time the domain was suspended for the last checkpoint.
+  pub last_pause_ms: u64
+}
+
+// Shared with the thread which runs the checkpoints.
+#[derive(Clone, Default)]
+pub struct CheckpointControl {
+  stop: Arc<AtomicBool>,
+  stats: Arc<Mutex<CheckpointStats>>
+}
+
+impl CheckpointControl {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  // The domain continues to run, the destination keeps the last checkpoint.
+  pub fn stop (&self) {
+    self.stop.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_stopped (&self) -> bool {
+    self.stop.load(Ordering::Relaxed)
+  }
+
+  pub fn stats (&self) -> CheckpointStats {
+    *self.stats.lock().unwrap()
+  }
+
+  // Wait the interval before the next checkpoint, false if stopped.
+  fn next (&self, interval: Duration, pause: Duration) -> bool {
+    {
+      let mut stats = self.stats.lock().unwrap();
+      stats.checkpoints += 1;
+      stats.last_pause_ms = pause.as_millis() as u64;
+    }
+
+    let start = Instant::now();
+    while !self.is_stopped() && start.elapsed() < interval {
+      std::thread::sleep(STOP_POLL_INTERVAL.min(interval));
+    }
+    !self.is_stopped()
+  }
+}
+
+// Send checkpoints of a domain until `control` is stopped. Its device model,
+// if given, is stopped with the domain at each checkpoint to send its devices
+// state.
+pub fn run (
+  xc: &Xenctrl,
+  xs: &Xenstore,
+  dom_id: u32,
+  destination: &Destination,
+  config: &CheckpointConfig,
+  control: &CheckpointControl,
+  device_model: Option<&mut Qmp<UnixStream>>
+) -> save::Result<()> {
+  let mut save_config = SaveConfig::from_domain(xc, xs, dom_id)?;
+  save_config.checkpointed = true;
+  save_config.device_model = device_model.is_some();
+
+  let mut output = match destination {
+    Destination::File(path) => File::create(path)?,
+    // libxc only needs a file descriptor.
+    Destination::Peer(address) => {
+      let stream = TcpStream::connect(migration::destination_address(address))?;
+      unsafe { File::from_raw_fd(stream.into_raw_fd()) }
+    }
+  };
+  save::write_header(&mut output, &save_config)?;
+  output.flush()?;
+
+  let interval = Duration::from_millis(config.interval_ms);
+  let mut policy = |pause| control.next(interval, pause);
+  let result = save::save_stream(
+    xc, xs, dom_id, output.as_raw_fd(), save_config.hvm, SaveMode::Checkpointed(&mut policy), device_model
+  );
+
+  // libxc fails when the policy stops the checkpoints.
+  match result {
+    Err(_) if control.is_stopped() => Ok(()),
+    result => result
+  }
+}
+
+// -----------------------------------------------------------------------------
+// Receiver.
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+enum Request {
+  Activate,
+  Discard
+}
+
+enum StandbyState {
+  // Checkpoints are received, the function stops their stream.
+  Receiving(Box<dyn Fn() + Send>),
+  Ready(RestoredDomain),
+  // Activated while its checkpoints were received, the domain is paused until
+  // its device model is started with this devices state. It is given by the
+  // next activation.
+  Started(PathBuf)
+}
+
+struct StandbyDomain {
+  name: String,
+  state: StandbyState,
+  // Request received while the checkpoints are received.
+  request: Option<Request>
+}
+
+#[derive(Serialize)]
+pub struct StandbyInfo {
+  pub dom_id: u32,
+  pub name: String,
+  // "receiving", "ready" or "started".
+  pub state: &'static str
+}
+
+pub enum Activation {
+  // The domain is started, or paused until its device model is started with
+  // its devices state, see `RestoredDomain`.
+  Started(u32, Option<PathBuf>),
+  // The domain will be started once restored from the last checkpoint.
+  Pending
+}
+
+// Domains kept from the checkpoints of the peers, by domain id.
+#[derive(Default)]
+pub struct Standby {
+  domains: Mutex<BTreeMap<u32, StandbyDomain>>
+}
+
+impl Standby {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  pub fn list (&self) -> Vec<StandbyInfo> {
+    self.domains.lock().unwrap().iter().map(|(dom_id, domain)| StandbyInfo {
+      dom_id: *dom_id,
+      name: domain.name.clone(),
+      state: match domain.state {
+        StandbyState::Receiving(_) => "receiving",
+        StandbyState::Ready(_) => "ready",
+        StandbyState::Started(_) => "started"
+      }
+    }).collect()
+  }
+
+  // Start a standby domain from its last checkpoint. The checkpoints of the
+  // primary are stopped: it must be dead or stopped to not run the domain twice.
+  pub fn activate (&self, xc: &Xenctrl, xs: &Xenstore, dom_id: u32) -> save::Result<Activation> {
+    match self.request(dom_id, Request::Activate)? {
+      Some(StandbyState::Ready(domain)) => {
+        let devices_state = domain.devices_state.clone();
+        Ok(Activation::Started(domain.activate(xc, xs)?, devices_state))
+      },
+      Some(StandbyState::Started(devices_state)) => Ok(Activation::Started(dom_id, Some(devices_state))),
+      _ => Ok(Activation::Pending)
+    }
+  }
+
+  pub fn discard (&self, xc: &Xenctrl, xs: &Xenstore, dom_id: u32) -> save::Result<()> {
+    match self.request(dom_id, Request::Discard)? {
+      Some(StandbyState::Ready(domain)) => domain.discard(xc, xs),
+      Some(StandbyState::Started(devices_state)) => {
+        let _ = std::fs::remove_file(devices_state);
+        Ok(vm::destroy(xc, xs, dom_id)?)
+      },
+      _ => Ok(())
+    }
+  }
+
+  fn insert_receiving (&self, dom_id: u32, name: String, stop: Box<dyn Fn() + Send>) {
+    let state = StandbyState::Receiving(stop);
+    self.domains.lock().unwrap().insert(dom_id, StandbyDomain { name, state, request: None });
+  }
+
+  // Remove and return the domain once received, else stop the stream and keep
+  // the request.
+  fn request (&self, dom_id: u32, request: Request) -> save::Result<Option<StandbyState>> {
+    let mut domains = self.domains.lock().unwrap();
+    let receiving = match domains.get_mut(&dom_id) {
+      Some(StandbyDomain { state: StandbyState::Receiving(stop), request: pending, .. }) => {
+        *pending = Some(request);
+        stop();
+        true
+      },
+      Some(_) => false,
+      None => return Err(save::Error::NoSuchDomain(dom_id))
+    };
+
+    if receiving {
+      return Ok(None)
+    }
+    Ok(domains.remove(&dom_id).map(|domain| domain.state))
+  }
+
+  // Keep the domain restored from the last checkpoint, or return it with the
+  // request received meanwhile.
+  fn restored (&self, dom_id: u32, restored: RestoredDomain) -> Option<(Request, RestoredDomain)> {
+    let mut domains = self.domains.lock().unwrap();
+    let domain = domains.get_mut(&dom_id)?;
+    match domain.request {
+      Some(request) => {
+        domains.remove(&dom_id);
+        Some((request, restored))
+      },
+      None => {
+        domain.state = StandbyState::Ready(restored);
+        None
+      }
+    }
+  }
+
+  // Keep the devices state of a domain activated by a pending request.
+  fn started (&self, dom_id: u32, name: String, devices_state: PathBuf) {
+    let state = StandbyState::Started(devices_state);
+    self.domains.lock().unwrap().insert(dom_id, StandbyDomain { name, state, request: None });
+  }
+
+  // Receive the checkpoints of a peer until its stream stops. The domain is
+  // then kept paused, unless a request was made meanwhile.
+  pub fn receive (&self, xc: &Xenctrl, xs: &Xenstore, stream: &TcpStream, config: &SaveConfig) -> save::Result<u32> {
+    let dom_id = save::create_domain(xc, config)?;
+    let receiving = stream.try_clone()?;
+    self.insert_receiving(dom_id, config.name.clone(), Box::new(move || { let _ = receiving.shutdown(Shutdown::Both); }));
+
+    let restored = match save::restore_paused(xc, stream.as_raw_fd(), dom_id, config) {
+      Ok(restored) => restored,
+      Err(e) => {
+        self.domains.lock().unwrap().remove(&dom_id);
+        let _ = vm::destroy(xc, xs, dom_id);
+        return Err(e)
+      }
+    };
+
+    match self.restored(dom_id, restored) {
+      None => Ok(dom_id),
+      Some((Request::Activate, restored)) => {
+        let devices_state = restored.devices_state.clone();
+        let dom_id = restored.activate(xc, xs)?;
+        if let Some(devices_state) = devices_state {
+          self.started(dom_id, config.name.clone(), devices_state);
+        }
+        Ok(dom_id)
+      },
+      Some((Request::Discard, restored)) => restored.discard(xc, xs).map(|_| dom_id)
+    }
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::error;
+  use std::io::Read;
+
+  fn config (name: &str) -> SaveConfig {
+    SaveConfig {
+      name: String::from(name),
+      uuid: String::from("3a5ebd8e-1cbb-4bde-a8e6-8bbc4f1ad9a6"),
+      hvm: true,
+      max_vcpus: 1,
+      max_memkb: 1024,
+      checkpointed: true,
+      device_model: true
+    }
+  }
+
+  fn states (standby: &Standby) -> Vec<(u32, &'static str)> {
+    standby.list().iter().map(|info| (info.dom_id, info.state)).collect()
+  }
+
+  // A domain which receives its checkpoints on `stream`.
+  fn receiving (standby: &Standby, dom_id: u32) -> UnixStream {
+    let (stream, peer) = UnixStream::pair().unwrap();
+    standby.insert_receiving(dom_id, String::from("vm"), Box::new(move || { let _ = stream.shutdown(Shutdown::Both); }));
+    peer
+  }
+
+  #[test]
+  fn checkpoint_loop () {
+    let control = CheckpointControl::new();
+    assert!(control.next(Duration::from_millis(1), Duration::from_millis(12)));
+    assert!(control.next(Duration::from_millis(1), Duration::from_millis(7)));
+    assert_eq!(control.stats().checkpoints, 2);
+    assert_eq!(control.stats().last_pause_ms, 7);
+
+    // A stop ends the wait before the interval.
+    let stopper = control.clone();
+    let thread = std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_millis(100));
+      stopper.stop();
+    });
+    let start = Instant::now();
+    assert!(!control.next(Duration::from_secs(60), Duration::from_millis(5)));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    thread.join().unwrap();
+    assert!(control.is_stopped());
+    assert_eq!(control.stats().checkpoints, 3);
+  }
+
+  #[test]
+  fn ready () {
+    let standby = Standby::new();
+    let _peer = receiving(&standby, 3);
+    assert_eq!(states(&standby), vec![(3, "receiving")]);
+
+    // The stream stopped by itself.
+    assert!(standby.restored(3, RestoredDomain::simulated(3, config("vm"), None)).is_none());
+    assert_eq!(states(&standby), vec![(3, "ready")]);
+
+    match standby.request(3, Request::Discard).ok().unwrap() {
+      Some(StandbyState::Ready(domain)) => assert_eq!(domain.dom_id, 3),
+      _ => panic!("domain not ready")
+    }
+    assert!(standby.list().is_empty());
+    assert_eq!(error(standby.request(3, Request::Activate)), "no such domain: 3");
+  }
+
+  #[test]
+  fn activate_while_receiving () {
+    let standby = Standby::new();
+    let mut peer = receiving(&standby, 5);
+
+    // The stream of the checkpoints is stopped, the activation waits for the
+    // last checkpoint.
+    assert!(standby.request(5, Request::Activate).ok().unwrap().is_none());
+    assert_eq!(peer.read(&mut [0u8; 8]).unwrap(), 0);
+    assert_eq!(states(&standby), vec![(5, "receiving")]);
+
+    // The domain is activated with the devices state of the last checkpoint,
+    // which is kept for the next activation.
+    let state = PathBuf::from("/var/run/xenops/qmp/5.state");
+    let restored = RestoredDomain::simulated(5, config("vm"), Some(state.clone()));
+    match standby.restored(5, restored) {
+      Some((Request::Activate, domain)) => assert!(domain.devices_state == Some(state.clone())),
+      _ => panic!("activation lost")
+    }
+    assert!(standby.list().is_empty());
+    standby.started(5, String::from("vm"), state.clone());
+    assert_eq!(states(&standby), vec![(5, "started")]);
+
+    match standby.request(5, Request::Activate).ok().unwrap() {
+      Some(StandbyState::Started(devices_state)) => assert!(devices_state == state),
+      _ => panic!("devices state lost")
+    }
+    assert!(standby.list().is_empty());
+  }
+
+  #[test]
+  fn discard_while_receiving () {
+    let standby = Standby::new();
+    let mut peer = receiving(&standby, 6);
+    let _other = receiving(&standby, 7);
+
+    assert!(standby.request(6, Request::Discard).ok().unwrap().is_none());
+    assert_eq!(peer.read(&mut [0u8; 8]).unwrap(), 0);
+    match standby.restored(6, RestoredDomain::simulated(6, config("vm"), None)) {
+      Some((Request::Discard, domain)) => assert_eq!(domain.dom_id, 6),
+      _ => panic!("discard lost")
+    }
+    assert_eq!(states(&standby), vec![(7, "receiving")]);
+  }
+}
@@ -0,0 +1,712 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::bindings;
+use super::evtchn::EventChannel;
+use super::foreignmemory::{ForeignMemory, Mapping};
+use super::xenctrl::{self, Xenctrl};
+use super::xenstore::{self, Store, Xenstore};
+
+// =============================================================================
+// PV consoles.
+//
+// A guest writes its console output in a ring shared with the backend (see
+// xen/include/public/io/console.h). The frame of the ring and its event
+// channel are given in the `console/` nodes of the domain.
+//
+// The backend of this module serves the consoles of type CONSOLE_TYPE, and
+// the serial ports of the HVM guests given by their device model (a pty).
+// The output is appended to a log file per VM, rotated when it is too large,
+// and sent to the clients attached with `Sessions`. The consoles are polled,
+// the guests don't wait for the backend to write.
+//
+// The clients attach on CONSOLE_PORT of the local host: they send an
+// `AttachRequest` line, receive an `AttachReply` line, then the raw output.
+// What they send next is the input of the guest.
+// =============================================================================
+
+pub const CONSOLE_TYPE: &str = "xenops";
+
+pub const LOG_DIR: &str = "/var/log/xenops/console";
+
+pub const CONSOLE_PORT: u16 = 3032;
+
+// Key of the clients to detach: Ctrl-].
+pub const DETACH_KEY: u8 = 0x1d;
+
+// Interval between two reads of the rings by the backend.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Delays before opening again a console that failed, doubled at each failure.
+const OPEN_RETRY_MIN: Duration = Duration::from_secs(1);
+const OPEN_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// At most this size is read by `tail`.
+const TAIL_MAX_BYTES: u64 = 1 << 16;
+
+// Input of the writer not yet given to the guest.
+const MAX_PENDING_INPUT: usize = 4096;
+
+// Layout of `struct xencons_interface`.
+const IN_SIZE: u32 = 1024;
+const OUT_SIZE: u32 = 2048;
+const IN_OFFSET: usize = 0;
+const OUT_OFFSET: usize = IN_OFFSET + IN_SIZE as usize;
+const IN_CONS_OFFSET: usize = OUT_OFFSET + OUT_SIZE as usize;
+const IN_PROD_OFFSET: usize = IN_CONS_OFFSET + 4;
+const OUT_CONS_OFFSET: usize = IN_PROD_OFFSET + 4;
+const OUT_PROD_OFFSET: usize = OUT_CONS_OFFSET + 4;
+
+// Path of the console log of a VM.
+pub fn log_path (uuid: &str) -> PathBuf {
+  PathBuf::from(LOG_DIR).join(format!("{}.log", uuid))
+}
+
+fn rotated_path (path: &Path, index: u32) -> PathBuf {
+  let mut path = path.as_os_str().to_os_string();
+  path.push(format!(".{}", index));
+  PathBuf::from(path)
+}
+
+// Write the console nodes of a domain.
+pub fn write_nodes (store: &dyn Store, dom_id: u32, uuid: &str, ring_ref: u64, port: u32) -> xenstore::Result<()> {
+  let path = format!("{}/console", xenstore::get_domain_path(dom_id));
+  store.write(&format!("{}/ring-ref", path), &ring_ref.to_string())?;
+  store.write(&format!("{}/port", path), &port.to_string())?;
+  store.write(&format!("{}/type", path), CONSOLE_TYPE)?;
+  store.write(&format!("{}/output", path), &format!("file:{}", log_path(uuid).display()))
+}
+
+// -----------------------------------------------------------------------------
+
+fn read_index (page: &[u8], offset: usize) -> u32 {
+  unsafe { std::ptr::read_volatile(page[offset..offset + 4].as_ptr() as *const u32) }
+}
+
+fn write_index (page: &mut [u8], offset: usize, value: u32) {
+  unsafe { std::ptr::write_volatile(page[offset..offset + 4].as_mut_ptr() as *mut u32, value) }
+}
+
+// Consume the output written by the guest in the ring.
+pub fn read_output (page: &mut [u8]) -> Vec<u8> {
+  let cons = read_index(page, OUT_CONS_OFFSET);
+  let prod = read_index(page, OUT_PROD_OFFSET);
+  fence(Ordering::SeqCst);
+
+  // A guest can write anything in the ring, the indexes are not trusted.
+  let length = prod.wrapping_sub(cons);
+  let data = if length > OUT_SIZE {
+    Vec::new()
+  } else {
+    (0..length)
+      .map(|i| page[OUT_OFFSET + (cons.wrapping_add(i) & (OUT_SIZE - 1)) as usize])
+      .collect()
+  };
+
+  fence(Ordering::SeqCst);
+  write_index(page, OUT_CONS_OFFSET, prod);
+  data
+}
+
+// Give input to the guest. Returns the count of bytes written, less than
+// `data` if the ring is full.
+pub fn write_input (page: &mut [u8], data: &[u8]) -> usize {
+  let cons = read_index(page, IN_CONS_OFFSET);
+  let prod = read_index(page, IN_PROD_OFFSET);
+  fence(Ordering::SeqCst);
+
+  let used = prod.wrapping_sub(cons);
+  if used > IN_SIZE {
+    return 0
+  }
+  let count = data.len().min((IN_SIZE - used) as usize);
+  for (i, byte) in data[..count].iter().enumerate() {
+    page[IN_OFFSET + (prod.wrapping_add(i as u32) & (IN_SIZE - 1)) as usize] = *byte;
+  }
+
+  fence(Ordering::SeqCst);
+  write_index(page, IN_PROD_OFFSET, prod.wrapping_add(count as u32));
+  count
+}
+
+// The console ring of a domain, mapped in our address space.
+pub struct Ring<'a> {
+  mapping: Mapping<'a>
+}
+
+impl<'a> Ring<'a> {
+  pub fn map (fmem: &'a ForeignMemory, dom_id: u32, ring_ref: u64) -> xenctrl::Result<Self> {
+    Ok(Self { mapping: fmem.map(dom_id, &[ring_ref], true)? })
+  }
+
+  pub fn read_output (&mut self) -> Vec<u8> {
+    read_output(self.mapping.as_mut_slice())
+  }
+
+  pub fn write_input (&mut self, data: &[u8]) -> usize {
+    write_input(self.mapping.as_mut_slice(), data)
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+pub struct LogConfig {
+  // A log is rotated when it is larger.
+  pub max_size: u64,
+  // Count of rotated logs kept: <uuid>.log.1, <uuid>.log.2...
+  pub rotations: u32
+}
+
+impl Default for LogConfig {
+  fn default () -> Self {
+    Self { max_size: 1 << 20, rotations: 4 }
+  }
+}
+
+pub struct ConsoleLog {
+  path: PathBuf,
+  file: File,
+  size: u64,
+  config: LogConfig
+}
+
+impl ConsoleLog {
+  pub fn open (path: PathBuf, config: LogConfig) -> std::io::Result<Self> {
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let size = file.metadata()?.len();
+    Ok(Self { path, file, size, config })
+  }
+
+  pub fn write (&mut self, data: &[u8]) -> std::io::Result<()> {
+    if self.size > 0 && self.size + data.len() as u64 > self.config.max_size {
+      self.rotate()?;
+    }
+    self.file.write_all(data)?;
+    self.size += data.len() as u64;
+    Ok(())
+  }
+
+  fn rotate (&mut self) -> std::io::Result<()> {
+    if self.config.rotations == 0 {
+      self.file.set_len(0)?;
+      self.size = 0;
+      return Ok(())
+    }
+
+    for index in (1..self.config.rotations).rev() {
+      let from = rotated_path(&self.path, index);
+      if from.exists() {
+        std::fs::rename(&from, rotated_path(&self.path, index + 1))?;
+      }
+    }
+    std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+    self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+    self.size = 0;
+    Ok(())
+  }
+}
+
+// Last lines of the console log of a VM, the current log is completed with
+// the previous one if it is too short.
+pub fn tail (path: &Path, lines: usize) -> std::io::Result<String> {
+  let read_end = |path: &Path, max_bytes: u64| -> std::io::Result<Vec<u8>> {
+    let mut file = match File::open(path) {
+      Ok(file) => file,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e)
+    };
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(size.saturating_sub(max_bytes)))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+  };
+
+  let mut data = read_end(path, TAIL_MAX_BYTES)?;
+  if data.iter().filter(|byte| **byte == b'\n').count() <= lines && (data.len() as u64) < TAIL_MAX_BYTES {
+    let mut previous = read_end(&rotated_path(path, 1), TAIL_MAX_BYTES - data.len() as u64)?;
+    previous.append(&mut data);
+    data = previous;
+  }
+
+  // Keep the last `lines` lines, the last one may be incomplete.
+  let mut starts = data.iter().enumerate().rev()
+    .filter(|(i, byte)| **byte == b'\n' && i + 1 < data.len())
+    .map(|(i, _)| i + 1);
+  let start = match lines {
+    0 => data.len(),
+    lines => starts.nth(lines - 1).unwrap_or(0)
+  };
+  Ok(String::from_utf8_lossy(&data[start..]).into_owned())
+}
+
+// -----------------------------------------------------------------------------
+
+// Clients attached to the consoles served by a `Backend`. They all receive
+// the output, and one at most gives input to the guest.
+#[derive(Default)]
+pub struct Sessions {
+  channels: Mutex<HashMap<u32, Channel>>,
+  next_id: AtomicU64
+}
+
+#[derive(Default)]
+struct Channel {
+  readers: Vec<(u64, Sender<Vec<u8>>)>,
+  writer: Option<u64>,
+  input: Vec<u8>
+}
+
+// A client attached to a console, detached on drop.
+pub struct Attachment<'a> {
+  sessions: &'a Sessions,
+  dom_id: u32,
+  id: u64,
+  writer: bool
+}
+
+impl Sessions {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  // Attach a client to the console of a domain. It is the writer if asked
+  // and if there is no other one. Returns the receiver of the output, closed
+  // when the console is.
+  pub fn attach (&self, dom_id: u32, write: bool) -> Result<(Attachment<'_>, Receiver<Vec<u8>>), String> {
+    let mut channels = self.channels.lock().unwrap();
+    let channel = channels.get_mut(&dom_id).ok_or_else(|| format!("no console served for domain {}", dom_id))?;
+
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::channel();
+    channel.readers.push((id, sender));
+    let writer = write && channel.writer.is_none();
+    if writer {
+      channel.writer = Some(id);
+    }
+    Ok((Attachment { sessions: self, dom_id, id, writer }, receiver))
+  }
+
+  fn open (&self, dom_id: u32) {
+    self.channels.lock().unwrap().insert(dom_id, Channel::default());
+  }
+
+  // Disconnect the clients of a console.
+  fn close (&self, dom_id: u32) {
+    self.channels.lock().unwrap().remove(&dom_id);
+  }
+
+  fn broadcast (&self, dom_id: u32, data: &[u8]) {
+    if let Some(channel) = self.channels.lock().unwrap().get_mut(&dom_id) {
+      channel.readers.retain(|(_, sender)| sender.send(data.to_vec()).is_ok());
+    }
+  }
+
+  // Give the pending input to `write`, which returns the count of bytes used.
+  fn take_input<F: FnOnce(&[u8]) -> usize> (&self, dom_id: u32, write: F) -> usize {
+    match self.channels.lock().unwrap().get_mut(&dom_id) {
+      Some(channel) if !channel.input.is_empty() => {
+        let count = write(&channel.input);
+        channel.input.drain(..count);
+        count
+      },
+      _ => 0
+    }
+  }
+}
+
+impl Attachment<'_> {
+  pub fn is_writer (&self) -> bool {
+    self.writer
+  }
+
+  // Queue input for the guest. Ignored if the client is not the writer or
+  // if too much input is pending.
+  pub fn write (&self, data: &[u8]) {
+    if !self.writer {
+      return
+    }
+    if let Some(channel) = self.sessions.channels.lock().unwrap().get_mut(&self.dom_id) {
+      let count = data.len().min(MAX_PENDING_INPUT.saturating_sub(channel.input.len()));
+      channel.input.extend_from_slice(&data[..count]);
+    }
+  }
+}
+
+impl Drop for Attachment<'_> {
+  fn drop (&mut self) {
+    if let Some(channel) = self.sessions.channels.lock().unwrap().get_mut(&self.dom_id) {
+      channel.readers.retain(|(id, _)| *id != self.id);
+      if channel.writer == Some(self.id) {
+        channel.writer = None;
+        channel.input.clear();
+      }
+    }
+  }
+}
+
+// First line sent by a client on the console port, in JSON.
+#[derive(Serialize, Deserialize)]
+pub struct AttachRequest {
+  pub dom_id: u32,
+  pub write: bool
+}
+
+// Answer of the server, followed by the raw output if there is no error.
+#[derive(Serialize, Deserialize)]
+pub struct AttachReply {
+  pub writer: bool,
+  pub error: Option<String>
+}
+
+// Read a line without reading the data after it.
+pub fn read_line<R: Read> (reader: &mut R) -> std::io::Result<String> {
+  let mut line = Vec::new();
+  let mut byte = [0u8; 1];
+  loop {
+    if reader.read(&mut byte)? == 0 {
+      return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed"))
+    }
+    if byte[0] == b'\n' {
+      return String::from_utf8(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+    line.push(byte[0]);
+  }
+}
+
+fn send_json<T: Serialize> (stream: &mut TcpStream, value: &T) -> std::io::Result<()> {
+  let mut line = serde_json::to_vec(value)?;
+  line.push(b'\n');
+  stream.write_all(&line)
+}
+
+// Serve a client of the console port until it leaves or the console is closed.
+pub fn serve (sessions: &Sessions, mut stream: TcpStream) -> std::io::Result<()> {
+  let request: AttachRequest = serde_json::from_str(&read_line(&mut stream)?)?;
+  let (attachment, output) = match sessions.attach(request.dom_id, request.write) {
+    Ok(attached) => attached,
+    Err(e) => return send_json(&mut stream, &AttachReply { writer: false, error: Some(e) })
+  };
+  send_json(&mut stream, &AttachReply { writer: attachment.is_writer(), error: None })?;
+
+  let mut output_stream = stream.try_clone()?;
+  std::thread::spawn(move || {
+    for data in output {
+      if output_stream.write_all(&data).is_err() {
+        break
+      }
+    }
+    let _ = output_stream.shutdown(Shutdown::Both);
+  });
+
+  let mut buffer = [0u8; 1024];
+  loop {
+    match stream.read(&mut buffer) {
+      Ok(0) | Err(_) => break,
+      Ok(count) => attachment.write(&buffer[..count])
+    }
+  }
+  drop(attachment);
+  let _ = stream.shutdown(Shutdown::Both);
+  Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+enum Source<'a> {
+  // PV console, with the local port bound to its event channel.
+  Ring(Ring<'a>, u32),
+  // Serial port of a HVM guest: a pty of its device model.
+  Pty(File)
+}
+
+struct Console<'a> {
+  source: Source<'a>,
+  log: ConsoleLog
+}
+
+impl Console<'_> {
+  // Exchange the pending output and input with the guest.
+  fn pump (&mut self, dom_id: u32, sessions: &Sessions, evtchn: &EventChannel) -> Vec<u8> {
+    match &mut self.source {
+      Source::Ring(ring, port) => {
+        let output = ring.read_output();
+        let written = sessions.take_input(dom_id, |input| ring.write_input(input));
+        if !output.is_empty() || written > 0 {
+          let _ = evtchn.notify(*port);
+        }
+        output
+      },
+      Source::Pty(file) => {
+        let mut output = Vec::new();
+        let mut buffer = [0u8; 4096];
+        // Until it would block, or fails if the device model is gone.
+        while let Ok(count) = file.read(&mut buffer) {
+          if count == 0 {
+            break
+          }
+          output.extend_from_slice(&buffer[..count]);
+        }
+        sessions.take_input(dom_id, |input| file.write(input).unwrap_or(0));
+        output
+      }
+    }
+  }
+}
+
+// Console backend of the domains of this host.
+pub struct Backend<'a> {
+  xc: &'a Xenctrl,
+  xs: &'a Xenstore,
+  fmem: &'a ForeignMemory,
+  evtchn: &'a EventChannel,
+  sessions: &'a Sessions,
+  config: LogConfig,
+  consoles: HashMap<u32, Console<'a>>,
+  // Consoles that failed to open: when to retry and the next delay.
+  failures: HashMap<u32, (Instant, Duration)>
+}
+
+impl<'a> Backend<'a> {
+  pub fn new (
+    xc: &'a Xenctrl,
+    xs: &'a Xenstore,
+    fmem: &'a ForeignMemory,
+    evtchn: &'a EventChannel,
+    sessions: &'a Sessions,
+    config: LogConfig
+  ) -> Self {
+    Self { xc, xs, fmem, evtchn, sessions, config, consoles: HashMap::new(), failures: HashMap::new() }
+  }
+
+  // The serial port of a HVM guest is used if its device model gives one,
+  // else its PV console if it is served by us. None if there is no console
+  // to serve yet.
+  fn open_source (&self, dom_id: u32) -> Result<Option<Source<'a>>, String> {
+    let path = xenstore::get_domain_path(dom_id);
+    let read = |key: &str| self.xs.read(&format!("{}/{}", path, key)).ok();
+
+    if let Some(tty) = read("serial/0/tty") {
+      let file = OpenOptions::new().read(true).write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(&tty)
+        .map_err(|e| format!("failed to open serial port: {}", e))?;
+      return Ok(Some(Source::Pty(file)))
+    }
+
+    if read("console/type").as_deref() != Some(CONSOLE_TYPE) {
+      return Ok(None)
+    }
+    let (ring_ref, remote_port) = match (read("console/ring-ref"), read("console/port")) {
+      (Some(ring_ref), Some(port)) => (
+        ring_ref.parse().map_err(|_| format!("invalid ring-ref: `{}`", ring_ref))?,
+        port.parse().map_err(|_| format!("invalid port: `{}`", port))?
+      ),
+      _ => return Ok(None)
+    };
+    let ring = Ring::map(self.fmem, dom_id, ring_ref).map_err(|e| format!("failed to map ring: {}", e))?;
+    let port = self.evtchn.bind_interdomain(dom_id, remote_port)
+      .map_err(|e| format!("failed to bind event channel: {}", e))?;
+    Ok(Some(Source::Ring(ring, port)))
+  }
+
+  fn open_console (&self, info: &xenctrl::DomainInfo) -> Result<Option<Console<'a>>, String> {
+    let source = match self.open_source(u32::from(info.domain))? {
+      Some(source) => source,
+      None => return Ok(None)
+    };
+    let uuid = xenctrl::get_uuid_from_domain_handle(&info.handle);
+    match ConsoleLog::open(log_path(&uuid), self.config) {
+      Ok(log) => Ok(Some(Console { source, log })),
+      Err(e) => {
+        self.close_source(source);
+        Err(format!("failed to open log: {}", e))
+      }
+    }
+  }
+
+  // Open the console of a new domain. After a failure, it is retried later,
+  // and only the first failure is logged.
+  fn try_open_console (&mut self, info: &xenctrl::DomainInfo) {
+    let dom_id = u32::from(info.domain);
+    if let Some((retry, _)) = self.failures.get(&dom_id) {
+      if Instant::now() < *retry {
+        return
+      }
+    }
+
+    match self.open_console(info) {
+      Ok(Some(console)) => {
+        self.failures.remove(&dom_id);
+        self.consoles.insert(dom_id, console);
+        self.sessions.open(dom_id);
+      },
+      Ok(None) => (),
+      Err(e) => {
+        let delay = match self.failures.get(&dom_id) {
+          Some((_, delay)) => *delay,
+          None => {
+            eprintln!("Failed to serve console of domain {}: {}, retrying", dom_id, e);
+            OPEN_RETRY_MIN
+          }
+        };
+        self.failures.insert(dom_id, (Instant::now() + delay, (delay * 2).min(OPEN_RETRY_MAX)));
+      }
+    }
+  }
+
+  fn close_source (&self, source: Source<'a>) {
+    if let Source::Ring(_, port) = source {
+      let _ = self.evtchn.unbind(port);
+    }
+  }
+
+  // Serve the consoles of the new domains, forget the destroyed ones and
+  // exchange the pending output and input.
+  pub fn poll (&mut self) -> xenctrl::Result<()> {
+    let domains: Vec<xenctrl::DomainInfo> = self.xc.get_domain_info_list()?.into_iter()
+      .filter(|info| info.domain != 0 && info.flags & (1 << bindings::_XEN_DOMINF_dying) == 0)
+      .collect();
+
+    let destroyed: Vec<u32> = self.consoles.keys()
+      .filter(|dom_id| !domains.iter().any(|info| u32::from(info.domain) == **dom_id))
+      .copied()
+      .collect();
+    for dom_id in destroyed {
+      if let Some(console) = self.consoles.remove(&dom_id) {
+        self.close_source(console.source);
+      }
+      self.sessions.close(dom_id);
+    }
+    self.failures.retain(|dom_id, _| domains.iter().any(|info| u32::from(info.domain) == *dom_id));
+
+    for info in &domains {
+      if !self.consoles.contains_key(&u32::from(info.domain)) {
+        self.try_open_console(info);
+      }
+    }
+
+    for (dom_id, console) in self.consoles.iter_mut() {
+      let output = console.pump(*dom_id, self.sessions, self.evtchn);
+      if !output.is_empty() {
+        if let Err(e) = console.log.write(&output) {
+          eprintln!("Failed to write console log of domain {}: {}", dom_id, e);
+        }
+        self.sessions.broadcast(*dom_id, &output);
+      }
+    }
+    Ok(())
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::TempDir;
+
+  fn ring (in_cons: u32, in_prod: u32, out_cons: u32, out_prod: u32) -> Vec<u8> {
+    let mut page = vec![0u8; 4096];
+    write_index(&mut page, IN_CONS_OFFSET, in_cons);
+    write_index(&mut page, IN_PROD_OFFSET, in_prod);
+    write_index(&mut page, OUT_CONS_OFFSET, out_cons);
+    write_index(&mut page, OUT_PROD_OFFSET, out_prod);
+    page
+  }
+
+  #[test]
+  fn output () {
+    let mut page = ring(0, 0, 2040, 2052);
+    for i in 0..12u32 {
+      page[OUT_OFFSET + ((2040 + i) & (OUT_SIZE - 1)) as usize] = b'a' + i as u8;
+    }
+    assert_eq!(read_output(&mut page), b"abcdefghijkl");
+    assert_eq!(read_index(&page, OUT_CONS_OFFSET), 2052);
+    assert!(read_output(&mut page).is_empty());
+
+    // The indexes wrap around.
+    let mut page = ring(0, 0, u32::MAX - 1, 2);
+    page[OUT_OFFSET + 2046..OUT_OFFSET + 2048].copy_from_slice(b"ab");
+    page[OUT_OFFSET..OUT_OFFSET + 2].copy_from_slice(b"cd");
+    assert_eq!(read_output(&mut page), b"abcd");
+    assert_eq!(read_index(&page, OUT_CONS_OFFSET), 2);
+
+    // More than the ring: the output is dropped.
+    let mut page = ring(0, 0, 0, OUT_SIZE + 1);
+    assert!(read_output(&mut page).is_empty());
+    assert_eq!(read_index(&page, OUT_CONS_OFFSET), OUT_SIZE + 1);
+  }
+
+  #[test]
+  fn input () {
+    let mut page = ring(1020, 1020, 0, 0);
+    assert_eq!(write_input(&mut page, b"abcdefgh"), 8);
+    assert_eq!(&page[IN_OFFSET + 1020..IN_OFFSET + 1024], b"abcd");
+    assert_eq!(&page[IN_OFFSET..IN_OFFSET + 4], b"efgh");
+    assert_eq!(read_index(&page, IN_PROD_OFFSET), 1028);
+
+    // The ring is full after IN_SIZE bytes.
+    let mut page = ring(u32::MAX - 9, u32::MAX - 9, 0, 0);
+    assert_eq!(write_input(&mut page, &[b'x'; 2000]), IN_SIZE as usize);
+    assert_eq!(read_index(&page, IN_PROD_OFFSET), (u32::MAX - 9).wrapping_add(IN_SIZE));
+    assert_eq!(write_input(&mut page, b"y"), 0);
+
+    let mut page = ring(0, IN_SIZE + 1, 0, 0);
+    assert_eq!(write_input(&mut page, b"z"), 0);
+  }
+
+  #[test]
+  fn rotation () {
+    let dir = TempDir::new("console-rotation");
+    let path = dir.join("vm.log");
+    let mut log = ConsoleLog::open(path.clone(), LogConfig { max_size: 10, rotations: 2 }).unwrap();
+    for line in &["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+      log.write(line.as_bytes()).unwrap();
+    }
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 4\n");
+    assert_eq!(std::fs::read_to_string(rotated_path(&path, 1)).unwrap(), "line 3\n");
+    assert_eq!(std::fs::read_to_string(rotated_path(&path, 2)).unwrap(), "line 2\n");
+    assert!(!rotated_path(&path, 3).exists());
+
+    // The size of the existing log is kept.
+    let mut log = ConsoleLog::open(path.clone(), LogConfig { max_size: 10, rotations: 0 }).unwrap();
+    log.write(b"abc").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 4\nabc");
+    log.write(b"defg").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "defg");
+  }
+
+  #[test]
+  fn tail_lines () {
+    let dir = TempDir::new("console-tail");
+    let path = dir.join("vm.log");
+    assert_eq!(tail(&path, 10).unwrap(), "");
+
+    std::fs::write(rotated_path(&path, 1), "a\nb\nc\n").unwrap();
+    std::fs::write(&path, "d\ne").unwrap();
+    assert_eq!(tail(&path, 1).unwrap(), "e");
+    assert_eq!(tail(&path, 2).unwrap(), "d\ne");
+    // Completed with the previous log.
+    assert_eq!(tail(&path, 3).unwrap(), "c\nd\ne");
+    assert_eq!(tail(&path, 100).unwrap(), "a\nb\nc\nd\ne");
+    assert_eq!(tail(&path, 0).unwrap(), "");
+
+    std::fs::write(&path, "d\ne\n").unwrap();
+    assert_eq!(tail(&path, 1).unwrap(), "e\n");
+  }
+}
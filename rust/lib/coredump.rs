@@ -0,0 +1,497 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::bindings;
+use super::foreignmemory::{ForeignMemory, PAGE_SHIFT, PAGE_SIZE};
+use super::hvm_context::HvmContext;
+use super::vcpu_context::{self, X86Registers};
+use super::vm::CrashAction;
+use super::xenctrl::{self, Xenctrl};
+
+// =============================================================================
+// Guest core dumps.
+//
+// PV guests are dumped with `xc_domain_dumpcore`. HVM guests are dumped in an
+// ELF core file: one PT_LOAD segment per range of populated guest frames
+// (identity mapped: p_vaddr == p_paddr), and a PT_NOTE segment with the
+// registers of each vCPU taken from the HVM context, in NT_PRSTATUS notes for
+// gdb and in "QEMU" notes (same layout as `dump-guest-memory`) for crash.
+// =============================================================================
+
+pub enum Error {
+  Xen(xenctrl::Error),
+  Io(std::io::Error),
+  InvalidContext(String)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Xen(e) => write!(f, "{}", e),
+      Error::Io(e) => write!(f, "{}", e),
+      Error::InvalidContext(details) => write!(f, "invalid HVM context: {}", details)
+    }
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Xen(e)
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+
+// Number of frames mapped at once while dumping memory.
+const CHUNK_PAGES: u64 = 1024;
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+pub struct MemoryRange {
+  pub first_frame: u64,
+  pub pages: u64
+}
+
+fn push_note (notes: &mut Vec<u8>, name: &str, note_type: u32, desc: &[u8]) {
+  let align = |notes: &mut Vec<u8>| notes.resize((notes.len() + 3) & !3, 0);
+
+  notes.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+  notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+  notes.extend_from_slice(&note_type.to_le_bytes());
+  notes.extend_from_slice(name.as_bytes());
+  notes.push(0);
+  align(notes);
+  notes.extend_from_slice(desc);
+  align(notes);
+}
+
+// `struct elf_prstatus` of x86_64 Linux, only the pid and registers are set.
+pub fn prstatus (vcpu: u32, registers: &X86Registers) -> Vec<u8> {
+  let mut desc = vec![0u8; PRSTATUS_SIZE];
+  // pr_pid, gdb shows one thread per vCPU.
+  desc[32..36].copy_from_slice(&(vcpu + 1).to_le_bytes());
+
+  // `struct user_regs_struct`.
+  let regs = [
+    registers.r15, registers.r14, registers.r13, registers.r12,
+    registers.rbp, registers.rbx, registers.r11, registers.r10,
+    registers.r9, registers.r8, registers.rax, registers.rcx,
+    registers.rdx, registers.rsi, registers.rdi, 0,
+    registers.rip, registers.cs.selector.into(), registers.rflags, registers.rsp,
+    registers.ss.selector.into(), registers.fs.base, registers.gs.base,
+    registers.ds.selector.into(), registers.es.selector.into(),
+    registers.fs.selector.into(), registers.gs.selector.into()
+  ];
+  for (i, value) in regs.iter().enumerate() {
+    let offset = PRSTATUS_REGS_OFFSET + i * 8;
+    desc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+  }
+  desc
+}
+
+// `QEMUCPUState` of QEMU's dump.c, version 1.
+pub fn qemu_cpu_state (cpu: &bindings::HvmSaveTypeCpu) -> Vec<u8> {
+  let mut desc = Vec::new();
+  let mut push = |value: u64| desc.extend_from_slice(&value.to_le_bytes());
+
+  for value in &[
+    cpu.rax, cpu.rbx, cpu.rcx, cpu.rdx, cpu.rsi, cpu.rdi, cpu.rsp, cpu.rbp,
+    cpu.r8, cpu.r9, cpu.r10, cpu.r11, cpu.r12, cpu.r13, cpu.r14, cpu.r15,
+    cpu.rip, cpu.rflags
+  ] {
+    push(*value);
+  }
+
+  // QEMUCPUSegment: selector, limit, flags, pad (u32) and base (u64).
+  for (selector, limit, flags, base) in &[
+    (cpu.cs_sel, cpu.cs_limit, cpu.cs_arbytes, cpu.cs_base),
+    (cpu.ds_sel, cpu.ds_limit, cpu.ds_arbytes, cpu.ds_base),
+    (cpu.es_sel, cpu.es_limit, cpu.es_arbytes, cpu.es_base),
+    (cpu.fs_sel, cpu.fs_limit, cpu.fs_arbytes, cpu.fs_base),
+    (cpu.gs_sel, cpu.gs_limit, cpu.gs_arbytes, cpu.gs_base),
+    (cpu.ss_sel, cpu.ss_limit, cpu.ss_arbytes, cpu.ss_base),
+    (cpu.ldtr_sel, cpu.ldtr_limit, cpu.ldtr_arbytes, cpu.ldtr_base),
+    (cpu.tr_sel, cpu.tr_limit, cpu.tr_arbytes, cpu.tr_base),
+    (0, cpu.gdtr_limit, 0, cpu.gdtr_base),
+    (0, cpu.idtr_limit, 0, cpu.idtr_base)
+  ] {
+    push(u64::from(*selector) | u64::from(*limit) << 32);
+    push(u64::from(*flags));
+    push(*base);
+  }
+
+  for value in &[cpu.cr0, 0, cpu.cr2, cpu.cr3, cpu.cr4, cpu.shadow_gs] {
+    push(*value);
+  }
+
+  let mut state = Vec::with_capacity(desc.len() + 8);
+  state.extend_from_slice(&QEMU_CPU_STATE_VERSION.to_le_bytes());
+  state.extend_from_slice(&(desc.len() as u32 + 8).to_le_bytes());
+  state.extend_from_slice(&desc);
+  state
+}
+
+pub fn build_notes (context: &HvmContext) -> Vec<u8> {
+  let mut notes = Vec::new();
+  for (instance, cpu) in context.cpus() {
+    let vcpu = u32::from(instance);
+    push_note(&mut notes, "CORE", NT_PRSTATUS, &prstatus(vcpu, &vcpu_context::from_hvm_cpu(cpu)));
+    push_note(&mut notes, "QEMU", 0, &qemu_cpu_state(cpu));
+  }
+  notes
+}
+
+fn headers_size (ranges: &[MemoryRange]) -> usize {
+  ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * (ranges.len() + 1)
+}
+
+// Offset of the first PT_LOAD segment in the file.
+pub fn data_offset (ranges: &[MemoryRange], notes_size: usize) -> usize {
+  (headers_size(ranges) + notes_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn push_program_header (headers: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, addr: u64, size: u64, align: u64) {
+  headers.extend_from_slice(&kind.to_le_bytes());
+  headers.extend_from_slice(&flags.to_le_bytes());
+  headers.extend_from_slice(&offset.to_le_bytes());
+  headers.extend_from_slice(&addr.to_le_bytes()); // p_vaddr
+  headers.extend_from_slice(&addr.to_le_bytes()); // p_paddr
+  headers.extend_from_slice(&size.to_le_bytes()); // p_filesz
+  headers.extend_from_slice(&size.to_le_bytes()); // p_memsz
+  headers.extend_from_slice(&align.to_le_bytes());
+}
+
+// ELF header followed by the program headers: PT_NOTE then one PT_LOAD per range.
+pub fn build_headers (ranges: &[MemoryRange], notes_size: usize) -> Vec<u8> {
+  let mut headers = Vec::with_capacity(headers_size(ranges));
+
+  // e_ident: magic, ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_SYSV.
+  headers.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+  headers.extend_from_slice(&[0; 8]);
+  headers.extend_from_slice(&ET_CORE.to_le_bytes());
+  headers.extend_from_slice(&EM_X86_64.to_le_bytes());
+  headers.extend_from_slice(&1u32.to_le_bytes()); // e_version
+  headers.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+  headers.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+  headers.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+  headers.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+  headers.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+  headers.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+  headers.extend_from_slice(&(ranges.len() as u16 + 1).to_le_bytes()); // e_phnum
+  headers.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+  push_program_header(&mut headers, PT_NOTE, 0, headers_size(ranges) as u64, 0, notes_size as u64, 0);
+
+  let mut offset = data_offset(ranges, notes_size) as u64;
+  for range in ranges {
+    let size = range.pages << PAGE_SHIFT;
+    push_program_header(
+      &mut headers, PT_LOAD, PF_R | PF_W | PF_X, offset, range.first_frame << PAGE_SHIFT, size, PAGE_SIZE as u64
+    );
+    offset += size;
+  }
+
+  headers
+}
+
+// -----------------------------------------------------------------------------
+
+fn chunks (first_frame: u64, pages: u64) -> impl Iterator<Item = Vec<u64>> {
+  (0..pages).step_by(CHUNK_PAGES as usize).map(move |start| {
+    let end = std::cmp::min(start + CHUNK_PAGES, pages);
+    (first_frame + start..first_frame + end).collect()
+  })
+}
+
+pub fn find_memory_ranges (fmem: &ForeignMemory, dom_id: u32, max_frame: u64) -> Result<Vec<MemoryRange>> {
+  let mut ranges: Vec<MemoryRange> = Vec::new();
+
+  for frames in chunks(0, max_frame + 1) {
+    let (_mapping, errors) = fmem.map_sparse(dom_id, &frames, false)?;
+    for (frame, error) in frames.iter().zip(errors.iter()) {
+      if *error != 0 {
+        continue
+      }
+      match ranges.last_mut() {
+        Some(range) if range.first_frame + range.pages == *frame => range.pages += 1,
+        _ => ranges.push(MemoryRange { first_frame: *frame, pages: 1 })
+      }
+    }
+  }
+
+  Ok(ranges)
+}
+
+fn write_hvm_core (xc: &Xenctrl, fmem: &ForeignMemory, dom_id: u32, path: &Path) -> Result<()> {
+  let context = HvmContext::parse(&xc.get_hvm_context(dom_id)?)
+    .map_err(|e| Error::InvalidContext(e.to_string()))?;
+  let notes = build_notes(&context);
+  let ranges = find_memory_ranges(fmem, dom_id, xc.get_maximum_gpfn(dom_id)?)?;
+
+  let mut file = BufWriter::new(File::create(path)?);
+  let headers = build_headers(&ranges, notes.len());
+  file.write_all(&headers)?;
+  file.write_all(&notes)?;
+  file.write_all(&vec![0; data_offset(&ranges, notes.len()) - headers.len() - notes.len()])?;
+
+  for range in &ranges {
+    for frames in chunks(range.first_frame, range.pages) {
+      file.write_all(fmem.map(dom_id, &frames, false)?.as_slice())?;
+    }
+  }
+
+  file.flush()?;
+  Ok(())
+}
+
+pub fn dump (xc: &Xenctrl, fmem: &ForeignMemory, dom_id: u32, path: &Path) -> Result<()> {
+  if !vcpu_context::is_hvm(xc, dom_id)? {
+    return Ok(xc.dumpcore(dom_id, path)?)
+  }
+
+  // The memory must not change during the dump.
+  let was_paused = vcpu_context::is_paused(xc, dom_id)?;
+  if !was_paused {
+    xc.pause_domain(dom_id)?;
+  }
+  let result = write_hvm_core(xc, fmem, dom_id, path);
+  if !was_paused {
+    xc.unpause_domain(dom_id)?;
+  }
+  result
+}
+
+// Dump the core of a crashed domain in `directory` if required by the action.
+// Returns the path of the core file if any.
+pub fn dump_on_crash (
+  xc: &Xenctrl,
+  fmem: &ForeignMemory,
+  dom_id: u32,
+  action: CrashAction,
+  directory: &Path
+) -> Result<Option<PathBuf>> {
+  if !action.is_coredump() {
+    return Ok(None)
+  }
+
+  std::fs::create_dir_all(directory)?;
+  let uuid = xenctrl::get_uuid_from_domain_handle(&xc.get_domain_info(dom_id)?.handle);
+  let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+  let path = directory.join(format!("{}-{}.core", uuid, timestamp));
+  dump(xc, fmem, dom_id, &path)?;
+  Ok(Some(path))
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::mem::size_of;
+
+  fn u32_at (data: &[u8], offset: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(value)
+  }
+
+  fn u64_at (data: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(value)
+  }
+
+  fn cpu (rip: u64) -> bindings::HvmSaveTypeCpu {
+    let mut cpu: bindings::HvmSaveTypeCpu = unsafe { std::mem::zeroed() };
+    cpu.rax = 0x1111;
+    cpu.r15 = 0xf0f0;
+    cpu.rip = rip;
+    cpu.rsp = 0xffff_c900_0001_3f58;
+    cpu.rflags = 0x246;
+    cpu.cr0 = 0x8005_0033;
+    cpu.cr3 = 0x1_2345_6000;
+    cpu.cs_sel = 0x10;
+    cpu.cs_limit = 0xffff_ffff;
+    cpu.cs_arbytes = 0xa09b;
+    cpu.ss_sel = 0x18;
+    cpu.gs_base = 0xffff_8880_7fc0_0000;
+    cpu.idtr_limit = 0xfff;
+    cpu.idtr_base = 0xffff_fe00_0000_0000;
+    cpu.shadow_gs = 0x7f00_0000_1000;
+    cpu
+  }
+
+  fn context (cpus: &[bindings::HvmSaveTypeCpu]) -> HvmContext {
+    let mut blob = Vec::new();
+    for (instance, cpu) in cpus.iter().enumerate() {
+      blob.extend_from_slice(&bindings::HVM_SAVE_CODE_CPU.to_le_bytes());
+      blob.extend_from_slice(&(instance as u16).to_le_bytes());
+      blob.extend_from_slice(&(size_of::<bindings::HvmSaveTypeCpu>() as u32).to_le_bytes());
+      blob.extend_from_slice(unsafe {
+        std::slice::from_raw_parts(cpu as *const _ as *const u8, size_of::<bindings::HvmSaveTypeCpu>())
+      });
+    }
+    blob.extend_from_slice(&[0; 8]);
+    HvmContext::parse(&blob).ok().unwrap()
+  }
+
+  #[test]
+  fn elf_headers () {
+    let ranges = [MemoryRange { first_frame: 0, pages: 0xa0 }, MemoryRange { first_frame: 0x100, pages: 2 }];
+    let headers = build_headers(&ranges, 0x330);
+    assert_eq!(headers.len(), 64 + 3 * 56);
+    assert_eq!(data_offset(&ranges, 0x330), 0x1000);
+    assert_eq!(data_offset(&ranges, 0x1000 - 64 - 3 * 56 + 1), 0x2000);
+
+    assert_eq!(headers[..64], [
+      0x7f, 0x45, 0x4c, 0x46, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x04, 0x00, 0x3e, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ][..]);
+
+    // PT_NOTE, right after the program headers.
+    assert_eq!(headers[64..120], [
+      0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x30, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ][..]);
+
+    // PT_LOAD, RWX, identity mapped, the segments follow each other.
+    assert_eq!(headers[120..176], [
+      0x01, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ][..]);
+    assert_eq!(headers[176..232], [
+      0x01, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ][..]);
+  }
+
+  #[test]
+  fn note_padding () {
+    let mut notes = Vec::new();
+    push_note(&mut notes, "CORE", NT_PRSTATUS, &[1, 2, 3, 4, 5]);
+    push_note(&mut notes, "QEMU", 0, &[6, 7, 8, 9]);
+    assert_eq!(notes, [
+      0x05, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+      b'C', b'O', b'R', b'E', 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x00, 0x00,
+      0x05, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+      b'Q', b'E', b'M', b'U', 0x00, 0x00, 0x00, 0x00, 0x06, 0x07, 0x08, 0x09
+    ]);
+  }
+
+  #[test]
+  fn prstatus_layout () {
+    let registers = vcpu_context::from_hvm_cpu(&cpu(0xffff_ffff_8100_1234));
+    let desc = prstatus(2, &registers);
+    assert_eq!(desc.len(), 336);
+    // pr_pid.
+    assert_eq!(u32_at(&desc, 32), 3);
+
+    // user_regs_struct: r15, ..., rax at 10, orig_rax at 15, rip at 16.
+    let reg = |index: usize| u64_at(&desc, 112 + index * 8);
+    assert_eq!(reg(0), 0xf0f0);
+    assert_eq!(reg(10), 0x1111);
+    assert_eq!(reg(15), 0);
+    assert_eq!(reg(16), 0xffff_ffff_8100_1234);
+    assert_eq!(reg(17), 0x10);
+    assert_eq!(reg(18), 0x246);
+    assert_eq!(reg(19), 0xffff_c900_0001_3f58);
+    assert_eq!(reg(20), 0x18);
+    assert_eq!(reg(22), 0xffff_8880_7fc0_0000);
+    // pr_fpvalid and the padding.
+    assert!(desc[328..].iter().all(|byte| *byte == 0));
+  }
+
+  #[test]
+  fn qemu_cpu_state_layout () {
+    let state = qemu_cpu_state(&cpu(0x1000));
+    assert_eq!(state.len(), 440);
+    assert_eq!(u32_at(&state, 0), 1);
+    assert_eq!(u32_at(&state, 4), 440);
+
+    // rax..r15, rip and rflags.
+    assert_eq!(u64_at(&state, 8), 0x1111);
+    assert_eq!(u64_at(&state, 8 + 15 * 8), 0xf0f0);
+    assert_eq!(u64_at(&state, 8 + 16 * 8), 0x1000);
+    assert_eq!(u64_at(&state, 8 + 17 * 8), 0x246);
+
+    // Segments of 24 bytes from 152: cs first, idtr last.
+    assert_eq!(u64_at(&state, 152), 0xffff_ffff_0000_0010);
+    assert_eq!(u64_at(&state, 160), 0xa09b);
+    assert_eq!(u64_at(&state, 152 + 5 * 24), 0x18);
+    assert_eq!(u64_at(&state, 152 + 9 * 24), 0xfff << 32);
+    assert_eq!(u64_at(&state, 152 + 9 * 24 + 16), 0xffff_fe00_0000_0000);
+
+    // cr0, cr1, cr2, cr3, cr4 and kernel_gs_base.
+    assert_eq!(u64_at(&state, 392), 0x8005_0033);
+    assert_eq!(u64_at(&state, 416), 0x1_2345_6000);
+    assert_eq!(u64_at(&state, 432), 0x7f00_0000_1000);
+  }
+
+  #[test]
+  fn notes_per_vcpu () {
+    let notes = build_notes(&context(&[cpu(0x1000), cpu(0x2000)]));
+    assert_eq!(notes.len(), 2 * (20 + 336 + 20 + 440));
+
+    let mut offset = 0;
+    for (vcpu, rip) in &[(1, 0x1000), (2, 0x2000)] {
+      assert_eq!((u32_at(&notes, offset), u32_at(&notes, offset + 4), u32_at(&notes, offset + 8)), (5, 336, NT_PRSTATUS));
+      assert_eq!(&notes[offset + 12..offset + 17], b"CORE\0");
+      let desc = &notes[offset + 20..offset + 356];
+      assert_eq!(u32_at(desc, 32), *vcpu);
+      assert_eq!(u64_at(desc, 112 + 16 * 8), *rip);
+      offset += 356;
+
+      assert_eq!((u32_at(&notes, offset), u32_at(&notes, offset + 4), u32_at(&notes, offset + 8)), (5, 440, 0));
+      assert_eq!(&notes[offset + 12..offset + 17], b"QEMU\0");
+      assert_eq!(u64_at(&notes, offset + 20 + 8 + 16 * 8), *rip);
+      offset += 460;
+    }
+  }
+
+  #[test]
+  fn chunked_frames () {
+    let frames: Vec<Vec<u64>> = chunks(0x10, CHUNK_PAGES + 2).collect();
+    assert_eq!(frames.len(), 2);
+    assert_eq!((frames[0][0], frames[0].len()), (0x10, CHUNK_PAGES as usize));
+    assert_eq!(frames[1], [0x10 + CHUNK_PAGES, 0x11 + CHUNK_PAGES]);
+    assert_eq!(chunks(0, 0).count(), 0);
+  }
+}
@@ -0,0 +1,490 @@
+use std::time::{Duration, Instant};
+
+use super::xenstore::{self, Permission, PermissionKind, Store};
+
+pub mod pci;
+pub mod vbd;
+pub mod vif;
+
+// =============================================================================
+// Split drivers.
+//
+// A device is described by two xenstore directories: the frontend one in the
+// guest (`/local/domain/<fe>/device/<kind>/<devid>`) and the backend one in the
+// driver domain (`/local/domain/<be>/backend/<kind>/<fe>/<devid>`). Each side
+// publishes its XenbusState in its `state` node. The toolstack creates both
+// directories in the Initialising state, then waits for the backend to be
+// ready. With a `script` node, the backend domain also runs a hotplug script
+// and reports its result in `hotplug-status`: like libxl, the toolstack waits
+// for it. To unplug, it asks the backend to close, waits for it and removes
+// the directories.
+// =============================================================================
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Directory of the hotplug scripts, see `script_path`.
+pub const SCRIPTS_DIR: &str = "/etc/xen/scripts";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub enum Error {
+  Xenstore(&'static str),
+  InvalidConfig(String),
+  NoSuchDevice(u32),
+  // The backend failed, with the error reported by the hotplug scripts if any.
+  Backend(String),
+  Timeout(XenbusState)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Xenstore(details) => write!(f, "xenstore error: {}", details),
+      Error::InvalidConfig(details) => write!(f, "invalid device: {}", details),
+      Error::NoSuchDevice(devid) => write!(f, "no such device: {}", devid),
+      Error::Backend(details) => write!(f, "backend error: {}", details),
+      Error::Timeout(state) => write!(f, "timeout, the backend is {}", state)
+    }
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn xenstore_error (details: &'static str) -> impl FnOnce(xenstore::Error) -> Error {
+  move |_| Error::Xenstore(details)
+}
+
+// -----------------------------------------------------------------------------
+
+// See xen/include/public/io/xenbus.h.
+#[derive(Clone, Copy, PartialEq)]
+pub enum XenbusState {
+  Unknown,
+  Initialising,
+  InitWait,
+  Initialised,
+  Connected,
+  Closing,
+  Closed,
+  Reconfiguring,
+  Reconfigured
+}
+
+impl XenbusState {
+  pub fn from_value (value: u32) -> Self {
+    match value {
+      1 => XenbusState::Initialising,
+      2 => XenbusState::InitWait,
+      3 => XenbusState::Initialised,
+      4 => XenbusState::Connected,
+      5 => XenbusState::Closing,
+      6 => XenbusState::Closed,
+      7 => XenbusState::Reconfiguring,
+      8 => XenbusState::Reconfigured,
+      _ => XenbusState::Unknown
+    }
+  }
+
+  pub fn value (self) -> u32 {
+    match self {
+      XenbusState::Unknown => 0,
+      XenbusState::Initialising => 1,
+      XenbusState::InitWait => 2,
+      XenbusState::Initialised => 3,
+      XenbusState::Connected => 4,
+      XenbusState::Closing => 5,
+      XenbusState::Closed => 6,
+      XenbusState::Reconfiguring => 7,
+      XenbusState::Reconfigured => 8
+    }
+  }
+}
+
+impl std::fmt::Display for XenbusState {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      XenbusState::Unknown => write!(f, "unknown"),
+      XenbusState::Initialising => write!(f, "initialising"),
+      XenbusState::InitWait => write!(f, "initwait"),
+      XenbusState::Initialised => write!(f, "initialised"),
+      XenbusState::Connected => write!(f, "connected"),
+      XenbusState::Closing => write!(f, "closing"),
+      XenbusState::Closed => write!(f, "closed"),
+      XenbusState::Reconfiguring => write!(f, "reconfiguring"),
+      XenbusState::Reconfigured => write!(f, "reconfigured")
+    }
+  }
+}
+
+// What the toolstack does when it sees a backend state.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Step {
+  Wait,
+  Done,
+  Failed
+}
+
+// Plugging: the backend is ready once it waits for the frontend. The frontend
+// connects when the guest runs, it is not waited.
+pub fn attach_step (backend: XenbusState) -> Step {
+  match backend {
+    XenbusState::Unknown | XenbusState::Initialising => Step::Wait,
+    XenbusState::InitWait | XenbusState::Initialised | XenbusState::Connected => Step::Done,
+    _ => Step::Failed
+  }
+}
+
+// Hotplug: "connected" once the script succeeded, "error" otherwise.
+pub fn hotplug_step (status: Option<&str>) -> Step {
+  match status {
+    None => Step::Wait,
+    Some("connected") => Step::Done,
+    Some(_) => Step::Failed
+  }
+}
+
+// Unplugging: the backend closes once the frontend has released the device.
+pub fn detach_step (backend: XenbusState) -> Step {
+  match backend {
+    XenbusState::Closed => Step::Done,
+    XenbusState::Unknown => Step::Failed,
+    _ => Step::Wait
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Location of a device in xenstore.
+#[derive(Clone, Copy)]
+pub struct DevicePath {
+  pub kind: &'static str,
+  // Usually `kind`, another one selects another driver in the backend domain.
+  pub backend_kind: &'static str,
+  pub frontend_dom_id: u32,
+  pub backend_dom_id: u32,
+  pub devid: u32
+}
+
+impl DevicePath {
+  pub fn frontend (&self) -> String {
+    format!("{}/device/{}/{}", xenstore::get_domain_path(self.frontend_dom_id), self.kind, self.devid)
+  }
+
+  pub fn backend (&self) -> String {
+    format!(
+      "{}/backend/{}/{}/{}",
+      xenstore::get_domain_path(self.backend_dom_id), self.backend_kind, self.frontend_dom_id, self.devid
+    )
+  }
+
+  // Find a device of a guest from its frontend directory.
+  pub fn read (store: &dyn Store, kind: &'static str, frontend_dom_id: u32, devid: u32) -> Result<Self> {
+    let mut path = Self { kind, backend_kind: kind, frontend_dom_id, backend_dom_id: 0, devid };
+    let backend_id = store.read(&format!("{}/backend-id", path.frontend()))
+      .map_err(|_| Error::NoSuchDevice(devid))?;
+    path.backend_dom_id = backend_id.parse().map_err(|_| Error::Xenstore("invalid backend-id"))?;
+    Ok(path)
+  }
+}
+
+pub fn read_state (store: &dyn Store, path: &str) -> XenbusState {
+  store.read(&format!("{}/state", path)).ok()
+    .and_then(|state| state.parse().ok())
+    .map_or(XenbusState::Unknown, XenbusState::from_value)
+}
+
+// Path of a hotplug script of SCRIPTS_DIR given by its name. The backend domain
+// runs it as root: the other paths are refused.
+pub fn script_path (name: &str) -> Result<String> {
+  if name.is_empty() || name.contains('/') || name.contains("..") {
+    return Err(Error::InvalidConfig(format!("invalid hotplug script `{}`", name)))
+  }
+  Ok(format!("{}/{}", SCRIPTS_DIR, name))
+}
+
+// Error reported by the hotplug script of a failed backend, or its state.
+fn backend_error (store: &dyn Store, path: &str, state: XenbusState) -> Error {
+  Error::Backend(
+    store.read(&format!("{}/hotplug-error", path)).unwrap_or_else(|_| format!("backend is {}", state))
+  )
+}
+
+// Devids of the devices of a kind of a guest.
+pub fn list (store: &dyn Store, kind: &str, frontend_dom_id: u32) -> Vec<u32> {
+  let path = format!("{}/device/{}", xenstore::get_domain_path(frontend_dom_id), kind);
+  let mut devids: Vec<u32> = store.directory(&path).unwrap_or_default().iter()
+    .filter_map(|devid| devid.parse().ok())
+    .collect();
+  devids.sort_unstable();
+  devids
+}
+
+// Write the directories of a new device, with the nodes of each side.
+pub fn create (
+  store: &dyn Store,
+  device: &DevicePath,
+  backend: &[(&str, String)],
+  frontend: &[(&str, String)]
+) -> Result<()> {
+  let backend_path = device.backend();
+  let frontend_path = device.frontend();
+  let initialising = XenbusState::Initialising.value().to_string();
+
+  let backend_nodes = [
+    ("frontend", frontend_path.clone()),
+    ("frontend-id", device.frontend_dom_id.to_string()),
+    ("online", String::from("1")),
+    ("state", initialising.clone())
+  ];
+  let frontend_nodes = [
+    ("backend", backend_path.clone()),
+    ("backend-id", device.backend_dom_id.to_string()),
+    ("state", initialising)
+  ];
+
+  store.atomically(&mut |store| {
+    if store.read(&format!("{}/state", frontend_path)).is_ok() {
+      return Err(xenstore::Error::new())
+    }
+
+    // Each side owns its directory and can read the other one.
+    store.mkdir(&backend_path)?;
+    store.set_permissions(&backend_path, &[
+      Permission::new(device.backend_dom_id, PermissionKind::None),
+      Permission::new(device.frontend_dom_id, PermissionKind::Read)
+    ])?;
+    for (key, value) in backend_nodes.iter().chain(backend) {
+      store.write(&format!("{}/{}", backend_path, key), value)?;
+    }
+
+    store.mkdir(&frontend_path)?;
+    store.set_permissions(&frontend_path, &[
+      Permission::new(device.frontend_dom_id, PermissionKind::None),
+      Permission::new(device.backend_dom_id, PermissionKind::Read)
+    ])?;
+    for (key, value) in frontend_nodes.iter().chain(frontend) {
+      store.write(&format!("{}/{}", frontend_path, key), value)?;
+    }
+    Ok(())
+  }).map_err(|_| Error::InvalidConfig(format!("failed to create device {}, does it exist?", device.devid)))
+}
+
+// Poll the backend state until `step` is done.
+pub fn wait_backend (
+  store: &dyn Store,
+  device: &DevicePath,
+  step: fn(XenbusState) -> Step,
+  timeout: Duration
+) -> Result<XenbusState> {
+  let backend_path = device.backend();
+  let start = Instant::now();
+  loop {
+    let state = read_state(store, &backend_path);
+    match step(state) {
+      Step::Done => return Ok(state),
+      Step::Failed => return Err(backend_error(store, &backend_path, state)),
+      Step::Wait if start.elapsed() > timeout => return Err(Error::Timeout(state)),
+      Step::Wait => std::thread::sleep(POLL_INTERVAL)
+    }
+  }
+}
+
+// Poll `hotplug-status` until the script of the backend is done. A backend
+// closed meanwhile fails.
+pub fn wait_hotplug (store: &dyn Store, device: &DevicePath, timeout: Duration) -> Result<()> {
+  let backend_path = device.backend();
+  let start = Instant::now();
+  loop {
+    let status = store.read(&format!("{}/hotplug-status", backend_path)).ok();
+    let state = read_state(store, &backend_path);
+    match hotplug_step(status.as_deref()) {
+      Step::Done => return Ok(()),
+      Step::Failed => return Err(backend_error(store, &backend_path, state)),
+      Step::Wait if attach_step(state) == Step::Failed => return Err(backend_error(store, &backend_path, state)),
+      Step::Wait if start.elapsed() > timeout => return Err(Error::Timeout(state)),
+      Step::Wait => std::thread::sleep(POLL_INTERVAL)
+    }
+  }
+}
+
+// Create a device and wait for its backend, and for its hotplug script if any.
+pub fn attach (
+  store: &dyn Store,
+  device: &DevicePath,
+  backend: &[(&str, String)],
+  frontend: &[(&str, String)],
+  timeout: Duration
+) -> Result<XenbusState> {
+  create(store, device, backend, frontend)?;
+  let start = Instant::now();
+  let hotplug = backend.iter().any(|(key, _)| *key == "script");
+  wait_backend(store, device, attach_step, timeout).and_then(|state| {
+    if hotplug {
+      wait_hotplug(store, device, timeout.checked_sub(start.elapsed()).unwrap_or_default())?;
+    }
+    Ok(state)
+  }).map_err(|e| {
+    destroy(store, device);
+    e
+  })
+}
+
+// Remove the directories of a device.
+pub fn destroy (store: &dyn Store, device: &DevicePath) {
+  let _ = store.rm(&device.frontend());
+  let _ = store.rm(&device.backend());
+}
+
+// Ask the backend to close and remove the device once closed. With `force`,
+// the device is removed without waiting, even if the guest still uses it.
+pub fn detach (store: &dyn Store, device: &DevicePath, force: bool, timeout: Duration) -> Result<()> {
+  let backend_path = device.backend();
+  if !force && store.read(&format!("{}/state", backend_path)).is_err() {
+    return Err(Error::NoSuchDevice(device.devid))
+  }
+
+  let closing = store.atomically(&mut |store| {
+    store.write(&format!("{}/online", backend_path), "0")?;
+    store.write(&format!("{}/state", backend_path), &XenbusState::Closing.value().to_string())
+  });
+
+  if !force {
+    closing.map_err(xenstore_error("failed to close backend"))?;
+    wait_backend(store, device, detach_step, timeout)?;
+  }
+
+  destroy(store, device);
+  Ok(())
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::error;
+  use crate::xenstore::MemoryStore;
+  use std::sync::Arc;
+  use std::thread;
+
+  const DEVICE: DevicePath = DevicePath { kind: "vbd", backend_kind: "vbd", frontend_dom_id: 3, backend_dom_id: 0, devid: 51712 };
+
+  const TIMEOUT: Duration = Duration::from_secs(5);
+
+  // Driver domain: once the device is created, write `nodes` in its backend,
+  // then close it when asked to.
+  fn spawn_backend (store: Arc<MemoryStore>, nodes: &'static [(&'static str, &'static str)]) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+      let path = DEVICE.backend();
+      let read = |key: &str| store.read(&format!("{}/{}", path, key)).ok();
+      let start = Instant::now();
+      while read("state").is_none() {
+        assert!(start.elapsed() < TIMEOUT);
+        thread::sleep(POLL_INTERVAL);
+      }
+      for (key, value) in nodes {
+        assert!(store.write(&format!("{}/{}", path, key), value).is_ok());
+      }
+      // Removed by the toolstack on failure, or closed.
+      while let Some(state) = read("state") {
+        if read("online").as_deref() == Some("0") && state == "5" {
+          assert!(store.write(&format!("{}/state", path), "6").is_ok());
+          return
+        }
+        assert!(start.elapsed() < TIMEOUT);
+        thread::sleep(POLL_INTERVAL);
+      }
+    })
+  }
+
+  fn script () -> Vec<(&'static str, String)> {
+    vec![("script", String::from("/etc/xen/scripts/block"))]
+  }
+
+  #[test]
+  fn script_paths () {
+    assert_eq!(script_path("block").ok().unwrap(), "/etc/xen/scripts/block");
+    assert_eq!(script_path("vif-openvswitch").ok().unwrap(), "/etc/xen/scripts/vif-openvswitch");
+    assert_eq!(error(script_path("/tmp/evil")), "invalid device: invalid hotplug script `/tmp/evil`");
+    assert!(script_path("../../../tmp/evil").is_err());
+    assert!(script_path("..").is_err());
+    assert!(script_path("").is_err());
+  }
+
+  #[test]
+  fn attach_detach () {
+    let store = Arc::new(MemoryStore::new());
+    let backend = spawn_backend(store.clone(), &[("state", "2"), ("hotplug-status", "connected")]);
+
+    assert!(attach(&*store, &DEVICE, &script(), &[], TIMEOUT).map(|state| state == XenbusState::InitWait).unwrap_or(false));
+    assert_eq!(store.read(&format!("{}/frontend-id", DEVICE.backend())).ok().as_deref(), Some("3"));
+    assert_eq!(store.read(&format!("{}/backend-id", DEVICE.frontend())).ok().as_deref(), Some("0"));
+    assert_eq!(list(&*store, "vbd", 3), vec![51712]);
+    assert_eq!(error(create(&*store, &DEVICE, &[], &[])), "invalid device: failed to create device 51712, does it exist?");
+
+    assert!(detach(&*store, &DEVICE, false, TIMEOUT).is_ok());
+    backend.join().unwrap();
+    assert!(store.read(&DEVICE.backend()).is_err());
+    assert!(store.read(&DEVICE.frontend()).is_err());
+    assert_eq!(error(detach(&*store, &DEVICE, false, TIMEOUT)), "no such device: 51712");
+  }
+
+  #[test]
+  fn attach_without_script () {
+    // No hotplug-status is waited.
+    let store = Arc::new(MemoryStore::new());
+    let backend = spawn_backend(store.clone(), &[("state", "4")]);
+    assert!(attach(&*store, &DEVICE, &[], &[], TIMEOUT).is_ok());
+    assert!(detach(&*store, &DEVICE, false, TIMEOUT).is_ok());
+    backend.join().unwrap();
+  }
+
+  #[test]
+  fn backend_closed_during_attach () {
+    let store = Arc::new(MemoryStore::new());
+    let backend = spawn_backend(store.clone(), &[("state", "2"), ("state", "5")]);
+    assert_eq!(error(attach(&*store, &DEVICE, &script(), &[], TIMEOUT)), "backend error: backend is closing");
+    backend.join().unwrap();
+    assert!(store.read(&DEVICE.backend()).is_err());
+
+    let backend = spawn_backend(store.clone(), &[("state", "6")]);
+    assert_eq!(error(attach(&*store, &DEVICE, &[], &[], TIMEOUT)), "backend error: backend is closed");
+    backend.join().unwrap();
+    assert!(store.read(&DEVICE.frontend()).is_err());
+  }
+
+  #[test]
+  fn hotplug_error () {
+    let store = Arc::new(MemoryStore::new());
+    let backend = spawn_backend(store.clone(), &[
+      ("state", "2"),
+      ("hotplug-error", "/dev/missing does not exist"),
+      ("hotplug-status", "error")
+    ]);
+    assert_eq!(error(attach(&*store, &DEVICE, &script(), &[], TIMEOUT)), "backend error: /dev/missing does not exist");
+    backend.join().unwrap();
+    assert!(store.read(&DEVICE.backend()).is_err());
+    assert!(store.read(&DEVICE.frontend()).is_err());
+  }
+
+  #[test]
+  fn timeout () {
+    // Nobody serves the device.
+    let store = MemoryStore::new();
+    let timeout = Duration::from_millis(50);
+    assert_eq!(error(attach(&store, &DEVICE, &[], &[], timeout)), "timeout, the backend is initialising");
+    assert!(store.read(&DEVICE.backend()).is_err());
+
+    // The hotplug script does not finish.
+    let store = Arc::new(MemoryStore::new());
+    let backend = spawn_backend(store.clone(), &[("state", "2")]);
+    assert_eq!(error(attach(&*store, &DEVICE, &script(), &[], timeout)), "timeout, the backend is initwait");
+    backend.join().unwrap();
+
+    // The frontend does not release the device, unless forced.
+    assert!(create(&*store, &DEVICE, &[], &[]).is_ok());
+    assert_eq!(error(detach(&*store, &DEVICE, false, timeout)), "timeout, the backend is closing");
+    assert!(detach(&*store, &DEVICE, true, timeout).is_ok());
+    assert!(store.read(&DEVICE.backend()).is_err());
+  }
+}
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use super::{DevicePath, Error, Result, Step, XenbusState};
+use crate::xenstore::Store;
+
+// =============================================================================
+// PCI devices of PV guests (pcifront/pciback).
+//
+// A guest has one pci device (devid 0) whose backend lists the PCI devices
+// given to it: `num_devs`, then `dev-<n>`, `key-<n>`, `opts-<n>` and
+// `state-<n>` for each one. A device is added or removed from a connected
+// backend by putting it in the Reconfiguring state.
+// =============================================================================
+
+pub const KIND: &str = "pci";
+
+fn device_path (dom_id: u32) -> DevicePath {
+  DevicePath { kind: KIND, backend_kind: KIND, frontend_dom_id: dom_id, backend_dom_id: 0, devid: 0 }
+}
+
+// Reconfiguring: the backend is connected again once done.
+fn reconfigure_step (backend: XenbusState) -> Step {
+  match backend {
+    XenbusState::Connected => Step::Done,
+    XenbusState::Reconfiguring | XenbusState::Reconfigured => Step::Wait,
+    _ => Step::Failed
+  }
+}
+
+fn read_count (store: &dyn Store, backend_path: &str) -> u32 {
+  store.read(&format!("{}/num_devs", backend_path)).ok()
+    .and_then(|count| count.parse().ok())
+    .unwrap_or(0)
+}
+
+// PCI devices given to a guest, by BDF.
+pub fn list (store: &dyn Store, dom_id: u32) -> Vec<String> {
+  let backend_path = device_path(dom_id).backend();
+  (0..read_count(store, &backend_path))
+    .filter_map(|index| store.read(&format!("{}/dev-{}", backend_path, index)).ok())
+    .collect()
+}
+
+// Give a PCI device to the backend of a guest. `opts` are the options of
+// pciback: "permissive=0,msitranslate=0...".
+pub fn add (store: &dyn Store, dom_id: u32, bdf: &str, opts: &str, timeout: Duration) -> Result<()> {
+  let device = device_path(dom_id);
+  let backend_path = device.backend();
+  let state = super::read_state(store, &backend_path);
+  let initialising = XenbusState::Initialising.value().to_string();
+
+  if state == XenbusState::Unknown {
+    let backend = [
+      ("num_devs", String::from("1")),
+      ("dev-0", bdf.to_string()),
+      ("key-0", bdf.to_string()),
+      ("opts-0", opts.to_string()),
+      ("state-0", initialising)
+    ];
+    return super::attach(store, &device, &backend, &[], timeout).map(|_| ())
+  }
+
+  if list(store, dom_id).iter().any(|dev| dev == bdf) {
+    return Err(Error::InvalidConfig(format!("{} is already given to domain {}", bdf, dom_id)))
+  }
+
+  // Until the guest connects, the backend reads the new nodes by itself.
+  let reconfigure = state == XenbusState::Connected;
+  store.atomically(&mut |store| {
+    let index = read_count(store, &backend_path);
+    let write = |key: &str, value: &str| store.write(&format!("{}/{}-{}", backend_path, key, index), value);
+    write("dev", bdf)?;
+    write("key", bdf)?;
+    write("opts", opts)?;
+    write("state", &initialising)?;
+    store.write(&format!("{}/num_devs", backend_path), &(index + 1).to_string())?;
+    if reconfigure {
+      store.write(&format!("{}/state", backend_path), &XenbusState::Reconfiguring.value().to_string())?;
+    }
+    Ok(())
+  }).map_err(super::xenstore_error("failed to add PCI device"))?;
+
+  if reconfigure {
+    super::wait_backend(store, &device, reconfigure_step, timeout)?;
+  }
+  Ok(())
+}
+
+// Take a PCI device back from the backend of a guest. The whole pci device is
+// unplugged with the last one, see `device::detach`.
+pub fn remove (store: &dyn Store, dom_id: u32, bdf: &str, force: bool, timeout: Duration) -> Result<()> {
+  let device = device_path(dom_id);
+  let backend_path = device.backend();
+  let devices = list(store, dom_id);
+  let index = devices.iter().position(|dev| dev == bdf)
+    .ok_or_else(|| Error::InvalidConfig(format!("{} is not given to domain {}", bdf, dom_id)))?;
+
+  if devices.len() == 1 {
+    return super::detach(store, &device, force, timeout)
+  }
+
+  if !force && super::read_state(store, &backend_path) == XenbusState::Connected {
+    store.atomically(&mut |store| {
+      store.write(&format!("{}/state-{}", backend_path, index), &XenbusState::Closing.value().to_string())?;
+      store.write(&format!("{}/state", backend_path), &XenbusState::Reconfiguring.value().to_string())
+    }).map_err(super::xenstore_error("failed to close PCI device"))?;
+    super::wait_backend(store, &device, reconfigure_step, timeout)?;
+  }
+
+  // The next devices take the place of the removed one.
+  let count = devices.len();
+  store.atomically(&mut |store| {
+    for key in &["dev", "key", "opts", "state", "vdevfn"] {
+      let node = |index: usize| format!("{}/{}-{}", backend_path, key, index);
+      let _ = store.rm(&node(index));
+      for next in index + 1..count {
+        if let Ok(value) = store.read(&node(next)) {
+          store.write(&node(next - 1), &value)?;
+          let _ = store.rm(&node(next));
+        }
+      }
+    }
+    store.write(&format!("{}/num_devs", backend_path), &(count - 1).to_string())
+  }).map_err(super::xenstore_error("failed to remove PCI device"))
+}
@@ -0,0 +1,378 @@
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{script_path, DevicePath, Error, Result, XenbusState};
+use crate::blkback::image::Format;
+use crate::xenstore::Store;
+
+// =============================================================================
+// Virtual block devices (blkfront/blkback).
+//
+// The backend is the blkback driver of the kernel of the backend domain, with
+// a hotplug script, or the native backend of the daemon (see `blkback`). The
+// latter has its own backend kind: the frontend reads the path of its backend.
+// =============================================================================
+
+pub const KIND: &str = "vbd";
+
+pub const NATIVE_BACKEND_KIND: &str = "xenops-vbd";
+
+const BLOCK_SCRIPT: &str = "block";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+  ReadOnly,
+  ReadWrite
+}
+
+impl std::str::FromStr for Mode {
+  type Err = String;
+
+  fn from_str (value: &str) -> std::result::Result<Self, Self::Err> {
+    match value {
+      "r" => Ok(Mode::ReadOnly),
+      "w" => Ok(Mode::ReadWrite),
+      _ => Err(format!("invalid mode: `{}`", value))
+    }
+  }
+}
+
+impl std::fmt::Display for Mode {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Mode::ReadOnly => write!(f, "r"),
+      Mode::ReadWrite => write!(f, "w")
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DeviceType {
+  Disk,
+  Cdrom
+}
+
+impl std::str::FromStr for DeviceType {
+  type Err = String;
+
+  fn from_str (value: &str) -> std::result::Result<Self, Self::Err> {
+    match value {
+      "disk" => Ok(DeviceType::Disk),
+      "cdrom" => Ok(DeviceType::Cdrom),
+      _ => Err(format!("invalid device type: `{}`", value))
+    }
+  }
+}
+
+impl std::fmt::Display for DeviceType {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      DeviceType::Disk => write!(f, "disk"),
+      DeviceType::Cdrom => write!(f, "cdrom")
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+  Blkback,
+  Native
+}
+
+impl std::str::FromStr for Backend {
+  type Err = String;
+
+  fn from_str (value: &str) -> std::result::Result<Self, Self::Err> {
+    match value {
+      "blkback" => Ok(Backend::Blkback),
+      "native" => Ok(Backend::Native),
+      _ => Err(format!("invalid backend: `{}`", value))
+    }
+  }
+}
+
+impl std::fmt::Display for Backend {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Backend::Blkback => write!(f, "blkback"),
+      Backend::Native => write!(f, "native")
+    }
+  }
+}
+
+pub struct VbdConfig {
+  // Name of the disk in the guest: xvda, hdc, sdb1... or a raw devid.
+  pub vdev: String,
+  // Disk given to the backend: a block device, a file...
+  pub target: String,
+  pub backend: Backend,
+  // Kind of target for the hotplug script: "phy", "file"... The format of the
+  // image for the native backend: "raw" or "qcow2".
+  pub backend_type: String,
+  pub mode: Mode,
+  pub device_type: DeviceType,
+  pub backend_dom_id: u32,
+  // Name of the hotplug script run in the backend domain, see `script_path`.
+  // The block script by default.
+  pub script: Option<String>
+}
+
+impl VbdConfig {
+  pub fn new (vdev: &str, target: &str) -> Self {
+    Self {
+      vdev: vdev.to_string(),
+      target: target.to_string(),
+      backend: Backend::Blkback,
+      backend_type: String::from("phy"),
+      mode: Mode::ReadWrite,
+      device_type: DeviceType::Disk,
+      backend_dom_id: 0,
+      script: None
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct VbdInfo {
+  pub devid: u32,
+  pub vdev: String,
+  pub target: String,
+  pub mode: String,
+  pub device_type: String,
+  pub backend: String,
+  pub backend_dom_id: u32,
+  pub state: String
+}
+
+// I/O counters of a disk since its backend is connected.
+#[derive(Clone, Copy, Default)]
+pub struct VbdStatistics {
+  pub read_requests: u64,
+  pub write_requests: u64,
+  pub read_sectors: u64,
+  pub write_sectors: u64
+}
+
+// -----------------------------------------------------------------------------
+
+// Split "xvdb2" in the disk index (1) and the partition (2).
+fn parse_disk_name (name: &str) -> Option<(u32, u32)> {
+  let letters = name.bytes().take_while(u8::is_ascii_lowercase).count();
+  if letters == 0 || letters > 4 {
+    return None
+  }
+
+  // Bijective base 26: a..z, aa..zz...
+  let disk = name[..letters].bytes().fold(0, |disk, letter| disk * 26 + u32::from(letter - b'a') + 1) - 1;
+  let partition = match &name[letters..] {
+    "" => 0,
+    digits if !digits.starts_with('0') => digits.parse().ok()?,
+    _ => return None
+  };
+  Some((disk, partition))
+}
+
+// Devid of a virtual device, like libxl and the Linux frontend.
+pub fn parse_vdev (vdev: &str) -> Option<u32> {
+  if let Ok(devid) = vdev.parse() {
+    return Some(devid)
+  }
+
+  if let Some(name) = vdev.strip_prefix("xvd") {
+    let (disk, partition) = parse_disk_name(name)?;
+    return if disk < 16 && partition < 16 {
+      Some(202 << 8 | disk << 4 | partition)
+    } else if disk < 1 << 20 && partition < 256 {
+      // Extended numbering.
+      Some(1 << 28 | disk << 8 | partition)
+    } else {
+      None
+    }
+  }
+  if let Some(name) = vdev.strip_prefix("hd") {
+    let (disk, partition) = parse_disk_name(name)?;
+    if disk >= 4 || partition >= 64 {
+      return None
+    }
+    let major = if disk < 2 { 3 } else { 22 };
+    return Some(major << 8 | (disk & 1) << 6 | partition)
+  }
+  if let Some(name) = vdev.strip_prefix("sd") {
+    let (disk, partition) = parse_disk_name(name)?;
+    if disk >= 16 || partition >= 16 {
+      return None
+    }
+    return Some(8 << 8 | disk << 4 | partition)
+  }
+  None
+}
+
+fn device_path (config: &VbdConfig, dom_id: u32) -> Result<DevicePath> {
+  let devid = parse_vdev(&config.vdev)
+    .ok_or_else(|| Error::InvalidConfig(format!("invalid virtual device `{}`", config.vdev)))?;
+  let backend_kind = match config.backend {
+    Backend::Blkback => KIND,
+    Backend::Native => NATIVE_BACKEND_KIND
+  };
+  Ok(DevicePath { kind: KIND, backend_kind, frontend_dom_id: dom_id, backend_dom_id: config.backend_dom_id, devid })
+}
+
+// Find a disk of a guest, served by either backend.
+fn read_device_path (store: &dyn Store, dom_id: u32, devid: u32) -> Result<DevicePath> {
+  let mut device = DevicePath::read(store, KIND, dom_id, devid)?;
+  let backend_path = store.read(&format!("{}/backend", device.frontend())).unwrap_or_default();
+  if backend_path.contains(&format!("/backend/{}/", NATIVE_BACKEND_KIND)) {
+    device.backend_kind = NATIVE_BACKEND_KIND;
+  }
+  Ok(device)
+}
+
+// Plug a disk in a guest. Returns its devid once the backend is ready.
+pub fn attach (store: &dyn Store, dom_id: u32, config: &VbdConfig, timeout: Duration) -> Result<u32> {
+  let device = device_path(config, dom_id)?;
+  let removable = config.device_type == DeviceType::Cdrom;
+
+  let mut backend = vec![
+    ("dev", config.vdev.clone()),
+    ("params", config.target.clone()),
+    ("type", config.backend_type.clone()),
+    ("mode", config.mode.to_string()),
+    ("device-type", config.device_type.to_string()),
+    ("removable", (removable as u8).to_string()),
+    ("bootable", String::from("1"))
+  ];
+  match config.backend {
+    Backend::Blkback => {
+      backend.push(("script", script_path(config.script.as_deref().unwrap_or(BLOCK_SCRIPT))?));
+    },
+    Backend::Native => {
+      // Served by the daemon of dom0, without hotplug script.
+      config.backend_type.parse::<Format>().map_err(Error::InvalidConfig)?;
+      if config.backend_dom_id != 0 || config.script.is_some() {
+        return Err(Error::InvalidConfig(String::from("the native backend runs in dom0, without script")))
+      }
+    }
+  }
+  let frontend = [
+    ("virtual-device", device.devid.to_string()),
+    ("device-type", config.device_type.to_string())
+  ];
+
+  super::attach(store, &device, &backend, &frontend, timeout)?;
+  Ok(device.devid)
+}
+
+// Unplug a disk, see `device::detach`.
+pub fn detach (store: &dyn Store, dom_id: u32, devid: u32, force: bool, timeout: Duration) -> Result<()> {
+  let device = read_device_path(store, dom_id, devid)?;
+  super::detach(store, &device, force, timeout)
+}
+
+pub fn list (store: &dyn Store, dom_id: u32) -> Vec<VbdInfo> {
+  super::list(store, KIND, dom_id).into_iter().filter_map(|devid| {
+    let device = read_device_path(store, dom_id, devid).ok()?;
+    let backend_path = device.backend();
+    let read = |key: &str| store.read(&format!("{}/{}", backend_path, key)).unwrap_or_default();
+    let backend = match device.backend_kind {
+      NATIVE_BACKEND_KIND => Backend::Native,
+      _ => Backend::Blkback
+    };
+    Some(VbdInfo {
+      devid,
+      vdev: read("dev"),
+      target: read("params"),
+      mode: read("mode"),
+      device_type: read("device-type"),
+      backend: backend.to_string(),
+      backend_dom_id: device.backend_dom_id,
+      state: super::read_state(store, &backend_path).to_string()
+    })
+  }).collect()
+}
+
+// State of the backend of a disk.
+pub fn state (store: &dyn Store, dom_id: u32, devid: u32) -> Result<XenbusState> {
+  let device = read_device_path(store, dom_id, devid)?;
+  Ok(super::read_state(store, &device.backend()))
+}
+
+// Counters of a disk served in dom0: in the sysfs of blkback, or in the
+// `statistics` nodes of the native backend.
+pub fn statistics (store: &dyn Store, sysfs: &Path, dom_id: u32, devid: u32) -> Option<VbdStatistics> {
+  let device = read_device_path(store, dom_id, devid).ok()?;
+  if device.backend_dom_id != 0 {
+    return None
+  }
+  let backend_path = device.backend();
+  let read = |name: &str| -> Option<u64> {
+    let value = if device.backend_kind == NATIVE_BACKEND_KIND {
+      store.read(&format!("{}/statistics/{}", backend_path, name)).ok()?
+    } else {
+      let path = format!("bus/xen-backend/devices/vbd-{}-{}/statistics/{}", dom_id, devid, name);
+      std::fs::read_to_string(sysfs.join(path)).ok()?
+    };
+    value.trim().parse().ok()
+  };
+  Some(VbdStatistics {
+    read_requests: read("rd_req")?,
+    write_requests: read("wr_req")?,
+    read_sectors: read("rd_sect")?,
+    write_sectors: read("wr_sect")?
+  })
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::device::DEFAULT_TIMEOUT;
+  use crate::test_util::error;
+  use crate::xenstore::MemoryStore;
+
+  #[test]
+  fn disk_names () {
+    assert_eq!(parse_disk_name("a"), Some((0, 0)));
+    assert_eq!(parse_disk_name("z"), Some((25, 0)));
+    assert_eq!(parse_disk_name("aa"), Some((26, 0)));
+    assert_eq!(parse_disk_name("zz"), Some((701, 0)));
+    assert_eq!(parse_disk_name("ab12"), Some((27, 12)));
+    assert_eq!(parse_disk_name(""), None);
+    assert_eq!(parse_disk_name("1"), None);
+    assert_eq!(parse_disk_name("a01"), None);
+    assert_eq!(parse_disk_name("a1b"), None);
+    assert_eq!(parse_disk_name("abcde"), None);
+  }
+
+  #[test]
+  fn vdevs () {
+    assert_eq!(parse_vdev("51712"), Some(51712));
+    assert_eq!(parse_vdev("xvda"), Some(202 << 8));
+    assert_eq!(parse_vdev("xvdb1"), Some(202 << 8 | 1 << 4 | 1));
+    assert_eq!(parse_vdev("xvdp15"), Some(202 << 8 | 15 << 4 | 15));
+    // Extended numbering.
+    assert_eq!(parse_vdev("xvdq"), Some(1 << 28 | 16 << 8));
+    assert_eq!(parse_vdev("xvda16"), Some(1 << 28 | 16));
+    assert_eq!(parse_vdev("xvdaa255"), Some(1 << 28 | 26 << 8 | 255));
+    assert_eq!(parse_vdev("xvda256"), None);
+    assert_eq!(parse_vdev("hda"), Some(3 << 8));
+    assert_eq!(parse_vdev("hdb2"), Some(3 << 8 | 1 << 6 | 2));
+    assert_eq!(parse_vdev("hdc"), Some(22 << 8));
+    assert_eq!(parse_vdev("hde"), None);
+    assert_eq!(parse_vdev("hda64"), None);
+    assert_eq!(parse_vdev("sda"), Some(8 << 8));
+    assert_eq!(parse_vdev("sdp15"), Some(8 << 8 | 15 << 4 | 15));
+    assert_eq!(parse_vdev("sdq"), None);
+    assert_eq!(parse_vdev("xvd"), None);
+    assert_eq!(parse_vdev("vda"), None);
+  }
+
+  #[test]
+  fn script () {
+    let store = MemoryStore::new();
+    let config = VbdConfig { script: Some(String::from("../../tmp/evil")), ..VbdConfig::new("xvda", "/dev/vg0/disk") };
+    assert_eq!(error(attach(&store, 3, &config, DEFAULT_TIMEOUT)), "invalid device: invalid hotplug script `../../tmp/evil`");
+    assert!(list(&store, 3).is_empty());
+  }
+}
@@ -0,0 +1,274 @@
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{script_path, DevicePath, Result, XenbusState};
+use crate::xenstore::Store;
+
+// =============================================================================
+// Virtual network interfaces (netfront/netback).
+// =============================================================================
+
+pub const KIND: &str = "vif";
+
+const BRIDGE_SCRIPT: &str = "vif-bridge";
+const DEFAULT_BRIDGE: &str = "xenbr0";
+
+// Netback checks the rate limit at this interval, like libxl.
+const RATE_INTERVAL_USECS: u64 = 50000;
+
+// Organizationally unique identifier of Xen.
+const XEN_OUI: [u8; 3] = [0x00, 0x16, 0x3e];
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Mac(pub [u8; 6]);
+
+impl Mac {
+  // Same address for a given VM and device index, in the Xen OUI.
+  pub fn generate (uuid: &str, index: u32) -> Self {
+    // FNV-1a.
+    let uuid = uuid.bytes().filter(|byte| *byte != b'-').map(|byte| byte.to_ascii_lowercase());
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in uuid.chain(index.to_le_bytes().iter().copied()) {
+      hash = (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193);
+    }
+
+    // The high bit of the 4th byte is cleared like libxl does.
+    let hash = hash.to_le_bytes();
+    Mac([XEN_OUI[0], XEN_OUI[1], XEN_OUI[2], hash[0] & 0x7f, hash[1], hash[2]])
+  }
+}
+
+impl std::str::FromStr for Mac {
+  type Err = String;
+
+  fn from_str (value: &str) -> std::result::Result<Self, Self::Err> {
+    let invalid = || format!("invalid MAC address: `{}`", value);
+    let mut mac = [0u8; 6];
+    let mut bytes = value.split(':');
+    for byte in mac.iter_mut() {
+      let part = bytes.next().filter(|part| part.len() == 2).ok_or_else(invalid)?;
+      *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+    }
+    if bytes.next().is_some() {
+      return Err(invalid())
+    }
+    Ok(Mac(mac))
+  }
+}
+
+impl std::fmt::Display for Mac {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let bytes = self.0;
+    write!(
+      f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+      bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]
+    )
+  }
+}
+
+pub struct VifConfig {
+  // First free index if none.
+  pub devid: Option<u32>,
+  // Generated from the UUID of the VM and the devid if none.
+  pub mac: Option<Mac>,
+  pub bridge: String,
+  // Name of the hotplug script, see `script_path`.
+  pub script: String,
+  // Bytes per second.
+  pub rate: Option<u64>,
+  pub mtu: Option<u32>,
+  // Addresses allowed for the guest by the hotplug script.
+  pub ip: Vec<String>,
+  pub backend_dom_id: u32
+}
+
+impl Default for VifConfig {
+  fn default () -> Self {
+    Self {
+      devid: None,
+      mac: None,
+      bridge: String::from(DEFAULT_BRIDGE),
+      script: String::from(BRIDGE_SCRIPT),
+      rate: None,
+      mtu: None,
+      ip: Vec::new(),
+      backend_dom_id: 0
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct VifInfo {
+  pub devid: u32,
+  pub mac: String,
+  pub bridge: String,
+  pub backend_dom_id: u32,
+  pub state: String
+}
+
+// Traffic counters of a network interface, seen from the guest.
+#[derive(Clone, Copy, Default)]
+pub struct VifStatistics {
+  pub rx_bytes: u64,
+  pub tx_bytes: u64,
+  pub rx_packets: u64,
+  pub tx_packets: u64
+}
+
+// -----------------------------------------------------------------------------
+
+// Value of the `rate` node: bytes per interval and interval in microseconds.
+fn rate_node (bytes_per_second: u64) -> String {
+  let bytes = (u128::from(bytes_per_second) * u128::from(RATE_INTERVAL_USECS) / 1_000_000).max(1);
+  format!("{},{}", bytes, RATE_INTERVAL_USECS)
+}
+
+// Plug a network interface in a guest of the given UUID. Returns the devid and
+// the MAC address once the backend is ready.
+pub fn attach (
+  store: &dyn Store,
+  dom_id: u32,
+  uuid: &str,
+  config: &VifConfig,
+  timeout: Duration
+) -> Result<(u32, Mac)> {
+  let devid = match config.devid {
+    Some(devid) => devid,
+    None => {
+      let used = super::list(store, KIND, dom_id);
+      (0..).find(|devid| !used.contains(devid)).unwrap()
+    }
+  };
+  let mac = config.mac.unwrap_or_else(|| Mac::generate(uuid, devid));
+  let device = DevicePath { kind: KIND, backend_kind: KIND, frontend_dom_id: dom_id, backend_dom_id: config.backend_dom_id, devid };
+
+  let mut backend = vec![
+    ("handle", devid.to_string()),
+    ("mac", mac.to_string()),
+    ("bridge", config.bridge.clone()),
+    ("script", script_path(&config.script)?),
+    ("type", String::from("vif"))
+  ];
+  if let Some(rate) = config.rate {
+    backend.push(("rate", rate_node(rate)));
+  }
+  if let Some(mtu) = config.mtu {
+    backend.push(("mtu", mtu.to_string()));
+  }
+  if !config.ip.is_empty() {
+    backend.push(("ip", config.ip.join(" ")));
+  }
+  let mut frontend = vec![
+    ("handle", devid.to_string()),
+    ("mac", mac.to_string())
+  ];
+  if let Some(mtu) = config.mtu {
+    frontend.push(("mtu", mtu.to_string()));
+  }
+
+  super::attach(store, &device, &backend, &frontend, timeout)?;
+  Ok((devid, mac))
+}
+
+// Unplug a network interface, see `device::detach`.
+pub fn detach (store: &dyn Store, dom_id: u32, devid: u32, force: bool, timeout: Duration) -> Result<()> {
+  let device = DevicePath::read(store, KIND, dom_id, devid)?;
+  super::detach(store, &device, force, timeout)
+}
+
+pub fn list (store: &dyn Store, dom_id: u32) -> Vec<VifInfo> {
+  super::list(store, KIND, dom_id).into_iter().filter_map(|devid| {
+    let device = DevicePath::read(store, KIND, dom_id, devid).ok()?;
+    let backend_path = device.backend();
+    let read = |key: &str| store.read(&format!("{}/{}", backend_path, key)).unwrap_or_default();
+    Some(VifInfo {
+      devid,
+      mac: read("mac"),
+      bridge: read("bridge"),
+      backend_dom_id: device.backend_dom_id,
+      state: super::read_state(store, &backend_path).to_string()
+    })
+  }).collect()
+}
+
+// State of the backend of a network interface.
+pub fn state (store: &dyn Store, dom_id: u32, devid: u32) -> Result<XenbusState> {
+  let device = DevicePath::read(store, KIND, dom_id, devid)?;
+  Ok(super::read_state(store, &device.backend()))
+}
+
+// Counters of the interface created by netback in dom0, what it sends is
+// received by the guest.
+pub fn statistics (sysfs: &Path, dom_id: u32, devid: u32) -> Option<VifStatistics> {
+  let directory = sysfs.join(format!("class/net/vif{}.{}/statistics", dom_id, devid));
+  let read = |name: &str| -> Option<u64> {
+    std::fs::read_to_string(directory.join(name)).ok()?.trim().parse().ok()
+  };
+  Some(VifStatistics {
+    rx_bytes: read("tx_bytes")?,
+    tx_bytes: read("rx_bytes")?,
+    rx_packets: read("tx_packets")?,
+    tx_packets: read("rx_packets")?
+  })
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::device::DEFAULT_TIMEOUT;
+  use crate::test_util::error;
+  use crate::xenstore::MemoryStore;
+
+  const UUID: &str = "3a5ebd8e-1cbb-4bde-a8e6-8bbc4f1ad9a6";
+
+  #[test]
+  fn generate () {
+    let mac = Mac::generate(UUID, 0);
+    assert!(mac == Mac::generate(UUID, 0));
+    assert!(mac == Mac::generate(&UUID.to_uppercase(), 0));
+    assert!(mac != Mac::generate(UUID, 1));
+    assert_eq!(&mac.0[..3], &XEN_OUI);
+
+    // The index is not a character of the UUID: 65 is `A`, 97 is `a`.
+    assert!(Mac::generate(UUID, 65) != Mac::generate(UUID, 97));
+
+    for index in 0..256 {
+      assert_eq!(Mac::generate(UUID, index).0[3] & 0x80, 0);
+    }
+  }
+
+  #[test]
+  fn parse () {
+    let mac: Mac = "00:16:3e:6a:15:5A".parse().ok().unwrap();
+    assert!(mac == Mac([0x00, 0x16, 0x3e, 0x6a, 0x15, 0x5a]));
+    assert_eq!(mac.to_string(), "00:16:3e:6a:15:5a");
+    assert_eq!(Mac([0, 1, 2, 3, 4, 255]).to_string(), "00:01:02:03:04:ff");
+
+    assert_eq!(error("00:16:3e:6a:15".parse::<Mac>()), "invalid MAC address: `00:16:3e:6a:15`");
+    assert!("00:16:3e:6a:15:5a:01".parse::<Mac>().is_err());
+    assert!("00:16:3e:6a:15:5".parse::<Mac>().is_err());
+    assert!("00:16:3e:6a:15:xx".parse::<Mac>().is_err());
+    assert!("00-16-3e-6a-15-5a".parse::<Mac>().is_err());
+    assert!("".parse::<Mac>().is_err());
+  }
+
+  #[test]
+  fn rate () {
+    assert_eq!(rate_node(1_000_000), "50000,50000");
+    assert_eq!(rate_node(125_000_000), "6250000,50000");
+    // At least a byte per interval.
+    assert_eq!(rate_node(10), "1,50000");
+    assert_eq!(rate_node(u64::MAX), format!("{},50000", u64::MAX / 20));
+  }
+
+  #[test]
+  fn script () {
+    let config = VifConfig { script: String::from("/tmp/evil"), ..VifConfig::default() };
+    let store = MemoryStore::new();
+    assert_eq!(error(attach(&store, 3, UUID, &config, DEFAULT_TIMEOUT)), "invalid device: invalid hotplug script `/tmp/evil`");
+    assert!(list(&store, 3).is_empty());
+  }
+}
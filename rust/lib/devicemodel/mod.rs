@@ -0,0 +1,718 @@
+use serde::Deserialize;
+use std::fs::OpenOptions;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use super::device::vif::Mac;
+use super::varstore;
+use super::vtpm::{self, Swtpm, Vtpm};
+use super::xenctrl::{self, Xenctrl};
+use super::xenstore::{self, Permission, PermissionKind, Store, Xenstore};
+
+pub mod qmp;
+pub mod supervisor;
+
+use qmp::Qmp;
+
+// =============================================================================
+// Device model (QEMU) of the HVM guests.
+//
+// QEMU runs in dom0 as a child of the daemon, or in a stubdomain. It writes
+// `running` in `/local/domain/0/device-model/<domid>/state` once it emulates
+// the guest. In dom0, it is controlled through its QMP socket; its output is
+// written in a log file per VM. A vTPM is emulated by a swtpm started with
+// QEMU, see `vtpm`. The UEFI variables of the VM are the second flash of the
+// firmware, QEMU writes the changes of the guest in them, see `varstore`.
+// =============================================================================
+
+pub const QEMU_PATH: &str = "/usr/lib/xen/bin/qemu-system-i386";
+
+// Firmware code of the VMs with UEFI variables, the first flash.
+pub const OVMF_CODE_PATH: &str = "/usr/share/OVMF/OVMF_CODE.fd";
+
+pub const QMP_DIR: &str = "/var/run/xenops/qmp";
+pub const LOG_DIR: &str = "/var/log/xenops/device-model";
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// QEMU is killed if it does not quit in time.
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_VGA_MEMORY_MB: u32 = 16;
+
+// IDE: two buses with a master and a slave.
+const MAX_IDE_DISKS: u32 = 4;
+
+pub enum Error {
+  Io(std::io::Error),
+  Xen(xenctrl::Error),
+  Xenstore(&'static str),
+  Qmp(qmp::Error),
+  Vtpm(vtpm::Error),
+  InvalidConfig(String),
+  // Exited before it was ready.
+  Exited(ExitStatus),
+  Timeout,
+  // QMP is only available in dom0.
+  NoQmp(u32),
+  NoSuchDeviceModel(u32),
+  // Its supervisor restarts it.
+  Restarting(u32)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Xen(e) => write!(f, "{}", e),
+      Error::Xenstore(details) => write!(f, "xenstore error: {}", details),
+      Error::Qmp(e) => write!(f, "{}", e),
+      Error::Vtpm(e) => write!(f, "{}", e),
+      Error::InvalidConfig(details) => write!(f, "invalid device model config: {}", details),
+      Error::Exited(status) => write!(f, "device model exited: {}", status),
+      Error::Timeout => write!(f, "device model did not start in time"),
+      Error::NoQmp(dom_id) => write!(f, "device model of domain {} runs in a stubdomain", dom_id),
+      Error::NoSuchDeviceModel(dom_id) => write!(f, "no device model for domain {}", dom_id),
+      Error::Restarting(dom_id) => write!(f, "device model of domain {} is restarting", dom_id)
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Xen(e)
+  }
+}
+
+impl From<qmp::Error> for Error {
+  fn from (e: qmp::Error) -> Self {
+    Error::Qmp(e)
+  }
+}
+
+impl From<vtpm::Error> for Error {
+  fn from (e: vtpm::Error) -> Self {
+    Error::Vtpm(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn xenstore_error (details: &'static str) -> impl FnOnce(xenstore::Error) -> Error {
+  move |_| Error::Xenstore(details)
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Vga {
+  None,
+  Std,
+  Cirrus
+}
+
+impl Default for Vga {
+  fn default () -> Self {
+    Vga::Std
+  }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Disk {
+  // IDE index: 0 is hda, 1 is hdb...
+  pub index: u32,
+  // Empty drive if none (cdrom only).
+  pub target: Option<String>,
+  #[serde(default = "default_disk_format")]
+  pub format: String,
+  #[serde(default)]
+  pub cdrom: bool,
+  #[serde(default)]
+  pub read_only: bool
+}
+
+fn default_disk_format () -> String {
+  String::from("raw")
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Nic {
+  // Devid of the vif of the guest.
+  pub devid: u32,
+  pub mac: String,
+  #[serde(default = "default_nic_model")]
+  pub model: String
+}
+
+fn default_nic_model () -> String {
+  String::from("rtl8139")
+}
+
+#[derive(Clone, Deserialize)]
+pub struct DeviceModelConfig {
+  pub name: String,
+  pub uuid: String,
+  pub memory_mb: u64,
+  pub vcpus: u32,
+  // `vcpus` if none.
+  pub max_vcpus: Option<u32>,
+  #[serde(default)]
+  pub disks: Vec<Disk>,
+  #[serde(default)]
+  pub nics: Vec<Nic>,
+  #[serde(default)]
+  pub vga: Vga,
+  pub vga_memory_mb: Option<u32>,
+  // VNC display: "127.0.0.1:0"...
+  pub vnc: Option<String>,
+  // USB controller with a tablet.
+  #[serde(default)]
+  pub usb: bool,
+  // Serial port: "pty", "file:/path"...
+  pub serial: Option<String>,
+  // Xen platform device, used by the PV drivers of the guest.
+  #[serde(default = "default_true")]
+  pub platform: bool,
+  // Boot order: "cd", "dc"...
+  #[serde(default = "default_boot")]
+  pub boot: String,
+  // TPM 2.0 emulated by swtpm, its state must be created by
+  // `Swtpm::create`. Not in a stubdomain.
+  #[serde(default)]
+  pub vtpm: bool,
+  // UEFI variables, created by `varstore::create`, the second flash after
+  // OVMF_CODE_PATH. Not in a stubdomain.
+  #[serde(default)]
+  pub uefi_vars: bool,
+  // Devices state to restore, see `Qmp::save_devices_state`.
+  pub restore: Option<PathBuf>
+}
+
+fn default_true () -> bool {
+  true
+}
+
+fn default_boot () -> String {
+  String::from("cd")
+}
+
+// -----------------------------------------------------------------------------
+
+pub fn qmp_path (dom_id: u32) -> PathBuf {
+  PathBuf::from(QMP_DIR).join(format!("{}.sock", dom_id))
+}
+
+// The pid of QEMU, to find it if the daemon is restarted.
+pub fn pid_path (dom_id: u32) -> PathBuf {
+  PathBuf::from(QMP_DIR).join(format!("{}.pid", dom_id))
+}
+
+// The devices state of a domain being saved or restored, see `save`.
+pub fn state_path (dom_id: u32) -> PathBuf {
+  PathBuf::from(QMP_DIR).join(format!("{}.state", dom_id))
+}
+
+pub fn log_path (uuid: &str) -> PathBuf {
+  PathBuf::from(LOG_DIR).join(format!("{}.log", uuid))
+}
+
+// Nodes of the device model of a domain, written by QEMU.
+pub fn store_path (dom_id: u32) -> String {
+  format!("{}/device-model/{}", xenstore::get_domain_path(0), dom_id)
+}
+
+// A comma ends a value in the options of QEMU, it is doubled to be kept.
+fn escape (value: &str) -> String {
+  value.replace(',', ",,")
+}
+
+// Command line of QEMU. In a stubdomain, there is no QMP socket.
+pub fn args (dom_id: u32, config: &DeviceModelConfig, stubdomain: bool) -> Result<Vec<String>> {
+  let mut args: Vec<String> = vec![
+    String::from("-xen-domid"), dom_id.to_string(),
+    String::from("-no-shutdown"),
+    String::from("-nodefaults"),
+    String::from("-name"), escape(&config.name)
+  ];
+  let mut push = |arg: &str, value: String| {
+    args.push(arg.to_string());
+    args.push(value);
+  };
+
+  if !stubdomain {
+    push("-chardev", format!("socket,id=xenops-qmp,path={},server=on,wait=off", qmp_path(dom_id).display()));
+    push("-mon", String::from("chardev=xenops-qmp,mode=control"));
+  }
+
+  push("-machine", String::from(if config.platform { "xenfv" } else { "pc,accel=xen" }));
+  push("-m", config.memory_mb.to_string());
+  let max_vcpus = config.max_vcpus.unwrap_or(config.vcpus);
+  if config.vcpus == 0 || max_vcpus < config.vcpus {
+    return Err(Error::InvalidConfig(format!("invalid vCPUs: {}/{}", config.vcpus, max_vcpus)))
+  }
+  push("-smp", format!("{},maxcpus={}", config.vcpus, max_vcpus));
+
+  let vga_memory_mb = config.vga_memory_mb.unwrap_or(DEFAULT_VGA_MEMORY_MB);
+  match config.vga {
+    Vga::None => push("-vga", String::from("none")),
+    Vga::Std => push("-device", format!("VGA,vgamem_mb={}", vga_memory_mb)),
+    Vga::Cirrus => push("-device", format!("cirrus-vga,vgamem_mb={}", vga_memory_mb))
+  }
+  match &config.vnc {
+    Some(vnc) => push("-vnc", escape(vnc)),
+    None => push("-display", String::from("none"))
+  }
+  if let Some(serial) = &config.serial {
+    push("-serial", serial.clone());
+  }
+  if config.usb {
+    push("-device", String::from("piix3-usb-uhci,id=usb"));
+    push("-device", String::from("usb-tablet,bus=usb.0"));
+  }
+  push("-boot", format!("order={}", escape(&config.boot)));
+
+  if config.vtpm {
+    if stubdomain {
+      return Err(Error::InvalidConfig(String::from("no vTPM in a stubdomain")))
+    }
+    push("-chardev", format!("socket,id=xenops-tpm,path={}", vtpm::socket_path(dom_id).display()));
+    push("-tpmdev", String::from("emulator,id=tpm0,chardev=xenops-tpm"));
+    push("-device", String::from("tpm-crb,tpmdev=tpm0"));
+  }
+
+  if config.uefi_vars {
+    if stubdomain {
+      return Err(Error::InvalidConfig(String::from("no UEFI variables in a stubdomain")))
+    }
+    let path = varstore::path(&config.uuid).map_err(|e| Error::InvalidConfig(e.to_string()))?;
+    push("-drive", format!("if=pflash,format=raw,unit=0,readonly=on,file={}", OVMF_CODE_PATH));
+    push("-drive", format!("if=pflash,format=raw,unit=1,file={}", escape(&path.to_string_lossy())));
+  }
+
+  for nic in &config.nics {
+    let mac: Mac = nic.mac.parse().map_err(Error::InvalidConfig)?;
+    push("-device", format!("{},id=nic{},netdev=net{},mac={}", escape(&nic.model), nic.devid, nic.devid, mac));
+    // Named after the vif of the guest with a suffix, like libxl.
+    push(
+      "-netdev",
+      format!("type=tap,id=net{},ifname=vif{}.{}-emu,script=no,downscript=no", nic.devid, dom_id, nic.devid)
+    );
+  }
+
+  for disk in &config.disks {
+    if disk.index >= MAX_IDE_DISKS {
+      return Err(Error::InvalidConfig(format!("invalid IDE index: {}", disk.index)))
+    }
+    let media = if disk.cdrom { "cdrom" } else { "disk" };
+    let mut drive = format!("if=ide,index={},media={},id=ide-{}", disk.index, media, disk.index);
+    match &disk.target {
+      Some(target) => drive += &format!(",file={},format={},cache=writeback", escape(target), escape(&disk.format)),
+      None if disk.cdrom => (),
+      None => return Err(Error::InvalidConfig(format!("no target for disk {}", disk.index)))
+    }
+    if disk.cdrom || disk.read_only {
+      drive += ",readonly=on";
+    }
+    push("-drive", drive);
+  }
+
+  if config.restore.is_some() {
+    push("-incoming", String::from("defer"));
+  }
+  Ok(args)
+}
+
+// -----------------------------------------------------------------------------
+
+enum Runner {
+  Process(Child),
+  Stubdomain(u32)
+}
+
+pub struct DeviceModel {
+  pub dom_id: u32,
+  pub uuid: String,
+  runner: Runner,
+  vtpm: Option<Vtpm>
+}
+
+// Wait for the device model to write `running`.
+fn wait_running (
+  store: &dyn Store,
+  dom_id: u32,
+  mut child: Option<&mut Child>,
+  timeout: Duration
+) -> Result<()> {
+  let state_path = format!("{}/state", store_path(dom_id));
+  let start = Instant::now();
+  loop {
+    if store.read(&state_path).ok().as_deref() == Some("running") {
+      return Ok(())
+    }
+    if let Some(child) = child.as_mut() {
+      if let Some(status) = child.try_wait()? {
+        return Err(Error::Exited(status))
+      }
+    }
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout)
+    }
+    std::thread::sleep(POLL_INTERVAL);
+  }
+}
+
+impl DeviceModel {
+  // Run QEMU in dom0 for a domain and wait for it to be ready.
+  pub fn start (store: &dyn Store, dom_id: u32, config: &DeviceModelConfig, timeout: Duration) -> Result<Self> {
+    let args = args(dom_id, config, false)?;
+    if config.uefi_vars {
+      varstore::load(&config.uuid).map_err(|e| Error::InvalidConfig(e.to_string()))?;
+    }
+
+    let _ = store.rm(&store_path(dom_id));
+    store.mkdir(&store_path(dom_id)).map_err(xenstore_error("failed to create device model nodes"))?;
+
+    std::fs::create_dir_all(QMP_DIR)?;
+    std::fs::create_dir_all(LOG_DIR)?;
+    let _ = std::fs::remove_file(qmp_path(dom_id));
+    let log = OpenOptions::new().create(true).append(true).open(log_path(&config.uuid))?;
+
+    // QEMU connects to swtpm when it starts.
+    let mut vtpm = match config.vtpm {
+      true => Some(Swtpm::default().start(&config.uuid, dom_id, vtpm::DEFAULT_TIMEOUT)?),
+      false => None
+    };
+    let child = Command::new(QEMU_PATH)
+      .args(&args)
+      .stdin(Stdio::null())
+      .stdout(log.try_clone()?)
+      .stderr(log)
+      .spawn();
+    let child = match child {
+      Ok(child) => child,
+      Err(e) => {
+        if let Some(vtpm) = vtpm.as_mut() {
+          vtpm.kill();
+        }
+        return Err(e.into())
+      }
+    };
+    let _ = std::fs::write(pid_path(dom_id), child.id().to_string());
+
+    let mut device_model = Self { dom_id, uuid: config.uuid.clone(), runner: Runner::Process(child), vtpm };
+    let result = device_model.wait_ready(store, config, timeout);
+    if let Err(e) = result {
+      device_model.kill(store, Duration::default());
+      return Err(e)
+    }
+
+    if let Some(pid) = device_model.pid() {
+      let _ = store.write(&format!("{}/image/device-model-pid", xenstore::get_domain_path(dom_id)), &pid.to_string());
+    }
+    Ok(device_model)
+  }
+
+  fn wait_ready (&mut self, store: &dyn Store, config: &DeviceModelConfig, timeout: Duration) -> Result<()> {
+    // QEMU waits for the state before emulating the guest.
+    if let Some(restore) = &config.restore {
+      self.qmp(timeout)?.load_devices_state(restore)?;
+    }
+    let child = match &mut self.runner {
+      Runner::Process(child) => Some(child),
+      Runner::Stubdomain(_) => None
+    };
+    wait_running(store, self.dom_id, child, timeout)
+  }
+
+  // Give a stubdomain created by the caller the command line of QEMU and wait
+  // for it to be ready. The stubdomain reads its arguments in `dmargs`.
+  pub fn start_in_stubdomain (
+    xs: &Xenstore,
+    dom_id: u32,
+    stubdomain_dom_id: u32,
+    config: &DeviceModelConfig,
+    timeout: Duration
+  ) -> Result<Self> {
+    let args = args(dom_id, config, true)?;
+    if config.restore.is_some() {
+      return Err(Error::InvalidConfig(String::from("no restore in a stubdomain")))
+    }
+    xs.set_target(stubdomain_dom_id, dom_id).map_err(xenstore_error("failed to set the target of the stubdomain"))?;
+
+    let stubdomain_path = xs.get_domain_path(stubdomain_dom_id);
+    let readable = [Permission::new(0, PermissionKind::None), Permission::new(stubdomain_dom_id, PermissionKind::Read)];
+    let writable = [Permission::new(0, PermissionKind::None), Permission::new(stubdomain_dom_id, PermissionKind::ReadWrite)];
+    let device_model_path = store_path(dom_id);
+
+    xs.atomically(&mut |store| {
+      let _ = store.rm(&format!("{}/dmargs", stubdomain_path));
+      for (index, arg) in args.iter().enumerate() {
+        store.write(&format!("{}/dmargs/{}", stubdomain_path, index), arg)?;
+      }
+      store.set_permissions(&format!("{}/dmargs", stubdomain_path), &readable)?;
+
+      let _ = store.rm(&device_model_path);
+      store.mkdir(&device_model_path)?;
+      store.set_permissions(&device_model_path, &writable)?;
+
+      let path = format!("{}/image/device-model-domid", xenstore::get_domain_path(dom_id));
+      store.write(&path, &stubdomain_dom_id.to_string())
+    }).map_err(xenstore_error("failed to write the stubdomain nodes"))?;
+
+    let mut device_model = Self { dom_id, uuid: config.uuid.clone(), runner: Runner::Stubdomain(stubdomain_dom_id), vtpm: None };
+    device_model.wait_ready(xs, config, timeout)?;
+    Ok(device_model)
+  }
+
+  pub fn pid (&self) -> Option<u32> {
+    match &self.runner {
+      Runner::Process(child) => Some(child.id()),
+      Runner::Stubdomain(_) => None
+    }
+  }
+
+  pub fn stubdomain (&self) -> Option<u32> {
+    match self.runner {
+      Runner::Process(_) => None,
+      Runner::Stubdomain(dom_id) => Some(dom_id)
+    }
+  }
+
+  pub fn vtpm_pid (&self) -> Option<u32> {
+    self.vtpm.as_ref().map(Vtpm::pid)
+  }
+
+  // Exit status of QEMU if it exited. A stubdomain is checked by its domain.
+  pub fn try_wait (&mut self) -> std::io::Result<Option<ExitStatus>> {
+    match &mut self.runner {
+      Runner::Process(child) => child.try_wait(),
+      Runner::Stubdomain(_) => Ok(None)
+    }
+  }
+
+  // Open a QMP session, one at a time.
+  pub fn qmp (&self, timeout: Duration) -> Result<Qmp<UnixStream>> {
+    match self.runner {
+      Runner::Process(_) => Ok(Qmp::connect(&qmp_path(self.dom_id), timeout)?),
+      Runner::Stubdomain(_) => Err(Error::NoQmp(self.dom_id))
+    }
+  }
+
+  // Wait for QEMU to exit at most `grace`, then kill it.
+  fn kill (&mut self, store: &dyn Store, grace: Duration) {
+    if let Runner::Process(child) = &mut self.runner {
+      let start = Instant::now();
+      while let Ok(None) = child.try_wait() {
+        if start.elapsed() >= grace {
+          let _ = child.kill();
+          let _ = child.wait();
+          break
+        }
+        std::thread::sleep(POLL_INTERVAL);
+      }
+      let _ = std::fs::remove_file(qmp_path(self.dom_id));
+      let _ = std::fs::remove_file(pid_path(self.dom_id));
+    }
+    // swtpm writes its state when the TPM changes, nothing is lost.
+    if let Some(vtpm) = self.vtpm.as_mut() {
+      vtpm.kill();
+    }
+    let _ = store.rm(&store_path(self.dom_id));
+  }
+
+  // Stop the device model: QEMU is asked to quit then killed, a stubdomain is
+  // destroyed.
+  pub fn stop (mut self, xc: &Xenctrl, store: &dyn Store) -> Result<()> {
+    match self.runner {
+      Runner::Process(_) => {
+        let quit = self.qmp(QUIT_TIMEOUT).and_then(|mut qmp| qmp.quit().map_err(Error::from));
+        self.kill(store, if quit.is_ok() { QUIT_TIMEOUT } else { Duration::default() });
+      },
+      Runner::Stubdomain(stubdomain_dom_id) => {
+        xc.destroy_domain(stubdomain_dom_id)?;
+        let _ = store.rm(&xenstore::get_domain_path(stubdomain_dom_id));
+        let _ = store.rm(&store_path(self.dom_id));
+      }
+    }
+    Ok(())
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::error;
+
+  const UUID: &str = "6a3f2c1e-8b4d-4e5f-9a0b-1c2d3e4f5a6b";
+
+  fn config () -> DeviceModelConfig {
+    serde_json::from_value(serde_json::json!({
+      "name": "vm", "uuid": UUID, "memory_mb": 1024, "vcpus": 2
+    })).unwrap()
+  }
+
+  // Value of the first occurrence of `option`.
+  fn value<'a> (args: &'a [String], option: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == option).map(|i| args[i + 1].as_str())
+  }
+
+  fn values<'a> (args: &'a [String], option: &str) -> Vec<&'a str> {
+    args.windows(2).filter(|pair| pair[0] == option).map(|pair| pair[1].as_str()).collect()
+  }
+
+  #[test]
+  fn defaults () {
+    let args = args(3, &config(), false).ok().unwrap();
+    assert_eq!(&args[..6], ["-xen-domid", "3", "-no-shutdown", "-nodefaults", "-name", "vm"]);
+    assert_eq!(value(&args, "-chardev"), Some("socket,id=xenops-qmp,path=/var/run/xenops/qmp/3.sock,server=on,wait=off"));
+    assert_eq!(value(&args, "-machine"), Some("xenfv"));
+    assert_eq!(value(&args, "-m"), Some("1024"));
+    assert_eq!(value(&args, "-smp"), Some("2,maxcpus=2"));
+    assert_eq!(value(&args, "-device"), Some("VGA,vgamem_mb=16"));
+    assert_eq!(value(&args, "-display"), Some("none"));
+    assert_eq!(value(&args, "-boot"), Some("order=cd"));
+    assert_eq!(value(&args, "-serial"), None);
+    assert_eq!(value(&args, "-incoming"), None);
+    assert_eq!(value(&args, "-drive"), None);
+  }
+
+  #[test]
+  fn stubdomain () {
+    let args = args(3, &config(), true).ok().unwrap();
+    assert_eq!(value(&args, "-chardev"), None);
+    assert_eq!(value(&args, "-mon"), None);
+
+    let mut config = config();
+    config.vtpm = true;
+    assert_eq!(error(super::args(3, &config, true)), "invalid device model config: no vTPM in a stubdomain");
+    config.vtpm = false;
+    config.uefi_vars = true;
+    assert_eq!(
+      error(super::args(3, &config, true)),
+      "invalid device model config: no UEFI variables in a stubdomain"
+    );
+  }
+
+  #[test]
+  fn vcpus () {
+    let mut config = config();
+    config.max_vcpus = Some(4);
+    assert_eq!(value(&args(3, &config, false).ok().unwrap(), "-smp"), Some("2,maxcpus=4"));
+    config.max_vcpus = Some(1);
+    assert_eq!(error(args(3, &config, false)), "invalid device model config: invalid vCPUs: 2/1");
+    config.vcpus = 0;
+    config.max_vcpus = None;
+    assert_eq!(error(args(3, &config, false)), "invalid device model config: invalid vCPUs: 0/0");
+  }
+
+  #[test]
+  fn devices () {
+    let mut config = config();
+    config.platform = false;
+    config.vga = Vga::Cirrus;
+    config.vga_memory_mb = Some(8);
+    config.vnc = Some(String::from("127.0.0.1:1,password=on"));
+    config.usb = true;
+    config.serial = Some(String::from("pty"));
+    config.boot = String::from("dc");
+    config.vtpm = true;
+    config.restore = Some(PathBuf::from("/var/run/xenops/qmp/3.state"));
+    let args = args(3, &config, false).ok().unwrap();
+
+    assert_eq!(value(&args, "-machine"), Some("pc,accel=xen"));
+    assert_eq!(values(&args, "-device"), [
+      "cirrus-vga,vgamem_mb=8",
+      "piix3-usb-uhci,id=usb",
+      "usb-tablet,bus=usb.0",
+      "tpm-crb,tpmdev=tpm0"
+    ]);
+    assert_eq!(value(&args, "-vnc"), Some("127.0.0.1:1,,password=on"));
+    assert_eq!(value(&args, "-display"), None);
+    assert_eq!(value(&args, "-serial"), Some("pty"));
+    assert_eq!(value(&args, "-boot"), Some("order=dc"));
+    assert_eq!(values(&args, "-chardev")[1], "socket,id=xenops-tpm,path=/var/run/xenops/vtpm/3.sock");
+    assert_eq!(value(&args, "-tpmdev"), Some("emulator,id=tpm0,chardev=xenops-tpm"));
+    assert_eq!(value(&args, "-incoming"), Some("defer"));
+
+    config.vga = Vga::None;
+    assert_eq!(value(&super::args(3, &config, false).ok().unwrap(), "-vga"), Some("none"));
+  }
+
+  #[test]
+  fn uefi_vars () {
+    let mut config = config();
+    config.uefi_vars = true;
+    let args = args(3, &config, false).ok().unwrap();
+    assert_eq!(values(&args, "-drive"), [
+      String::from("if=pflash,format=raw,unit=0,readonly=on,file=/usr/share/OVMF/OVMF_CODE.fd"),
+      format!("if=pflash,format=raw,unit=1,file=/var/lib/xenops/vms/{}/efivars", UUID)
+    ]);
+
+    config.uuid = String::from("vm");
+    assert_eq!(error(super::args(3, &config, false)), "invalid device model config: invalid VM UUID: `vm`");
+  }
+
+  #[test]
+  fn nics () {
+    let mut config = config();
+    config.nics = vec![
+      Nic { devid: 0, mac: String::from("00:16:3E:01:02:03"), model: default_nic_model() },
+      Nic { devid: 1, mac: String::from("00:16:3e:0a:0b:0c"), model: String::from("e1000,romfile=/rom") }
+    ];
+    let args = args(3, &config, false).ok().unwrap();
+    assert_eq!(values(&args, "-device")[1..], [
+      "rtl8139,id=nic0,netdev=net0,mac=00:16:3e:01:02:03",
+      "e1000,,romfile=/rom,id=nic1,netdev=net1,mac=00:16:3e:0a:0b:0c"
+    ]);
+    assert_eq!(values(&args, "-netdev"), [
+      "type=tap,id=net0,ifname=vif3.0-emu,script=no,downscript=no",
+      "type=tap,id=net1,ifname=vif3.1-emu,script=no,downscript=no"
+    ]);
+
+    config.nics[0].mac = String::from("00:16:3e");
+    assert!(super::args(3, &config, false).is_err());
+  }
+
+  #[test]
+  fn disks () {
+    let disk = |index, target: Option<&str>, cdrom, read_only| Disk {
+      index,
+      target: target.map(String::from),
+      format: default_disk_format(),
+      cdrom,
+      read_only
+    };
+    let mut config = config();
+    config.disks = vec![
+      disk(0, Some("/dev/sm/a,b"), false, false),
+      disk(1, Some("/srv/iso/install.iso"), true, false),
+      disk(2, None, true, false),
+      disk(3, Some("/dev/sm/c"), false, true)
+    ];
+    config.disks[3].format = String::from("qcow2");
+    assert_eq!(values(&args(3, &config, false).ok().unwrap(), "-drive"), [
+      "if=ide,index=0,media=disk,id=ide-0,file=/dev/sm/a,,b,format=raw,cache=writeback",
+      "if=ide,index=1,media=cdrom,id=ide-1,file=/srv/iso/install.iso,format=raw,cache=writeback,readonly=on",
+      "if=ide,index=2,media=cdrom,id=ide-2,readonly=on",
+      "if=ide,index=3,media=disk,id=ide-3,file=/dev/sm/c,format=qcow2,cache=writeback,readonly=on"
+    ]);
+
+    config.disks = vec![disk(0, None, false, false)];
+    assert_eq!(error(args(3, &config, false)), "invalid device model config: no target for disk 0");
+    config.disks = vec![disk(4, Some("/dev/sm/a"), false, false)];
+    assert_eq!(error(args(3, &config, false)), "invalid device model config: invalid IDE index: 4");
+  }
+}
@@ -0,0 +1,335 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// =============================================================================
+// QMP (QEMU Machine Protocol) client.
+//
+// The server sends a greeting, then the client must negotiate the
+// capabilities before sending commands. Each command gets a reply with its
+// `id`: `{"return": ...}` or `{"error": {"class": ..., "desc": ...}}`.
+// Events (`{"event": ...}`) can be received at any time, they are kept
+// until read with `take_events`.
+// =============================================================================
+
+const CONNECT_INTERVAL: Duration = Duration::from_millis(50);
+
+pub enum Error {
+  Io(std::io::Error),
+  Protocol(String),
+  // Error reply of a command.
+  Command { class: String, desc: String }
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Protocol(details) => write!(f, "QMP protocol error: {}", details),
+      Error::Command { class, desc } => write!(f, "QMP command failed ({}): {}", class, desc)
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Deserialize)]
+pub struct Event {
+  pub event: String,
+  #[serde(default)]
+  pub data: Value
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Status {
+  pub running: bool,
+  // "running", "paused", "inmigrate"...
+  pub status: String
+}
+
+pub struct Qmp<S: Read + Write> {
+  stream: BufReader<S>,
+  events: Vec<Event>,
+  next_id: u64
+}
+
+impl Qmp<UnixStream> {
+  // Connect to the QMP socket of a device model, which may not be created yet.
+  pub fn connect (path: &Path, timeout: Duration) -> Result<Self> {
+    let start = Instant::now();
+    let stream = loop {
+      match UnixStream::connect(path) {
+        Ok(stream) => break stream,
+        Err(e) if start.elapsed() > timeout => return Err(e.into()),
+        Err(_) => std::thread::sleep(CONNECT_INTERVAL)
+      }
+    };
+    stream.set_read_timeout(Some(timeout))?;
+    Self::new(stream)
+  }
+}
+
+impl<S: Read + Write> Qmp<S> {
+  // Read the greeting and negotiate the capabilities.
+  pub fn new (stream: S) -> Result<Self> {
+    let mut qmp = Self { stream: BufReader::new(stream), events: Vec::new(), next_id: 0 };
+    let greeting = qmp.read_message()?;
+    if greeting.get("QMP").is_none() {
+      return Err(Error::Protocol(format!("unexpected greeting: {}", greeting)))
+    }
+    qmp.execute("qmp_capabilities", None)?;
+    Ok(qmp)
+  }
+
+  fn read_message (&mut self) -> Result<Value> {
+    let mut line = String::new();
+    if self.stream.read_line(&mut line)? == 0 {
+      return Err(Error::Protocol(String::from("connection closed")))
+    }
+    serde_json::from_str(&line).map_err(|e| Error::Protocol(format!("invalid message: {}", e)))
+  }
+
+  // Run a command and wait for its reply.
+  pub fn execute (&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+    let id = self.next_id;
+    self.next_id += 1;
+
+    let mut request = json!({ "execute": command, "id": id });
+    if let Some(arguments) = arguments {
+      request["arguments"] = arguments;
+    }
+    let mut request = request.to_string();
+    request.push('\n');
+    self.stream.get_mut().write_all(request.as_bytes())?;
+
+    loop {
+      let mut message = self.read_message()?;
+      if message.get("event").is_some() {
+        let event = serde_json::from_value(message).map_err(|e| Error::Protocol(format!("invalid event: {}", e)))?;
+        self.events.push(event);
+        continue
+      }
+      if message.get("id") != Some(&json!(id)) {
+        return Err(Error::Protocol(format!("unexpected reply: {}", message)))
+      }
+
+      if let Some(value) = message.get_mut("return") {
+        return Ok(value.take())
+      }
+      return match message.get("error") {
+        Some(error) => Err(Error::Command {
+          class: error["class"].as_str().unwrap_or_default().to_string(),
+          desc: error["desc"].as_str().unwrap_or_default().to_string()
+        }),
+        None => Err(Error::Protocol(format!("unexpected reply: {}", message)))
+      }
+    }
+  }
+
+  // Events received since the last call.
+  pub fn take_events (&mut self) -> Vec<Event> {
+    std::mem::take(&mut self.events)
+  }
+
+  pub fn query_status (&mut self) -> Result<Status> {
+    let status = self.execute("query-status", None)?;
+    serde_json::from_value(status).map_err(|e| Error::Protocol(format!("invalid status: {}", e)))
+  }
+
+  pub fn stop (&mut self) -> Result<()> {
+    self.execute("stop", None).map(|_| ())
+  }
+
+  pub fn cont (&mut self) -> Result<()> {
+    self.execute("cont", None).map(|_| ())
+  }
+
+  pub fn quit (&mut self) -> Result<()> {
+    self.execute("quit", None).map(|_| ())
+  }
+
+  // Hot-plug a device: `driver` is the QEMU device ("e1000", "usb-tablet"...),
+  // `properties` the other ones ({"netdev": "net1", "bus": "pci.0"...}).
+  pub fn device_add (&mut self, driver: &str, id: &str, properties: Value) -> Result<()> {
+    let mut arguments = match properties {
+      Value::Object(properties) => Value::Object(properties),
+      Value::Null => json!({}),
+      _ => return Err(Error::Protocol(String::from("device properties must be an object")))
+    };
+    arguments["driver"] = json!(driver);
+    arguments["id"] = json!(id);
+    self.execute("device_add", Some(arguments)).map(|_| ())
+  }
+
+  // Ask the guest to release a device, the removal is signaled by a
+  // DEVICE_DELETED event.
+  pub fn device_del (&mut self, id: &str) -> Result<()> {
+    self.execute("device_del", Some(json!({ "id": id }))).map(|_| ())
+  }
+
+  // Insert a medium in a removable drive (`device` is the id of the drive).
+  pub fn change_medium (&mut self, device: &str, filename: &str, format: &str) -> Result<()> {
+    let arguments = json!({ "device": device, "filename": filename, "format": format });
+    self.execute("blockdev-change-medium", Some(arguments)).map(|_| ())
+  }
+
+  pub fn eject (&mut self, device: &str, force: bool) -> Result<()> {
+    self.execute("eject", Some(json!({ "device": device, "force": force }))).map(|_| ())
+  }
+
+  // Tell the device model to track the pages it writes during a live save.
+  pub fn set_global_dirty_log (&mut self, enable: bool) -> Result<()> {
+    self.execute("xen-set-global-dirty-log", Some(json!({ "enable": enable }))).map(|_| ())
+  }
+
+  // Write the state of the emulated devices in a file, the device model must be
+  // stopped unless `live`.
+  pub fn save_devices_state (&mut self, path: &Path, live: bool) -> Result<()> {
+    let arguments = json!({ "filename": path.to_string_lossy(), "live": live });
+    self.execute("xen-save-devices-state", Some(arguments)).map(|_| ())
+  }
+
+  // Load a state written by `save_devices_state`, the device model must have
+  // been started with `-incoming defer`.
+  pub fn load_devices_state (&mut self, path: &Path) -> Result<()> {
+    let arguments = json!({ "filename": path.to_string_lossy() });
+    self.execute("xen-load-devices-state", Some(arguments)).map(|_| ())
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::error;
+  use std::thread::JoinHandle;
+
+  const GREETING: &str = r#"{"QMP": {"version": {"qemu": {"major": 7, "minor": 2, "micro": 0}}, "capabilities": []}}"#;
+
+  // A QMP server: it sends `greeting`, then the messages of `replies[n]` after
+  // the n-th request, and closes the connection. Returns the requests.
+  fn serve (greeting: &'static str, replies: Vec<Vec<&'static str>>) -> (UnixStream, JoinHandle<Vec<Value>>) {
+    let (client, server) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || {
+      let mut writer = server.try_clone().unwrap();
+      let mut reader = BufReader::new(server);
+      writeln!(writer, "{}", greeting).unwrap();
+
+      let mut requests = Vec::new();
+      for messages in replies {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 {
+          break
+        }
+        requests.push(serde_json::from_str(&line).unwrap());
+        for message in messages {
+          writeln!(writer, "{}", message).unwrap();
+        }
+      }
+      requests
+    });
+    (client, handle)
+  }
+
+  #[test]
+  fn session () {
+    let (client, server) = serve(GREETING, vec![
+      vec![r#"{"return": {}, "id": 0}"#],
+      vec![
+        r#"{"event": "STOP", "timestamp": {"seconds": 1, "microseconds": 0}}"#,
+        r#"{"return": {"running": false, "singlestep": false, "status": "paused"}, "id": 1}"#
+      ],
+      vec![r#"{"return": {}, "id": 2}"#]
+    ]);
+
+    let mut qmp = Qmp::new(client).ok().unwrap();
+    let status = qmp.query_status().ok().unwrap();
+    assert!(!status.running);
+    assert_eq!(status.status, "paused");
+    qmp.set_global_dirty_log(true).ok().unwrap();
+
+    let events = qmp.take_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, "STOP");
+    assert!(qmp.take_events().is_empty());
+
+    drop(qmp);
+    assert_eq!(server.join().unwrap(), vec![
+      json!({ "execute": "qmp_capabilities", "id": 0 }),
+      json!({ "execute": "query-status", "id": 1 }),
+      json!({ "execute": "xen-set-global-dirty-log", "arguments": { "enable": true }, "id": 2 })
+    ]);
+  }
+
+  #[test]
+  fn command_error () {
+    let (client, server) = serve(GREETING, vec![
+      vec![r#"{"return": {}, "id": 0}"#],
+      vec![r#"{"error": {"class": "GenericError", "desc": "Device 'cd0' is locked"}, "id": 1}"#],
+      vec![r#"{"return": {}, "id": 2}"#]
+    ]);
+
+    let mut qmp = Qmp::new(client).ok().unwrap();
+    assert_eq!(error(qmp.eject("cd0", false)), "QMP command failed (GenericError): Device 'cd0' is locked");
+    // The session is still usable.
+    assert!(qmp.save_devices_state(Path::new("/var/run/xenops/qmp/5.state"), false).is_ok());
+
+    drop(qmp);
+    let requests = server.join().unwrap();
+    assert_eq!(requests[1], json!({ "execute": "eject", "arguments": { "device": "cd0", "force": false }, "id": 1 }));
+    assert_eq!(requests[2], json!({
+      "execute": "xen-save-devices-state",
+      "arguments": { "filename": "/var/run/xenops/qmp/5.state", "live": false },
+      "id": 2
+    }));
+  }
+
+  #[test]
+  fn bad_greeting () {
+    let (client, _) = serve(r#"{"hello": "world"}"#, Vec::new());
+    assert_eq!(error(Qmp::new(client)), r#"QMP protocol error: unexpected greeting: {"hello":"world"}"#);
+  }
+
+  #[test]
+  fn capabilities_refused () {
+    let (client, _) = serve(GREETING, vec![
+      vec![r#"{"error": {"class": "CommandNotFound", "desc": "Capabilities negotiation is already complete"}, "id": 0}"#]
+    ]);
+    assert_eq!(
+      error(Qmp::new(client)),
+      "QMP command failed (CommandNotFound): Capabilities negotiation is already complete"
+    );
+  }
+
+  #[test]
+  fn unexpected_reply () {
+    let (client, _) = serve(GREETING, vec![
+      vec![r#"{"return": {}, "id": 0}"#],
+      vec![r#"{"return": {}, "id": 7}"#]
+    ]);
+    let mut qmp = Qmp::new(client).ok().unwrap();
+    assert_eq!(error(qmp.stop()), r#"QMP protocol error: unexpected reply: {"id":7,"return":{}}"#);
+  }
+
+  #[test]
+  fn connection_closed () {
+    let (client, _) = serve(GREETING, vec![
+      vec![r#"{"return": {}, "id": 0}"#],
+      vec![]
+    ]);
+    let mut qmp = Qmp::new(client).ok().unwrap();
+    assert_eq!(error(qmp.cont()), "QMP protocol error: connection closed");
+  }
+}
@@ -0,0 +1,418 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::qmp::Qmp;
+use super::{DeviceModel, DeviceModelConfig, Error, Result, Runner};
+use crate::bindings;
+use crate::console;
+use crate::vcpu_context;
+use crate::vtpm;
+use crate::xenctrl::Xenctrl;
+use crate::xenstore::{self, Store};
+
+// =============================================================================
+// Supervision of the device models started by the daemon.
+//
+// When QEMU exits, or its stubdomain or its swtpm dies, while the domain
+// exists, the exit is recorded with the end of its log and the policy of the
+// device model is applied: the domain is crashed (its crash action follows,
+// see `vm::CrashHandler`), or QEMU is restarted (with its swtpm). A restart
+// loses the state of the emulated devices, so it is only done for a QEMU in
+// dom0, at most MAX_RESTARTS times in RESTART_WINDOW, with the domain paused
+// meanwhile; otherwise the domain is crashed. While it is restarted, the
+// device model stays supervised but can't be stopped or controlled through
+// QMP. When a domain is destroyed, its
+// device model is stopped and its nodes are removed, including the ones of a
+// QEMU left by a previous daemon.
+// =============================================================================
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(600);
+
+const LOG_TAIL_LINES: usize = 20;
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitPolicy {
+  Crash,
+  Restart
+}
+
+impl Default for ExitPolicy {
+  fn default () -> Self {
+    ExitPolicy::Crash
+  }
+}
+
+#[derive(Clone, Serialize)]
+pub struct ExitRecord {
+  // Seconds since the epoch.
+  pub time: u64,
+  // "qemu", "stubdomain" or "swtpm".
+  pub process: String,
+  // None if killed by a signal, or for a stubdomain.
+  pub code: Option<i32>,
+  pub signal: Option<i32>,
+  // End of the output of the process.
+  pub log_tail: String,
+  // "crash", "restart", or why the restart failed.
+  pub action: String
+}
+
+#[derive(Serialize)]
+pub struct DeviceModelInfo {
+  pub pid: Option<u32>,
+  pub stubdomain_dom_id: Option<u32>,
+  pub vtpm_pid: Option<u32>,
+  pub policy: ExitPolicy,
+  // In the restart window.
+  pub restarts: usize,
+  pub last_exit: Option<ExitRecord>
+}
+
+#[derive(Clone, Copy)]
+enum Process {
+  Qemu,
+  Stubdomain,
+  Swtpm
+}
+
+impl std::fmt::Display for Process {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Process::Qemu => write!(f, "qemu"),
+      Process::Stubdomain => write!(f, "stubdomain"),
+      Process::Swtpm => write!(f, "swtpm")
+    }
+  }
+}
+
+// Done by `poll` on a device model.
+pub enum Event {
+  Exited(u32, ExitRecord),
+  // The domain was destroyed.
+  Stopped(u32)
+}
+
+enum State {
+  Running(DeviceModel),
+  // Its previous QEMU exited, the new one is starting.
+  Restarting
+}
+
+struct Supervised {
+  state: State,
+  config: DeviceModelConfig,
+  policy: ExitPolicy,
+  restarts: Vec<Instant>
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+  device_models: Mutex<HashMap<u32, Supervised>>,
+  exits: Mutex<HashMap<u32, ExitRecord>>
+}
+
+// Flags of the domains which are not dying.
+fn live_domains (xc: &Xenctrl) -> Option<HashMap<u32, u32>> {
+  let domains = xc.get_domain_info_list().ok()?;
+  Some(
+    domains.iter()
+      .filter(|info| info.flags & (1 << bindings::_XEN_DOMINF_dying) == 0)
+      .map(|info| (u32::from(info.domain), info.flags))
+      .collect()
+  )
+}
+
+fn is_shutdown (flags: u32) -> bool {
+  flags & (1 << bindings::_XEN_DOMINF_shutdown) != 0
+}
+
+// Kill a QEMU which is not a child of the daemon, if its pid is still the one
+// of the QEMU of this domain.
+fn kill_orphan (dom_id: u32) {
+  let pid = match std::fs::read_to_string(super::pid_path(dom_id)).ok().and_then(|pid| pid.trim().parse::<i32>().ok()) {
+    Some(pid) => pid,
+    None => return
+  };
+  let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+  let domain_arg = format!("-xen-domid\0{}\0", dom_id);
+  if cmdline.windows(domain_arg.len()).any(|arg| arg == domain_arg.as_bytes()) {
+    unsafe { libc::kill(pid, libc::SIGKILL); }
+  }
+}
+
+impl Supervisor {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  pub fn contains (&self, dom_id: u32) -> bool {
+    self.device_models.lock().unwrap().contains_key(&dom_id)
+  }
+
+  // Supervise a device model started with `config`.
+  pub fn add (&self, device_model: DeviceModel, config: &DeviceModelConfig, policy: ExitPolicy) {
+    // The devices state is not loaded again on restart.
+    let mut config = config.clone();
+    config.restore = None;
+
+    let dom_id = device_model.dom_id;
+    let supervised = Supervised { state: State::Running(device_model), config, policy, restarts: Vec::new() };
+    self.device_models.lock().unwrap().insert(dom_id, supervised);
+    self.exits.lock().unwrap().remove(&dom_id);
+  }
+
+  // Stop the supervision, to stop the device model.
+  pub fn remove (&self, dom_id: u32) -> Result<DeviceModel> {
+    let mut device_models = self.device_models.lock().unwrap();
+    match device_models.remove(&dom_id) {
+      Some(Supervised { state: State::Running(device_model), .. }) => Ok(device_model),
+      // Kept until the end of its restart.
+      Some(supervised) => {
+        device_models.insert(dom_id, supervised);
+        Err(Error::Restarting(dom_id))
+      },
+      None => Err(Error::NoSuchDeviceModel(dom_id))
+    }
+  }
+
+  // Whether a swtpm uses the TPM state of a VM.
+  pub fn uses_vtpm (&self, uuid: &str) -> bool {
+    self.device_models.lock().unwrap().values()
+      .any(|supervised| supervised.config.vtpm && supervised.config.uuid.eq_ignore_ascii_case(uuid))
+  }
+
+  // Whether QEMU uses the UEFI variables of a VM as the flash of its firmware.
+  pub fn uses_uefi_vars (&self, uuid: &str) -> bool {
+    self.device_models.lock().unwrap().values()
+      .any(|supervised| supervised.config.uefi_vars && supervised.config.uuid.eq_ignore_ascii_case(uuid))
+  }
+
+  pub fn qmp (&self, dom_id: u32, timeout: Duration) -> Result<Qmp<UnixStream>> {
+    match self.device_models.lock().unwrap().get(&dom_id).map(|supervised| &supervised.state) {
+      Some(State::Running(device_model)) => device_model.qmp(timeout),
+      Some(State::Restarting) => Err(Error::Restarting(dom_id)),
+      None => Err(Error::NoSuchDeviceModel(dom_id))
+    }
+  }
+
+  // A device model which exited, or is restarting, has no pid and no
+  // stubdomain.
+  pub fn info (&self, dom_id: u32) -> Option<DeviceModelInfo> {
+    let last_exit = self.exits.lock().unwrap().get(&dom_id).cloned();
+    match self.device_models.lock().unwrap().get(&dom_id) {
+      Some(supervised) => Some(match &supervised.state {
+        State::Running(device_model) => DeviceModelInfo {
+          pid: device_model.pid(),
+          stubdomain_dom_id: device_model.stubdomain(),
+          vtpm_pid: device_model.vtpm_pid(),
+          policy: supervised.policy,
+          restarts: supervised.restarts.len(),
+          last_exit
+        },
+        State::Restarting => DeviceModelInfo {
+          pid: None,
+          stubdomain_dom_id: None,
+          vtpm_pid: None,
+          policy: supervised.policy,
+          restarts: supervised.restarts.len(),
+          last_exit
+        }
+      }),
+      None => last_exit.map(|last_exit| DeviceModelInfo {
+        pid: None,
+        stubdomain_dom_id: None,
+        vtpm_pid: None,
+        policy: ExitPolicy::default(),
+        restarts: 0,
+        last_exit: Some(last_exit)
+      })
+    }
+  }
+
+  // Check the device models once, see the top of the module.
+  pub fn poll (&self, xc: &Xenctrl, store: &dyn Store) -> Vec<Event> {
+    let domains = match live_domains(xc) {
+      Some(domains) => domains,
+      None => return Vec::new()
+    };
+
+    // Handled out of the lock, a restart waits for QEMU. A device model to
+    // restart stays in the map meanwhile, the other ones are taken out of it.
+    let mut exited = Vec::new();
+    let mut destroyed = Vec::new();
+    {
+      let mut device_models = self.device_models.lock().unwrap();
+      let dom_ids: Vec<u32> = device_models.keys().copied().collect();
+      for dom_id in dom_ids {
+        let supervised = device_models.get_mut(&dom_id).unwrap();
+        let device_model = match &mut supervised.state {
+          State::Running(device_model) => device_model,
+          State::Restarting => continue
+        };
+        if !domains.contains_key(&dom_id) {
+          if let Some(State::Running(device_model)) = device_models.remove(&dom_id).map(|supervised| supervised.state) {
+            destroyed.push(device_model);
+          }
+          continue
+        }
+
+        let status = match device_model.runner {
+          Runner::Process(_) => match device_model.try_wait() {
+            Ok(Some(status)) => Some((Process::Qemu, Some(status))),
+            _ => match device_model.vtpm.as_mut().map(|vtpm| vtpm.try_wait()) {
+              Some(Ok(Some(status))) => Some((Process::Swtpm, Some(status))),
+              _ => None
+            }
+          },
+          Runner::Stubdomain(stubdomain_dom_id) => match domains.get(&stubdomain_dom_id) {
+            Some(flags) if !is_shutdown(*flags) => None,
+            _ => Some((Process::Stubdomain, None))
+          }
+        };
+        let (process, status) = match status {
+          Some(exit) => exit,
+          None => continue
+        };
+
+        let now = Instant::now();
+        supervised.restarts.retain(|restart| now.duration_since(*restart) < RESTART_WINDOW);
+        let flags = domains.get(&dom_id).copied().unwrap_or_default();
+        let can_restart = supervised.policy == ExitPolicy::Restart &&
+          !is_shutdown(flags) &&
+          device_model.stubdomain().is_none() &&
+          supervised.restarts.len() < MAX_RESTARTS;
+
+        let (state, config) = if can_restart {
+          (std::mem::replace(&mut supervised.state, State::Restarting), Some(supervised.config.clone()))
+        } else {
+          (device_models.remove(&dom_id).unwrap().state, None)
+        };
+        if let State::Running(device_model) = state {
+          exited.push((device_model, config, process, status));
+        }
+      }
+    }
+
+    let mut events = Vec::new();
+    for device_model in destroyed {
+      let dom_id = device_model.dom_id;
+      if let Err(e) = device_model.stop(xc, store) {
+        eprintln!("Failed to stop device model of domain {}: {}", dom_id, e);
+      }
+      events.push(Event::Stopped(dom_id));
+    }
+
+    for (device_model, config, process, status) in exited {
+      let dom_id = device_model.dom_id;
+      let record = self.handle_exit(xc, store, device_model, config, process, status);
+      self.exits.lock().unwrap().insert(dom_id, record.clone());
+      events.push(Event::Exited(dom_id, record));
+    }
+
+    events.extend(self.reap_orphans(store, &domains).into_iter().map(Event::Stopped));
+    events
+  }
+
+  // Restart the device model with `config` if any, crash its domain otherwise.
+  fn handle_exit (
+    &self,
+    xc: &Xenctrl,
+    store: &dyn Store,
+    mut device_model: DeviceModel,
+    config: Option<DeviceModelConfig>,
+    process: Process,
+    status: Option<ExitStatus>
+  ) -> ExitRecord {
+    let dom_id = device_model.dom_id;
+    let uuid = &device_model.uuid;
+    let log_path = match process {
+      Process::Swtpm => vtpm::log_path(uuid),
+      Process::Qemu | Process::Stubdomain => super::log_path(uuid)
+    };
+    let log_tail = console::tail(&log_path, LOG_TAIL_LINES).unwrap_or_default();
+    let mut record = ExitRecord {
+      time: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+      process: process.to_string(),
+      code: status.and_then(|status| status.code()),
+      signal: status.and_then(|status| status.signal()),
+      log_tail,
+      action: String::from("crash")
+    };
+
+    // Nothing is left of the previous device model.
+    match device_model.runner {
+      Runner::Process(_) => device_model.kill(store, Duration::default()),
+      Runner::Stubdomain(stubdomain_dom_id) => {
+        let _ = xc.destroy_domain(stubdomain_dom_id);
+        let _ = store.rm(&xenstore::get_domain_path(stubdomain_dom_id));
+        let _ = store.rm(&super::store_path(dom_id));
+      }
+    }
+
+    if let Some(config) = config {
+      let result = restart(xc, store, dom_id, &config);
+      let mut device_models = self.device_models.lock().unwrap();
+      match result {
+        Ok(device_model) => {
+          if let Some(supervised) = device_models.get_mut(&dom_id) {
+            supervised.state = State::Running(device_model);
+            supervised.restarts.push(Instant::now());
+          }
+          record.action = String::from("restart");
+          return record
+        },
+        Err(e) => {
+          device_models.remove(&dom_id);
+          record.action = format!("crash (restart failed: {})", e);
+        }
+      }
+    }
+
+    if let Err(e) = xc.shutdown_domain(dom_id, bindings::SHUTDOWN_crash) {
+      eprintln!("Failed to crash domain {}: {}", dom_id, e);
+    }
+    record
+  }
+
+  // Remove the device model nodes of the destroyed domains which are not
+  // supervised, and kill their QEMU. Returns their domains.
+  fn reap_orphans (&self, store: &dyn Store, domains: &HashMap<u32, u32>) -> Vec<u32> {
+    let root = format!("{}/device-model", xenstore::get_domain_path(0));
+    let device_models = self.device_models.lock().unwrap();
+    store.directory(&root).unwrap_or_default().iter()
+      .filter_map(|dom_id| dom_id.parse().ok())
+      .filter(|dom_id| !domains.contains_key(dom_id) && !device_models.contains_key(dom_id))
+      .inspect(|dom_id| {
+        kill_orphan(*dom_id);
+        let _ = std::fs::remove_file(super::pid_path(*dom_id));
+        let _ = std::fs::remove_file(super::qmp_path(*dom_id));
+        let _ = store.rm(&super::store_path(*dom_id));
+      })
+      .collect()
+  }
+}
+
+// Start QEMU again with the domain paused.
+fn restart (xc: &Xenctrl, store: &dyn Store, dom_id: u32, config: &DeviceModelConfig) -> Result<DeviceModel> {
+  let was_paused = vcpu_context::is_paused(xc, dom_id)?;
+  if !was_paused {
+    xc.pause_domain(dom_id)?;
+  }
+  let mut device_model = DeviceModel::start(store, dom_id, config, super::DEFAULT_TIMEOUT)?;
+  if !was_paused {
+    if let Err(e) = xc.unpause_domain(dom_id) {
+      device_model.kill(store, Duration::default());
+      return Err(e.into())
+    }
+  }
+  Ok(device_model)
+}
@@ -0,0 +1,103 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use super::bindings;
+use super::xenctrl::{Error, Result};
+
+// =============================================================================
+// Event channels bound in our process (libxenevtchn).
+//
+// The file descriptor of the interface is readable when an event is pending on
+// one of its ports. A port is masked once its event is read by `pending`, and
+// no other event is received on it until `unmask`.
+// =============================================================================
+
+pub struct EventChannel {
+  xce: *mut bindings::xenevtchn_handle
+}
+
+unsafe impl Send for EventChannel {}
+
+impl Drop for EventChannel {
+  fn drop (&mut self) {
+    unsafe { bindings::xenevtchn_close(self.xce); }
+  }
+}
+
+impl EventChannel {
+  pub fn new () -> std::result::Result<Self, &'static str> {
+    unsafe {
+      let xce = bindings::xenevtchn_open(std::ptr::null_mut(), 0);
+      if !xce.is_null() { Ok(Self { xce }) } else { Err("Failed to open event channel interface") }
+    }
+  }
+
+  // Allocate a local port which the domain `dom_id` can connect to with
+  // EVTCHNOP_bind_interdomain. Returns the local port.
+  pub fn bind_unbound_port (&self, dom_id: u32) -> Result<u32> {
+    let ret = unsafe { bindings::xenevtchn_bind_unbound_port(self.xce, dom_id) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(ret as u32) }
+  }
+
+  // Connect a local port to the unbound port `remote_port` of a domain.
+  // Returns the local port.
+  pub fn bind_interdomain (&self, dom_id: u32, remote_port: u32) -> Result<u32> {
+    let ret = unsafe { bindings::xenevtchn_bind_interdomain(self.xce, dom_id, remote_port) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(ret as u32) }
+  }
+
+  pub fn unbind (&self, port: u32) -> Result<()> {
+    let ret = unsafe { bindings::xenevtchn_unbind(self.xce, port) };
+    match ret {
+      0 => Ok(()),
+      _ => Err(Error::last_os_error())
+    }
+  }
+
+  // Send an event to the remote end of a local port.
+  pub fn notify (&self, port: u32) -> Result<()> {
+    let ret = unsafe { bindings::xenevtchn_notify(self.xce, port) };
+    match ret {
+      0 => Ok(()),
+      _ => Err(Error::last_os_error())
+    }
+  }
+
+  // Read the next pending port, blocks until there is one.
+  pub fn pending (&self) -> Result<u32> {
+    let ret = unsafe { bindings::xenevtchn_pending(self.xce) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(ret as u32) }
+  }
+
+  pub fn unmask (&self, port: u32) -> Result<()> {
+    let ret = unsafe { bindings::xenevtchn_unmask(self.xce, port) };
+    match ret {
+      0 => Ok(()),
+      _ => Err(Error::last_os_error())
+    }
+  }
+
+  // Wait at most `timeout` (forever if none) for a pending port.
+  pub fn wait (&self, timeout: Option<Duration>) -> Result<Option<u32>> {
+    let mut fd = libc::pollfd { fd: self.fd(), events: libc::POLLIN, revents: 0 };
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_millis().min(i32::MAX as u128) as i32);
+    loop {
+      match unsafe { libc::poll(&mut fd, 1, timeout) } {
+        0 => return Ok(None),
+        ret if ret > 0 => return self.pending().map(Some),
+        _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => (),
+        _ => return Err(Error::last_os_error())
+      }
+    }
+  }
+
+  pub fn fd (&self) -> RawFd {
+    unsafe { bindings::xenevtchn_fd(self.xce) }
+  }
+}
+
+impl AsRawFd for EventChannel {
+  fn as_raw_fd (&self) -> RawFd {
+    self.fd()
+  }
+}
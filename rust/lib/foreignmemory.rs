@@ -0,0 +1,84 @@
+use super::bindings;
+use super::xenctrl::{Error, ErrorCode, Result};
+
+// =============================================================================
+
+pub const PAGE_SHIFT: u32 = bindings::XC_PAGE_SHIFT;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+// -----------------------------------------------------------------------------
+
+pub struct ForeignMemory {
+  fmem: *mut bindings::xenforeignmemory_handle
+}
+
+unsafe impl Send for ForeignMemory {}
+
+impl Drop for ForeignMemory {
+  fn drop (&mut self) {
+    unsafe { bindings::xenforeignmemory_close(self.fmem); }
+  }
+}
+
+impl ForeignMemory {
+  pub fn new () -> std::result::Result<Self, &'static str> {
+    unsafe {
+      let fmem = bindings::xenforeignmemory_open(std::ptr::null_mut(), 0);
+      if !fmem.is_null() { Ok(Self { fmem }) } else { Err("Failed to open foreign memory interface") }
+    }
+  }
+
+  // Map the given guest frames contiguously in our address space.
+  pub fn map (&self, dom_id: u32, frames: &[u64], writable: bool) -> Result<Mapping<'_>> {
+    let (mapping, errors) = self.map_sparse(dom_id, frames, writable)?;
+    if let Some(error) = errors.iter().find(|error| **error != 0) {
+      return Err(Error::new(ErrorCode::OsError(-error), "failed to map guest frame"))
+    }
+
+    Ok(mapping)
+  }
+
+  // Like `map` but frames that can't be mapped (holes...) are not an error.
+  // The returned vector contains the error of each frame: 0 or -errno.
+  // Accessing a frame that failed to be mapped raises SIGBUS.
+  pub fn map_sparse (&self, dom_id: u32, frames: &[u64], writable: bool) -> Result<(Mapping<'_>, Vec<i32>)> {
+    let prot = if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+    let mut errors: Vec<i32> = vec![0; frames.len()];
+
+    unsafe {
+      let addr = bindings::xenforeignmemory_map(
+        self.fmem, dom_id, prot, frames.len() as _, frames.as_ptr(), errors.as_mut_ptr()
+      );
+      if addr.is_null() {
+        return Err(Error::last_os_error())
+      }
+
+      Ok((Mapping { fmem: self, addr: addr as *mut u8, pages: frames.len() }, errors))
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Guest frames mapped in our address space, unmapped on drop.
+pub struct Mapping<'a> {
+  fmem: &'a ForeignMemory,
+  addr: *mut u8,
+  pages: usize
+}
+
+impl Mapping<'_> {
+  pub fn as_slice (&self) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(self.addr, self.pages * PAGE_SIZE) }
+  }
+
+  pub fn as_mut_slice (&mut self) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(self.addr, self.pages * PAGE_SIZE) }
+  }
+}
+
+impl Drop for Mapping<'_> {
+  fn drop (&mut self) {
+    unsafe { bindings::xenforeignmemory_unmap(self.fmem.fmem, self.addr as *mut libc::c_void, self.pages as _); }
+  }
+}
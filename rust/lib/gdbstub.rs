@@ -0,0 +1,844 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::bindings;
+use super::foreignmemory::ForeignMemory;
+use super::guest::Guest;
+use super::vcpu_context::{self, X86Registers};
+use super::xenctrl::{Error, ErrorCode, Result, Xenctrl};
+
+// =============================================================================
+// GDB remote serial protocol.
+//
+// See: https://sourceware.org/gdb/current/onlinedocs/gdb/Remote-Protocol.html
+// =============================================================================
+
+const INTERRUPT: u8 = 0x03;
+const BREAKPOINT_INSTRUCTION: u8 = 0xcc;
+
+// Reply sent for a stop: SIGTRAP, or SIGINT when stopped by the client.
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+
+// -----------------------------------------------------------------------------
+// Packet layer.
+// -----------------------------------------------------------------------------
+
+pub fn checksum (data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+pub fn encode_packet (data: &[u8]) -> Vec<u8> {
+  let mut body = Vec::with_capacity(data.len());
+  for byte in data {
+    match byte {
+      b'$' | b'#' | b'}' | b'*' => {
+        body.push(b'}');
+        body.push(byte ^ 0x20);
+      },
+      _ => body.push(*byte)
+    }
+  }
+
+  let mut packet = Vec::with_capacity(body.len() + 4);
+  packet.push(b'$');
+  packet.extend_from_slice(&body);
+  packet.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+  packet
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+  Packet(Vec<u8>),
+  BadChecksum,
+  Interrupt,
+  Ack,
+  Nack
+}
+
+enum DecoderState {
+  Idle,
+  Body,
+  Escape,
+  Checksum(Option<u8>)
+}
+
+// Incremental decoder of the bytes sent by a client.
+pub struct PacketDecoder {
+  state: DecoderState,
+  // Raw body, used to verify the checksum.
+  raw: Vec<u8>,
+  data: Vec<u8>
+}
+
+impl Default for PacketDecoder {
+  fn default () -> Self {
+    Self::new()
+  }
+}
+
+impl PacketDecoder {
+  pub fn new () -> Self {
+    Self { state: DecoderState::Idle, raw: Vec::new(), data: Vec::new() }
+  }
+
+  pub fn push (&mut self, byte: u8) -> Option<Event> {
+    match self.state {
+      DecoderState::Idle => match byte {
+        b'$' => {
+          self.raw.clear();
+          self.data.clear();
+          self.state = DecoderState::Body;
+          None
+        },
+        b'+' => Some(Event::Ack),
+        b'-' => Some(Event::Nack),
+        INTERRUPT => Some(Event::Interrupt),
+        _ => None
+      },
+      DecoderState::Body => {
+        match byte {
+          b'#' => self.state = DecoderState::Checksum(None),
+          b'}' => {
+            self.raw.push(byte);
+            self.state = DecoderState::Escape;
+          },
+          _ => {
+            self.raw.push(byte);
+            self.data.push(byte);
+          }
+        }
+        None
+      },
+      DecoderState::Escape => {
+        self.raw.push(byte);
+        self.data.push(byte ^ 0x20);
+        self.state = DecoderState::Body;
+        None
+      },
+      DecoderState::Checksum(None) => {
+        self.state = DecoderState::Checksum(Some(byte));
+        None
+      },
+      DecoderState::Checksum(Some(high)) => {
+        self.state = DecoderState::Idle;
+        let expected = std::str::from_utf8(&[high, byte]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if expected == Some(checksum(&self.raw)) {
+          Some(Event::Packet(std::mem::take(&mut self.data)))
+        } else {
+          Some(Event::BadChecksum)
+        }
+      }
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+// Commands.
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  HaltReason,
+  ReadRegisters,
+  WriteRegisters(Vec<u8>),
+  ReadMemory { addr: u64, len: usize },
+  WriteMemory { addr: u64, data: Vec<u8> },
+  Continue,
+  Step,
+  InsertBreakpoint(u64),
+  RemoveBreakpoint(u64),
+  SetThread(u32),
+  ThreadAlive(u32),
+  Query(String),
+  Detach,
+  Kill,
+  Unknown
+}
+
+fn decode_hex (hex: &[u8]) -> Option<Vec<u8>> {
+  let pairs = hex.chunks_exact(2);
+  if !pairs.remainder().is_empty() {
+    return None
+  }
+  pairs
+    .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+    .collect()
+}
+
+fn encode_hex (data: &[u8]) -> String {
+  data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_number (hex: &[u8]) -> Option<u64> {
+  u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+fn parse_range (args: &[u8]) -> Option<(u64, usize)> {
+  let mut parts = args.splitn(2, |byte| *byte == b',');
+  let addr = parse_number(parts.next()?)?;
+  let len = parse_number(parts.next()?)? as usize;
+  Some((addr, len))
+}
+
+// Thread ids are vCPU numbers + 1, 0 and -1 mean "any" and "all".
+fn parse_thread (args: &[u8]) -> Option<u32> {
+  match args {
+    b"-1" | b"0" => Some(0),
+    _ => parse_number(args).and_then(|id| (id as u32).checked_sub(1))
+  }
+}
+
+impl Command {
+  pub fn parse (packet: &[u8]) -> Self {
+    let (command, args) = match packet.split_first() {
+      Some((command, args)) => (*command, args),
+      None => return Command::Unknown
+    };
+
+    let parsed = match command {
+      b'?' => Some(Command::HaltReason),
+      b'g' => Some(Command::ReadRegisters),
+      b'G' => decode_hex(args).map(Command::WriteRegisters),
+      b'm' => parse_range(args).map(|(addr, len)| Command::ReadMemory { addr, len }),
+      b'M' => {
+        let mut parts = args.splitn(2, |byte| *byte == b':');
+        match (parts.next().and_then(parse_range), parts.next().and_then(decode_hex)) {
+          (Some((addr, len)), Some(data)) if data.len() == len => Some(Command::WriteMemory { addr, data }),
+          _ => None
+        }
+      },
+      // Resuming at another address is not supported, the optional argument is ignored.
+      b'c' => Some(Command::Continue),
+      b's' => Some(Command::Step),
+      b'Z' | b'z' => {
+        // Only software breakpoints are supported: Z0,addr,kind.
+        match args.splitn(3, |byte| *byte == b',').collect::<Vec<_>>().as_slice() {
+          [b"0", addr, _] => parse_number(addr).map(|addr| {
+            if command == b'Z' { Command::InsertBreakpoint(addr) } else { Command::RemoveBreakpoint(addr) }
+          }),
+          _ => None
+        }
+      },
+      b'H' => args.split_first().and_then(|(_, thread)| parse_thread(thread)).map(Command::SetThread),
+      b'T' => parse_thread(args).map(Command::ThreadAlive),
+      b'q' => std::str::from_utf8(args).ok().map(|query| Command::Query(query.to_string())),
+      b'D' => Some(Command::Detach),
+      b'k' => Some(Command::Kill),
+      _ => None
+    };
+
+    parsed.unwrap_or(Command::Unknown)
+  }
+}
+
+// -----------------------------------------------------------------------------
+// Registers, in the order of the amd64 `g` packet of GDB.
+// Registers after the segment selectors (x87, SSE...) are reported unavailable.
+// -----------------------------------------------------------------------------
+
+pub fn encode_registers (registers: &X86Registers) -> String {
+  let mut data = Vec::new();
+  for value in &[
+    registers.rax, registers.rbx, registers.rcx, registers.rdx,
+    registers.rsi, registers.rdi, registers.rbp, registers.rsp,
+    registers.r8, registers.r9, registers.r10, registers.r11,
+    registers.r12, registers.r13, registers.r14, registers.r15,
+    registers.rip
+  ] {
+    data.extend_from_slice(&value.to_le_bytes());
+  }
+  data.extend_from_slice(&(registers.rflags as u32).to_le_bytes());
+  for segment in &[registers.cs, registers.ss, registers.ds, registers.es, registers.fs, registers.gs] {
+    data.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+  }
+  encode_hex(&data)
+}
+
+pub fn decode_registers (data: &[u8], registers: &mut X86Registers) -> Option<()> {
+  let mut values = data.chunks(8);
+  let mut next = || -> Option<u64> {
+    let chunk = values.next().filter(|chunk| chunk.len() == 8)?;
+    let mut value = [0u8; 8];
+    value.copy_from_slice(chunk);
+    Some(u64::from_le_bytes(value))
+  };
+
+  for register in &mut [
+    &mut registers.rax, &mut registers.rbx, &mut registers.rcx, &mut registers.rdx,
+    &mut registers.rsi, &mut registers.rdi, &mut registers.rbp, &mut registers.rsp,
+    &mut registers.r8, &mut registers.r9, &mut registers.r10, &mut registers.r11,
+    &mut registers.r12, &mut registers.r13, &mut registers.r14, &mut registers.r15,
+    &mut registers.rip
+  ] {
+    **register = next()?;
+  }
+
+  let mut values = data[17 * 8..].chunks(4);
+  let mut next = || -> Option<u32> {
+    let chunk = values.next().filter(|chunk| chunk.len() == 4)?;
+    let mut value = [0u8; 4];
+    value.copy_from_slice(chunk);
+    Some(u32::from_le_bytes(value))
+  };
+
+  registers.rflags = (registers.rflags & !0xffff_ffff) | u64::from(next()?);
+  for segment in &mut [
+    &mut registers.cs, &mut registers.ss, &mut registers.ds,
+    &mut registers.es, &mut registers.fs, &mut registers.gs
+  ] {
+    segment.selector = next()? as u16;
+  }
+
+  Some(())
+}
+
+// =============================================================================
+// Debugged target.
+// =============================================================================
+
+pub trait Target {
+  fn vcpu_count (&self) -> u32;
+
+  fn read_registers (&mut self, vcpu: u32) -> Result<X86Registers>;
+  fn write_registers (&mut self, vcpu: u32, registers: &X86Registers) -> Result<()>;
+
+  fn read_memory (&mut self, vcpu: u32, addr: u64, len: usize) -> Result<Vec<u8>>;
+  fn write_memory (&mut self, vcpu: u32, addr: u64, data: &[u8]) -> Result<()>;
+
+  // Resume the target, with a single step of the given vCPU if any.
+  fn resume (&mut self, step: Option<u32>) -> Result<()>;
+  fn stop (&mut self) -> Result<()>;
+  fn is_stopped (&mut self) -> Result<bool>;
+
+  // Release the target and let it run.
+  fn detach (&mut self) -> Result<()>;
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct XenTarget<'a> {
+  xc: &'a Xenctrl,
+  guest: Guest<'a>,
+  vcpu_count: u32,
+  stepping: Option<u32>
+}
+
+impl<'a> XenTarget<'a> {
+  // Enable the debugger mode of the domain and pause it.
+  pub fn attach (xc: &'a Xenctrl, fmem: &'a ForeignMemory, dom_id: u32) -> Result<Self> {
+    if !vcpu_context::is_hvm(xc, dom_id)? {
+      return Err(Error::new(ErrorCode::InvalidParam, "only HVM guests can be debugged"))
+    }
+
+    let vcpu_count = xc.get_domain_info(dom_id)?.max_vcpu_id + 1;
+    xc.set_domain_debugging(dom_id, true)?;
+    xc.pause_domain(dom_id)?;
+
+    Ok(Self { xc, guest: Guest::new(xc, fmem, dom_id), vcpu_count, stepping: None })
+  }
+
+  fn dom_id (&self) -> u32 {
+    self.guest.get_dom_id()
+  }
+}
+
+impl Target for XenTarget<'_> {
+  fn vcpu_count (&self) -> u32 {
+    self.vcpu_count
+  }
+
+  fn read_registers (&mut self, vcpu: u32) -> Result<X86Registers> {
+    vcpu_context::get(self.xc, self.dom_id(), vcpu)
+  }
+
+  fn write_registers (&mut self, vcpu: u32, registers: &X86Registers) -> Result<()> {
+    vcpu_context::set(self.xc, self.dom_id(), vcpu, registers)
+  }
+
+  fn read_memory (&mut self, vcpu: u32, addr: u64, len: usize) -> Result<Vec<u8>> {
+    Ok(self.guest.read_virt(vcpu, addr, len)?.to_vec())
+  }
+
+  fn write_memory (&mut self, vcpu: u32, addr: u64, data: &[u8]) -> Result<()> {
+    self.guest.write_virt(vcpu, addr, data)
+  }
+
+  fn resume (&mut self, step: Option<u32>) -> Result<()> {
+    if let Some(vcpu) = step {
+      self.xc.debug_control(self.dom_id(), bindings::XEN_DOMCTL_DEBUG_OP_SINGLE_STEP_ON, vcpu)?;
+      self.stepping = Some(vcpu);
+    }
+    self.xc.unpause_domain(self.dom_id())
+  }
+
+  fn stop (&mut self) -> Result<()> {
+    self.xc.pause_domain(self.dom_id())
+  }
+
+  // The domain is paused by the hypervisor when a breakpoint or a single step traps.
+  fn is_stopped (&mut self) -> Result<bool> {
+    if !vcpu_context::is_paused(self.xc, self.dom_id())? {
+      return Ok(false)
+    }
+
+    if let Some(vcpu) = self.stepping.take() {
+      self.xc.debug_control(self.dom_id(), bindings::XEN_DOMCTL_DEBUG_OP_SINGLE_STEP_OFF, vcpu)?;
+    }
+    Ok(true)
+  }
+
+  fn detach (&mut self) -> Result<()> {
+    self.xc.set_domain_debugging(self.dom_id(), false)?;
+    if vcpu_context::is_paused(self.xc, self.dom_id())? {
+      self.xc.unpause_domain(self.dom_id())?;
+    }
+    Ok(())
+  }
+}
+
+// =============================================================================
+// Stub.
+// =============================================================================
+
+pub enum Action {
+  Reply(String),
+  // Wait for the target to stop before sending the stop reply.
+  Resume,
+  // Send the reply and close the connection.
+  Close(String)
+}
+
+pub struct GdbStub<T: Target> {
+  target: T,
+  vcpu: u32,
+  // Original bytes replaced by breakpoints.
+  breakpoints: HashMap<u64, u8>,
+  signal: u8
+}
+
+fn error_reply (code: u8) -> String {
+  format!("E{:02x}", code)
+}
+
+impl<T: Target> GdbStub<T> {
+  pub fn new (target: T) -> Self {
+    Self { target, vcpu: 0, breakpoints: HashMap::new(), signal: SIGTRAP }
+  }
+
+  pub fn target (&mut self) -> &mut T {
+    &mut self.target
+  }
+
+  pub fn stop_reply (&self) -> String {
+    format!("T{:02x}thread:{:x};", self.signal, self.vcpu + 1)
+  }
+
+  fn remove_breakpoints (&mut self) {
+    for (addr, byte) in std::mem::take(&mut self.breakpoints) {
+      let _ = self.target.write_memory(self.vcpu, addr, &[byte]);
+    }
+  }
+
+  fn resume (&mut self, step: Option<u32>) -> Action {
+    match self.target.resume(step) {
+      Ok(_) => Action::Resume,
+      Err(_) => Action::Reply(error_reply(1))
+    }
+  }
+
+  fn query (&mut self, query: &str) -> String {
+    if query.starts_with("Supported") {
+      return String::from("PacketSize=4000")
+    }
+
+    match query {
+      "C" => format!("QC{:x}", self.vcpu + 1),
+      "fThreadInfo" => {
+        let threads: Vec<String> = (1..=self.target.vcpu_count()).map(|id| format!("{:x}", id)).collect();
+        format!("m{}", threads.join(","))
+      },
+      "sThreadInfo" => String::from("l"),
+      "Attached" => String::from("1"),
+      _ => String::new()
+    }
+  }
+
+  pub fn handle_packet (&mut self, packet: &[u8]) -> Action {
+    let reply = match Command::parse(packet) {
+      Command::HaltReason => self.stop_reply(),
+      Command::ReadRegisters => match self.target.read_registers(self.vcpu) {
+        Ok(registers) => encode_registers(&registers),
+        Err(_) => error_reply(1)
+      },
+      Command::WriteRegisters(data) => {
+        let result = self.target.read_registers(self.vcpu).and_then(|mut registers| {
+          decode_registers(&data, &mut registers)
+            .ok_or_else(|| Error::new(ErrorCode::InvalidParam, "invalid register packet"))?;
+          self.target.write_registers(self.vcpu, &registers)
+        });
+        match result {
+          Ok(_) => String::from("OK"),
+          Err(_) => error_reply(1)
+        }
+      },
+      Command::ReadMemory { addr, len } => match self.target.read_memory(self.vcpu, addr, len) {
+        Ok(data) => encode_hex(&data),
+        Err(_) => error_reply(14)
+      },
+      Command::WriteMemory { addr, data } => match self.target.write_memory(self.vcpu, addr, &data) {
+        Ok(_) => String::from("OK"),
+        Err(_) => error_reply(14)
+      },
+      Command::Continue => return self.resume(None),
+      Command::Step => return self.resume(Some(self.vcpu)),
+      Command::InsertBreakpoint(addr) => {
+        if self.breakpoints.contains_key(&addr) {
+          String::from("OK")
+        } else {
+          let result = self.target.read_memory(self.vcpu, addr, 1).and_then(|original| {
+            self.target.write_memory(self.vcpu, addr, &[BREAKPOINT_INSTRUCTION])?;
+            Ok(original[0])
+          });
+          match result {
+            Ok(original) => {
+              self.breakpoints.insert(addr, original);
+              String::from("OK")
+            },
+            Err(_) => error_reply(14)
+          }
+        }
+      },
+      Command::RemoveBreakpoint(addr) => match self.breakpoints.remove(&addr) {
+        Some(original) => match self.target.write_memory(self.vcpu, addr, &[original]) {
+          Ok(_) => String::from("OK"),
+          Err(_) => error_reply(14)
+        },
+        None => String::from("OK")
+      },
+      Command::SetThread(vcpu) => {
+        if vcpu < self.target.vcpu_count() {
+          self.vcpu = vcpu;
+          String::from("OK")
+        } else {
+          error_reply(1)
+        }
+      },
+      Command::ThreadAlive(vcpu) => {
+        if vcpu < self.target.vcpu_count() { String::from("OK") } else { error_reply(1) }
+      },
+      Command::Query(query) => self.query(&query),
+      // The guest is never killed by the debugger, it is only released.
+      Command::Detach | Command::Kill => {
+        self.remove_breakpoints();
+        return match self.target.detach() {
+          Ok(_) => Action::Close(String::from("OK")),
+          Err(_) => Action::Close(error_reply(1))
+        }
+      },
+      Command::Unknown => String::new()
+    };
+
+    Action::Reply(reply)
+  }
+
+  pub fn handle_stop (&mut self, interrupted: bool) {
+    self.signal = if interrupted { SIGINT } else { SIGTRAP };
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn send_packet (stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+  stream.write_all(&encode_packet(data.as_bytes()))
+}
+
+fn is_timeout (error: &std::io::Error) -> bool {
+  matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+// Wait for the target to stop, the client can interrupt it with ^C.
+fn wait_stop<T: Target> (stub: &mut GdbStub<T>, stream: &mut TcpStream) -> std::io::Result<()> {
+  stream.set_read_timeout(Some(Duration::from_millis(10)))?;
+  let mut byte = [0u8; 1];
+  let interrupted = loop {
+    if stub.target().is_stopped().unwrap_or(false) {
+      break false
+    }
+
+    match stream.read(&mut byte) {
+      Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+      Ok(_) if byte[0] == INTERRUPT => {
+        let _ = stub.target().stop();
+        break true
+      },
+      Ok(_) => (),
+      Err(ref e) if is_timeout(e) => (),
+      Err(e) => return Err(e)
+    }
+  };
+  stream.set_read_timeout(None)?;
+
+  stub.handle_stop(interrupted);
+  let reply = stub.stop_reply();
+  send_packet(stream, &reply)
+}
+
+pub fn serve<T: Target> (stub: &mut GdbStub<T>, mut stream: TcpStream) -> std::io::Result<()> {
+  let mut decoder = PacketDecoder::new();
+  let mut buffer = [0u8; 4096];
+
+  loop {
+    let size = stream.read(&mut buffer)?;
+    if size == 0 {
+      // Connection closed without detach: release the guest anyway.
+      stub.remove_breakpoints();
+      let _ = stub.target().detach();
+      return Ok(())
+    }
+
+    for byte in &buffer[..size] {
+      match decoder.push(*byte) {
+        Some(Event::Packet(packet)) => {
+          stream.write_all(b"+")?;
+          match stub.handle_packet(&packet) {
+            Action::Reply(reply) => send_packet(&mut stream, &reply)?,
+            Action::Resume => wait_stop(stub, &mut stream)?,
+            Action::Close(reply) => return send_packet(&mut stream, &reply)
+          }
+        },
+        Some(Event::BadChecksum) => stream.write_all(b"-")?,
+        Some(Event::Interrupt) => {
+          let _ = stub.target().stop();
+          stub.handle_stop(true);
+          let reply = stub.stop_reply();
+          send_packet(&mut stream, &reply)?;
+        },
+        // Retransmissions are not supported, TCP is reliable.
+        Some(Event::Ack) | Some(Event::Nack) | None => ()
+      }
+    }
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::{Shutdown, TcpListener};
+
+  const MEMORY_BASE: u64 = 0x1000;
+
+  #[derive(Default)]
+  struct FakeTarget {
+    registers: X86Registers,
+    memory: Vec<u8>,
+    resumed: Vec<Option<u32>>,
+    stopped: u32,
+    detached: bool
+  }
+
+  impl FakeTarget {
+    fn range (&self, addr: u64, len: usize) -> Result<std::ops::Range<usize>> {
+      let start = addr.checked_sub(MEMORY_BASE).map(|start| start as usize);
+      match start {
+        Some(start) if start + len <= self.memory.len() => Ok(start..start + len),
+        _ => Err(Error::new(ErrorCode::InvalidParam, "address not mapped"))
+      }
+    }
+  }
+
+  impl Target for FakeTarget {
+    fn vcpu_count (&self) -> u32 {
+      2
+    }
+
+    fn read_registers (&mut self, _vcpu: u32) -> Result<X86Registers> {
+      Ok(self.registers)
+    }
+
+    fn write_registers (&mut self, _vcpu: u32, registers: &X86Registers) -> Result<()> {
+      self.registers = *registers;
+      Ok(())
+    }
+
+    fn read_memory (&mut self, _vcpu: u32, addr: u64, len: usize) -> Result<Vec<u8>> {
+      let range = self.range(addr, len)?;
+      Ok(self.memory[range].to_vec())
+    }
+
+    fn write_memory (&mut self, _vcpu: u32, addr: u64, data: &[u8]) -> Result<()> {
+      let range = self.range(addr, data.len())?;
+      self.memory[range].copy_from_slice(data);
+      Ok(())
+    }
+
+    fn resume (&mut self, step: Option<u32>) -> Result<()> {
+      self.resumed.push(step);
+      Ok(())
+    }
+
+    fn stop (&mut self) -> Result<()> {
+      self.stopped += 1;
+      Ok(())
+    }
+
+    // Stops as soon as it is resumed: a breakpoint or a step.
+    fn is_stopped (&mut self) -> Result<bool> {
+      Ok(true)
+    }
+
+    fn detach (&mut self) -> Result<()> {
+      self.detached = true;
+      Ok(())
+    }
+  }
+
+  fn fake_target () -> FakeTarget {
+    let mut registers = X86Registers { rax: 1, rip: MEMORY_BASE, rflags: 0x246, ..Default::default() };
+    registers.cs.selector = 0x10;
+    FakeTarget { registers, memory: vec![0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0], ..Default::default() }
+  }
+
+  #[test]
+  fn packets () {
+    assert_eq!(checksum(b"OK"), 0x9a);
+    assert_eq!(encode_packet(b"OK"), b"$OK#9a");
+    // Escaped bytes, the checksum is the one of the sent body.
+    assert_eq!(encode_packet(b"a$b"), b"$a}\x04b#44");
+
+    let mut decoder = PacketDecoder::new();
+    let mut events = Vec::new();
+    for byte in b"+-\x03junk$m1000,4#8e$a}\x04b#44$g#00$g#6" {
+      events.extend(decoder.push(*byte));
+    }
+    assert_eq!(events, [
+      Event::Ack,
+      Event::Nack,
+      Event::Interrupt,
+      Event::Packet(b"m1000,4".to_vec()),
+      Event::Packet(b"a$b".to_vec()),
+      Event::BadChecksum
+    ]);
+    assert_eq!(decoder.push(b'7'), Some(Event::Packet(b"g".to_vec())));
+  }
+
+  #[test]
+  fn commands () {
+    assert_eq!(Command::parse(b"?"), Command::HaltReason);
+    assert_eq!(Command::parse(b"g"), Command::ReadRegisters);
+    assert_eq!(Command::parse(b"m1000,4"), Command::ReadMemory { addr: 0x1000, len: 4 });
+    assert_eq!(Command::parse(b"M1000,2:aabb"), Command::WriteMemory { addr: 0x1000, data: vec![0xaa, 0xbb] });
+    // The length doesn't match the data.
+    assert_eq!(Command::parse(b"M1000,3:aabb"), Command::Unknown);
+    assert_eq!(Command::parse(b"Z0,1000,1"), Command::InsertBreakpoint(0x1000));
+    assert_eq!(Command::parse(b"z0,1000,1"), Command::RemoveBreakpoint(0x1000));
+    // Hardware breakpoints and watchpoints are not supported.
+    assert_eq!(Command::parse(b"Z1,1000,1"), Command::Unknown);
+    assert_eq!(Command::parse(b"c"), Command::Continue);
+    assert_eq!(Command::parse(b"c1000"), Command::Continue);
+    assert_eq!(Command::parse(b"s"), Command::Step);
+    assert_eq!(Command::parse(b"Hg2"), Command::SetThread(1));
+    assert_eq!(Command::parse(b"Hc-1"), Command::SetThread(0));
+    assert_eq!(Command::parse(b"vCont?"), Command::Unknown);
+  }
+
+  #[test]
+  fn registers () {
+    let target = fake_target();
+    let hex = encode_registers(&target.registers);
+    // 17 64-bit registers, eflags and 6 selectors.
+    assert_eq!(hex.len(), (17 * 8 + 7 * 4) * 2);
+    assert!(hex.starts_with("0100000000000000"));
+    assert_eq!(&hex[16 * 16..17 * 16], "0010000000000000");
+    assert_eq!(&hex[17 * 16..17 * 16 + 16], "4602000010000000");
+
+    let mut registers = X86Registers { rflags: 0x1_0000_0000, ..Default::default() };
+    decode_registers(&decode_hex(hex.as_bytes()).unwrap(), &mut registers).unwrap();
+    assert_eq!(registers.rax, 1);
+    assert_eq!(registers.rip, MEMORY_BASE);
+    // The upper half of rflags is not in the packet.
+    assert_eq!(registers.rflags, 0x1_0000_0246);
+    assert_eq!(registers.cs.selector, 0x10);
+    assert!(decode_registers(&[0; 16], &mut registers).is_none());
+  }
+
+  // A session recorded from gdb, replayed over a connection to the stub.
+  #[test]
+  fn session () {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut stub = GdbStub::new(fake_target());
+      serve(&mut stub, stream).unwrap();
+      stub
+    });
+
+    let mut client = TcpStream::connect(address).unwrap();
+    let requests: &[&[u8]] = &[
+      b"+$?#3f",
+      b"+$g#67",
+      b"+$m1000,4#8e",
+      b"+$M1000,2:aabb#2c",
+      b"+$Z0,1000,1#d4",
+      b"+$c#63",
+      b"+$s#73",
+      b"+\x03",
+      b"+$?#3f",
+      b"+$z0,1000,1#f4",
+      b"+$m2000,4#8f",
+      // Corrupted, then sent again after the nack.
+      b"+$g#00",
+      b"$g#67",
+      b"+-+$D#44"
+    ];
+    for request in requests {
+      client.write_all(request).unwrap();
+    }
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut transcript = Vec::new();
+    client.read_to_end(&mut transcript).unwrap();
+
+    let registers = format!("+{}", String::from_utf8(encode_packet(encode_registers(&fake_target().registers).as_bytes())).unwrap());
+    let replies: &[&str] = &[
+      "+$T05thread:1;#d7",
+      &registers,
+      "+$deadbeef#20",
+      "+$OK#9a",
+      "+$OK#9a",
+      "+$T05thread:1;#d7",
+      "+$T05thread:1;#d7",
+      "$T02thread:1;#d4",
+      "+$T02thread:1;#d4",
+      "+$OK#9a",
+      "+$E0e#da",
+      "-",
+      &registers,
+      "+$OK#9a"
+    ];
+    assert_eq!(String::from_utf8(transcript).unwrap(), replies.concat());
+
+    let mut stub = server.join().unwrap();
+    let target = stub.target();
+    assert_eq!(target.resumed, [None, Some(0)]);
+    assert_eq!(target.stopped, 1);
+    assert!(target.detached);
+    // Written, then the breakpoint removed.
+    assert_eq!(target.memory[..4], [0xaa, 0xbb, 0xbe, 0xef]);
+  }
+
+  #[test]
+  fn breakpoints_removed_on_disconnect () {
+    let mut stub = GdbStub::new(fake_target());
+    assert!(matches!(stub.handle_packet(b"Z0,1001,1"), Action::Reply(ref reply) if reply == "OK"));
+    assert_eq!(stub.target().memory[1], BREAKPOINT_INSTRUCTION);
+    assert!(matches!(stub.handle_packet(b"Z0,3000,1"), Action::Reply(ref reply) if reply == "E0e"));
+    assert!(matches!(stub.handle_packet(b"k"), Action::Close(ref reply) if reply == "OK"));
+    assert_eq!(stub.target().memory[1], 0xad);
+    assert!(stub.target().detached);
+  }
+}
@@ -0,0 +1,202 @@
+use super::bindings;
+use super::foreignmemory::PAGE_SIZE;
+use super::xenctrl::{Error, ErrorCode, Result};
+
+// =============================================================================
+// Grant tables (libxengnttab).
+//
+// `GrantTable` maps in our process the pages granted to us by other domains,
+// `GrantShare` grants our pages to a domain. The pages are unmapped, or no
+// longer shared, when the returned mapping is dropped.
+// =============================================================================
+
+// Written in a mapping when it is unmapped, with an event sent on a port, so
+// the other end knows it is gone (see `map_notify` and `share_notify`).
+pub struct Notify {
+  // Offset of the byte set to 0 in the mapping.
+  pub offset: u32,
+  pub port: u32
+}
+
+fn protection (writable: bool) -> i32 {
+  if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ }
+}
+
+fn check_count (count: usize) -> Result<()> {
+  match count {
+    0 => Err(Error::new(ErrorCode::InvalidParam, "no grant reference")),
+    _ => Ok(())
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct GrantTable {
+  xgt: *mut bindings::xengnttab_handle
+}
+
+unsafe impl Send for GrantTable {}
+
+impl Drop for GrantTable {
+  fn drop (&mut self) {
+    unsafe { bindings::xengnttab_close(self.xgt); }
+  }
+}
+
+impl GrantTable {
+  pub fn new () -> std::result::Result<Self, &'static str> {
+    unsafe {
+      let xgt = bindings::xengnttab_open(std::ptr::null_mut(), 0);
+      if !xgt.is_null() { Ok(Self { xgt }) } else { Err("Failed to open grant table interface") }
+    }
+  }
+
+  // Limit the count of grants mapped at once by this handle.
+  pub fn set_max_grants (&self, count: u32) -> Result<()> {
+    let ret = unsafe { bindings::xengnttab_set_max_grants(self.xgt, count) };
+    match ret {
+      0 => Ok(()),
+      _ => Err(Error::last_os_error())
+    }
+  }
+
+  // Map the pages granted by a domain contiguously in our address space.
+  pub fn map (&self, dom_id: u32, refs: &[u32], writable: bool) -> Result<Mapping<'_>> {
+    check_count(refs.len())?;
+    let addr = unsafe {
+      bindings::xengnttab_map_domain_grant_refs(
+        self.xgt, refs.len() as u32, dom_id, refs.as_ptr() as *mut u32, protection(writable)
+      )
+    };
+    self.mapping(addr, refs.len())
+  }
+
+  // Map a granted page, `notify` is applied when it is unmapped.
+  pub fn map_notify (&self, dom_id: u32, gref: u32, writable: bool, notify: Notify) -> Result<Mapping<'_>> {
+    let addr = unsafe {
+      bindings::xengnttab_map_grant_ref_notify(
+        self.xgt, dom_id, gref, protection(writable), notify.offset, notify.port
+      )
+    };
+    self.mapping(addr, 1)
+  }
+
+  fn mapping (&self, addr: *mut libc::c_void, pages: usize) -> Result<Mapping<'_>> {
+    if addr.is_null() {
+      return Err(Error::last_os_error())
+    }
+    Ok(Mapping { xgt: self, addr: addr as *mut u8, pages })
+  }
+}
+
+// Granted pages mapped in our address space, unmapped on drop.
+pub struct Mapping<'a> {
+  xgt: &'a GrantTable,
+  addr: *mut u8,
+  pages: usize
+}
+
+impl Mapping<'_> {
+  pub fn as_slice (&self) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(self.addr, self.pages * PAGE_SIZE) }
+  }
+
+  pub fn as_mut_slice (&mut self) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(self.addr, self.pages * PAGE_SIZE) }
+  }
+
+  pub fn as_ptr (&self) -> *mut u8 {
+    self.addr
+  }
+}
+
+impl Drop for Mapping<'_> {
+  fn drop (&mut self) {
+    unsafe { bindings::xengnttab_unmap(self.xgt.xgt, self.addr as *mut libc::c_void, self.pages as u32); }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct GrantShare {
+  xgs: *mut bindings::xengntshr_handle
+}
+
+unsafe impl Send for GrantShare {}
+
+impl Drop for GrantShare {
+  fn drop (&mut self) {
+    unsafe { bindings::xengntshr_close(self.xgs); }
+  }
+}
+
+impl GrantShare {
+  pub fn new () -> std::result::Result<Self, &'static str> {
+    unsafe {
+      let xgs = bindings::xengntshr_open(std::ptr::null_mut(), 0);
+      if !xgs.is_null() { Ok(Self { xgs }) } else { Err("Failed to open grant share interface") }
+    }
+  }
+
+  // Allocate zeroed pages granted to a domain, their grant references are
+  // given by `SharedPages::refs`.
+  pub fn share (&self, dom_id: u32, count: usize, writable: bool) -> Result<SharedPages<'_>> {
+    check_count(count)?;
+    let mut refs = vec![0u32; count];
+    let addr = unsafe {
+      bindings::xengntshr_share_pages(self.xgs, dom_id, count as i32, refs.as_mut_ptr(), writable as i32)
+    };
+    self.shared_pages(addr, refs)
+  }
+
+  // Allocate a page granted to a domain, `notify` is applied when it is no
+  // longer shared.
+  pub fn share_notify (&self, dom_id: u32, writable: bool, notify: Notify) -> Result<SharedPages<'_>> {
+    let mut gref = 0u32;
+    let addr = unsafe {
+      bindings::xengntshr_share_page_notify(self.xgs, dom_id, &mut gref, writable as i32, notify.offset, notify.port)
+    };
+    self.shared_pages(addr, vec![gref])
+  }
+
+  fn shared_pages (&self, addr: *mut libc::c_void, refs: Vec<u32>) -> Result<SharedPages<'_>> {
+    if addr.is_null() {
+      return Err(Error::last_os_error())
+    }
+    Ok(SharedPages { xgs: self, addr: addr as *mut u8, refs })
+  }
+}
+
+// Pages of our address space granted to a domain, no longer shared on drop.
+// The domain must have unmapped them before, or they are only freed when it
+// does.
+pub struct SharedPages<'a> {
+  xgs: &'a GrantShare,
+  addr: *mut u8,
+  refs: Vec<u32>
+}
+
+impl SharedPages<'_> {
+  // Grant reference of each page.
+  pub fn refs (&self) -> &[u32] {
+    &self.refs
+  }
+
+  pub fn as_slice (&self) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(self.addr, self.refs.len() * PAGE_SIZE) }
+  }
+
+  pub fn as_mut_slice (&mut self) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(self.addr, self.refs.len() * PAGE_SIZE) }
+  }
+
+  pub fn as_ptr (&self) -> *mut u8 {
+    self.addr
+  }
+}
+
+impl Drop for SharedPages<'_> {
+  fn drop (&mut self) {
+    unsafe { bindings::xengntshr_unshare(self.xgs.xgs, self.addr as *mut libc::c_void, self.refs.len() as u32); }
+  }
+}
@@ -0,0 +1,140 @@
+use super::foreignmemory::{ForeignMemory, Mapping, PAGE_SHIFT, PAGE_SIZE};
+use super::xenctrl::{Error, ErrorCode, Result, Xenctrl};
+
+// =============================================================================
+// Access to the memory of a guest for diagnostics.
+// =============================================================================
+
+// A range of guest memory, unmapped when dropped.
+pub struct GuestMemory<'a> {
+  mapping: Mapping<'a>,
+  offset: usize,
+  len: usize
+}
+
+impl std::ops::Deref for GuestMemory<'_> {
+  type Target = [u8];
+
+  fn deref (&self) -> &[u8] {
+    &self.mapping.as_slice()[self.offset..self.offset + self.len]
+  }
+}
+
+impl std::ops::DerefMut for GuestMemory<'_> {
+  fn deref_mut (&mut self) -> &mut [u8] {
+    let (offset, len) = (self.offset, self.len);
+    &mut self.mapping.as_mut_slice()[offset..offset + len]
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn page_offset (addr: u64) -> usize {
+  (addr & (PAGE_SIZE as u64 - 1)) as usize
+}
+
+fn page_count (addr: u64, len: usize) -> usize {
+  (page_offset(addr) + len + PAGE_SIZE - 1) >> PAGE_SHIFT
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct Guest<'a> {
+  xc: &'a Xenctrl,
+  fmem: &'a ForeignMemory,
+  dom_id: u32
+}
+
+impl<'a> Guest<'a> {
+  pub fn new (xc: &'a Xenctrl, fmem: &'a ForeignMemory, dom_id: u32) -> Self {
+    Self { xc, fmem, dom_id }
+  }
+
+  pub fn get_dom_id (&self) -> u32 {
+    self.dom_id
+  }
+
+  // Translate a virtual address using the page tables of a vCPU.
+  pub fn translate (&self, vcpu: u32, addr: u64) -> Result<u64> {
+    let frame = self.xc.translate_foreign_address(self.dom_id, vcpu, addr)?;
+    Ok((frame << PAGE_SHIFT) | page_offset(addr) as u64)
+  }
+
+  fn map (&self, frames: &[u64], addr: u64, len: usize, writable: bool) -> Result<GuestMemory<'a>> {
+    if len == 0 {
+      return Err(Error::new(ErrorCode::InvalidParam, "empty memory range"))
+    }
+
+    Ok(GuestMemory {
+      mapping: self.fmem.map(self.dom_id, frames, writable)?,
+      offset: page_offset(addr),
+      len
+    })
+  }
+
+  fn phys_frames (addr: u64, len: usize) -> Vec<u64> {
+    let first = addr >> PAGE_SHIFT;
+    (first..first + page_count(addr, len) as u64).collect()
+  }
+
+  fn virt_frames (&self, vcpu: u32, addr: u64, len: usize) -> Result<Vec<u64>> {
+    // Virtually contiguous pages are not always physically contiguous.
+    let first = addr & !(PAGE_SIZE as u64 - 1);
+    (0..page_count(addr, len) as u64)
+      .map(|i| self.xc.translate_foreign_address(self.dom_id, vcpu, first + (i << PAGE_SHIFT)))
+      .collect()
+  }
+
+  pub fn map_phys (&self, addr: u64, len: usize, writable: bool) -> Result<GuestMemory<'a>> {
+    self.map(&Self::phys_frames(addr, len), addr, len, writable)
+  }
+
+  pub fn map_virt (&self, vcpu: u32, addr: u64, len: usize, writable: bool) -> Result<GuestMemory<'a>> {
+    self.map(&self.virt_frames(vcpu, addr, len)?, addr, len, writable)
+  }
+
+  pub fn read_phys (&self, addr: u64, len: usize) -> Result<GuestMemory<'a>> {
+    self.map_phys(addr, len, false)
+  }
+
+  pub fn read_virt (&self, vcpu: u32, addr: u64, len: usize) -> Result<GuestMemory<'a>> {
+    self.map_virt(vcpu, addr, len, false)
+  }
+
+  pub fn write_phys (&self, addr: u64, data: &[u8]) -> Result<()> {
+    self.map_phys(addr, data.len(), true)?.copy_from_slice(data);
+    Ok(())
+  }
+
+  pub fn write_virt (&self, vcpu: u32, addr: u64, data: &[u8]) -> Result<()> {
+    self.map_virt(vcpu, addr, data.len(), true)?.copy_from_slice(data);
+    Ok(())
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pages () {
+    assert_eq!(page_offset(0x1234_5678), 0x678);
+    assert_eq!(page_offset(0x1000), 0);
+
+    assert_eq!(page_count(0x1000, 1), 1);
+    assert_eq!(page_count(0x1000, PAGE_SIZE), 1);
+    assert_eq!(page_count(0x1000, PAGE_SIZE + 1), 2);
+    assert_eq!(page_count(0x1fff, 1), 1);
+    assert_eq!(page_count(0x1fff, 2), 2);
+    assert_eq!(page_count(0x1800, 2 * PAGE_SIZE), 3);
+  }
+
+  #[test]
+  fn phys_frames () {
+    assert_eq!(Guest::phys_frames(0x1000, 16), [1]);
+    assert_eq!(Guest::phys_frames(0x2ffc, 8), [2, 3]);
+    assert_eq!(Guest::phys_frames(0x1_0000_0800, 3 * PAGE_SIZE), [0x10_0000, 0x10_0001, 0x10_0002, 0x10_0003]);
+  }
+}
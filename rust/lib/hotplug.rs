@@ -0,0 +1,487 @@
+use ini::Ini;
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+use super::device::vif;
+use super::device::XenbusState;
+use super::xenstore::Store;
+
+// =============================================================================
+// Hotplug of the backends of a driver domain.
+//
+// When a backend waits for the toolstack (InitWait), the handler of its kind
+// sets up the host side: a script with the environment of the Xen hotplug
+// scripts, or a built-in handler. The result is written in `hotplug-status`
+// ("connected" or "error", with the details in `hotplug-error`). When the
+// backend is closed and offline, or removed, the handler tears it down.
+//
+// Handlers are configured per device kind in an INI file:
+//
+//   [vif]
+//   handler = openvswitch
+//
+//   [vbd]
+//   handler = script
+//   script = /usr/libexec/xenops/block
+//
+// By default, the script given by the `script` node of the backend is run.
+// The backends of the other kinds without script (pci, qdisk...) have no
+// hotplug: they are left alone.
+// =============================================================================
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/xenops/hotplug.ini";
+
+const OVS_VSCTL_TIMEOUT: &str = "--timeout=30";
+
+// Runs the commands of the handlers, a fake one can be used to check them.
+pub trait CommandRunner {
+  // Returns the stdout of the command, or an error message.
+  fn run (&self, program: &str, args: &[&str], env: &[(&str, String)]) -> Result<String, String>;
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+  fn run (&self, program: &str, args: &[&str], env: &[(&str, String)]) -> Result<String, String> {
+    let output = Command::new(program)
+      .args(args)
+      .envs(env.iter().map(|(key, value)| (key, value)))
+      .output()
+      .map_err(|e| format!("failed to run {}: {}", program, e))?;
+
+    if output.status.success() {
+      Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+      let stderr = String::from_utf8_lossy(&output.stderr);
+      Err(format!("{} failed ({}): {}", program, output.status, stderr.trim()))
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, PartialEq)]
+pub enum Handler {
+  // External script, the one of the backend if none.
+  Script(Option<String>),
+  // Built-in: add the interface of a vif to a Linux bridge.
+  Bridge,
+  // Built-in: add the interface of a vif to an Open vSwitch bridge.
+  OpenVswitch,
+  // Built-in: give the device number of a "phy" vbd to the backend.
+  Block
+}
+
+impl std::str::FromStr for Handler {
+  type Err = String;
+
+  fn from_str (value: &str) -> Result<Self, Self::Err> {
+    match value {
+      "script" => Ok(Handler::Script(None)),
+      "bridge" => Ok(Handler::Bridge),
+      "openvswitch" => Ok(Handler::OpenVswitch),
+      "block" => Ok(Handler::Block),
+      _ => Err(format!("invalid hotplug handler: `{}`", value))
+    }
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct HotplugConfig {
+  // Handler per device kind.
+  pub handlers: HashMap<String, Handler>
+}
+
+impl HotplugConfig {
+  // A missing file is the default config.
+  pub fn load (path: &Path) -> Result<Self, String> {
+    if !path.exists() {
+      return Ok(Self::default())
+    }
+    let ini = Ini::load_from_file(path).map_err(|e| format!("failed to load {}: {}", path.display(), e))?;
+
+    let mut config = Self::default();
+    for (kind, properties) in ini.iter() {
+      let kind = match kind {
+        Some(kind) => kind,
+        None => continue
+      };
+      let handler = match properties.get("handler").unwrap_or("script").parse()? {
+        Handler::Script(_) => Handler::Script(properties.get("script").map(String::from)),
+        handler => handler
+      };
+      config.handlers.insert(kind.to_string(), handler);
+    }
+    Ok(config)
+  }
+
+  // Handler of the kind of a backend, else its script if it has one.
+  pub fn handler (&self, backend: &Backend) -> Option<Handler> {
+    match self.handlers.get(&backend.kind) {
+      Some(handler) => Some(handler.clone()),
+      None if backend.nodes.contains_key("script") => Some(Handler::Script(None)),
+      None => None
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+  Add,
+  Remove
+}
+
+impl std::fmt::Display for Action {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Action::Add => write!(f, "add"),
+      Action::Remove => write!(f, "remove")
+    }
+  }
+}
+
+// A backend seen by the hotplug, with its nodes when it was added.
+pub struct Backend {
+  pub path: String,
+  pub kind: String,
+  pub frontend_dom_id: u32,
+  pub devid: u32,
+  pub nodes: BTreeMap<String, String>
+}
+
+impl Backend {
+  // Parse `/local/domain/<be>/backend/<kind>/<fe>/<devid>[/...]`, returns
+  // the backend and the length of its path.
+  fn parse_path (path: &str) -> Option<(String, u32, u32, usize)> {
+    let parts: Vec<&str> = path.split('/').collect();
+    if parts.len() < 8 || parts[1] != "local" || parts[2] != "domain" || parts[4] != "backend" {
+      return None
+    }
+    let length = parts[..8].iter().map(|part| part.len() + 1).sum::<usize>() - 1;
+    Some((parts[5].to_string(), parts[6].parse().ok()?, parts[7].parse().ok()?, length))
+  }
+
+  fn node (&self, key: &str) -> Result<&str, String> {
+    self.nodes.get(key).map(String::as_str).ok_or_else(|| format!("missing backend node `{}`", key))
+  }
+
+  // Name of the interface created by netback.
+  fn vif_name (&self) -> String {
+    format!("vif{}.{}", self.frontend_dom_id, self.devid)
+  }
+
+  // Environment given by libxl to the hotplug scripts.
+  fn script_env (&self) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+      ("XENBUS_TYPE", self.kind.clone()),
+      ("XENBUS_PATH", self.path.clone()),
+      ("XENBUS_BASE_PATH", String::from("backend"))
+    ];
+    if self.kind == vif::KIND {
+      env.push(("netdev", String::new()));
+      env.push(("vif", self.vif_name()));
+    }
+    env
+  }
+}
+
+// Device number of a block device, like the `block` script: "major:minor" in hex.
+pub fn physical_device (path: &str) -> Result<String, String> {
+  let metadata = std::fs::metadata(path).map_err(|e| format!("failed to stat {}: {}", path, e))?;
+  if !metadata.file_type().is_block_device() {
+    return Err(format!("{} is not a block device", path))
+  }
+  let rdev = metadata.rdev();
+  // See gnu_dev_major/gnu_dev_minor.
+  let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+  let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+  Ok(format!("{:x}:{:x}", major, minor))
+}
+
+pub struct Hotplug<R: CommandRunner> {
+  config: HotplugConfig,
+  runner: R,
+  // Backends set up, by path.
+  added: Mutex<HashMap<String, Backend>>
+}
+
+impl<R: CommandRunner> Hotplug<R> {
+  pub fn new (config: HotplugConfig, runner: R) -> Self {
+    Self { config, runner, added: Mutex::new(HashMap::new()) }
+  }
+
+  // Handle a change under the backend directory of a driver domain. Returns
+  // the action taken on a backend, with the result of its handler.
+  pub fn backend_changed (&self, store: &dyn Store, path: &str) -> Option<(Action, String, Result<(), String>)> {
+    let (kind, frontend_dom_id, devid, length) = Backend::parse_path(path)?;
+    let backend_path = &path[..length];
+    let read = |key: &str| store.read(&format!("{}/{}", backend_path, key)).ok();
+
+    let state = read("state").and_then(|state| state.parse().ok()).map(XenbusState::from_value);
+    let online = read("online");
+    let is_added = self.added.lock().unwrap().contains_key(backend_path);
+
+    match state {
+      Some(XenbusState::InitWait) if !is_added && online.as_deref() == Some("1") && read("hotplug-status").is_none() => {
+        let nodes = store.directory(backend_path).unwrap_or_default().into_iter()
+          .filter_map(|key| read(&key).map(|value| (key, value)))
+          .collect();
+        let backend = Backend { path: backend_path.to_string(), kind, frontend_dom_id, devid, nodes };
+        let handler = self.config.handler(&backend)?;
+
+        let result = self.run(&backend, handler, Action::Add, store);
+        match &result {
+          Ok(_) => {
+            let _ = store.write(&format!("{}/hotplug-status", backend_path), "connected");
+          },
+          Err(e) => {
+            let _ = store.write(&format!("{}/hotplug-error", backend_path), e);
+            let _ = store.write(&format!("{}/hotplug-status", backend_path), "error");
+          }
+        }
+        self.added.lock().unwrap().insert(backend_path.to_string(), backend);
+        Some((Action::Add, backend_path.to_string(), result))
+      },
+      Some(XenbusState::Closed) | None if is_added && online.as_deref() != Some("1") => {
+        let backend = self.added.lock().unwrap().remove(backend_path)?;
+        let handler = self.config.handler(&backend)?;
+        let result = self.run(&backend, handler, Action::Remove, store);
+        Some((Action::Remove, backend.path, result))
+      },
+      _ => None
+    }
+  }
+
+  fn run (&self, backend: &Backend, handler: Handler, action: Action, store: &dyn Store) -> Result<(), String> {
+    match handler {
+      Handler::Script(script) => {
+        let script = match script {
+          Some(script) => script,
+          None => backend.node("script")?.to_string()
+        };
+        self.runner.run(&script, &[&action.to_string()], &backend.script_env()).map(|_| ())
+      },
+      Handler::Bridge => self.bridge(backend, action),
+      Handler::OpenVswitch => self.openvswitch(backend, action),
+      Handler::Block => {
+        if action == Action::Remove {
+          return Ok(())
+        }
+        if backend.node("type")? != "phy" {
+          return Err(format!("block handler: unsupported type `{}`", backend.node("type")?))
+        }
+        let params = backend.node("params")?;
+        let device = physical_device(params)?;
+        store.write(&format!("{}/physical-device", backend.path), &device)
+          .and_then(|_| store.write(&format!("{}/physical-device-path", backend.path), params))
+          .map_err(|_| String::from("failed to write physical-device"))
+      }
+    }
+  }
+
+  fn link_up (&self, backend: &Backend) -> Result<(), String> {
+    let name = backend.vif_name();
+    if let Some(mtu) = backend.nodes.get("mtu") {
+      self.runner.run("ip", &["link", "set", "dev", &name, "mtu", mtu], &[])?;
+    }
+    self.runner.run("ip", &["link", "set", "dev", &name, "up"], &[]).map(|_| ())
+  }
+
+  fn bridge (&self, backend: &Backend, action: Action) -> Result<(), String> {
+    let name = backend.vif_name();
+    match action {
+      Action::Add => {
+        let bridge = backend.node("bridge")?;
+        self.runner.run("ip", &["link", "set", "dev", &name, "master", bridge], &[])?;
+        self.link_up(backend)
+      },
+      // Netback destroys the interface, it may already be gone.
+      Action::Remove => {
+        let _ = self.runner.run("ip", &["link", "set", "dev", &name, "nomaster"], &[]);
+        Ok(())
+      }
+    }
+  }
+
+  fn openvswitch (&self, backend: &Backend, action: Action) -> Result<(), String> {
+    let name = backend.vif_name();
+    let bridge = backend.node("bridge")?;
+    match action {
+      Action::Add => {
+        self.runner.run("ovs-vsctl", &[OVS_VSCTL_TIMEOUT, "--", "--may-exist", "add-port", bridge, &name], &[])?;
+        self.link_up(backend)
+      },
+      Action::Remove => {
+        self.runner.run("ovs-vsctl", &[OVS_VSCTL_TIMEOUT, "--", "--if-exists", "del-port", bridge, &name], &[])
+          .map(|_| ())
+      }
+    }
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::TempDir;
+  use crate::xenstore::MemoryStore;
+
+  const SCRIPT: &str = "/etc/xen/scripts/block";
+
+  // Records the commands run, fails those of `failing`.
+  #[derive(Default)]
+  struct FakeRunner {
+    commands: Mutex<Vec<(String, Vec<String>)>>,
+    failing: Option<&'static str>
+  }
+
+  impl CommandRunner for FakeRunner {
+    fn run (&self, program: &str, args: &[&str], env: &[(&str, String)]) -> Result<String, String> {
+      let command = std::iter::once(program).chain(args.iter().cloned()).collect::<Vec<_>>().join(" ");
+      let env = env.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+      self.commands.lock().unwrap().push((command, env));
+      match self.failing {
+        Some(failing) if failing == program => Err(format!("{} failed", program)),
+        _ => Ok(String::new())
+      }
+    }
+  }
+
+  fn write_backend (store: &MemoryStore, path: &str, nodes: &[(&str, &str)]) {
+    for (key, value) in nodes {
+      assert!(store.write(&format!("{}/{}", path, key), value).is_ok());
+    }
+  }
+
+  // The action taken on a change of the state of a backend.
+  fn state_changed<R: CommandRunner> (
+    hotplug: &Hotplug<R>,
+    store: &MemoryStore,
+    path: &str
+  ) -> Option<(String, String, Result<(), String>)> {
+    hotplug.backend_changed(store, &format!("{}/state", path))
+      .map(|(action, path, result)| (action.to_string(), path, result))
+  }
+
+  fn commands (hotplug: &Hotplug<FakeRunner>) -> Vec<String> {
+    hotplug.runner.commands.lock().unwrap().iter().map(|(command, _)| command.clone()).collect()
+  }
+
+  #[test]
+  fn script_add_remove () {
+    let store = MemoryStore::new();
+    let hotplug = Hotplug::new(HotplugConfig::default(), FakeRunner::default());
+    let path = "/local/domain/0/backend/vbd/3/51712";
+
+    // Not ready yet.
+    write_backend(&store, path, &[("script", SCRIPT), ("params", "/dev/vg/disk"), ("online", "1"), ("state", "1")]);
+    assert_eq!(state_changed(&hotplug, &store, path), None);
+
+    write_backend(&store, path, &[("state", "2")]);
+    assert_eq!(state_changed(&hotplug, &store, path), Some((String::from("add"), path.to_string(), Ok(()))));
+    assert_eq!(store.read(&format!("{}/hotplug-status", path)).ok().as_deref(), Some("connected"));
+    {
+      let commands = hotplug.runner.commands.lock().unwrap();
+      assert_eq!(commands[0].0, format!("{} add", SCRIPT));
+      assert_eq!(commands[0].1, vec![
+        String::from("XENBUS_TYPE=vbd"),
+        format!("XENBUS_PATH={}", path),
+        String::from("XENBUS_BASE_PATH=backend")
+      ]);
+    }
+
+    // Added once.
+    assert_eq!(state_changed(&hotplug, &store, path), None);
+    write_backend(&store, path, &[("state", "4")]);
+    assert_eq!(state_changed(&hotplug, &store, path), None);
+
+    // Closed but still online: the guest may reconnect.
+    write_backend(&store, path, &[("state", "6")]);
+    assert_eq!(state_changed(&hotplug, &store, path), None);
+
+    write_backend(&store, path, &[("online", "0")]);
+    assert_eq!(state_changed(&hotplug, &store, path), Some((String::from("remove"), path.to_string(), Ok(()))));
+    assert_eq!(commands(&hotplug), vec![format!("{} add", SCRIPT), format!("{} remove", SCRIPT)]);
+    assert_eq!(state_changed(&hotplug, &store, path), None);
+  }
+
+  #[test]
+  fn script_failure () {
+    let store = MemoryStore::new();
+    let runner = FakeRunner { failing: Some(SCRIPT), ..FakeRunner::default() };
+    let hotplug = Hotplug::new(HotplugConfig::default(), runner);
+    let path = "/local/domain/0/backend/vbd/3/51712";
+
+    write_backend(&store, path, &[("script", SCRIPT), ("online", "1"), ("state", "2")]);
+    let error = format!("{} failed", SCRIPT);
+    assert_eq!(state_changed(&hotplug, &store, path), Some((String::from("add"), path.to_string(), Err(error.clone()))));
+    assert_eq!(store.read(&format!("{}/hotplug-status", path)).ok().as_deref(), Some("error"));
+    assert_eq!(store.read(&format!("{}/hotplug-error", path)).ok(), Some(error));
+  }
+
+  #[test]
+  fn backends_without_script () {
+    let store = MemoryStore::new();
+    let hotplug = Hotplug::new(HotplugConfig::default(), FakeRunner::default());
+
+    for path in &[
+      "/local/domain/0/backend/pci/3/0",
+      "/local/domain/0/backend/qdisk/3/768",
+      "/local/domain/0/backend/console/3/0"
+    ] {
+      write_backend(&store, path, &[("online", "1"), ("state", "2")]);
+      assert_eq!(state_changed(&hotplug, &store, path), None);
+      assert!(store.read(&format!("{}/hotplug-status", path)).is_err());
+
+      write_backend(&store, path, &[("online", "0"), ("state", "6")]);
+      assert_eq!(state_changed(&hotplug, &store, path), None);
+    }
+    assert!(commands(&hotplug).is_empty());
+
+    // Not a backend.
+    assert!(hotplug.backend_changed(&store, "/local/domain/0/backend/vbd").is_none());
+  }
+
+  #[test]
+  fn bridge () {
+    let store = MemoryStore::new();
+    let mut config = HotplugConfig::default();
+    config.handlers.insert(String::from("vif"), Handler::Bridge);
+    let hotplug = Hotplug::new(config, FakeRunner::default());
+    let path = "/local/domain/0/backend/vif/3/1";
+
+    write_backend(&store, path, &[("bridge", "xenbr0"), ("mtu", "9000"), ("online", "1"), ("state", "2")]);
+    assert_eq!(state_changed(&hotplug, &store, path), Some((String::from("add"), path.to_string(), Ok(()))));
+
+    write_backend(&store, path, &[("online", "0"), ("state", "6")]);
+    assert_eq!(state_changed(&hotplug, &store, path), Some((String::from("remove"), path.to_string(), Ok(()))));
+
+    assert_eq!(commands(&hotplug), vec![
+      "ip link set dev vif3.1 master xenbr0",
+      "ip link set dev vif3.1 mtu 9000",
+      "ip link set dev vif3.1 up",
+      "ip link set dev vif3.1 nomaster"
+    ]);
+  }
+
+  #[test]
+  fn load_config () {
+    let dir = TempDir::new("hotplug");
+    let path = dir.join("hotplug.ini");
+    std::fs::write(&path, "[vif]\nhandler = openvswitch\n\n[vbd]\nscript = /usr/libexec/xenops/block\n").unwrap();
+    let config = HotplugConfig::load(&path).unwrap();
+
+    assert!(config.handlers.get("vif") == Some(&Handler::OpenVswitch));
+    assert!(config.handlers.get("vbd") == Some(&Handler::Script(Some(String::from("/usr/libexec/xenops/block")))));
+    assert!(!config.handlers.contains_key("pci"));
+
+    assert!(HotplugConfig::load(Path::new("/nonexistent/hotplug.ini")).unwrap().handlers.is_empty());
+    assert_eq!("invalid".parse::<Handler>().err().as_deref(), Some("invalid hotplug handler: `invalid`"));
+  }
+}
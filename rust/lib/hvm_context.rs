@@ -0,0 +1,525 @@
+use std::mem::size_of;
+
+use super::bindings;
+
+// =============================================================================
+// Parser of the blobs returned by `xc_domain_hvm_getcontext`.
+//
+// A blob is a sequence of records, each one starting with an
+// `hvm_save_descriptor` (typecode, instance, length) followed by `length`
+// bytes of payload. The sequence is terminated by an END record.
+// Records whose code or length doesn't match a known `HvmSaveType*` are kept
+// verbatim, so that `HvmContext::to_bytes` always gives back the parsed blob.
+// =============================================================================
+
+const DESCRIPTOR_LENGTH: usize = size_of::<bindings::hvm_save_descriptor>();
+
+// -----------------------------------------------------------------------------
+
+pub enum Error {
+  Truncated(usize),
+  MissingEnd
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      Error::Truncated(offset) => write!(f, "truncated HVM context record at offset {}", offset),
+      Error::MissingEnd => write!(f, "HVM context without END record")
+    }
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+fn read_struct<T: Copy> (data: &[u8]) -> T {
+  assert!(data.len() >= size_of::<T>());
+  unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) }
+}
+
+fn struct_bytes<T> (value: &T) -> &[u8] {
+  unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+// -----------------------------------------------------------------------------
+
+macro_rules! DefRecordData {
+  ($($name:ident: $type:ident, $code:ident, $length:ident)*) => {
+    #[derive(Clone)]
+    pub enum RecordData {
+      $($name(bindings::$type), )*
+      End,
+      Raw(u16, Vec<u8>)
+    }
+
+    impl RecordData {
+      pub fn code (&self) -> u16 {
+        match *self {
+          $(Self::$name(_) => bindings::$code, )*
+          Self::End => bindings::HVM_SAVE_CODE_END,
+          Self::Raw(code, _) => code
+        }
+      }
+
+      pub fn name (&self) -> &'static str {
+        match *self {
+          $(Self::$name(_) => stringify!($name), )*
+          Self::End => "End",
+          Self::Raw(..) => "Raw"
+        }
+      }
+
+      fn from_bytes (code: u16, data: &[u8]) -> Self {
+        $(
+          if code == bindings::$code &&
+            data.len() == bindings::$length as usize &&
+            data.len() == size_of::<bindings::$type>() {
+            return Self::$name(read_struct(data))
+          }
+        )*
+
+        if code == bindings::HVM_SAVE_CODE_END && data.is_empty() {
+          return Self::End
+        }
+
+        Self::Raw(code, data.to_vec())
+      }
+
+      fn as_bytes (&self) -> &[u8] {
+        match self {
+          $(Self::$name(value) => struct_bytes(value), )*
+          Self::End => &[],
+          Self::Raw(_, data) => data
+        }
+      }
+    }
+  }
+}
+
+DefRecordData! {
+  Header: HvmSaveTypeHeader, HVM_SAVE_CODE_HEADER, HVM_SAVE_LENGTH_HEADER
+  Cpu: HvmSaveTypeCpu, HVM_SAVE_CODE_CPU, HVM_SAVE_LENGTH_CPU
+  Pic: HvmSaveTypePic, HVM_SAVE_CODE_PIC, HVM_SAVE_LENGTH_PIC
+  Ioapic: HvmSaveTypeIoapic, HVM_SAVE_CODE_IOAPIC, HVM_SAVE_LENGTH_IOAPIC
+  Lapic: HvmSaveTypeLapic, HVM_SAVE_CODE_LAPIC, HVM_SAVE_LENGTH_LAPIC
+  LapicRegs: HvmSaveTypeLapicRegs, HVM_SAVE_CODE_LAPIC_REGS, HVM_SAVE_LENGTH_LAPIC_REGS
+  PciIrq: HvmSaveTypePciIrq, HVM_SAVE_CODE_PCI_IRQ, HVM_SAVE_LENGTH_PCI_IRQ
+  IsaIrq: HvmSaveTypeIsaIrq, HVM_SAVE_CODE_ISA_IRQ, HVM_SAVE_LENGTH_ISA_IRQ
+  PciLink: HvmSaveTypePciLink, HVM_SAVE_CODE_PCI_LINK, HVM_SAVE_LENGTH_PCI_LINK
+  Pit: HvmSaveTypePit, HVM_SAVE_CODE_PIT, HVM_SAVE_LENGTH_PIT
+  Rtc: HvmSaveTypeRtc, HVM_SAVE_CODE_RTC, HVM_SAVE_LENGTH_RTC
+  Hpet: HvmSaveTypeHpet, HVM_SAVE_CODE_HPET, HVM_SAVE_LENGTH_HPET
+  Pmtimer: HvmSaveTypePmtimer, HVM_SAVE_CODE_PMTIMER, HVM_SAVE_LENGTH_PMTIMER
+  Mtrr: HvmSaveTypeMtrr, HVM_SAVE_CODE_MTRR, HVM_SAVE_LENGTH_MTRR
+  ViridianDomain: HvmSaveTypeViridianDomain, HVM_SAVE_CODE_VIRIDIAN_DOMAIN, HVM_SAVE_LENGTH_VIRIDIAN_DOMAIN
+  ViridianVcpu: HvmSaveTypeViridianVcpu, HVM_SAVE_CODE_VIRIDIAN_VCPU, HVM_SAVE_LENGTH_VIRIDIAN_VCPU
+  VmceVcpu: HvmSaveTypeVmceVcpu, HVM_SAVE_CODE_VMCE_VCPU, HVM_SAVE_LENGTH_VMCE_VCPU
+  TscAdjust: HvmSaveTypeTscAdjust, HVM_SAVE_CODE_TSC_ADJUST, HVM_SAVE_LENGTH_TSC_ADJUST
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone)]
+pub struct Record {
+  pub instance: u16,
+  pub data: RecordData
+}
+
+impl Record {
+  pub fn code (&self) -> u16 {
+    self.data.code()
+  }
+
+  pub fn as_bytes (&self) -> &[u8] {
+    self.data.as_bytes()
+  }
+}
+
+// =============================================================================
+
+#[derive(Clone)]
+pub struct HvmContext {
+  pub records: Vec<Record>,
+
+  // Bytes found after the END record, kept to re-serialize the blob as is.
+  trailing: Vec<u8>
+}
+
+impl HvmContext {
+  pub fn parse (blob: &[u8]) -> Result<Self> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    loop {
+      if blob.len() - offset < DESCRIPTOR_LENGTH {
+        return Err(if offset == blob.len() { Error::MissingEnd } else { Error::Truncated(offset) })
+      }
+
+      let descriptor: bindings::hvm_save_descriptor = read_struct(&blob[offset..]);
+      let length = descriptor.length as usize;
+      let start = offset + DESCRIPTOR_LENGTH;
+      if blob.len() - start < length {
+        return Err(Error::Truncated(offset))
+      }
+
+      let data = RecordData::from_bytes(descriptor.typecode, &blob[start..start + length]);
+      let is_end = matches!(data, RecordData::End);
+      records.push(Record { instance: descriptor.instance, data });
+      offset = start + length;
+
+      if is_end {
+        break
+      }
+    }
+
+    Ok(Self { records, trailing: blob[offset..].to_vec() })
+  }
+
+  pub fn to_bytes (&self) -> Vec<u8> {
+    let mut blob = Vec::new();
+    for record in &self.records {
+      let data = record.as_bytes();
+      let descriptor = bindings::hvm_save_descriptor {
+        typecode: record.code(),
+        instance: record.instance,
+        length: data.len() as u32
+      };
+      blob.extend_from_slice(struct_bytes(&descriptor));
+      blob.extend_from_slice(data);
+    }
+    blob.extend_from_slice(&self.trailing);
+    blob
+  }
+
+  pub fn header (&self) -> Option<&bindings::HvmSaveTypeHeader> {
+    self.records.iter().find_map(|record| match record.data {
+      RecordData::Header(ref header) => Some(header),
+      _ => None
+    })
+  }
+
+  pub fn cpus (&self) -> impl Iterator<Item = (u16, &bindings::HvmSaveTypeCpu)> {
+    self.records.iter().filter_map(|record| match record.data {
+      RecordData::Cpu(ref cpu) => Some((record.instance, cpu)),
+      _ => None
+    })
+  }
+}
+
+// =============================================================================
+// Field-level view of the records, used to dump and compare contexts.
+// =============================================================================
+
+pub struct Field {
+  pub name: String,
+  pub value: u64
+}
+
+macro_rules! fields {
+  ($fields:ident, $prefix:expr, $value:expr; $($field:ident),*) => {
+    $($fields.push(Field { name: format!("{}{}", $prefix, stringify!($field)), value: $value.$field as u64 }); )*
+  }
+}
+
+fn array_fields<T: Copy + Into<u64>> (fields: &mut Vec<Field>, name: &str, values: &[T]) {
+  for (i, value) in values.iter().enumerate() {
+    fields.push(Field { name: format!("{}[{}]", name, i), value: (*value).into() });
+  }
+}
+
+// See the APIC register map in the Intel SDM, vol. 3A, table 10-1.
+const LAPIC_REGISTERS: &[(&str, usize)] = &[
+  ("id", 0x20), ("version", 0x30), ("tpr", 0x80), ("apr", 0x90), ("ppr", 0xa0),
+  ("ldr", 0xd0), ("dfr", 0xe0), ("svr", 0xf0),
+  ("isr0", 0x100), ("isr1", 0x110), ("isr2", 0x120), ("isr3", 0x130),
+  ("isr4", 0x140), ("isr5", 0x150), ("isr6", 0x160), ("isr7", 0x170),
+  ("tmr0", 0x180), ("tmr1", 0x190), ("tmr2", 0x1a0), ("tmr3", 0x1b0),
+  ("tmr4", 0x1c0), ("tmr5", 0x1d0), ("tmr6", 0x1e0), ("tmr7", 0x1f0),
+  ("irr0", 0x200), ("irr1", 0x210), ("irr2", 0x220), ("irr3", 0x230),
+  ("irr4", 0x240), ("irr5", 0x250), ("irr6", 0x260), ("irr7", 0x270),
+  ("esr", 0x280), ("icr_low", 0x300), ("icr_high", 0x310),
+  ("lvt_timer", 0x320), ("lvt_thermal", 0x330), ("lvt_pmc", 0x340),
+  ("lvt_lint0", 0x350), ("lvt_lint1", 0x360), ("lvt_error", 0x370),
+  ("timer_initial_count", 0x380), ("timer_current_count", 0x390), ("timer_divide_config", 0x3e0)
+];
+
+impl RecordData {
+  pub fn fields (&self) -> Vec<Field> {
+    let mut fields = Vec::new();
+    match self {
+      Self::Header(header) => {
+        fields!(fields, "", header; magic, version, changeset, cpuid, gtsc_khz);
+      },
+      Self::Cpu(cpu) => {
+        fields!(fields, "", cpu;
+          rax, rbx, rcx, rdx, rbp, rsi, rdi, rsp, r8, r9, r10, r11, r12, r13, r14, r15,
+          rip, rflags, cr0, cr2, cr3, cr4, dr0, dr1, dr2, dr3, dr6, dr7,
+          cs_sel, ds_sel, es_sel, fs_sel, gs_sel, ss_sel, tr_sel, ldtr_sel,
+          cs_limit, ds_limit, es_limit, fs_limit, gs_limit, ss_limit, tr_limit, ldtr_limit,
+          idtr_limit, gdtr_limit,
+          cs_base, ds_base, es_base, fs_base, gs_base, ss_base, tr_base, ldtr_base,
+          idtr_base, gdtr_base,
+          cs_arbytes, ds_arbytes, es_arbytes, fs_arbytes, gs_arbytes, ss_arbytes, tr_arbytes, ldtr_arbytes,
+          sysenter_cs, sysenter_esp, sysenter_eip, shadow_gs,
+          msr_flags, msr_lstar, msr_star, msr_cstar, msr_syscall_mask, msr_efer, msr_tsc_aux,
+          tsc, error_code, flags
+        );
+      },
+      Self::Pic(pic) => {
+        fields!(fields, "", pic; irq_base, irr, imr, isr, elcr);
+      },
+      Self::Ioapic(ioapic) => {
+        fields!(fields, "", ioapic; base_address, ioregsel, id);
+      },
+      Self::Lapic(lapic) => {
+        fields!(fields, "", lapic; apic_base_msr, disabled, timer_divisor, tdt_msr);
+      },
+      Self::LapicRegs(regs) => {
+        for (name, offset) in LAPIC_REGISTERS {
+          let mut value = [0u8; 4];
+          value.copy_from_slice(&regs.data[*offset..*offset + 4]);
+          fields.push(Field { name: name.to_string(), value: u32::from_le_bytes(value).into() });
+        }
+      },
+      Self::PciLink(link) => {
+        array_fields(&mut fields, "route", &link.route);
+      },
+      Self::Pit(pit) => {
+        for (i, channel) in pit.channels.iter().enumerate() {
+          fields!(fields, format!("channel{}.", i), channel;
+            count, latched_count, count_latched, status_latched, status, read_state, write_state,
+            write_latch, rw_mode, mode, bcd, gate, count_load_time
+          );
+        }
+        fields!(fields, "", pit; speaker_data_on);
+      },
+      Self::Rtc(rtc) => {
+        array_fields(&mut fields, "cmos_data", &rtc.cmos_data);
+        fields!(fields, "", rtc; cmos_index);
+      },
+      Self::Hpet(hpet) => {
+        fields!(fields, "", hpet; capability, config, isr, mc64);
+        for (i, timer) in hpet.timers.iter().enumerate() {
+          fields!(fields, format!("timer{}.", i), timer; config, cmp, fsb);
+        }
+        array_fields(&mut fields, "period", &hpet.period);
+      },
+      Self::Pmtimer(pmtimer) => {
+        fields!(fields, "", pmtimer; tmr_val, pm1a_sts, pm1a_en);
+      },
+      Self::Mtrr(mtrr) => {
+        fields!(fields, "", mtrr; msr_pat_cr, msr_mtrr_cap, msr_mtrr_def_type);
+        array_fields(&mut fields, "msr_mtrr_var", &mtrr.msr_mtrr_var);
+        array_fields(&mut fields, "msr_mtrr_fixed", &mtrr.msr_mtrr_fixed);
+      },
+      Self::ViridianDomain(viridian) => {
+        fields!(fields, "", viridian; hypercall_gpa, guest_os_id, time_ref_count, reference_tsc);
+      },
+      Self::ViridianVcpu(viridian) => {
+        fields!(fields, "", viridian; vp_assist_msr, apic_assist_pending);
+      },
+      Self::VmceVcpu(vmce) => {
+        fields!(fields, "", vmce; caps, mci_ctl2_bank0, mci_ctl2_bank1, mcg_ext_ctl);
+      },
+      Self::TscAdjust(tsc) => {
+        fields!(fields, "", tsc; tsc_adjust);
+      },
+      Self::End => (),
+      // Bitmaps and unknown records are only compared byte by byte.
+      Self::PciIrq(_) | Self::IsaIrq(_) | Self::Raw(..) => {
+        array_fields(&mut fields, "data", self.as_bytes());
+      }
+    }
+    fields
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+pub enum Difference {
+  Missing { name: &'static str, instance: u16, in_first: bool },
+  Field { name: &'static str, instance: u16, field: String, first: u64, second: u64 }
+}
+
+impl std::fmt::Display for Difference {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Difference::Missing { name, instance, in_first } => {
+        write!(f, "{}#{}: only in {} context", name, instance, if *in_first { "first" } else { "second" })
+      },
+      Difference::Field { name, instance, field, first, second } => {
+        write!(f, "{}#{}.{}: {:#x} != {:#x}", name, instance, field, first, second)
+      }
+    }
+  }
+}
+
+fn find_record (context: &HvmContext, code: u16, instance: u16) -> Option<&Record> {
+  context.records.iter().find(|record| record.code() == code && record.instance == instance)
+}
+
+pub fn diff (first: &HvmContext, second: &HvmContext) -> Vec<Difference> {
+  let mut differences = Vec::new();
+
+  for record in &first.records {
+    let name = record.data.name();
+    let other = match find_record(second, record.code(), record.instance) {
+      Some(other) => other,
+      None => {
+        differences.push(Difference::Missing { name, instance: record.instance, in_first: true });
+        continue
+      }
+    };
+
+    let (length, other_length) = (record.as_bytes().len(), other.as_bytes().len());
+    if length != other_length {
+      differences.push(Difference::Field {
+        name,
+        instance: record.instance,
+        field: String::from("length"),
+        first: length as u64,
+        second: other_length as u64
+      });
+      continue
+    }
+
+    let fields = record.data.fields();
+    let other_fields = other.data.fields();
+    for field in &fields {
+      let other_value = other_fields.iter().find(|other| other.name == field.name).map(|other| other.value);
+      if other_value != Some(field.value) {
+        differences.push(Difference::Field {
+          name,
+          instance: record.instance,
+          field: field.name.clone(),
+          first: field.value,
+          second: other_value.unwrap_or(0)
+        });
+      }
+    }
+  }
+
+  for record in &second.records {
+    if find_record(first, record.code(), record.instance).is_none() {
+      differences.push(Difference::Missing { name: record.data.name(), instance: record.instance, in_first: false });
+    }
+  }
+
+  differences
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Records of a context saved from a single vCPU guest, with the payloads
+  // of the header and of the LAPIC as found in the blob.
+  const HEADER: &[u8] = &[
+    0x01, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00,
+    0x86, 0x12, 0x38, 0x54, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xe3, 0x06, 0x05, 0x00, 0xe0, 0xa9, 0x2a, 0x00
+  ];
+  const LAPIC: &[u8] = &[
+    0x05, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00,
+    0x00, 0x09, 0xe0, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+  ];
+  const END: &[u8] = &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+  fn cpu_record (instance: u16, rip: u64) -> Vec<u8> {
+    let mut cpu: bindings::HvmSaveTypeCpu = unsafe { std::mem::zeroed() };
+    cpu.rip = rip;
+    cpu.rsp = 0x7000;
+    cpu.cr0 = 0x8005_0033;
+    let descriptor = bindings::hvm_save_descriptor {
+      typecode: bindings::HVM_SAVE_CODE_CPU,
+      instance,
+      length: size_of::<bindings::HvmSaveTypeCpu>() as u32
+    };
+    let mut record = struct_bytes(&descriptor).to_vec();
+    record.extend_from_slice(struct_bytes(&cpu));
+    record
+  }
+
+  fn blob () -> Vec<u8> {
+    [HEADER, &cpu_record(0, 0xffff_ffff_8100_0000)[..], LAPIC, END].concat()
+  }
+
+  #[test]
+  fn parse_records () {
+    let context = HvmContext::parse(&blob()).ok().unwrap();
+    let names: Vec<&str> = context.records.iter().map(|record| record.data.name()).collect();
+    assert_eq!(names, ["Header", "Cpu", "Lapic", "End"]);
+
+    let header = context.header().unwrap();
+    assert_eq!(header.magic, 0x5438_1286);
+    assert_eq!(header.version, 1);
+    assert_eq!(header.cpuid, 0x0005_06e3);
+    assert_eq!(header.gtsc_khz, 2_796_000);
+
+    let cpus: Vec<(u16, u64, u64)> = context.cpus().map(|(instance, cpu)| (instance, cpu.rip, cpu.rsp)).collect();
+    assert_eq!(cpus, [(0, 0xffff_ffff_8100_0000, 0x7000)]);
+
+    match context.records[2].data {
+      RecordData::Lapic(ref lapic) => {
+        assert_eq!(lapic.apic_base_msr, 0xfee0_0900);
+        assert_eq!(lapic.disabled, 0);
+        assert_eq!(lapic.timer_divisor, 16);
+      },
+      _ => panic!("expected a LAPIC record")
+    }
+    let fields = context.records[2].data.fields();
+    assert_eq!(fields[0].name, "apic_base_msr");
+    assert_eq!(fields[0].value, 0xfee0_0900);
+  }
+
+  #[test]
+  fn round_trip () {
+    // Unknown records and bytes after END are kept verbatim.
+    let unknown: [u8; 11] = [0x7f, 0x00, 0x02, 0x00, 0x03, 0x00, 0x00, 0x00, 0xaa, 0xbb, 0xcc];
+    let blob = [HEADER, &unknown[..], LAPIC, END, &[0x42, 0x42][..]].concat();
+    let context = HvmContext::parse(&blob).ok().unwrap();
+    match context.records[1].data {
+      RecordData::Raw(code, ref data) => {
+        assert_eq!(code, 0x7f);
+        assert_eq!(data, &[0xaa, 0xbb, 0xcc]);
+      },
+      _ => panic!("expected a raw record")
+    }
+    assert_eq!(context.records[1].instance, 2);
+    assert_eq!(context.to_bytes(), blob);
+    assert_eq!(HvmContext::parse(&self::blob()).ok().unwrap().to_bytes(), self::blob());
+  }
+
+  #[test]
+  fn truncated_records () {
+    let blob = blob();
+    let cpu_offset = HEADER.len();
+    // In the payload of the CPU record, then in its descriptor.
+    for length in &[cpu_offset + DESCRIPTOR_LENGTH + 100, cpu_offset + 3] {
+      match HvmContext::parse(&blob[..*length]) {
+        Err(Error::Truncated(offset)) => assert_eq!(offset, cpu_offset),
+        _ => panic!("expected a truncated record")
+      }
+    }
+    // A record length past the end of the blob.
+    let mut header = HEADER.to_vec();
+    header[4] = 0xff;
+    assert!(matches!(HvmContext::parse(&header), Err(Error::Truncated(0))));
+    assert!(matches!(HvmContext::parse(&blob[..blob.len() - END.len()]), Err(Error::MissingEnd)));
+    assert!(matches!(HvmContext::parse(&[]), Err(Error::MissingEnd)));
+  }
+
+  #[test]
+  fn diff_contexts () {
+    let first = HvmContext::parse(&blob()).ok().unwrap();
+    let second = HvmContext::parse(&[HEADER, &cpu_record(0, 0x1000)[..], END].concat()).ok().unwrap();
+    let differences: Vec<String> = diff(&first, &second).iter().map(|difference| difference.to_string()).collect();
+    assert_eq!(differences, ["Cpu#0.rip: 0xffffffff81000000 != 0x1000", "Lapic#0: only in first context"]);
+    assert!(diff(&first, &first).is_empty());
+  }
+}
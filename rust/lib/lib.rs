@@ -1,5 +1,31 @@
+pub mod blkback;
+pub mod checkpoint;
+pub mod console;
+pub mod coredump;
+pub mod device;
+pub mod devicemodel;
+pub mod evtchn;
+pub mod foreignmemory;
+pub mod gdbstub;
+pub mod gnttab;
+pub mod guest;
+pub mod hotplug;
+pub mod hvm_context;
+pub mod logdirty;
+pub mod migration;
+pub mod migration_stream;
+pub mod pci;
+pub mod save;
+pub mod snapshot;
+pub mod stats;
+pub mod varstore;
+pub mod vcpu_context;
 pub mod vm;
+pub mod vtpm;
 pub mod xenctrl;
 pub mod xenstore;
 
 mod bindings;
+
+#[cfg(test)]
+mod test_util;
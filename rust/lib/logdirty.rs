@@ -0,0 +1,239 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use super::foreignmemory::PAGE_SIZE;
+use super::xenctrl::{self, Xenctrl};
+
+// =============================================================================
+// Dirty page tracking (log-dirty mode).
+// =============================================================================
+
+// One bit per pfn, in words of 64 bits like the bitmaps of Xen.
+#[derive(Clone)]
+pub struct DirtyBitmap {
+  words: Vec<u64>,
+  pages: u64
+}
+
+impl DirtyBitmap {
+  pub fn new (pages: u64) -> Self {
+    Self { words: vec![0; ((pages + 63) >> 6) as usize], pages }
+  }
+
+  // Build a bitmap from the bytes written by Xen.
+  pub fn from_bytes (data: &[u8], pages: u64) -> Self {
+    let mut bitmap = Self::new(pages);
+    for (word, bytes) in bitmap.words.iter_mut().zip(data.chunks(8)) {
+      let mut value = [0u8; 8];
+      value[..bytes.len()].copy_from_slice(bytes);
+      *word = u64::from_le_bytes(value);
+    }
+    bitmap.clear_tail();
+    bitmap
+  }
+
+  pub fn pages (&self) -> u64 {
+    self.pages
+  }
+
+  pub fn is_dirty (&self, pfn: u64) -> bool {
+    pfn < self.pages && self.words[(pfn >> 6) as usize] & (1 << (pfn & 63)) != 0
+  }
+
+  pub fn set (&mut self, pfn: u64) {
+    if pfn < self.pages {
+      self.words[(pfn >> 6) as usize] |= 1 << (pfn & 63);
+    }
+  }
+
+  pub fn clear (&mut self) {
+    self.words.iter_mut().for_each(|word| *word = 0);
+  }
+
+  // Add the dirty pages of `other`, those after the last page are ignored.
+  pub fn merge (&mut self, other: &DirtyBitmap) {
+    for (word, other) in self.words.iter_mut().zip(&other.words) {
+      *word |= other;
+    }
+    self.clear_tail();
+  }
+
+  pub fn count (&self) -> u64 {
+    self.words.iter().map(|word| u64::from(word.count_ones())).sum()
+  }
+
+  pub fn is_empty (&self) -> bool {
+    self.words.iter().all(|word| *word == 0)
+  }
+
+  // Dirty pfns in increasing order.
+  pub fn iter (&self) -> DirtyPfns<'_> {
+    DirtyPfns { words: &self.words, index: 0, current: self.words.first().copied().unwrap_or(0) }
+  }
+
+  // Bits after the last page must stay clean.
+  fn clear_tail (&mut self) {
+    if self.pages & 63 != 0 {
+      if let Some(last) = self.words.last_mut() {
+        *last &= (1 << (self.pages & 63)) - 1;
+      }
+    }
+  }
+}
+
+pub struct DirtyPfns<'a> {
+  words: &'a [u64],
+  index: usize,
+  current: u64
+}
+
+impl Iterator for DirtyPfns<'_> {
+  type Item = u64;
+
+  fn next (&mut self) -> Option<u64> {
+    while self.current == 0 {
+      self.index += 1;
+      self.current = *self.words.get(self.index)?;
+    }
+
+    let bit = self.current.trailing_zeros();
+    self.current &= self.current - 1;
+    Some(((self.index as u64) << 6) + u64::from(bit))
+  }
+}
+
+impl<'a> IntoIterator for &'a DirtyBitmap {
+  type Item = u64;
+  type IntoIter = DirtyPfns<'a>;
+
+  fn into_iter (self) -> DirtyPfns<'a> {
+    self.iter()
+  }
+}
+
+// Dirty pages since the last clean, the first `pages` pfns are read.
+pub fn peek_dirty (xc: &Xenctrl, dom_id: u32, pages: u64) -> xenctrl::Result<DirtyBitmap> {
+  Ok(DirtyBitmap::from_bytes(&xc.peek_dirty(dom_id, pages)?, pages))
+}
+
+// Same as `peek_dirty` but the bitmap of Xen is reset.
+pub fn clean_dirty (xc: &Xenctrl, dom_id: u32, pages: u64) -> xenctrl::Result<DirtyBitmap> {
+  Ok(DirtyBitmap::from_bytes(&xc.clean_dirty(dom_id, pages)?, pages))
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Serialize)]
+pub struct DirtyRate {
+  pub dirty_pages: u64,
+  pub window_ms: u64,
+  pub pages_per_second: f64,
+  pub bytes_per_second: f64
+}
+
+impl DirtyRate {
+  pub fn new (dirty_pages: u64, window: Duration) -> Self {
+    let seconds = window.as_secs_f64();
+    let pages_per_second = if seconds > 0.0 { dirty_pages as f64 / seconds } else { 0.0 };
+    Self {
+      dirty_pages,
+      window_ms: window.as_millis() as u64,
+      pages_per_second,
+      bytes_per_second: pages_per_second * PAGE_SIZE as f64
+    }
+  }
+}
+
+// Count the pages written by a domain during `window`. Log-dirty mode is
+// enabled for the measure, so it fails if a migration is in progress.
+pub fn measure_dirty_rate (xc: &Xenctrl, dom_id: u32, window: Duration) -> xenctrl::Result<DirtyRate> {
+  let pages = xc.get_maximum_gpfn(dom_id)? + 1;
+
+  xc.enable_logdirty(dom_id)?;
+  let result = xc.clean_dirty(dom_id, pages).and_then(|_| {
+    let start = Instant::now();
+    std::thread::sleep(window);
+    let bitmap = clean_dirty(xc, dom_id, pages)?;
+    Ok(DirtyRate::new(bitmap.count(), start.elapsed()))
+  });
+  let disabled = xc.disable_logdirty(dom_id);
+
+  let rate = result?;
+  disabled?;
+  Ok(rate)
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bitmap (pages: u64, pfns: &[u64]) -> DirtyBitmap {
+    let mut bitmap = DirtyBitmap::new(pages);
+    pfns.iter().for_each(|pfn| bitmap.set(*pfn));
+    bitmap
+  }
+
+  #[test]
+  fn set () {
+    let bitmap = bitmap(130, &[0, 63, 64, 129, 130, 1000]);
+    assert_eq!(bitmap.iter().collect::<Vec<_>>(), vec![0, 63, 64, 129]);
+    assert_eq!(bitmap.count(), 4);
+    assert!(bitmap.is_dirty(63) && bitmap.is_dirty(129));
+    assert!(!bitmap.is_dirty(62) && !bitmap.is_dirty(130));
+  }
+
+  #[test]
+  fn iter () {
+    assert_eq!(DirtyBitmap::new(0).iter().next(), None);
+    assert_eq!(DirtyBitmap::new(200).iter().next(), None);
+
+    // Empty words are skipped, the first one included.
+    let pfns: Vec<u64> = bitmap(256, &[191, 192, 255]).into_iter().collect();
+    assert_eq!(pfns, vec![191, 192, 255]);
+    let pfns: Vec<u64> = bitmap(64, &(0..64).collect::<Vec<_>>()).iter().collect();
+    assert_eq!(pfns, (0..64).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn from_bytes () {
+    // Bytes of the little-endian words of Xen, the last one is partial.
+    let mut data = vec![0u8; 9];
+    data[0] = 0x81;
+    data[7] = 0x80;
+    data[8] = 0xff;
+    let bitmap = DirtyBitmap::from_bytes(&data, 68);
+    assert_eq!(bitmap.iter().collect::<Vec<_>>(), vec![0, 7, 63, 64, 65, 66, 67]);
+    assert_eq!(bitmap.count(), 7);
+
+    // Shorter than the bitmap.
+    let bitmap = DirtyBitmap::from_bytes(&[0x02], 1000);
+    assert_eq!(bitmap.iter().collect::<Vec<_>>(), vec![1]);
+    assert_eq!(bitmap.pages(), 1000);
+  }
+
+  #[test]
+  fn merge () {
+    let mut dirty = bitmap(100, &[1, 64]);
+    dirty.merge(&bitmap(128, &[1, 2, 99, 100, 127]));
+    assert_eq!(dirty.iter().collect::<Vec<_>>(), vec![1, 2, 64, 99]);
+    assert_eq!(dirty.count(), 4);
+
+    dirty.merge(&bitmap(10, &[3]));
+    assert_eq!(dirty.count(), 5);
+    assert!(!dirty.is_empty());
+    dirty.clear();
+    assert!(dirty.is_empty());
+    assert_eq!(dirty.count(), 0);
+  }
+
+  #[test]
+  fn dirty_rate () {
+    let rate = DirtyRate::new(3072, Duration::from_millis(2000));
+    assert_eq!(rate.window_ms, 2000);
+    assert_eq!(rate.pages_per_second, 1536.0);
+    assert_eq!(rate.bytes_per_second, 1536.0 * 4096.0);
+    assert_eq!(DirtyRate::new(10, Duration::from_secs(0)).pages_per_second, 0.0);
+  }
+}
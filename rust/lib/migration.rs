@@ -0,0 +1,708 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::devicemodel::qmp::Qmp;
+use super::logdirty::DirtyBitmap;
+use super::migration_stream::{self, DomainHeader, DomainType, PageData, StreamReader, StreamWriter};
+use super::save::{self, SaveConfig};
+use super::vm::{self, ShutdownReason};
+use super::xenctrl::{self, Xenctrl};
+use super::xenstore::Xenstore;
+
+// =============================================================================
+// Live migration between two hosts.
+//
+// The sender connects to the receiver and writes the same data as a save file:
+// the save header followed by the migration stream v2. The memory is sent
+// with iterative pre-copy, then the domain is suspended to send the last dirty
+// pages and its state (stop-and-copy). The receiver creates the domain and
+// replies with one line of JSON, with the file of the devices state to give to
+// the device model started on the receiver, like after a restore.
+// =============================================================================
+
+pub const MIGRATION_PORT: u16 = 3031;
+
+// Records of PAGE_DATA written by the simulated host, like libxc.
+const PAGES_PER_RECORD: usize = 1024;
+
+// Size of the devices state of the simulated domains.
+const SIMULATED_DEVICES_STATE: usize = 16 * 1024;
+
+// -----------------------------------------------------------------------------
+
+pub enum Error {
+  Io(std::io::Error),
+  Save(save::Error),
+  Stream(migration_stream::Error),
+  Protocol(String),
+  Remote(String),
+  Aborted,
+  NoSuchDomain(u32)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Save(e) => write!(f, "{}", e),
+      Error::Stream(e) => write!(f, "{}", e),
+      Error::Protocol(details) => write!(f, "migration protocol error: {}", details),
+      Error::Remote(details) => write!(f, "receiver failed: {}", details),
+      Error::Aborted => write!(f, "migration aborted"),
+      Error::NoSuchDomain(dom_id) => write!(f, "no domain {}", dom_id)
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<save::Error> for Error {
+  fn from (e: save::Error) -> Self {
+    Error::Save(e)
+  }
+}
+
+impl From<migration_stream::Error> for Error {
+  fn from (e: migration_stream::Error) -> Self {
+    Error::Stream(e)
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Save(save::Error::Xen(e))
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct MigrationConfig {
+  // Maximum time the domain can stay suspended during the stop-and-copy.
+  pub max_downtime_ms: u64,
+  // In bytes per second, unlimited if not set.
+  pub bandwidth_limit: Option<u64>,
+  // The stop-and-copy is forced after this number of pre-copy iterations.
+  pub max_iterations: u32
+}
+
+impl Default for MigrationConfig {
+  fn default () -> Self {
+    Self { max_downtime_ms: 300, bandwidth_limit: None, max_iterations: 30 }
+  }
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Progress {
+  pub iteration: u32,
+  // Pages to send in the next iteration.
+  pub dirty_pages: u64,
+  pub bytes_sent: u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+  ContinuePrecopy,
+  StopAndCopy,
+  Abort
+}
+
+// Stop the pre-copy when the remaining dirty pages can be sent within the
+// downtime at the bandwidth measured since the start.
+pub struct Policy {
+  config: MigrationConfig,
+  start: Instant
+}
+
+impl Policy {
+  pub fn new (config: MigrationConfig) -> Self {
+    Self { config, start: Instant::now() }
+  }
+
+  pub fn decide (&self, progress: &Progress) -> Decision {
+    if progress.iteration >= self.config.max_iterations {
+      return Decision::StopAndCopy
+    }
+
+    let elapsed = self.start.elapsed().as_secs_f64();
+    if elapsed <= 0.0 || progress.bytes_sent == 0 {
+      return Decision::ContinuePrecopy
+    }
+
+    let mut bandwidth = progress.bytes_sent as f64 / elapsed;
+    if let Some(limit) = self.config.bandwidth_limit {
+      bandwidth = bandwidth.min(limit as f64);
+    }
+
+    let remaining = (progress.dirty_pages as usize * migration_stream::PAGE_SIZE) as f64;
+    if remaining / bandwidth * 1000.0 <= self.config.max_downtime_ms as f64 {
+      Decision::StopAndCopy
+    } else {
+      Decision::ContinuePrecopy
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Count the written bytes and sleep to stay under the bandwidth limit.
+pub struct LimitedWriter<W: Write> {
+  writer: W,
+  limit: Option<u64>,
+  start: Instant,
+  written: Arc<AtomicU64>
+}
+
+impl<W: Write> LimitedWriter<W> {
+  pub fn new (writer: W, limit: Option<u64>, written: Arc<AtomicU64>) -> Self {
+    Self { writer, limit, start: Instant::now(), written }
+  }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+  fn write (&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    let size = self.writer.write(buf)?;
+    let written = self.written.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+
+    if let Some(limit) = self.limit.filter(|limit| *limit > 0) {
+      let expected = Duration::from_secs_f64(written as f64 / limit as f64);
+      let elapsed = self.start.elapsed();
+      if expected > elapsed {
+        std::thread::sleep(expected - elapsed);
+      }
+    }
+    Ok(size)
+  }
+
+  fn flush (&mut self) -> std::io::Result<()> {
+    self.writer.flush()
+  }
+}
+
+// =============================================================================
+// Hosts.
+// =============================================================================
+
+pub trait Host {
+  fn domain_config (&self, dom_id: u32) -> Result<SaveConfig>;
+
+  // Write the migration stream of a domain. `policy` is called after each
+  // pre-copy iteration with the number of pages still dirty.
+  fn send_domain (
+    &self,
+    dom_id: u32,
+    config: &SaveConfig,
+    writer: &mut (dyn Write + Send),
+    policy: &mut save::PrecopyPolicy
+  ) -> Result<()>;
+
+  // Create and start a domain from the migration stream read in `stream`.
+  // Returns the new domain id and its devices state, see `save::restore`.
+  fn receive_domain (&self, config: &SaveConfig, stream: &TcpStream) -> Result<(u32, Option<PathBuf>)>;
+
+  // Resume a domain if it was suspended by a failed migration.
+  fn resume_domain (&self, dom_id: u32) -> Result<()>;
+
+  fn destroy_domain (&self, dom_id: u32) -> Result<()>;
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct XenHost<'a> {
+  xc: &'a Xenctrl,
+  xs: &'a Xenstore,
+  // Device model of the sent domain, its devices state is sent with it.
+  device_model: Option<Mutex<Qmp<UnixStream>>>
+}
+
+impl<'a> XenHost<'a> {
+  pub fn new (xc: &'a Xenctrl, xs: &'a Xenstore) -> Self {
+    Self { xc, xs, device_model: None }
+  }
+
+  pub fn with_device_model (mut self, qmp: Qmp<UnixStream>) -> Self {
+    self.device_model = Some(Mutex::new(qmp));
+    self
+  }
+}
+
+fn pipe () -> Result<(File, File)> {
+  let mut fds = [0; 2];
+  unsafe {
+    if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+      return Err(std::io::Error::last_os_error().into())
+    }
+    Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+  }
+}
+
+impl Host for XenHost<'_> {
+  fn domain_config (&self, dom_id: u32) -> Result<SaveConfig> {
+    let mut config = SaveConfig::from_domain(self.xc, self.xs, dom_id)?;
+    config.device_model = self.device_model.is_some();
+    Ok(config)
+  }
+
+  // libxc writes in a file descriptor: the stream goes through a pipe to be
+  // counted and throttled by `writer`.
+  fn send_domain (
+    &self,
+    dom_id: u32,
+    config: &SaveConfig,
+    writer: &mut (dyn Write + Send),
+    policy: &mut save::PrecopyPolicy
+  ) -> Result<()> {
+    let (mut read_end, write_end) = pipe()?;
+    let mut device_model = self.device_model.as_ref().map(|qmp| qmp.lock().unwrap());
+    std::thread::scope(|scope| {
+      let copier = scope.spawn(move || std::io::copy(&mut read_end, writer));
+
+      let result = save::save_stream(
+        self.xc, self.xs, dom_id, write_end.as_raw_fd(), config.hvm, save::SaveMode::Live(policy),
+        device_model.as_deref_mut()
+      );
+      drop(write_end);
+
+      let copied = copier.join().unwrap();
+      result?;
+      copied?;
+      Ok(())
+    })
+  }
+
+  // A domain with a devices state stays paused until its device model is
+  // started, see `save::RestoredDomain`.
+  fn receive_domain (&self, config: &SaveConfig, stream: &TcpStream) -> Result<(u32, Option<PathBuf>)> {
+    Ok(save::restore_stream(self.xc, self.xs, stream.as_raw_fd(), config)?)
+  }
+
+  fn resume_domain (&self, dom_id: u32) -> Result<()> {
+    let info = self.xc.get_domain_info(dom_id)?;
+    if let Some(ShutdownReason::Suspend) = ShutdownReason::from_domain_info(&info) {
+      self.xc.resume_domain(dom_id, true)?;
+      // Stopped at the suspend.
+      if let Some(qmp) = &self.device_model {
+        qmp.lock().unwrap().cont().map_err(save::Error::from)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn destroy_domain (&self, dom_id: u32) -> Result<()> {
+    Ok(vm::destroy(self.xc, self.xs, dom_id)?)
+  }
+}
+
+// -----------------------------------------------------------------------------
+// Simulated hypervisor: domains are buffers in memory and the guest dirties
+// pages at a fixed rate while it runs. Used to test the migrations between two
+// processes without Xen.
+// -----------------------------------------------------------------------------
+
+struct SimulatedDomain {
+  name: String,
+  memory: Vec<u8>,
+  dirty: DirtyBitmap,
+  // Pages written by the guest per second.
+  dirty_rate: u64,
+  last_run: Instant,
+  suspended: bool,
+  seed: u64,
+  // State of the simulated device model, it follows the stream like the one of
+  // QEMU.
+  devices_state: Option<Vec<u8>>
+}
+
+impl SimulatedDomain {
+  fn new (name: String, pages: usize, dirty_rate: u64, seed: u64) -> Self {
+    let mut domain = Self {
+      name,
+      memory: vec![0u8; pages * migration_stream::PAGE_SIZE],
+      dirty: DirtyBitmap::new(pages as u64),
+      dirty_rate,
+      last_run: Instant::now(),
+      suspended: false,
+      seed: seed | 1,
+      devices_state: None
+    };
+    for i in 0..domain.memory.len() / 8 {
+      let value = domain.random();
+      domain.memory[i * 8..i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    domain
+  }
+
+  fn pages (&self) -> usize {
+    self.dirty.pages() as usize
+  }
+
+  // xorshift64.
+  fn random (&mut self) -> u64 {
+    self.seed ^= self.seed << 13;
+    self.seed ^= self.seed >> 7;
+    self.seed ^= self.seed << 17;
+    self.seed
+  }
+
+  // Write the pages the guest would have modified since its last run.
+  fn run (&mut self) {
+    if self.suspended {
+      return
+    }
+
+    let count = (self.last_run.elapsed().as_secs_f64() * self.dirty_rate as f64) as usize;
+    self.last_run = Instant::now();
+    for _ in 0..count {
+      let value = self.random();
+      let pfn = (value % self.pages() as u64) as usize;
+      let offset = pfn * migration_stream::PAGE_SIZE + (value >> 32) as usize % (migration_stream::PAGE_SIZE / 8) * 8;
+      self.memory[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+      self.dirty.set(pfn as u64);
+    }
+  }
+
+  // Return the dirty pages and clean the bitmap, like log-dirty mode.
+  fn take_dirty (&mut self) -> Vec<u64> {
+    self.run();
+    let pfns = self.dirty.iter().collect();
+    self.dirty.clear();
+    pfns
+  }
+
+  fn page_data (&self, pfns: &[u64]) -> PageData {
+    let mut data = Vec::with_capacity(pfns.len() * migration_stream::PAGE_SIZE);
+    for pfn in pfns {
+      let offset = *pfn as usize * migration_stream::PAGE_SIZE;
+      data.extend_from_slice(&self.memory[offset..offset + migration_stream::PAGE_SIZE]);
+    }
+    PageData { pages: pfns.iter().map(|pfn| (*pfn, 0)).collect(), data }
+  }
+
+  // FNV-1a of the memory and of the devices state.
+  fn checksum (&self) -> u64 {
+    let devices_state = self.devices_state.iter().flatten();
+    self.memory.iter().chain(devices_state)
+      .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3))
+  }
+}
+
+pub struct SimulatedHost {
+  domains: Mutex<BTreeMap<u32, SimulatedDomain>>,
+  // Checksum of the domains when they were suspended.
+  checksums: Mutex<BTreeMap<u32, u64>>,
+  next_dom_id: Mutex<u32>
+}
+
+impl SimulatedHost {
+  pub fn new () -> Self {
+    Self {
+      domains: Mutex::new(BTreeMap::new()),
+      checksums: Mutex::new(BTreeMap::new()),
+      next_dom_id: Mutex::new(1)
+    }
+  }
+
+  pub fn create_domain (&self, dom_id: u32, memkb: u64, dirty_rate: u64) {
+    let pages = (memkb as usize * 1024) >> migration_stream::PAGE_SHIFT;
+    let mut domain = SimulatedDomain::new(format!("simulated-{}", dom_id), pages, dirty_rate, u64::from(dom_id));
+    let devices_state = (0..SIMULATED_DEVICES_STATE / 8).flat_map(|_| domain.random().to_le_bytes().to_vec()).collect();
+    domain.devices_state = Some(devices_state);
+    self.domains.lock().unwrap().insert(dom_id, domain);
+
+    let mut next_dom_id = self.next_dom_id.lock().unwrap();
+    *next_dom_id = (*next_dom_id).max(dom_id + 1);
+  }
+
+  pub fn checksum (&self, dom_id: u32) -> Option<u64> {
+    self.domains.lock().unwrap().get(&dom_id).map(SimulatedDomain::checksum)
+  }
+
+  pub fn suspended_checksum (&self, dom_id: u32) -> Option<u64> {
+    self.checksums.lock().unwrap().get(&dom_id).copied()
+  }
+
+  // Size of the devices state of a domain, if it has a device model.
+  pub fn devices_state_size (&self, dom_id: u32) -> Option<usize> {
+    self.domains.lock().unwrap().get(&dom_id).and_then(|domain| domain.devices_state.as_ref().map(Vec::len))
+  }
+
+  fn with_domain<T> (&self, dom_id: u32, f: impl FnOnce(&mut SimulatedDomain) -> T) -> Result<T> {
+    match self.domains.lock().unwrap().get_mut(&dom_id) {
+      Some(domain) => Ok(f(domain)),
+      None => Err(Error::NoSuchDomain(dom_id))
+    }
+  }
+
+  fn send_pages<W: Write> (&self, dom_id: u32, stream: &mut StreamWriter<W>, pfns: &[u64]) -> Result<()> {
+    for chunk in pfns.chunks(PAGES_PER_RECORD) {
+      let body = self.with_domain(dom_id, |domain| domain.page_data(chunk).to_bytes())?;
+      stream.write_record(migration_stream::REC_TYPE_PAGE_DATA, &body)?;
+    }
+    Ok(())
+  }
+}
+
+impl Default for SimulatedHost {
+  fn default () -> Self {
+    Self::new()
+  }
+}
+
+impl Host for SimulatedHost {
+  fn domain_config (&self, dom_id: u32) -> Result<SaveConfig> {
+    self.with_domain(dom_id, |domain| SaveConfig {
+      name: domain.name.clone(),
+      uuid: format!("00000000-0000-0000-0000-{:012x}", dom_id),
+      hvm: true,
+      max_vcpus: 1,
+      max_memkb: (domain.memory.len() / 1024) as u64,
+      checkpointed: false,
+      device_model: domain.devices_state.is_some()
+    })
+  }
+
+  fn send_domain (
+    &self,
+    dom_id: u32,
+    _config: &SaveConfig,
+    writer: &mut (dyn Write + Send),
+    policy: &mut save::PrecopyPolicy
+  ) -> Result<()> {
+    let header = DomainHeader {
+      domain_type: DomainType::X86Hvm,
+      page_shift: migration_stream::PAGE_SHIFT,
+      xen_major: 0,
+      xen_minor: 0
+    };
+    let mut stream = StreamWriter::new(writer, &header)?;
+
+    // The first iteration sends all the pages.
+    let mut pfns: Vec<u64> = (0..self.with_domain(dom_id, |domain| domain.pages())? as u64).collect();
+    self.with_domain(dom_id, SimulatedDomain::take_dirty)?;
+    let mut iteration = 0;
+    loop {
+      match policy(iteration, pfns.len() as u64) {
+        Decision::ContinuePrecopy => (),
+        Decision::StopAndCopy => break,
+        Decision::Abort => return Err(Error::Aborted)
+      }
+      self.send_pages(dom_id, &mut stream, &pfns)?;
+      pfns = self.with_domain(dom_id, SimulatedDomain::take_dirty)?;
+      iteration += 1;
+    }
+
+    // Stop-and-copy.
+    let checksum = self.with_domain(dom_id, |domain| {
+      let mut last = domain.take_dirty();
+      domain.suspended = true;
+      pfns.append(&mut last);
+      pfns.sort_unstable();
+      pfns.dedup();
+      domain.checksum()
+    })?;
+    self.checksums.lock().unwrap().insert(dom_id, checksum);
+
+    self.send_pages(dom_id, &mut stream, &pfns)?;
+    stream.write_record(migration_stream::REC_TYPE_END, &[])?;
+
+    let writer = stream.into_inner();
+    if let Some(state) = self.with_domain(dom_id, |domain| domain.devices_state.clone())? {
+      writer.write_all(&(state.len() as u64).to_le_bytes())?;
+      writer.write_all(&state)?;
+    }
+    Ok(())
+  }
+
+  // The devices state is kept by the simulated domain: it has no file.
+  fn receive_domain (&self, config: &SaveConfig, stream: &TcpStream) -> Result<(u32, Option<PathBuf>)> {
+    let pages = (config.max_memkb as usize * 1024) >> migration_stream::PAGE_SHIFT;
+    let mut domain = SimulatedDomain::new(config.name.clone(), pages, 0, 1);
+
+    let mut reader = StreamReader::new(stream)?;
+    loop {
+      let record = reader.read_record()?;
+      migration_stream::validate_record(&record, reader.domain_header.domain_type)?;
+      match record.record_type {
+        migration_stream::REC_TYPE_END => break,
+        migration_stream::REC_TYPE_PAGE_DATA => {
+          let page_data = PageData::parse(&record.body)?;
+          let pages = page_data.pages.iter().filter(|(_, page_type)| migration_stream::page_has_data(*page_type));
+          for ((pfn, _), data) in pages.zip(page_data.data.chunks_exact(migration_stream::PAGE_SIZE)) {
+            if *pfn as usize >= domain.pages() {
+              return Err(Error::Protocol(format!("pfn {:#x} out of the domain memory", pfn)))
+            }
+            let offset = *pfn as usize * migration_stream::PAGE_SIZE;
+            domain.memory[offset..offset + migration_stream::PAGE_SIZE].copy_from_slice(data);
+          }
+        },
+        _ => ()
+      }
+    }
+    if config.device_model {
+      domain.devices_state = Some(save::read_devices_state(stream.as_raw_fd())?);
+    }
+
+    let mut next_dom_id = self.next_dom_id.lock().unwrap();
+    let dom_id = *next_dom_id;
+    *next_dom_id += 1;
+    self.domains.lock().unwrap().insert(dom_id, domain);
+    Ok((dom_id, None))
+  }
+
+  fn resume_domain (&self, dom_id: u32) -> Result<()> {
+    self.with_domain(dom_id, |domain| {
+      domain.suspended = false;
+      domain.last_run = Instant::now();
+    })
+  }
+
+  fn destroy_domain (&self, dom_id: u32) -> Result<()> {
+    match self.domains.lock().unwrap().remove(&dom_id) {
+      Some(_) => Ok(()),
+      None => Err(Error::NoSuchDomain(dom_id))
+    }
+  }
+}
+
+// =============================================================================
+// Transport.
+// =============================================================================
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+  Success {
+    dom_id: u32,
+    #[serde(default)]
+    devices_state: Option<PathBuf>
+  },
+  Error { message: String }
+}
+
+// Add `default_port` to an address without port: a host name or an IP, IPv6
+// addresses with a port are in brackets (`[::1]:5000`).
+pub fn address_with_port (address: &str, default_port: u16) -> String {
+  if address.parse::<SocketAddr>().is_ok() {
+    return String::from(address)
+  }
+
+  let ip = address.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(address);
+  match ip.parse::<IpAddr>() {
+    Ok(ip) => SocketAddr::new(ip, default_port).to_string(),
+    Err(_) if address.contains(':') => String::from(address),
+    Err(_) => format!("{}:{}", address, default_port)
+  }
+}
+
+pub fn destination_address (destination: &str) -> String {
+  address_with_port(destination, MIGRATION_PORT)
+}
+
+// Migrate a domain to `destination` and destroy it once started by the
+// receiver. Returns the domain id on the receiver and the file of its devices
+// state there: the domain stays paused until a device model is started with it.
+pub fn send<H: Host> (
+  host: &H,
+  dom_id: u32,
+  destination: &str,
+  config: &MigrationConfig,
+  progress: &mut dyn FnMut(&Progress)
+) -> Result<(u32, Option<PathBuf>)> {
+  let domain = host.domain_config(dom_id)?;
+  let stream = TcpStream::connect(destination_address(destination))?;
+
+  let bytes_sent = Arc::new(AtomicU64::new(0));
+  let mut writer = LimitedWriter::new(&stream, config.bandwidth_limit, bytes_sent.clone());
+  save::write_header(&mut writer, &domain)?;
+
+  let policy = Policy::new(*config);
+  let result = host.send_domain(dom_id, &domain, &mut writer, &mut |iteration, dirty_pages| {
+    let current = Progress { iteration, dirty_pages, bytes_sent: bytes_sent.load(Ordering::Relaxed) };
+    progress(&current);
+    policy.decide(&current)
+  }).and_then(|_| {
+    writer.flush()?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    match serde_json::from_str(&reply) {
+      Ok(Reply::Success { dom_id, devices_state }) => Ok((dom_id, devices_state)),
+      Ok(Reply::Error { message }) => Err(Error::Remote(message)),
+      Err(e) => Err(Error::Protocol(format!("bad reply: {}", e)))
+    }
+  });
+
+  match result {
+    Ok(remote) => {
+      host.destroy_domain(dom_id)?;
+      Ok(remote)
+    },
+    Err(e) => {
+      if let Err(resume_error) = host.resume_domain(dom_id) {
+        eprintln!("Failed to resume domain {} after a failed migration: {}", dom_id, resume_error);
+      }
+      Err(e)
+    }
+  }
+}
+
+// Receive one migration and reply to the sender. Returns the new domain id and
+// its devices state.
+pub fn receive<H: Host> (host: &H, stream: &TcpStream) -> Result<(u32, Option<PathBuf>)> {
+  let mut reader = stream;
+  let result = save::read_header(&mut reader).map_err(Error::from);
+  receive_with_config(host, stream, result)
+}
+
+// Same as `receive` when the save header was already read by the caller, a
+// failure to read it is also reported to the sender.
+pub fn receive_with_config<H: Host> (
+  host: &H,
+  stream: &TcpStream,
+  config: Result<SaveConfig>
+) -> Result<(u32, Option<PathBuf>)> {
+  let result = config.and_then(|config| host.receive_domain(&config, stream));
+
+  let reply = match &result {
+    Ok((dom_id, devices_state)) => Reply::Success { dom_id: *dom_id, devices_state: devices_state.clone() },
+    Err(e) => Reply::Error { message: e.to_string() }
+  };
+  let mut writer = stream;
+  serde_json::to_writer(&mut writer, &reply).map_err(|e| Error::Protocol(e.to_string()))?;
+  writer.write_all(b"\n")?;
+
+  result
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn addresses () {
+    assert_eq!(destination_address("10.0.0.2"), format!("10.0.0.2:{}", MIGRATION_PORT));
+    assert_eq!(address_with_port("10.0.0.2:5000", 1), "10.0.0.2:5000");
+    assert_eq!(address_with_port("host", 1), "host:1");
+    assert_eq!(address_with_port("host.example:5000", 1), "host.example:5000");
+    assert_eq!(address_with_port("::1", 1), "[::1]:1");
+    assert_eq!(address_with_port("fd00::2", 1), "[fd00::2]:1");
+    assert_eq!(address_with_port("[fd00::2]", 1), "[fd00::2]:1");
+    assert_eq!(address_with_port("[fd00::2]:5000", 1), "[fd00::2]:5000");
+  }
+}
@@ -0,0 +1,664 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use super::hvm_context::HvmContext;
+
+// =============================================================================
+// Reader/writer of the libxc migration stream v2.
+//
+// See: docs/specs/libxc-migration-stream.pandoc in the Xen tree.
+// The image header is big-endian, everything else uses the endianness of the
+// stream (always little-endian on x86). Records are padded to 8 octets.
+// =============================================================================
+
+pub const IMAGE_MARKER: u64 = 0xffff_ffff_ffff_ffff;
+pub const IMAGE_ID: u32 = 0x5845_4e46; // "XENF"
+pub const IMAGE_VERSION: u32 = 2;
+pub const IMAGE_OPTION_BIG_ENDIAN: u16 = 1;
+
+pub const IMAGE_HEADER_LENGTH: usize = 24;
+pub const DOMAIN_HEADER_LENGTH: usize = 16;
+pub const RECORD_HEADER_LENGTH: usize = 8;
+
+pub const REC_TYPE_END: u32 = 0x0000_0000;
+pub const REC_TYPE_PAGE_DATA: u32 = 0x0000_0001;
+pub const REC_TYPE_X86_PV_INFO: u32 = 0x0000_0002;
+pub const REC_TYPE_X86_PV_P2M_FRAMES: u32 = 0x0000_0003;
+pub const REC_TYPE_X86_PV_VCPU_BASIC: u32 = 0x0000_0004;
+pub const REC_TYPE_X86_PV_VCPU_EXTENDED: u32 = 0x0000_0005;
+pub const REC_TYPE_X86_PV_VCPU_XSAVE: u32 = 0x0000_0006;
+pub const REC_TYPE_SHARED_INFO: u32 = 0x0000_0007;
+pub const REC_TYPE_X86_TSC_INFO: u32 = 0x0000_0008;
+pub const REC_TYPE_HVM_CONTEXT: u32 = 0x0000_0009;
+pub const REC_TYPE_HVM_PARAMS: u32 = 0x0000_000a;
+pub const REC_TYPE_TOOLSTACK: u32 = 0x0000_000b;
+pub const REC_TYPE_X86_PV_VCPU_MSRS: u32 = 0x0000_000c;
+pub const REC_TYPE_VERIFY: u32 = 0x0000_000d;
+pub const REC_TYPE_CHECKPOINT: u32 = 0x0000_000e;
+pub const REC_TYPE_CHECKPOINT_DIRTY_PFN_LIST: u32 = 0x0000_000f;
+
+// Longest record body, as in libxc: a corrupted length is not allocated.
+pub const REC_LENGTH_MAX: usize = 128 << 20;
+
+// Records with this bit can be ignored by a receiver which doesn't know them.
+pub const REC_TYPE_OPTIONAL: u32 = 0x8000_0000;
+
+pub const PAGE_SHIFT: u16 = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+// PAGE_DATA pfns: bits 0-51 are the pfn, bits 60-63 the page type.
+const PAGE_DATA_PFN_MASK: u64 = (1 << 52) - 1;
+const PAGE_DATA_TYPE_SHIFT: u32 = 60;
+
+// Type of the pages of HVM guests.
+pub const PAGE_TYPE_NOTAB: u8 = 0x0;
+
+// Page types without data in the stream.
+pub const PAGE_TYPE_BROKEN: u8 = 0xd;
+pub const PAGE_TYPE_XALLOC: u8 = 0xe;
+pub const PAGE_TYPE_XTAB: u8 = 0xf;
+
+pub fn record_type_name (record_type: u32) -> &'static str {
+  match record_type & !REC_TYPE_OPTIONAL {
+    REC_TYPE_END => "END",
+    REC_TYPE_PAGE_DATA => "PAGE_DATA",
+    REC_TYPE_X86_PV_INFO => "X86_PV_INFO",
+    REC_TYPE_X86_PV_P2M_FRAMES => "X86_PV_P2M_FRAMES",
+    REC_TYPE_X86_PV_VCPU_BASIC => "X86_PV_VCPU_BASIC",
+    REC_TYPE_X86_PV_VCPU_EXTENDED => "X86_PV_VCPU_EXTENDED",
+    REC_TYPE_X86_PV_VCPU_XSAVE => "X86_PV_VCPU_XSAVE",
+    REC_TYPE_SHARED_INFO => "SHARED_INFO",
+    REC_TYPE_X86_TSC_INFO => "X86_TSC_INFO",
+    REC_TYPE_HVM_CONTEXT => "HVM_CONTEXT",
+    REC_TYPE_HVM_PARAMS => "HVM_PARAMS",
+    REC_TYPE_TOOLSTACK => "TOOLSTACK",
+    REC_TYPE_X86_PV_VCPU_MSRS => "X86_PV_VCPU_MSRS",
+    REC_TYPE_VERIFY => "VERIFY",
+    REC_TYPE_CHECKPOINT => "CHECKPOINT",
+    REC_TYPE_CHECKPOINT_DIRTY_PFN_LIST => "CHECKPOINT_DIRTY_PFN_LIST",
+    _ => "UNKNOWN"
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+pub enum Error {
+  Io(std::io::Error),
+  Invalid(String)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Invalid(details) => write!(f, "invalid migration stream: {}", details)
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn invalid<T> (details: String) -> Result<T> {
+  Err(Error::Invalid(details))
+}
+
+// -----------------------------------------------------------------------------
+
+fn u16_at (data: &[u8], offset: usize) -> u16 {
+  let mut value = [0u8; 2];
+  value.copy_from_slice(&data[offset..offset + 2]);
+  u16::from_le_bytes(value)
+}
+
+fn u32_at (data: &[u8], offset: usize) -> u32 {
+  let mut value = [0u8; 4];
+  value.copy_from_slice(&data[offset..offset + 4]);
+  u32::from_le_bytes(value)
+}
+
+fn u64_at (data: &[u8], offset: usize) -> u64 {
+  let mut value = [0u8; 8];
+  value.copy_from_slice(&data[offset..offset + 8]);
+  u64::from_le_bytes(value)
+}
+
+fn padding (length: usize) -> usize {
+  (8 - length % 8) % 8
+}
+
+// =============================================================================
+// Headers.
+// =============================================================================
+
+#[derive(Clone, Copy)]
+pub struct ImageHeader {
+  pub version: u32,
+  pub options: u16
+}
+
+impl ImageHeader {
+  pub fn parse (data: &[u8; IMAGE_HEADER_LENGTH]) -> Result<Self> {
+    let mut marker = [0u8; 8];
+    marker.copy_from_slice(&data[0..8]);
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&data[8..12]);
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&data[12..16]);
+    let options = u16::from_be_bytes([data[16], data[17]]);
+
+    if u64::from_be_bytes(marker) != IMAGE_MARKER {
+      return invalid(String::from("bad image marker"))
+    }
+    if u32::from_be_bytes(id) != IMAGE_ID {
+      return invalid(String::from("bad image id"))
+    }
+
+    let header = Self { version: u32::from_be_bytes(version), options };
+    if header.version != IMAGE_VERSION {
+      return invalid(format!("unsupported version {}", header.version))
+    }
+    if header.options & IMAGE_OPTION_BIG_ENDIAN != 0 {
+      return invalid(String::from("big-endian streams are not supported"))
+    }
+    Ok(header)
+  }
+
+  pub fn to_bytes (&self) -> [u8; IMAGE_HEADER_LENGTH] {
+    let mut data = [0u8; IMAGE_HEADER_LENGTH];
+    data[0..8].copy_from_slice(&IMAGE_MARKER.to_be_bytes());
+    data[8..12].copy_from_slice(&IMAGE_ID.to_be_bytes());
+    data[12..16].copy_from_slice(&self.version.to_be_bytes());
+    data[16..18].copy_from_slice(&self.options.to_be_bytes());
+    data
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DomainType {
+  X86Pv,
+  X86Hvm,
+  X86Pvh,
+  Arm,
+  Unknown(u32)
+}
+
+impl DomainType {
+  fn from_u32 (value: u32) -> Self {
+    match value {
+      1 => DomainType::X86Pv,
+      2 => DomainType::X86Hvm,
+      3 => DomainType::X86Pvh,
+      4 => DomainType::Arm,
+      value => DomainType::Unknown(value)
+    }
+  }
+
+  fn to_u32 (self) -> u32 {
+    match self {
+      DomainType::X86Pv => 1,
+      DomainType::X86Hvm => 2,
+      DomainType::X86Pvh => 3,
+      DomainType::Arm => 4,
+      DomainType::Unknown(value) => value
+    }
+  }
+}
+
+impl std::fmt::Display for DomainType {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      DomainType::X86Pv => write!(f, "x86 PV"),
+      DomainType::X86Hvm => write!(f, "x86 HVM"),
+      DomainType::X86Pvh => write!(f, "x86 PVH"),
+      DomainType::Arm => write!(f, "ARM"),
+      DomainType::Unknown(value) => write!(f, "(unknown {})", value)
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct DomainHeader {
+  pub domain_type: DomainType,
+  pub page_shift: u16,
+  pub xen_major: u32,
+  pub xen_minor: u32
+}
+
+impl DomainHeader {
+  pub fn parse (data: &[u8; DOMAIN_HEADER_LENGTH]) -> Result<Self> {
+    let header = Self {
+      domain_type: DomainType::from_u32(u32_at(data, 0)),
+      page_shift: u16_at(data, 4),
+      xen_major: u32_at(data, 8),
+      xen_minor: u32_at(data, 12)
+    };
+
+    if let DomainType::Unknown(value) = header.domain_type {
+      return invalid(format!("unknown domain type {}", value))
+    }
+    if header.page_shift != PAGE_SHIFT {
+      return invalid(format!("unsupported page shift {}", header.page_shift))
+    }
+    Ok(header)
+  }
+
+  pub fn to_bytes (&self) -> [u8; DOMAIN_HEADER_LENGTH] {
+    let mut data = [0u8; DOMAIN_HEADER_LENGTH];
+    data[0..4].copy_from_slice(&self.domain_type.to_u32().to_le_bytes());
+    data[4..6].copy_from_slice(&self.page_shift.to_le_bytes());
+    data[8..12].copy_from_slice(&self.xen_major.to_le_bytes());
+    data[12..16].copy_from_slice(&self.xen_minor.to_le_bytes());
+    data
+  }
+}
+
+// =============================================================================
+// Records.
+// =============================================================================
+
+pub struct Record {
+  pub record_type: u32,
+  pub body: Vec<u8>
+}
+
+impl Record {
+  pub fn name (&self) -> &'static str {
+    record_type_name(self.record_type)
+  }
+}
+
+pub struct PageData {
+  // Pfn and type of each page.
+  pub pages: Vec<(u64, u8)>,
+  // Data of the pages which have some, in the same order.
+  pub data: Vec<u8>
+}
+
+pub fn page_has_data (page_type: u8) -> bool {
+  !matches!(page_type, PAGE_TYPE_BROKEN | PAGE_TYPE_XALLOC | PAGE_TYPE_XTAB)
+}
+
+impl PageData {
+  pub fn parse (body: &[u8]) -> Result<Self> {
+    if body.len() < 8 {
+      return invalid(String::from("PAGE_DATA record too short"))
+    }
+
+    let count = u32_at(body, 0) as usize;
+    let data_offset = 8 + count * 8;
+    if body.len() < data_offset {
+      return invalid(format!("PAGE_DATA record too short for {} pfns", count))
+    }
+
+    let pages: Vec<(u64, u8)> = (0..count).map(|i| {
+      let value = u64_at(body, 8 + i * 8);
+      (value & PAGE_DATA_PFN_MASK, (value >> PAGE_DATA_TYPE_SHIFT) as u8)
+    }).collect();
+
+    let data_pages = pages.iter().filter(|(_, page_type)| page_has_data(*page_type)).count();
+    if body.len() != data_offset + data_pages * PAGE_SIZE {
+      return invalid(format!(
+        "PAGE_DATA record of {} bytes for {} pages with data", body.len(), data_pages
+      ))
+    }
+
+    Ok(Self { pages, data: body[data_offset..].to_vec() })
+  }
+
+  pub fn to_bytes (&self) -> Vec<u8> {
+    let mut body = Vec::with_capacity(8 + self.pages.len() * 8 + self.data.len());
+    body.extend_from_slice(&(self.pages.len() as u32).to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    for (pfn, page_type) in &self.pages {
+      body.extend_from_slice(&((pfn & PAGE_DATA_PFN_MASK) | u64::from(*page_type) << PAGE_DATA_TYPE_SHIFT).to_le_bytes());
+    }
+    body.extend_from_slice(&self.data);
+    body
+  }
+}
+
+// Check the fixed layout of a record body.
+pub fn validate_record (record: &Record, domain_type: DomainType) -> Result<()> {
+  let length = record.body.len();
+  let expect_length = |expected: usize| {
+    if length == expected {
+      Ok(())
+    } else {
+      invalid(format!("{} record of {} bytes instead of {}", record.name(), length, expected))
+    }
+  };
+  let expect_pv = || {
+    if domain_type == DomainType::X86Pv {
+      Ok(())
+    } else {
+      invalid(format!("{} record in a {} stream", record.name(), domain_type))
+    }
+  };
+
+  match record.record_type {
+    REC_TYPE_END | REC_TYPE_CHECKPOINT => expect_length(0),
+    REC_TYPE_PAGE_DATA => PageData::parse(&record.body).map(|_| ()),
+    REC_TYPE_X86_PV_INFO => {
+      expect_pv()?;
+      expect_length(8)
+    },
+    REC_TYPE_X86_PV_P2M_FRAMES |
+    REC_TYPE_X86_PV_VCPU_BASIC |
+    REC_TYPE_X86_PV_VCPU_EXTENDED |
+    REC_TYPE_X86_PV_VCPU_XSAVE |
+    REC_TYPE_X86_PV_VCPU_MSRS => {
+      expect_pv()?;
+      if length < 8 {
+        return invalid(format!("{} record too short", record.name()))
+      }
+      Ok(())
+    },
+    REC_TYPE_SHARED_INFO => expect_length(PAGE_SIZE),
+    REC_TYPE_X86_TSC_INFO => expect_length(24),
+    REC_TYPE_HVM_CONTEXT => {
+      HvmContext::parse(&record.body).map_err(|e| Error::Invalid(e.to_string()))?;
+      Ok(())
+    },
+    REC_TYPE_HVM_PARAMS => {
+      if length < 8 || length != 8 + u32_at(&record.body, 0) as usize * 16 {
+        return invalid(String::from("HVM_PARAMS record with a bad length"))
+      }
+      Ok(())
+    },
+    REC_TYPE_CHECKPOINT_DIRTY_PFN_LIST => {
+      if length & 7 != 0 {
+        return invalid(String::from("CHECKPOINT_DIRTY_PFN_LIST record with a bad length"))
+      }
+      Ok(())
+    },
+    record_type if record_type & REC_TYPE_OPTIONAL != 0 => Ok(()),
+    REC_TYPE_TOOLSTACK | REC_TYPE_VERIFY => Ok(()),
+    record_type => invalid(format!("unknown mandatory record {:#x}", record_type))
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct StreamReader<R: Read> {
+  reader: R,
+  pub image_header: ImageHeader,
+  pub domain_header: DomainHeader
+}
+
+impl<R: Read> StreamReader<R> {
+  pub fn new (mut reader: R) -> Result<Self> {
+    let mut image_header = [0u8; IMAGE_HEADER_LENGTH];
+    reader.read_exact(&mut image_header)?;
+    let image_header = ImageHeader::parse(&image_header)?;
+
+    let mut domain_header = [0u8; DOMAIN_HEADER_LENGTH];
+    reader.read_exact(&mut domain_header)?;
+    let domain_header = DomainHeader::parse(&domain_header)?;
+
+    Ok(Self { reader, image_header, domain_header })
+  }
+
+  pub fn read_record (&mut self) -> Result<Record> {
+    let mut header = [0u8; RECORD_HEADER_LENGTH];
+    self.reader.read_exact(&mut header)?;
+    let record_type = u32_at(&header, 0);
+    let length = u32_at(&header, 4) as usize;
+    if length > REC_LENGTH_MAX {
+      return invalid(format!("{} record of {} bytes, longer than {}", record_type_name(record_type), length, REC_LENGTH_MAX))
+    }
+
+    let mut body = vec![0u8; length + padding(length)];
+    self.reader.read_exact(&mut body)?;
+    body.truncate(length);
+
+    Ok(Record { record_type, body })
+  }
+
+  pub fn into_inner (self) -> R {
+    self.reader
+  }
+}
+
+pub struct StreamWriter<W: Write> {
+  writer: W
+}
+
+impl<W: Write> StreamWriter<W> {
+  pub fn new (mut writer: W, domain_header: &DomainHeader) -> Result<Self> {
+    let image_header = ImageHeader { version: IMAGE_VERSION, options: 0 };
+    writer.write_all(&image_header.to_bytes())?;
+    writer.write_all(&domain_header.to_bytes())?;
+    Ok(Self { writer })
+  }
+
+  pub fn write_record (&mut self, record_type: u32, body: &[u8]) -> Result<()> {
+    self.writer.write_all(&record_type.to_le_bytes())?;
+    self.writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    self.writer.write_all(body)?;
+    self.writer.write_all(&[0u8; 8][..padding(body.len())])?;
+    Ok(())
+  }
+
+  pub fn into_inner (self) -> W {
+    self.writer
+  }
+}
+
+// =============================================================================
+// Validation.
+// =============================================================================
+
+pub struct StreamSummary {
+  pub image_header: ImageHeader,
+  pub domain_header: DomainHeader,
+  // Count of records per type.
+  pub records: BTreeMap<u32, usize>,
+  pub pages: usize,
+  pub vcpus: usize
+}
+
+// Read a whole stream and check its records, up to the END record. A stream of
+// checkpoints can also end after a CHECKPOINT record.
+pub fn validate<R: Read> (reader: R) -> Result<StreamSummary> {
+  let mut stream = StreamReader::new(reader)?;
+  let mut summary = StreamSummary {
+    image_header: stream.image_header,
+    domain_header: stream.domain_header,
+    records: BTreeMap::new(),
+    pages: 0,
+    vcpus: 0
+  };
+
+  let mut checkpoint_end = false;
+  loop {
+    let record = match stream.read_record() {
+      Ok(record) => record,
+      Err(Error::Io(e)) if checkpoint_end && e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(summary),
+      Err(e) => return Err(e)
+    };
+    validate_record(&record, summary.domain_header.domain_type)?;
+    checkpoint_end = record.record_type == REC_TYPE_CHECKPOINT;
+    *summary.records.entry(record.record_type).or_insert(0) += 1;
+
+    match record.record_type {
+      REC_TYPE_PAGE_DATA => summary.pages += u32_at(&record.body, 0) as usize,
+      REC_TYPE_X86_PV_VCPU_BASIC => summary.vcpus += 1,
+      REC_TYPE_HVM_CONTEXT => {
+        if let Ok(context) = HvmContext::parse(&record.body) {
+          summary.vcpus = context.cpus().count();
+        }
+      },
+      REC_TYPE_END => return Ok(summary),
+      _ => ()
+    }
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::error;
+  use std::io::Cursor;
+
+  fn hvm_header () -> DomainHeader {
+    DomainHeader { domain_type: DomainType::X86Hvm, page_shift: PAGE_SHIFT, xen_major: 4, xen_minor: 17 }
+  }
+
+  fn reader (stream: Vec<u8>) -> StreamReader<Cursor<Vec<u8>>> {
+    StreamReader::new(Cursor::new(stream)).ok().unwrap()
+  }
+
+  #[test]
+  fn headers () {
+    let mut writer = StreamWriter::new(Vec::new(), &hvm_header()).ok().unwrap();
+    writer.write_record(REC_TYPE_END, &[]).ok().unwrap();
+    let stream = writer.into_inner();
+    assert_eq!(stream.len(), IMAGE_HEADER_LENGTH + DOMAIN_HEADER_LENGTH + RECORD_HEADER_LENGTH);
+    assert_eq!(&stream[..18], &[
+      0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'X', b'E', b'N', b'F', 0, 0, 0, 2, 0, 0
+    ]);
+    assert_eq!(&stream[24..40], &[2, 0, 0, 0, 12, 0, 0, 0, 4, 0, 0, 0, 17, 0, 0, 0]);
+
+    let stream = reader(stream);
+    assert_eq!(stream.image_header.version, IMAGE_VERSION);
+    assert!(stream.domain_header.domain_type == DomainType::X86Hvm);
+    assert_eq!(stream.domain_header.xen_minor, 17);
+  }
+
+  #[test]
+  fn bad_headers () {
+    let mut image_header = ImageHeader { version: IMAGE_VERSION, options: 0 }.to_bytes();
+    image_header[8] = b'Y';
+    assert_eq!(error(ImageHeader::parse(&image_header)), "invalid migration stream: bad image id");
+    let image_header = ImageHeader { version: 3, options: 0 }.to_bytes();
+    assert_eq!(error(ImageHeader::parse(&image_header)), "invalid migration stream: unsupported version 3");
+    let image_header = ImageHeader { version: IMAGE_VERSION, options: IMAGE_OPTION_BIG_ENDIAN }.to_bytes();
+    assert!(ImageHeader::parse(&image_header).is_err());
+
+    let domain_header = DomainHeader { domain_type: DomainType::Unknown(9), ..hvm_header() }.to_bytes();
+    assert_eq!(error(DomainHeader::parse(&domain_header)), "invalid migration stream: unknown domain type 9");
+    let domain_header = DomainHeader { page_shift: 16, ..hvm_header() }.to_bytes();
+    assert_eq!(error(DomainHeader::parse(&domain_header)), "invalid migration stream: unsupported page shift 16");
+  }
+
+  #[test]
+  fn records () {
+    let mut writer = StreamWriter::new(Vec::new(), &hvm_header()).ok().unwrap();
+    for length in 0..=17 {
+      writer.write_record(REC_TYPE_TOOLSTACK, &vec![length as u8; length]).ok().unwrap();
+    }
+    writer.write_record(REC_TYPE_END, &[]).ok().unwrap();
+    let stream = writer.into_inner();
+
+    let records: usize = (0..=17).map(|length| RECORD_HEADER_LENGTH + length + padding(length)).sum();
+    assert_eq!(stream.len(), IMAGE_HEADER_LENGTH + DOMAIN_HEADER_LENGTH + records + RECORD_HEADER_LENGTH);
+
+    let mut stream = reader(stream);
+    for length in 0..=17 {
+      let record = stream.read_record().ok().unwrap();
+      assert_eq!(record.record_type, REC_TYPE_TOOLSTACK);
+      assert_eq!(record.body, vec![length as u8; length]);
+    }
+    assert_eq!(stream.read_record().ok().unwrap().name(), "END");
+    assert!(stream.read_record().is_err());
+  }
+
+  #[test]
+  fn record_padding () {
+    let mut writer = StreamWriter::new(Vec::new(), &hvm_header()).ok().unwrap();
+    writer.write_record(REC_TYPE_VERIFY, &[1, 2, 3]).ok().unwrap();
+    let mut stream = writer.into_inner();
+    assert_eq!(&stream[40..], &[13, 0, 0, 0, 3, 0, 0, 0, 1, 2, 3, 0, 0, 0, 0, 0]);
+
+    // A record cut in its padding is truncated.
+    stream.truncate(stream.len() - 1);
+    match reader(stream).read_record() {
+      Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+      _ => panic!("record read without its padding")
+    }
+  }
+
+  #[test]
+  fn record_length () {
+    let mut stream = StreamWriter::new(Vec::new(), &hvm_header()).ok().unwrap().into_inner();
+    stream.extend_from_slice(&REC_TYPE_PAGE_DATA.to_le_bytes());
+    stream.extend_from_slice(&(REC_LENGTH_MAX as u32 + 1).to_le_bytes());
+    assert_eq!(
+      error(reader(stream).read_record()),
+      format!("invalid migration stream: PAGE_DATA record of {} bytes, longer than {}", REC_LENGTH_MAX + 1, REC_LENGTH_MAX)
+    );
+
+    let record = Record { record_type: REC_TYPE_X86_TSC_INFO, body: vec![0; 16] };
+    assert_eq!(
+      error(validate_record(&record, DomainType::X86Hvm)),
+      "invalid migration stream: X86_TSC_INFO record of 16 bytes instead of 24"
+    );
+    let record = Record { record_type: REC_TYPE_END, body: vec![0; 8] };
+    assert!(validate_record(&record, DomainType::X86Hvm).is_err());
+    let record = Record { record_type: REC_TYPE_HVM_PARAMS, body: [&1u32.to_le_bytes()[..], &[0; 4]].concat() };
+    assert_eq!(error(validate_record(&record, DomainType::X86Hvm)), "invalid migration stream: HVM_PARAMS record with a bad length");
+    let record = Record { record_type: REC_TYPE_CHECKPOINT_DIRTY_PFN_LIST, body: vec![0; 12] };
+    assert!(validate_record(&record, DomainType::X86Hvm).is_err());
+  }
+
+  #[test]
+  fn record_types () {
+    let record = Record { record_type: 0x42, body: Vec::new() };
+    assert_eq!(record.name(), "UNKNOWN");
+    assert_eq!(error(validate_record(&record, DomainType::X86Hvm)), "invalid migration stream: unknown mandatory record 0x42");
+    let record = Record { record_type: 0x42 | REC_TYPE_OPTIONAL, body: Vec::new() };
+    assert!(validate_record(&record, DomainType::X86Hvm).is_ok());
+
+    let record = Record { record_type: REC_TYPE_X86_PV_INFO, body: vec![0; 8] };
+    assert!(validate_record(&record, DomainType::X86Pv).is_ok());
+    assert_eq!(error(validate_record(&record, DomainType::X86Hvm)), "invalid migration stream: X86_PV_INFO record in a x86 HVM stream");
+    assert_eq!(record_type_name(REC_TYPE_CHECKPOINT | REC_TYPE_OPTIONAL), "CHECKPOINT");
+  }
+
+  #[test]
+  fn page_data () {
+    let page_data = PageData {
+      pages: vec![(0x10, PAGE_TYPE_NOTAB), (0x11, PAGE_TYPE_XTAB), (PAGE_DATA_PFN_MASK, PAGE_TYPE_NOTAB)],
+      data: vec![7; 2 * PAGE_SIZE]
+    };
+    let body = page_data.to_bytes();
+    assert_eq!(body.len(), 8 + 3 * 8 + 2 * PAGE_SIZE);
+    assert_eq!(&body[16..24], &[0x11, 0, 0, 0, 0, 0, 0, 0xf0]);
+
+    let parsed = PageData::parse(&body).ok().unwrap();
+    assert_eq!(parsed.pages, page_data.pages);
+    assert_eq!(parsed.data, page_data.data);
+    assert_eq!(
+      error(PageData::parse(&body[..body.len() - 1])),
+      format!("invalid migration stream: PAGE_DATA record of {} bytes for 2 pages with data", body.len() - 1)
+    );
+    assert!(PageData::parse(&body[..20]).is_err());
+  }
+
+  #[test]
+  fn validate_checkpoints () {
+    let mut writer = StreamWriter::new(Vec::new(), &hvm_header()).ok().unwrap();
+    let page_data = PageData { pages: vec![(1, PAGE_TYPE_NOTAB)], data: vec![0; PAGE_SIZE] };
+    writer.write_record(REC_TYPE_PAGE_DATA, &page_data.to_bytes()).ok().unwrap();
+    writer.write_record(REC_TYPE_CHECKPOINT, &[]).ok().unwrap();
+    writer.write_record(REC_TYPE_PAGE_DATA, &page_data.to_bytes()).ok().unwrap();
+    writer.write_record(REC_TYPE_CHECKPOINT, &[]).ok().unwrap();
+    let stream = writer.into_inner();
+
+    // The stream of checkpoints ends with the connection.
+    let summary = validate(Cursor::new(&stream)).ok().unwrap();
+    assert_eq!(summary.pages, 2);
+    assert_eq!(summary.records.get(&REC_TYPE_CHECKPOINT), Some(&2));
+
+    // But not in the middle of a checkpoint.
+    assert!(validate(Cursor::new(&stream[..stream.len() - RECORD_HEADER_LENGTH])).is_err());
+  }
+}
@@ -0,0 +1,719 @@
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::device::{self, pci as pci_device};
+use super::devicemodel::qmp::{self, Qmp};
+use super::foreignmemory::PAGE_SIZE;
+use super::vcpu_context;
+use super::xenctrl::{self, Xenctrl};
+use super::xenstore::Store;
+
+// =============================================================================
+// PCI passthrough.
+//
+// A device can be given to a guest once assignable: bound to pciback in dom0.
+// Its previous driver is saved in xenstore to bind it again when it is no
+// longer assignable. Attaching a device assigns it to the guest in Xen (IOMMU)
+// and allows the guest to access its I/O memory, its I/O ports and its IRQ.
+// PV guests get it through pcifront, HVM guests through their device model:
+// it is plugged in QEMU with QMP. The attachment of a destroyed domain is
+// stale, it is forgotten.
+// =============================================================================
+
+pub const PCIBACK_DRIVER: &str = "pciback";
+
+// Assignable devices and their attachments: <bdf>/driver, <bdf>/domain...
+const STORE_PATH: &str = "/xenops/pci";
+
+// See include/linux/ioport.h.
+const IORESOURCE_IO: u64 = 0x100;
+const IORESOURCE_MEM: u64 = 0x200;
+
+// BARs and ROM, the first lines of the `resource` file.
+const RESOURCES: usize = 7;
+
+// QEMU device of the devices given to HVM guests.
+const QEMU_DRIVER: &str = "xen-pci-passthrough";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum Error {
+  Xen(xenctrl::Error),
+  Io(std::io::Error),
+  Device(device::Error),
+  Qmp(qmp::Error),
+  Xenstore(&'static str),
+  InvalidBdf(String),
+  NoSuchDevice(Bdf),
+  NotAssignable(Bdf),
+  // Attached to a domain.
+  InUse(Bdf, u32),
+  // HVM guest without a device model reachable with QMP.
+  NoDeviceModel(u32),
+  // Not released by the guest.
+  Timeout(Bdf)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Xen(e) => write!(f, "{}", e),
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Device(e) => write!(f, "{}", e),
+      Error::Qmp(e) => write!(f, "{}", e),
+      Error::Xenstore(details) => write!(f, "xenstore error: {}", details),
+      Error::InvalidBdf(bdf) => write!(f, "invalid PCI address: `{}`", bdf),
+      Error::NoSuchDevice(bdf) => write!(f, "no such PCI device: {}", bdf),
+      Error::NotAssignable(bdf) => write!(f, "PCI device {} is not assignable", bdf),
+      Error::InUse(bdf, dom_id) => write!(f, "PCI device {} is attached to domain {}", bdf, dom_id),
+      Error::NoDeviceModel(dom_id) => write!(f, "no device model to plug PCI devices in domain {}", dom_id),
+      Error::Timeout(bdf) => write!(f, "timeout, PCI device {} is not released by the guest", bdf)
+    }
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Xen(e)
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<device::Error> for Error {
+  fn from (e: device::Error) -> Self {
+    Error::Device(e)
+  }
+}
+
+impl From<qmp::Error> for Error {
+  fn from (e: qmp::Error) -> Self {
+    Error::Qmp(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+// Address of a PCI device: segment (or domain), bus, device and function.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Bdf {
+  pub segment: u16,
+  pub bus: u8,
+  pub device: u8,
+  pub function: u8
+}
+
+impl Bdf {
+  // Value given to Xen.
+  pub fn sbdf (&self) -> u32 {
+    u32::from(self.segment) << 16 | u32::from(self.bus) << 8 | u32::from(self.device) << 3 | u32::from(self.function)
+  }
+}
+
+// "0000:03:00.1", the segment is 0 if omitted: "03:00.1".
+impl std::str::FromStr for Bdf {
+  type Err = Error;
+
+  fn from_str (value: &str) -> std::result::Result<Self, Self::Err> {
+    let invalid = || Error::InvalidBdf(value.to_string());
+    let dot = value.rfind('.').ok_or_else(invalid)?;
+    let (slot, function) = (&value[..dot], &value[dot + 1..]);
+    let parts: Vec<&str> = slot.split(':').collect();
+    let (segment, bus, device) = match parts[..] {
+      [segment, bus, device] if segment.len() <= 4 => (segment, bus, device),
+      [bus, device] => ("0", bus, device),
+      _ => return Err(invalid())
+    };
+    if bus.len() > 2 || device.len() > 2 || function.len() != 1 {
+      return Err(invalid())
+    }
+
+    let bdf = Bdf {
+      segment: u16::from_str_radix(segment, 16).map_err(|_| invalid())?,
+      bus: u8::from_str_radix(bus, 16).map_err(|_| invalid())?,
+      device: u8::from_str_radix(device, 16).map_err(|_| invalid())?,
+      function: u8::from_str_radix(function, 16).map_err(|_| invalid())?
+    };
+    if bdf.device > 0x1f || bdf.function > 7 {
+      return Err(invalid())
+    }
+    Ok(bdf)
+  }
+}
+
+impl std::fmt::Display for Bdf {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{:04x}:{:02x}:{:02x}.{:x}", self.segment, self.bus, self.device, self.function)
+  }
+}
+
+// I/O memory or port range of a device.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Resource {
+  Memory { start: u64, size: u64 },
+  Ports { start: u64, size: u64 }
+}
+
+// -----------------------------------------------------------------------------
+
+// PCI devices and drivers of the host in sysfs, under a root which can be a
+// fake tree.
+pub struct Sysfs {
+  root: PathBuf
+}
+
+impl Default for Sysfs {
+  fn default () -> Self {
+    Self::new("/sys")
+  }
+}
+
+impl Sysfs {
+  pub fn new<P: AsRef<Path>> (root: P) -> Self {
+    Self { root: root.as_ref().to_path_buf() }
+  }
+
+  fn device_path (&self, bdf: &Bdf) -> PathBuf {
+    self.root.join("bus/pci/devices").join(bdf.to_string())
+  }
+
+  fn driver_path (&self, driver: &str) -> PathBuf {
+    self.root.join("bus/pci/drivers").join(driver)
+  }
+
+  pub fn exists (&self, bdf: &Bdf) -> bool {
+    self.device_path(bdf).exists()
+  }
+
+  // Name of the driver bound to a device.
+  pub fn driver (&self, bdf: &Bdf) -> Option<String> {
+    let driver = std::fs::read_link(self.device_path(bdf).join("driver")).ok()?;
+    Some(driver.file_name()?.to_string_lossy().into_owned())
+  }
+
+  pub fn bind (&self, bdf: &Bdf, driver: &str) -> std::io::Result<()> {
+    std::fs::write(self.driver_path(driver).join("bind"), bdf.to_string())
+  }
+
+  pub fn unbind (&self, bdf: &Bdf) -> std::io::Result<()> {
+    std::fs::write(self.device_path(bdf).join("driver/unbind"), bdf.to_string())
+  }
+
+  // Devices bound to pciback.
+  pub fn assignable (&self) -> Vec<Bdf> {
+    let mut devices: Vec<Bdf> = std::fs::read_dir(self.driver_path(PCIBACK_DRIVER)).into_iter().flatten()
+      .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+      .collect();
+    devices.sort_unstable_by_key(Bdf::sbdf);
+    devices
+  }
+
+  // Make pciback accept a device, then bind it.
+  fn bind_pciback (&self, bdf: &Bdf) -> std::io::Result<()> {
+    std::fs::write(self.driver_path(PCIBACK_DRIVER).join("new_slot"), bdf.to_string())?;
+    self.bind(bdf, PCIBACK_DRIVER)
+  }
+
+  fn unbind_pciback (&self, bdf: &Bdf) -> std::io::Result<()> {
+    self.unbind(bdf)?;
+    std::fs::write(self.driver_path(PCIBACK_DRIVER).join("remove_slot"), bdf.to_string())
+  }
+
+  // Resources used by a device.
+  pub fn resources (&self, bdf: &Bdf) -> std::io::Result<Vec<Resource>> {
+    let content = std::fs::read_to_string(self.device_path(bdf).join("resource"))?;
+    let parse = |value: &str| u64::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+
+    Ok(content.lines().take(RESOURCES).filter_map(|line| {
+      let values: Vec<u64> = line.split_whitespace().filter_map(parse).collect();
+      let (start, end, flags) = match values[..] {
+        [start, end, flags] if start != 0 && end >= start => (start, end, flags),
+        _ => return None
+      };
+      let size = end - start + 1;
+      if flags & IORESOURCE_MEM != 0 {
+        Some(Resource::Memory { start, size })
+      } else if flags & IORESOURCE_IO != 0 {
+        Some(Resource::Ports { start, size })
+      } else {
+        None
+      }
+    }).collect())
+  }
+
+  // Legacy IRQ of a device, 0 if none.
+  pub fn irq (&self, bdf: &Bdf) -> std::io::Result<u32> {
+    let irq = std::fs::read_to_string(self.device_path(bdf).join("irq"))?;
+    irq.trim().parse().map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid irq"))
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn store_node (bdf: &Bdf, key: &str) -> String {
+  format!("{}/{}/{}", STORE_PATH, bdf, key)
+}
+
+// Domain using a device.
+fn attached_domain (store: &dyn Store, bdf: &Bdf) -> Option<u32> {
+  store.read(&store_node(bdf, "domain")).ok()?.parse().ok()
+}
+
+fn forget_attachment (store: &dyn Store, bdf: &Bdf) {
+  let _ = store.rm(&store_node(bdf, "domain"));
+  let _ = store.rm(&store_node(bdf, "pirq"));
+}
+
+// Forget the attachments of a domain, when it is destroyed: Xen takes its
+// devices back.
+pub fn release_domain (store: &dyn Store, dom_id: u32) {
+  for bdf in list(store, dom_id) {
+    forget_attachment(store, &bdf);
+  }
+}
+
+// Forget the attachments of the domains destroyed without `release_domain`.
+pub fn release_destroyed_domains (xc: &Xenctrl, store: &dyn Store) -> Result<()> {
+  let domains = xc.get_domain_info_list()?;
+  for bdf in store.directory(STORE_PATH).unwrap_or_default().iter().filter_map(|bdf| bdf.parse().ok()) {
+    if let Some(dom_id) = attached_domain(store, &bdf) {
+      if !domains.iter().any(|info| u32::from(info.domain) == dom_id) {
+        forget_attachment(store, &bdf);
+      }
+    }
+  }
+  Ok(())
+}
+
+pub fn list_assignable (sysfs: &Sysfs) -> Vec<Bdf> {
+  sysfs.assignable()
+}
+
+// Bind a device to pciback. Its driver is saved to be restored later.
+pub fn make_assignable (sysfs: &Sysfs, store: &dyn Store, bdf: &Bdf) -> Result<()> {
+  if !sysfs.exists(bdf) {
+    return Err(Error::NoSuchDevice(*bdf))
+  }
+
+  match sysfs.driver(bdf) {
+    Some(driver) if driver == PCIBACK_DRIVER => return Ok(()),
+    Some(driver) => {
+      store.write(&store_node(bdf, "driver"), &driver)
+        .map_err(|_| Error::Xenstore("failed to save the driver of the device"))?;
+      sysfs.unbind(bdf)?;
+    },
+    None => ()
+  }
+  sysfs.bind_pciback(bdf)?;
+  Ok(())
+}
+
+// Unbind a device from pciback, and bind it again to its previous driver if
+// `rebind`.
+pub fn remove_assignable (sysfs: &Sysfs, store: &dyn Store, bdf: &Bdf, rebind: bool) -> Result<()> {
+  if sysfs.driver(bdf).as_deref() != Some(PCIBACK_DRIVER) {
+    return Err(Error::NotAssignable(*bdf))
+  }
+  if let Some(dom_id) = attached_domain(store, bdf) {
+    return Err(Error::InUse(*bdf, dom_id))
+  }
+
+  sysfs.unbind_pciback(bdf)?;
+  if rebind {
+    if let Ok(driver) = store.read(&store_node(bdf, "driver")) {
+      sysfs.bind(bdf, &driver)?;
+    }
+  }
+  let _ = store.rm(&format!("{}/{}", STORE_PATH, bdf));
+  Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+pub struct PciConfig {
+  pub bdf: Bdf,
+  // Let the guest write the whole configuration space.
+  pub permissive: bool,
+  // Relaxed policy of the reserved memory regions.
+  pub rdm_relaxed: bool
+}
+
+impl PciConfig {
+  pub fn new (bdf: Bdf) -> Self {
+    Self { bdf, permissive: false, rdm_relaxed: true }
+  }
+}
+
+// Id of the QEMU device of a PCI device, like libxl.
+fn qemu_device_id (bdf: &Bdf) -> String {
+  format!("pci-pt-{:02x}_{:02x}.{:x}", bdf.bus, bdf.device, bdf.function)
+}
+
+// Whether a device is in the reply of `query-pci`.
+fn has_qemu_device (buses: &Value, id: &str) -> bool {
+  buses.as_array().into_iter().flatten()
+    .flat_map(|bus| bus["devices"].as_array().into_iter().flatten())
+    .any(|device| device["qdev_id"] == id)
+}
+
+// Plug a device in the device model of a HVM guest.
+fn qemu_device_add<S: Read + Write> (qmp: &mut Qmp<S>, config: &PciConfig) -> Result<()> {
+  let properties = json!({ "hostaddr": config.bdf.to_string(), "permissive": config.permissive });
+  Ok(qmp.device_add(QEMU_DRIVER, &qemu_device_id(&config.bdf), properties)?)
+}
+
+// Ask the guest to release a device and wait for its device model to remove
+// it, unless `force`.
+fn qemu_device_del<S: Read + Write> (qmp: &mut Qmp<S>, bdf: &Bdf, force: bool, timeout: Duration) -> Result<()> {
+  let id = qemu_device_id(bdf);
+  qmp.device_del(&id)?;
+  let start = Instant::now();
+  while !force && has_qemu_device(&qmp.execute("query-pci", None)?, &id) {
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout(*bdf))
+    }
+    std::thread::sleep(POLL_INTERVAL);
+  }
+  Ok(())
+}
+
+// Allow or deny the resources of a device to a domain. The pirq of the IRQ
+// is given when denied, returned when allowed.
+fn set_permissions (
+  xc: &Xenctrl,
+  sysfs: &Sysfs,
+  dom_id: u32,
+  bdf: &Bdf,
+  pirq: Option<u32>,
+  allow: bool
+) -> Result<Option<u32>> {
+  for resource in sysfs.resources(bdf)? {
+    match resource {
+      Resource::Memory { start, size } => {
+        let page_size = PAGE_SIZE as u64;
+        xc.iomem_permission(dom_id, start / page_size, (size + page_size - 1) / page_size, allow)?
+      },
+      Resource::Ports { start, size } => xc.ioport_permission(dom_id, start as u32, size as u32, allow)?
+    }
+  }
+
+  if !allow {
+    if let Some(pirq) = pirq {
+      xc.irq_permission(dom_id, pirq, false)?;
+      xc.unmap_pirq(dom_id, pirq)?;
+    }
+    return Ok(None)
+  }
+
+  match sysfs.irq(bdf)? {
+    0 => Ok(None),
+    irq => {
+      let pirq = xc.map_pirq(dom_id, irq)?;
+      xc.irq_permission(dom_id, pirq, true)?;
+      Ok(Some(pirq))
+    }
+  }
+}
+
+// Give an assignable device to a domain. A HVM guest gets it from its device
+// model, with `qmp`.
+pub fn attach<S: Read + Write> (
+  xc: &Xenctrl,
+  store: &dyn Store,
+  sysfs: &Sysfs,
+  qmp: Option<&mut Qmp<S>>,
+  dom_id: u32,
+  config: &PciConfig,
+  timeout: Duration
+) -> Result<()> {
+  let bdf = &config.bdf;
+  if sysfs.driver(bdf).as_deref() != Some(PCIBACK_DRIVER) {
+    return Err(Error::NotAssignable(*bdf))
+  }
+  release_destroyed_domains(xc, store)?;
+  if let Some(user) = attached_domain(store, bdf) {
+    return Err(Error::InUse(*bdf, user))
+  }
+  let hvm = vcpu_context::is_hvm(xc, dom_id)?;
+  if hvm && qmp.is_none() {
+    return Err(Error::NoDeviceModel(dom_id))
+  }
+
+  let pirq = set_permissions(xc, sysfs, dom_id, bdf, None, true)?;
+  let result = xc.assign_device(dom_id, bdf.sbdf(), config.rdm_relaxed).map_err(Error::from).and_then(|_| {
+    let plugged = match qmp {
+      Some(qmp) if hvm => qemu_device_add(qmp, config),
+      _ => {
+        let opts = format!("msitranslate=0,power_mgmt=0,permissive={}", config.permissive as u8);
+        pci_device::add(store, dom_id, &bdf.to_string(), &opts, timeout).map_err(Error::from)
+      }
+    };
+    if let Err(e) = plugged {
+      let _ = xc.deassign_device(dom_id, bdf.sbdf());
+      return Err(e)
+    }
+    store.write(&store_node(bdf, "domain"), &dom_id.to_string())
+      .and_then(|_| match pirq {
+        Some(pirq) => store.write(&store_node(bdf, "pirq"), &pirq.to_string()),
+        None => Ok(())
+      })
+      .map_err(|_| Error::Xenstore("failed to save the attachment of the device"))
+  });
+
+  if result.is_err() {
+    let _ = set_permissions(xc, sysfs, dom_id, bdf, pirq, false);
+  }
+  result
+}
+
+// Take a device back from a domain, from the device model of a HVM guest
+// with `qmp`. With `force`, the guest is not waited.
+#[allow(clippy::too_many_arguments)]
+pub fn detach<S: Read + Write> (
+  xc: &Xenctrl,
+  store: &dyn Store,
+  sysfs: &Sysfs,
+  qmp: Option<&mut Qmp<S>>,
+  dom_id: u32,
+  bdf: &Bdf,
+  force: bool,
+  timeout: Duration
+) -> Result<()> {
+  if attached_domain(store, bdf) != Some(dom_id) {
+    return Err(Error::NoSuchDevice(*bdf))
+  }
+
+  if !vcpu_context::is_hvm(xc, dom_id)? {
+    pci_device::remove(store, dom_id, &bdf.to_string(), force, timeout)?;
+  } else {
+    // A device model which is gone no longer uses the device.
+    let unplugged = qmp.ok_or(Error::NoDeviceModel(dom_id))
+      .and_then(|qmp| qemu_device_del(qmp, bdf, force, timeout));
+    if !force {
+      unplugged?;
+    }
+  }
+  xc.deassign_device(dom_id, bdf.sbdf())?;
+
+  let pirq = store.read(&store_node(bdf, "pirq")).ok().and_then(|pirq| pirq.parse().ok());
+  set_permissions(xc, sysfs, dom_id, bdf, pirq, false)?;
+  forget_attachment(store, bdf);
+  Ok(())
+}
+
+// Devices attached to a domain.
+pub fn list (store: &dyn Store, dom_id: u32) -> Vec<Bdf> {
+  store.directory(STORE_PATH).unwrap_or_default().iter()
+    .filter_map(|bdf| bdf.parse().ok())
+    .filter(|bdf| attached_domain(store, bdf) == Some(dom_id))
+    .collect()
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{error, TempDir};
+  use crate::xenstore::MemoryStore;
+  use std::cell::RefCell;
+  use std::io::Cursor;
+  use std::os::unix::fs::symlink;
+  use std::rc::Rc;
+
+  const BDF: &str = "0000:03:00.0";
+
+  // Tree of sysfs with a device bound to `driver`. What the kernel does on
+  // bind is done by `rebind`.
+  struct FakeSysfs {
+    root: TempDir
+  }
+
+  impl FakeSysfs {
+    fn new (name: &str, driver: &str) -> Self {
+      let root = TempDir::new(&format!("pci-{}", name));
+      for driver in &[driver, PCIBACK_DRIVER] {
+        std::fs::create_dir_all(root.join("bus/pci/drivers").join(driver)).unwrap();
+      }
+      let device = root.join("bus/pci/devices").join(BDF);
+      std::fs::create_dir_all(&device).unwrap();
+      std::fs::write(device.join("resource"), concat!(
+        "0x00000000f0000000 0x00000000f0ffffff 0x0000000000040200\n",
+        "0x0000000000000000 0x0000000000000000 0x0000000000000000\n",
+        "0x000000000000e000 0x000000000000e01f 0x0000000000040101\n"
+      )).unwrap();
+      std::fs::write(device.join("irq"), "16\n").unwrap();
+      let fake = Self { root };
+      fake.rebind(driver);
+      fake
+    }
+
+    fn sysfs (&self) -> Sysfs {
+      Sysfs::new(&self.root)
+    }
+
+    fn rebind (&self, driver: &str) {
+      let link = self.root.join("bus/pci/devices").join(BDF).join("driver");
+      let _ = std::fs::remove_file(&link);
+      symlink(self.root.join("bus/pci/drivers").join(driver), link).unwrap();
+    }
+
+    // What was written in a file of a driver.
+    fn written (&self, driver: &str, file: &str) -> Option<String> {
+      std::fs::read_to_string(self.root.join("bus/pci/drivers").join(driver).join(file)).ok()
+    }
+  }
+
+  #[test]
+  fn bdf () {
+    let bdf: Bdf = "03:00.1".parse().unwrap_or_else(|_| panic!());
+    assert_eq!(bdf.to_string(), "0000:03:00.1");
+    assert_eq!(bdf.sbdf(), 0x0301);
+    assert_eq!(qemu_device_id(&bdf), "pci-pt-03_00.1");
+    for invalid in &["03:00", "03:20.0", "03:00.8", "12345:03:00.0", "0000:003:00.0"] {
+      assert_eq!(error(invalid.parse::<Bdf>()), format!("invalid PCI address: `{}`", invalid));
+    }
+  }
+
+  #[test]
+  fn resources () {
+    let fake = FakeSysfs::new("resources", "e1000e");
+    let sysfs = fake.sysfs();
+    let bdf: Bdf = BDF.parse().unwrap_or_else(|_| panic!());
+    assert!(sysfs.exists(&bdf));
+    assert!(sysfs.resources(&bdf).unwrap() == vec![
+      Resource::Memory { start: 0xf000_0000, size: 0x100_0000 },
+      Resource::Ports { start: 0xe000, size: 0x20 }
+    ]);
+    assert_eq!(sysfs.irq(&bdf).unwrap(), 16);
+  }
+
+  #[test]
+  fn assignable () {
+    let fake = FakeSysfs::new("assignable", "e1000e");
+    let sysfs = fake.sysfs();
+    let store = MemoryStore::new();
+    let bdf: Bdf = BDF.parse().unwrap_or_else(|_| panic!());
+
+    assert_eq!(error(remove_assignable(&sysfs, &store, &bdf, true)), format!("PCI device {} is not assignable", BDF));
+    assert_eq!(error(make_assignable(&sysfs, &store, &"04:00.0".parse().unwrap_or_else(|_| panic!()))), "no such PCI device: 0000:04:00.0");
+
+    // Unbound from its driver, which is saved, and bound to pciback.
+    assert!(make_assignable(&sysfs, &store, &bdf).is_ok());
+    assert_eq!(fake.written("e1000e", "unbind").as_deref(), Some(BDF));
+    assert_eq!(fake.written(PCIBACK_DRIVER, "new_slot").as_deref(), Some(BDF));
+    assert_eq!(fake.written(PCIBACK_DRIVER, "bind").as_deref(), Some(BDF));
+    assert_eq!(store.read(&store_node(&bdf, "driver")).ok().as_deref(), Some("e1000e"));
+
+    fake.rebind(PCIBACK_DRIVER);
+    std::fs::create_dir(fake.root.join("bus/pci/drivers/pciback").join(BDF)).unwrap();
+    assert!(list_assignable(&sysfs) == vec![bdf]);
+    assert_eq!(sysfs.driver(&bdf).as_deref(), Some(PCIBACK_DRIVER));
+    assert!(make_assignable(&sysfs, &store, &bdf).is_ok());
+
+    // Attached to a domain until it is released.
+    assert!(store.write(&store_node(&bdf, "domain"), "5").is_ok());
+    assert!(store.write(&store_node(&bdf, "pirq"), "40").is_ok());
+    assert!(list(&store, 5) == vec![bdf]);
+    assert_eq!(error(remove_assignable(&sysfs, &store, &bdf, true)), format!("PCI device {} is attached to domain 5", BDF));
+    release_domain(&store, 5);
+    assert!(list(&store, 5).is_empty());
+
+    // Bound again to its driver.
+    assert!(remove_assignable(&sysfs, &store, &bdf, true).is_ok());
+    assert_eq!(fake.written(PCIBACK_DRIVER, "unbind").as_deref(), Some(BDF));
+    assert_eq!(fake.written(PCIBACK_DRIVER, "remove_slot").as_deref(), Some(BDF));
+    assert_eq!(fake.written("e1000e", "bind").as_deref(), Some(BDF));
+    assert!(store.read(&format!("{}/{}", STORE_PATH, BDF)).is_err());
+  }
+
+  // A QMP server replying `replies` in order, after its greeting.
+  struct FakeQmp {
+    replies: Cursor<Vec<u8>>,
+    requests: Rc<RefCell<Vec<u8>>>
+  }
+
+  impl FakeQmp {
+    fn connect (replies: &[Value]) -> (Qmp<Self>, Rc<RefCell<Vec<u8>>>) {
+      let mut lines = String::from("{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n{\"return\": {}, \"id\": 0}\n");
+      for reply in replies {
+        lines.push_str(&format!("{}\n", reply));
+      }
+      let requests = Rc::new(RefCell::new(Vec::new()));
+      let fake = Self { replies: Cursor::new(lines.into_bytes()), requests: requests.clone() };
+      (Qmp::new(fake).unwrap_or_else(|_| panic!()), requests)
+    }
+  }
+
+  impl Read for FakeQmp {
+    fn read (&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+      self.replies.read(buffer)
+    }
+  }
+
+  impl Write for FakeQmp {
+    fn write (&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+      self.requests.borrow_mut().write(buffer)
+    }
+
+    fn flush (&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn parse_requests (requests: &RefCell<Vec<u8>>) -> Vec<Value> {
+    requests.borrow().split(|byte| *byte == b'\n').filter(|line| !line.is_empty())
+      .map(|line| serde_json::from_slice(line).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn hvm_plug () {
+    let bdf: Bdf = BDF.parse().unwrap_or_else(|_| panic!());
+    let (mut qmp, requests) = FakeQmp::connect(&[json!({ "return": {}, "id": 1 })]);
+    let mut config = PciConfig::new(bdf);
+    config.permissive = true;
+    assert!(qemu_device_add(&mut qmp, &config).is_ok());
+    assert_eq!(parse_requests(&requests)[1], json!({
+      "execute": "device_add",
+      "id": 1,
+      "arguments": { "driver": QEMU_DRIVER, "id": "pci-pt-03_00.0", "hostaddr": BDF, "permissive": true }
+    }));
+  }
+
+  #[test]
+  fn hvm_unplug () {
+    let bdf: Bdf = BDF.parse().unwrap_or_else(|_| panic!());
+    let plugged = json!([{ "bus": 0, "devices": [{ "qdev_id": "" }, { "qdev_id": "pci-pt-03_00.0" }] }]);
+    let unplugged = json!([{ "bus": 0, "devices": [{ "qdev_id": "" }] }]);
+    assert!(has_qemu_device(&plugged, "pci-pt-03_00.0"));
+    assert!(!has_qemu_device(&unplugged, "pci-pt-03_00.0"));
+
+    // Removed once the guest released it.
+    let (mut qmp, requests) = FakeQmp::connect(&[
+      json!({ "return": {}, "id": 1 }),
+      json!({ "return": plugged, "id": 2 }),
+      json!({ "event": "DEVICE_DELETED", "data": { "device": "pci-pt-03_00.0" } }),
+      json!({ "return": unplugged, "id": 3 })
+    ]);
+    assert!(qemu_device_del(&mut qmp, &bdf, false, Duration::from_secs(5)).is_ok());
+    let requests = parse_requests(&requests);
+    assert_eq!(requests[1], json!({ "execute": "device_del", "id": 1, "arguments": { "id": "pci-pt-03_00.0" } }));
+    assert_eq!(requests[3], json!({ "execute": "query-pci", "id": 3 }));
+
+    let (mut qmp, _) = FakeQmp::connect(&[json!({ "return": {}, "id": 1 }), json!({ "return": plugged, "id": 2 })]);
+    assert_eq!(error(qemu_device_del(&mut qmp, &bdf, false, Duration::from_millis(0))), format!("timeout, PCI device {} is not released by the guest", BDF));
+
+    let (mut qmp, _) = FakeQmp::connect(&[json!({ "error": { "class": "DeviceNotFound", "desc": "no device" }, "id": 1 })]);
+    assert_eq!(error(qemu_device_del(&mut qmp, &bdf, true, Duration::from_secs(5))), "QMP command failed (DeviceNotFound): no device");
+  }
+}
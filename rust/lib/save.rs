@@ -0,0 +1,638 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::bindings;
+use super::console;
+use super::devicemodel::{self, qmp::{self, Qmp}};
+use super::foreignmemory::PAGE_SIZE;
+use super::migration::Decision;
+use super::vm::{self, ShutdownReason};
+use super::xenctrl::{self, Xenctrl};
+use super::xenstore::{self, Permission, PermissionKind, Xenstore};
+
+// =============================================================================
+// Save and restore of domains.
+//
+// A save file starts with SAVE_MAGIC, followed by the length (u32, LE) and
+// the JSON of a `SaveConfig` used to create the domain on restore, followed
+// by the libxc migration stream v2.
+//
+// The device model (QEMU) of an HVM domain is stopped with the domain and
+// saves the state of the emulated devices. This state follows the stream, or
+// each checkpoint of a checkpointed stream: its length (u64, LE), then the
+// data written by QEMU. On restore, it is written in a file given to the new
+// device model.
+// =============================================================================
+
+pub const SAVE_MAGIC: &[u8; 16] = b"xenops-ng save\n\0";
+
+const SUSPEND_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Limits given to the restored domains.
+const MAX_EVTCHN_PORT: u32 = 1023;
+const MAX_GRANT_FRAMES: u32 = 64;
+const MAX_MAPTRACK_FRAMES: u32 = 1024;
+
+// The devices state of QEMU is small: the RAM of the guest is not in it.
+const MAX_DEVICES_STATE: u64 = 64 * 1024 * 1024;
+
+// The JSON of a `SaveConfig` is far smaller, the length is checked before the
+// allocation because the header can come from the network.
+const MAX_HEADER_LENGTH: u32 = 1024 * 1024;
+
+// -----------------------------------------------------------------------------
+
+pub enum Error {
+  Xen(xenctrl::Error),
+  Xenstore(&'static str),
+  Io(std::io::Error),
+  DeviceModel(qmp::Error),
+  InvalidHeader(String),
+  InvalidDevicesState(u64),
+  NoSuchDomain(u32),
+  SuspendTimeout
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Xen(e) => write!(f, "{}", e),
+      Error::Xenstore(details) => write!(f, "xenstore error: {}", details),
+      Error::Io(e) => write!(f, "{}", e),
+      Error::DeviceModel(e) => write!(f, "device model error: {}", e),
+      Error::InvalidHeader(details) => write!(f, "invalid save file: {}", details),
+      Error::InvalidDevicesState(size) => write!(f, "invalid devices state of {} bytes", size),
+      Error::NoSuchDomain(dom_id) => write!(f, "no such domain: {}", dom_id),
+      Error::SuspendTimeout => write!(f, "domain did not suspend in time")
+    }
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Xen(e)
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<qmp::Error> for Error {
+  fn from (e: qmp::Error) -> Self {
+    Error::DeviceModel(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn xenstore_error (details: &'static str) -> impl FnOnce(xenstore::Error) -> Error {
+  move |_| Error::Xenstore(details)
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SaveConfig {
+  pub name: String,
+  pub uuid: String,
+  pub hvm: bool,
+  pub max_vcpus: u32,
+  pub max_memkb: u64,
+  // The stream is a sequence of checkpoints, see `checkpoint`.
+  #[serde(default)]
+  pub checkpointed: bool,
+  // The devices state of the device model follows the stream.
+  #[serde(default)]
+  pub device_model: bool
+}
+
+impl SaveConfig {
+  pub fn from_domain (xc: &Xenctrl, xs: &Xenstore, dom_id: u32) -> Result<Self> {
+    let info = xc.get_domain_info(dom_id)?;
+    Ok(Self {
+      name: vm::get_name(xs, dom_id).unwrap_or_default(),
+      uuid: xenctrl::get_uuid_from_domain_handle(&info.handle),
+      hvm: info.flags & (1 << bindings::_XEN_DOMINF_hvm_guest) != 0,
+      max_vcpus: info.max_vcpu_id + 1,
+      max_memkb: info.max_pages * (PAGE_SIZE / 1024) as u64,
+      checkpointed: false,
+      device_model: false
+    })
+  }
+}
+
+pub fn write_header<W: Write> (writer: &mut W, config: &SaveConfig) -> Result<()> {
+  let config = serde_json::to_vec(config).map_err(|e| Error::InvalidHeader(e.to_string()))?;
+  writer.write_all(SAVE_MAGIC)?;
+  writer.write_all(&(config.len() as u32).to_le_bytes())?;
+  writer.write_all(&config)?;
+  Ok(())
+}
+
+// Read the header, the reader is then at the start of the migration stream.
+pub fn read_header<R: Read> (reader: &mut R) -> Result<SaveConfig> {
+  let mut magic = [0u8; 16];
+  reader.read_exact(&mut magic)?;
+  if &magic != SAVE_MAGIC {
+    return Err(Error::InvalidHeader(String::from("bad magic")))
+  }
+
+  let mut length = [0u8; 4];
+  reader.read_exact(&mut length)?;
+  let length = u32::from_le_bytes(length);
+  if length > MAX_HEADER_LENGTH {
+    return Err(Error::InvalidHeader(format!("config of {} bytes", length)))
+  }
+  let mut config = vec![0u8; length as usize];
+  reader.read_exact(&mut config)?;
+  serde_json::from_slice(&config).map_err(|e| Error::InvalidHeader(e.to_string()))
+}
+
+// =============================================================================
+// Save.
+// =============================================================================
+
+// Ask the guest to suspend through xenstore and wait for it.
+pub fn suspend (xc: &Xenctrl, xs: &Xenstore, dom_id: u32) -> Result<()> {
+  vm::shutdown(xs, dom_id, ShutdownReason::Suspend).map_err(xenstore_error("failed to request suspend"))?;
+
+  let start = Instant::now();
+  loop {
+    let info = xc.get_domain_info(dom_id)?;
+    if let Some(ShutdownReason::Suspend) = ShutdownReason::from_domain_info(&info) {
+      return Ok(())
+    }
+    if start.elapsed() > SUSPEND_TIMEOUT {
+      return Err(Error::SuspendTimeout)
+    }
+    std::thread::sleep(POLL_INTERVAL);
+  }
+}
+
+// Called by libxc during a live save: receives the pre-copy iteration and the
+// number of pages to send in the next one.
+pub type PrecopyPolicy<'a> = dyn FnMut(u32, u64) -> Decision + 'a;
+
+// Called after each checkpoint with the time the domain was suspended.
+// Returns false to stop the checkpoints.
+pub type CheckpointPolicy<'a> = dyn FnMut(Duration) -> bool + 'a;
+
+pub(crate) enum SaveMode<'a, 'b> {
+  // The domain is suspended then saved.
+  Offline,
+  // Pre-copy iterations while the domain runs, then stop-and-copy.
+  Live(&'a mut PrecopyPolicy<'b>),
+  // A live save with the default pre-copy of libxc, then a checkpoint after
+  // each call to the policy: the domain is suspended, its dirty pages are
+  // sent and it is resumed.
+  Checkpointed(&'a mut CheckpointPolicy<'b>)
+}
+
+struct CallbackData<'a, 'b> {
+  xc: &'a Xenctrl,
+  xs: &'a Xenstore,
+  dom_id: u32,
+  fd: RawFd,
+  mode: SaveMode<'a, 'b>,
+  device_model: Option<&'a mut Qmp<UnixStream>>,
+  suspended_at: Option<Instant>
+}
+
+// The device model is stopped with the domain, then saves its devices state.
+fn save_devices_state (qmp: &mut Qmp<UnixStream>, dom_id: u32) -> Result<()> {
+  qmp.stop()?;
+  Ok(qmp.save_devices_state(&devicemodel::state_path(dom_id), false)?)
+}
+
+// Append the devices state saved at the suspend to the stream.
+fn write_devices_state (fd: RawFd, dom_id: u32) -> Result<()> {
+  let path = devicemodel::state_path(dom_id);
+  let state = std::fs::read(&path);
+  let _ = std::fs::remove_file(&path);
+  let state = state?;
+
+  // The file descriptor is owned by the caller.
+  let mut output = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+  output.write_all(&(state.len() as u64).to_le_bytes())?;
+  output.write_all(&state)?;
+  Ok(())
+}
+
+// libxc expects 1 on success.
+extern "C" fn suspend_callback (data: *mut libc::c_void) -> libc::c_int {
+  let data = unsafe { &mut *(data as *mut CallbackData) };
+  let result = suspend(data.xc, data.xs, data.dom_id).and_then(|_| match data.device_model.as_mut() {
+    Some(qmp) => save_devices_state(qmp, data.dom_id),
+    None => Ok(())
+  });
+  match result {
+    Ok(_) => {
+      data.suspended_at = Some(Instant::now());
+      1
+    },
+    Err(e) => {
+      eprintln!("Failed to suspend domain {}: {}", data.dom_id, e);
+      0
+    }
+  }
+}
+
+// The device model tracks the pages it writes during a live save, like the
+// guest. libxc expects 0 on success.
+extern "C" fn switch_qemu_logdirty_callback (dom_id: u32, enable: libc::c_uint, data: *mut libc::c_void) -> libc::c_int {
+  let data = unsafe { &mut *(data as *mut CallbackData) };
+  let qmp = match data.device_model.as_mut() {
+    Some(qmp) => qmp,
+    None => return 0
+  };
+  match qmp.set_global_dirty_log(enable != 0) {
+    Ok(_) => 0,
+    Err(e) => {
+      eprintln!("Failed to switch the log-dirty mode of the device model of domain {}: {}", dom_id, e);
+      -1
+    }
+  }
+}
+
+extern "C" fn precopy_policy_callback (stats: bindings::precopy_stats, data: *mut libc::c_void) -> libc::c_int {
+  let data = unsafe { &mut *(data as *mut CallbackData) };
+
+  // libxc also asks between the send of an iteration and the read of the
+  // dirty bitmap, the count is unknown (-1) at this time.
+  if stats.dirty_count < 0 {
+    return bindings::XGS_POLICY_CONTINUE_PRECOPY as _
+  }
+
+  let decision = match &mut data.mode {
+    SaveMode::Live(policy) => policy(stats.iteration, stats.dirty_count as u64),
+    _ => Decision::StopAndCopy
+  };
+  match decision {
+    Decision::ContinuePrecopy => bindings::XGS_POLICY_CONTINUE_PRECOPY as _,
+    Decision::StopAndCopy => bindings::XGS_POLICY_STOP_AND_COPY as _,
+    Decision::Abort => bindings::XGS_POLICY_ABORT as _
+  }
+}
+
+fn resume_after_checkpoint (data: &mut CallbackData) -> Result<()> {
+  if data.device_model.is_some() {
+    write_devices_state(data.fd, data.dom_id)?;
+  }
+  data.xc.resume_domain(data.dom_id, true)?;
+  if let Some(qmp) = data.device_model.as_mut() {
+    qmp.cont()?;
+  }
+  Ok(())
+}
+
+// Checkpoint sent: append its devices state and resume the domain.
+extern "C" fn postcopy_callback (data: *mut libc::c_void) -> libc::c_int {
+  let data = unsafe { &mut *(data as *mut CallbackData) };
+  match resume_after_checkpoint(data) {
+    Ok(_) => 1,
+    Err(e) => {
+      eprintln!("Failed to resume domain {} after a checkpoint: {}", data.dom_id, e);
+      0
+    }
+  }
+}
+
+extern "C" fn checkpoint_callback (data: *mut libc::c_void) -> libc::c_int {
+  let data = unsafe { &mut *(data as *mut CallbackData) };
+  let pause = data.suspended_at.take().map(|suspended_at| suspended_at.elapsed()).unwrap_or_default();
+  match &mut data.mode {
+    SaveMode::Checkpointed(policy) => policy(pause) as _,
+    _ => 0
+  }
+}
+
+// Write the migration stream of a domain in `fd`, followed by the devices
+// state if `device_model` is given.
+pub(crate) fn save_stream (
+  xc: &Xenctrl,
+  xs: &Xenstore,
+  dom_id: u32,
+  fd: RawFd,
+  hvm: bool,
+  mode: SaveMode,
+  device_model: Option<&mut Qmp<UnixStream>>
+) -> Result<()> {
+  let (flags, checkpointed) = match mode {
+    SaveMode::Offline => (0, false),
+    SaveMode::Live(_) => (bindings::XCFLAGS_LIVE, false),
+    SaveMode::Checkpointed(_) => (bindings::XCFLAGS_LIVE, true)
+  };
+  let live_policy = matches!(mode, SaveMode::Live(_));
+
+  let mut data = CallbackData { xc, xs, dom_id, fd, mode, device_model, suspended_at: None };
+  let mut callbacks = bindings::save_callbacks {
+    suspend: Some(suspend_callback),
+    switch_qemu_logdirty: Some(switch_qemu_logdirty_callback),
+    // Without policy, libxc uses its own.
+    precopy_policy: if live_policy { Some(precopy_policy_callback) } else { None },
+    postcopy: Some(postcopy_callback),
+    checkpoint: Some(checkpoint_callback),
+    data: &mut data as *mut CallbackData as *mut libc::c_void,
+    ..Default::default()
+  };
+
+  xc.save_domain(fd, dom_id, flags as _, &mut callbacks, hvm, checkpointed)?;
+
+  // Each checkpoint is followed by its devices state in `postcopy_callback`.
+  if data.device_model.is_some() && !checkpointed {
+    write_devices_state(fd, dom_id)?;
+  }
+  Ok(())
+}
+
+// Save a domain in a file, the domain is destroyed once saved. Its device
+// model, if given, saves the devices state in the file; it is stopped by its
+// supervisor once the domain is destroyed.
+pub fn save (
+  xc: &Xenctrl,
+  xs: &Xenstore,
+  dom_id: u32,
+  path: &str,
+  device_model: Option<&mut Qmp<UnixStream>>
+) -> Result<()> {
+  let mut config = SaveConfig::from_domain(xc, xs, dom_id)?;
+  config.device_model = device_model.is_some();
+  let mut file = File::create(path)?;
+  write_header(&mut file, &config)?;
+  file.flush()?;
+
+  save_stream(xc, xs, dom_id, file.as_raw_fd(), config.hvm, SaveMode::Offline, device_model)?;
+  file.sync_all()?;
+
+  Ok(vm::destroy(xc, xs, dom_id)?)
+}
+
+// =============================================================================
+// Restore.
+// =============================================================================
+
+pub(crate) fn create_domain (xc: &Xenctrl, config: &SaveConfig) -> Result<u32> {
+  let uuid = Uuid::parse_str(&config.uuid).map_err(|e| Error::InvalidHeader(e.to_string()))?;
+
+  let mut create = xenctrl::DomainCreateConfig {
+    handle: *uuid.as_bytes(),
+    max_vcpus: config.max_vcpus,
+    max_evtchn_port: MAX_EVTCHN_PORT,
+    max_grant_frames: MAX_GRANT_FRAMES,
+    max_maptrack_frames: MAX_MAPTRACK_FRAMES,
+    ..Default::default()
+  };
+  if config.hvm {
+    create.flags = (1 << bindings::_XEN_DOMCTL_CDF_hvm_guest) | (1 << bindings::_XEN_DOMCTL_CDF_hap);
+    create.arch.emulation_flags = bindings::XEN_X86_EMU_ALL & !bindings::XEN_X86_EMU_VPCI;
+  }
+
+  let dom_id = xc.create_domain(&mut create)?;
+  if let Err(e) = xc.set_max_mem(dom_id, config.max_memkb) {
+    let _ = xc.destroy_domain(dom_id);
+    return Err(e.into())
+  }
+  Ok(dom_id)
+}
+
+fn introduce_domain (
+  xs: &Xenstore,
+  dom_id: u32,
+  config: &SaveConfig,
+  store: (u64, u32),
+  console: (u64, u32)
+) -> Result<()> {
+  let path = xs.get_domain_path(dom_id);
+  let write = |key: &str, value: &str| {
+    xs.write(&format!("{}/{}", path, key), value).map_err(xenstore_error("failed to write domain nodes"))
+  };
+
+  xs.mkdir(&path).map_err(xenstore_error("failed to create domain path"))?;
+  xs.set_permissions(&path, &[Permission::new(0, PermissionKind::None), Permission::new(dom_id, PermissionKind::Read)])
+    .map_err(xenstore_error("failed to set domain path permissions"))?;
+
+  write("name", &config.name)?;
+  write("domid", &dom_id.to_string())?;
+  write("memory/static-max", &config.max_memkb.to_string())?;
+  write("memory/target", &config.max_memkb.to_string())?;
+  write("store/ring-ref", &store.0.to_string())?;
+  write("store/port", &store.1.to_string())?;
+  console::write_nodes(xs, dom_id, &config.uuid, console.0, console.1)
+    .map_err(xenstore_error("failed to write console nodes"))?;
+
+  // Directories owned by the guest.
+  for directory in &["control", "data", "device"] {
+    let directory = format!("{}/{}", path, directory);
+    xs.mkdir(&directory).map_err(xenstore_error("failed to create domain directory"))?;
+    xs.set_permissions(&directory, &[Permission::new(dom_id, PermissionKind::None)])
+      .map_err(xenstore_error("failed to set domain directory permissions"))?;
+  }
+
+  xs.introduce_domain(dom_id, store.0, store.1).map_err(xenstore_error("failed to introduce domain"))
+}
+
+// A restored domain, paused and unknown to xenstore until activated.
+pub struct RestoredDomain {
+  pub dom_id: u32,
+  pub config: SaveConfig,
+  // File of the devices state to give to the new device model, see
+  // `DeviceModelConfig::restore`.
+  pub devices_state: Option<PathBuf>,
+  store: (u64, u32),
+  console: (u64, u32)
+}
+
+impl RestoredDomain {
+  // Start the domain. It is destroyed on failure. With a devices state, the
+  // domain stays paused: it is unpaused once its device model is started.
+  pub fn activate (self, xc: &Xenctrl, xs: &Xenstore) -> Result<u32> {
+    let result = introduce_domain(xs, self.dom_id, &self.config, self.store, self.console)
+      .and_then(|_| match self.devices_state {
+        Some(_) => Ok(()),
+        None => Ok(xc.unpause_domain(self.dom_id)?)
+      });
+    match result {
+      Ok(_) => Ok(self.dom_id),
+      Err(e) => {
+        let _ = self.discard(xc, xs);
+        Err(e)
+      }
+    }
+  }
+
+  pub fn discard (self, xc: &Xenctrl, xs: &Xenstore) -> Result<()> {
+    if let Some(path) = &self.devices_state {
+      let _ = std::fs::remove_file(path);
+    }
+    Ok(vm::destroy(xc, xs, self.dom_id)?)
+  }
+}
+
+// A domain which is not in Xen, for the tests of the users.
+#[cfg(test)]
+impl RestoredDomain {
+  pub(crate) fn simulated (dom_id: u32, config: SaveConfig, devices_state: Option<PathBuf>) -> Self {
+    Self { dom_id, config, devices_state, store: (0, 0), console: (0, 0) }
+  }
+}
+
+pub(crate) fn read_devices_state (fd: RawFd) -> Result<Vec<u8>> {
+  // The file descriptor is owned by the caller.
+  let mut input = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+  let mut size = [0u8; 8];
+  input.read_exact(&mut size)?;
+  let size = u64::from_le_bytes(size);
+  if size > MAX_DEVICES_STATE {
+    return Err(Error::InvalidDevicesState(size))
+  }
+
+  let mut state = vec![0u8; size as usize];
+  input.read_exact(&mut state)?;
+  Ok(state)
+}
+
+struct RestoreData {
+  fd: RawFd,
+  device_model: bool,
+  // Devices state of the last complete checkpoint.
+  devices_state: Option<Vec<u8>>
+}
+
+// Called by libxc after each checkpoint of a checkpointed stream, its devices
+// state follows. A checkpoint without it can't be restored.
+extern "C" fn restore_checkpoint_callback (data: *mut libc::c_void) -> libc::c_int {
+  let data = unsafe { &mut *(data as *mut RestoreData) };
+  if !data.device_model {
+    return bindings::XGR_CHECKPOINT_SUCCESS as _
+  }
+  match read_devices_state(data.fd) {
+    Ok(state) => {
+      data.devices_state = Some(state);
+      bindings::XGR_CHECKPOINT_SUCCESS as _
+    },
+    Err(e) => {
+      eprintln!("Failed to read the devices state of a checkpoint: {}", e);
+      bindings::XGR_CHECKPOINT_ERROR as _
+    }
+  }
+}
+
+// Read the migration stream from `fd` in a domain created with `create_domain`.
+pub(crate) fn restore_paused (xc: &Xenctrl, fd: RawFd, dom_id: u32, config: &SaveConfig) -> Result<RestoredDomain> {
+  let store_port = xc.alloc_unbound_evtchn(dom_id, 0)?;
+  let console_port = xc.alloc_unbound_evtchn(dom_id, 0)?;
+
+  let mut data = RestoreData { fd, device_model: config.device_model, devices_state: None };
+  let mut callbacks = bindings::restore_callbacks {
+    checkpoint: Some(restore_checkpoint_callback),
+    data: &mut data as *mut RestoreData as *mut libc::c_void,
+    ..Default::default()
+  };
+  let (store_mfn, console_mfn) = xc.restore_domain(
+    fd, dom_id, store_port, console_port, config.hvm, config.checkpointed, &mut callbacks
+  )?;
+
+  let devices_state = if config.device_model {
+    let state = match data.devices_state.take() {
+      Some(state) => state,
+      None => read_devices_state(fd)?
+    };
+    let path = devicemodel::state_path(dom_id);
+    std::fs::create_dir_all(devicemodel::QMP_DIR)?;
+    std::fs::write(&path, state)?;
+    Some(path)
+  } else {
+    None
+  };
+
+  Ok(RestoredDomain {
+    dom_id,
+    config: config.clone(),
+    devices_state,
+    store: (store_mfn, store_port),
+    console: (console_mfn, console_port)
+  })
+}
+
+// Create a domain from the migration stream read in `fd` and start it.
+// Returns the new domain id and its devices state, see `RestoredDomain`.
+pub(crate) fn restore_stream (
+  xc: &Xenctrl,
+  xs: &Xenstore,
+  fd: RawFd,
+  config: &SaveConfig
+) -> Result<(u32, Option<PathBuf>)> {
+  let dom_id = create_domain(xc, config)?;
+  match restore_paused(xc, fd, dom_id, config) {
+    Ok(domain) => {
+      let devices_state = domain.devices_state.clone();
+      Ok((domain.activate(xc, xs)?, devices_state))
+    },
+    Err(e) => {
+      let _ = vm::destroy(xc, xs, dom_id);
+      Err(e)
+    }
+  }
+}
+
+// Create a domain from a save file and start it. Returns the new domain id
+// and its devices state, see `RestoredDomain`. A checkpoint file is restored
+// from its last complete checkpoint.
+pub fn restore (xc: &Xenctrl, xs: &Xenstore, path: &str) -> Result<(u32, Option<PathBuf>)> {
+  let mut file = File::open(path)?;
+  let config = read_header(&mut file)?;
+  restore_stream(xc, xs, file.as_raw_fd(), &config)
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::error;
+  use std::io::Cursor;
+
+  #[test]
+  fn header () {
+    let config = SaveConfig {
+      name: String::from("vm"),
+      uuid: String::from("3a5ebd8e-1cbb-4bde-a8e6-8bbc4f1ad9a6"),
+      hvm: true,
+      max_vcpus: 2,
+      max_memkb: 1024 * 1024,
+      checkpointed: false,
+      device_model: true
+    };
+    let mut file = Vec::new();
+    write_header(&mut file, &config).ok().unwrap();
+    file.extend_from_slice(b"stream");
+
+    let mut reader = Cursor::new(file);
+    let read = read_header(&mut reader).ok().unwrap();
+    assert_eq!(read.uuid, config.uuid);
+    assert!(read.hvm && read.device_model && !read.checkpointed);
+    assert_eq!(&reader.get_ref()[reader.position() as usize..], b"stream");
+  }
+
+  #[test]
+  fn header_length () {
+    let mut file = SAVE_MAGIC.to_vec();
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(error(read_header(&mut Cursor::new(file))), "invalid save file: config of 4294967295 bytes");
+
+    let mut file = b"xenops-ng save\n\x01".to_vec();
+    file.extend_from_slice(&0u32.to_le_bytes());
+    assert_eq!(error(read_header(&mut Cursor::new(file))), "invalid save file: bad magic");
+  }
+}
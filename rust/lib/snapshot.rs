@@ -0,0 +1,309 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::bindings;
+use super::foreignmemory::{ForeignMemory, PAGE_SIZE};
+use super::logdirty;
+use super::migration_stream::{self, DomainHeader, DomainType, PageData, StreamWriter};
+use super::save::{self, SaveConfig};
+use super::vcpu_context;
+use super::xenctrl::{self, Xenctrl};
+use super::xenstore::Xenstore;
+
+// =============================================================================
+// Memory snapshots of running HVM domains.
+//
+// Xen can't copy guest frames on write, so the memory is copied while the
+// domain runs with log-dirty mode, and the pages written meanwhile are copied
+// again. Once few pages remain dirty, the domain is paused: the last dirty
+// pages, the HVM context, the HVM params and the TSC info are written, then
+// the domain continues.
+//
+// The image is the state of the domain at this final pause, not at the start
+// of the snapshot: the previous content of the pages written during the copy
+// is lost. The time of the pause is returned with the statistics.
+//
+// The image is a save file (see `save`) which can be restored like any other.
+// The state of the device model is not part of it.
+// =============================================================================
+
+pub enum Error {
+  Xen(xenctrl::Error),
+  Io(std::io::Error),
+  Save(save::Error),
+  Stream(migration_stream::Error),
+  NotHvm(u32)
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Xen(e) => write!(f, "{}", e),
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Save(e) => write!(f, "{}", e),
+      Error::Stream(e) => write!(f, "{}", e),
+      Error::NotHvm(dom_id) => write!(f, "domain {} is not a HVM guest", dom_id)
+    }
+  }
+}
+
+impl From<xenctrl::Error> for Error {
+  fn from (e: xenctrl::Error) -> Self {
+    Error::Xen(e)
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+impl From<save::Error> for Error {
+  fn from (e: save::Error) -> Self {
+    Error::Save(e)
+  }
+}
+
+impl From<migration_stream::Error> for Error {
+  fn from (e: migration_stream::Error) -> Self {
+    Error::Stream(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+// Pages of a PAGE_DATA record, like libxc.
+const BATCH_PAGES: usize = 1024;
+
+// The domain is paused when less pages are dirty...
+const MAX_FINAL_DIRTY_PAGES: u64 = 256;
+
+// ... or after this count of copies while it runs.
+const MAX_ITERATIONS: u32 = 5;
+
+// Params saved by libxc for HVM guests.
+const HVM_PARAMS: &[u32] = &[
+  bindings::HVM_PARAM_STORE_PFN,
+  bindings::HVM_PARAM_IOREQ_PFN,
+  bindings::HVM_PARAM_BUFIOREQ_PFN,
+  bindings::HVM_PARAM_PAGING_RING_PFN,
+  bindings::HVM_PARAM_MONITOR_RING_PFN,
+  bindings::HVM_PARAM_SHARING_RING_PFN,
+  bindings::HVM_PARAM_VM86_TSS_SIZED,
+  bindings::HVM_PARAM_CONSOLE_PFN,
+  bindings::HVM_PARAM_ACPI_IOPORTS_LOCATION,
+  bindings::HVM_PARAM_VIRIDIAN,
+  bindings::HVM_PARAM_IDENT_PT,
+  bindings::HVM_PARAM_VM_GENERATION_ID_ADDR,
+  bindings::HVM_PARAM_IOREQ_SERVER_PFN,
+  bindings::HVM_PARAM_NR_IOREQ_SERVER_PAGES,
+  bindings::HVM_PARAM_X87_FIP_WIDTH,
+  bindings::HVM_PARAM_MCA_CAP
+];
+
+#[derive(Clone, Copy, Serialize)]
+pub struct SnapshotStats {
+  // Copies of the dirty pages, the first one is the whole memory.
+  pub iterations: u32,
+  pub pages_written: u64,
+  pub pause_ms: u64,
+  // Seconds since the epoch of the final pause: the time of the image.
+  pub time: u64
+}
+
+// -----------------------------------------------------------------------------
+
+// Body of a TSC_INFO record.
+pub fn tsc_info_record (info: &xenctrl::TscInfo) -> Vec<u8> {
+  let mut body = Vec::with_capacity(24);
+  body.extend_from_slice(&info.mode.to_le_bytes());
+  body.extend_from_slice(&info.khz.to_le_bytes());
+  body.extend_from_slice(&info.elapsed_nsec.to_le_bytes());
+  body.extend_from_slice(&info.incarnation.to_le_bytes());
+  body.extend_from_slice(&0u32.to_le_bytes());
+  body
+}
+
+// Body of a HVM_PARAMS record, from (index, value) pairs.
+pub fn hvm_params_record (params: &[(u32, u64)]) -> Vec<u8> {
+  let mut body = Vec::with_capacity(8 + params.len() * 16);
+  body.extend_from_slice(&(params.len() as u32).to_le_bytes());
+  body.extend_from_slice(&0u32.to_le_bytes());
+  for (index, value) in params {
+    body.extend_from_slice(&u64::from(*index).to_le_bytes());
+    body.extend_from_slice(&value.to_le_bytes());
+  }
+  body
+}
+
+// Write the given pages in PAGE_DATA records. Pages which can't be mapped are
+// holes. Returns the count of pages written with their data.
+fn write_pages<W: Write, I: IntoIterator<Item = u64>> (
+  fmem: &ForeignMemory,
+  dom_id: u32,
+  stream: &mut StreamWriter<W>,
+  pfns: I
+) -> Result<u64> {
+  let mut written = 0;
+  let mut pfns = pfns.into_iter().peekable();
+
+  while pfns.peek().is_some() {
+    let batch: Vec<u64> = pfns.by_ref().take(BATCH_PAGES).collect();
+    let (mapping, errors) = fmem.map_sparse(dom_id, &batch, false)?;
+    let memory = mapping.as_slice();
+
+    let mut page_data = PageData { pages: Vec::with_capacity(batch.len()), data: Vec::new() };
+    for (i, (pfn, error)) in batch.iter().zip(&errors).enumerate() {
+      if *error != 0 {
+        page_data.pages.push((*pfn, migration_stream::PAGE_TYPE_XTAB));
+        continue
+      }
+      page_data.pages.push((*pfn, migration_stream::PAGE_TYPE_NOTAB));
+      page_data.data.extend_from_slice(&memory[i * PAGE_SIZE..(i + 1) * PAGE_SIZE]);
+      written += 1;
+    }
+
+    stream.write_record(migration_stream::REC_TYPE_PAGE_DATA, &page_data.to_bytes())?;
+  }
+
+  Ok(written)
+}
+
+// Last records of the image, written while the domain is paused.
+fn write_domain_state<W: Write> (xc: &Xenctrl, dom_id: u32, stream: &mut StreamWriter<W>) -> Result<()> {
+  stream.write_record(migration_stream::REC_TYPE_X86_TSC_INFO, &tsc_info_record(&xc.get_tsc_info(dom_id)?))?;
+  stream.write_record(migration_stream::REC_TYPE_HVM_CONTEXT, &xc.get_hvm_context(dom_id)?)?;
+
+  let mut params = Vec::new();
+  for index in HVM_PARAMS {
+    let value = xc.get_hvm_param(dom_id, *index)?;
+    if value != 0 {
+      params.push((*index, value));
+    }
+  }
+  stream.write_record(migration_stream::REC_TYPE_HVM_PARAMS, &hvm_params_record(&params))?;
+  Ok(())
+}
+
+fn write_snapshot<W: Write> (
+  xc: &Xenctrl,
+  fmem: &ForeignMemory,
+  dom_id: u32,
+  pages: u64,
+  stream: &mut StreamWriter<W>
+) -> Result<SnapshotStats> {
+  let mut stats = SnapshotStats { iterations: 1, pages_written: 0, pause_ms: 0, time: 0 };
+
+  // The pages written from now on will be copied again.
+  logdirty::clean_dirty(xc, dom_id, pages)?;
+  stats.pages_written += write_pages(fmem, dom_id, stream, 0..pages)?;
+
+  let mut dirty = logdirty::clean_dirty(xc, dom_id, pages)?;
+  while dirty.count() > MAX_FINAL_DIRTY_PAGES && stats.iterations < MAX_ITERATIONS {
+    stats.pages_written += write_pages(fmem, dom_id, stream, &dirty)?;
+    dirty = logdirty::clean_dirty(xc, dom_id, pages)?;
+    stats.iterations += 1;
+  }
+
+  let was_paused = vcpu_context::is_paused(xc, dom_id)?;
+  if !was_paused {
+    xc.pause_domain(dom_id)?;
+  }
+  let paused_at = Instant::now();
+  stats.time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+
+  let result = logdirty::clean_dirty(xc, dom_id, pages).map_err(Error::from).and_then(|last| {
+    dirty.merge(&last);
+    stats.pages_written += write_pages(fmem, dom_id, stream, &dirty)?;
+    write_domain_state(xc, dom_id, stream)
+  });
+
+  // The image is complete even if the domain stays paused.
+  if !was_paused {
+    if let Err(e) = xc.unpause_domain(dom_id) {
+      eprintln!("Failed to unpause domain {} after its snapshot: {}", dom_id, e);
+    }
+  }
+  stats.pause_ms = paused_at.elapsed().as_millis() as u64;
+  result?;
+
+  stream.write_record(migration_stream::REC_TYPE_END, &[])?;
+  Ok(stats)
+}
+
+// Write a memory snapshot of a running HVM domain in `path`, its state at the
+// end of the copy. Log-dirty mode is used during the snapshot, so it fails if
+// a migration is in progress.
+pub fn snapshot_memory (
+  xc: &Xenctrl,
+  xs: &Xenstore,
+  fmem: &ForeignMemory,
+  dom_id: u32,
+  path: &str
+) -> Result<SnapshotStats> {
+  if !vcpu_context::is_hvm(xc, dom_id)? {
+    return Err(Error::NotHvm(dom_id))
+  }
+
+  let config = SaveConfig::from_domain(xc, xs, dom_id)?;
+  let (xen_major, xen_minor) = xc.get_xen_version()?;
+  let pages = xc.get_maximum_gpfn(dom_id)? + 1;
+
+  let mut file = BufWriter::new(File::create(path)?);
+  save::write_header(&mut file, &config)?;
+  let mut stream = StreamWriter::new(file, &DomainHeader {
+    domain_type: DomainType::X86Hvm,
+    page_shift: migration_stream::PAGE_SHIFT,
+    xen_major,
+    xen_minor
+  })?;
+
+  xc.enable_logdirty(dom_id)?;
+  let result = write_snapshot(xc, fmem, dom_id, pages, &mut stream);
+  let disabled = xc.disable_logdirty(dom_id);
+
+  let stats = result?;
+  disabled?;
+
+  let file = stream.into_inner().into_inner().map_err(|e| e.into_error())?;
+  file.sync_all()?;
+  Ok(stats)
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tsc_info () {
+    let info = xenctrl::TscInfo { mode: 1, elapsed_nsec: 0x0102_0304_0506_0708, khz: 2_796_000, incarnation: 3 };
+    let body = tsc_info_record(&info);
+    assert_eq!(body, vec![
+      1, 0, 0, 0, 0xe0, 0xa9, 0x2a, 0x00, 8, 7, 6, 5, 4, 3, 2, 1, 3, 0, 0, 0, 0, 0, 0, 0
+    ]);
+
+    let record = migration_stream::Record { record_type: migration_stream::REC_TYPE_X86_TSC_INFO, body };
+    assert!(migration_stream::validate_record(&record, DomainType::X86Hvm).is_ok());
+  }
+
+  #[test]
+  fn hvm_params () {
+    let body = hvm_params_record(&[(bindings::HVM_PARAM_STORE_PFN, 0xfeffc), (bindings::HVM_PARAM_VIRIDIAN, 1)]);
+    assert_eq!(body.len(), 8 + 2 * 16);
+    assert_eq!(&body[..8], &[2, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&body[8..16], &u64::from(bindings::HVM_PARAM_STORE_PFN).to_le_bytes());
+    assert_eq!(&body[16..24], &[0xfc, 0xef, 0x0f, 0, 0, 0, 0, 0]);
+    assert_eq!(&body[32..40], &[1, 0, 0, 0, 0, 0, 0, 0]);
+
+    let record = migration_stream::Record { record_type: migration_stream::REC_TYPE_HVM_PARAMS, body };
+    assert!(migration_stream::validate_record(&record, DomainType::X86Hvm).is_ok());
+    assert_eq!(hvm_params_record(&[]), vec![0; 8]);
+  }
+}
@@ -0,0 +1,395 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::bindings;
+use super::device::{vbd, vif};
+use super::foreignmemory::PAGE_SIZE;
+use super::xenctrl::{self, DomainInfo, Xenctrl};
+use super::xenstore::Store;
+
+pub mod rrd;
+
+use rrd::{Row, Rrd, Values};
+
+// =============================================================================
+// Statistics of the host and of its domains, sampled every SAMPLE_INTERVAL
+// and kept in the archives of `rrd`, in memory: they are lost when the daemon
+// stops, and the ones of a domain when it is destroyed.
+//
+// A sample has gauges and the rates of the counters since the previous one.
+// The CPU usages are fractions of a CPU, the memory is in bytes, the I/O in
+// bytes or requests per second:
+// - host: `cpu`, `cpu<N>` (physical CPUs), `memory_total`, `memory_free`;
+// - domain: `cpu`, `cpu<N>` (online vCPUs), `memory`, `memory_max`,
+//   `vbd_<vdev>_{read,write}`, `vbd_<vdev>_{read,write}_reqs`,
+//   `vif_<devid>_{rx,tx}`, `vif_<devid>_{rx,tx}_packets` (seen from the
+//   guest). The disks and interfaces are the ones served in dom0.
+// =============================================================================
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Range of a query without start.
+pub const DEFAULT_RANGE: u64 = 600;
+
+const SECTOR_SIZE: f64 = 512.0;
+
+// Delay until the next sample, aligned on SAMPLE_INTERVAL so that each
+// period of the finest archive has one sample.
+pub fn until_next_sample () -> Duration {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+  let interval = SAMPLE_INTERVAL.as_millis() as u64;
+  Duration::from_millis(interval - now.as_millis() as u64 % interval)
+}
+
+fn now () -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DomainState {
+  Running,
+  Blocked,
+  Paused,
+  Shutdown,
+  Dying
+}
+
+impl DomainState {
+  pub fn from_domain_info (info: &DomainInfo) -> Self {
+    let is_set = |flag: u32| info.flags & (1 << flag) != 0;
+    if is_set(bindings::_XEN_DOMINF_dying) {
+      DomainState::Dying
+    } else if is_set(bindings::_XEN_DOMINF_shutdown) {
+      DomainState::Shutdown
+    } else if is_set(bindings::_XEN_DOMINF_paused) {
+      DomainState::Paused
+    } else if is_set(bindings::_XEN_DOMINF_running) {
+      DomainState::Running
+    } else {
+      DomainState::Blocked
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+  // Seconds per row.
+  pub step: u64,
+  pub rows: Vec<Row>,
+  pub latest: Option<Row>
+}
+
+#[derive(Serialize)]
+pub struct DomainStats {
+  pub uuid: String,
+  pub state: DomainState,
+  #[serde(flatten)]
+  pub stats: Stats
+}
+
+// -----------------------------------------------------------------------------
+
+// Values read for a sample: the counters are converted in rates, multiplied
+// by their scale.
+#[derive(Default)]
+struct Sample {
+  gauges: Values,
+  counters: HashMap<String, (u64, f64)>
+}
+
+impl Sample {
+  fn gauge (&mut self, name: String, value: f64) {
+    self.gauges.insert(name, value);
+  }
+
+  fn counter (&mut self, name: String, value: u64, scale: f64) {
+    self.counters.insert(name, (value, scale));
+  }
+}
+
+#[derive(Default)]
+struct Series {
+  rrd: Rrd,
+  // Counters of the previous sample.
+  previous: Option<(Instant, HashMap<String, u64>)>,
+  latest: Option<Row>
+}
+
+impl Series {
+  // Gauges and rates of a sample. A counter has no rate in its first sample,
+  // nor when it was reset (a device plugged again).
+  fn rates (&mut self, now: Instant, sample: Sample) -> Values {
+    let mut values = sample.gauges;
+    if let Some((time, previous)) = &self.previous {
+      let elapsed = now.duration_since(*time).as_secs_f64();
+      for (name, (value, scale)) in &sample.counters {
+        match previous.get(name) {
+          Some(previous) if *previous <= *value && elapsed > 0.0 => {
+            values.insert(name.clone(), (value - previous) as f64 * scale / elapsed);
+          },
+          _ => ()
+        }
+      }
+    }
+    let counters = sample.counters.into_iter().map(|(name, (value, _))| (name, value)).collect();
+    self.previous = Some((now, counters));
+    values
+  }
+
+  fn record (&mut self, time: u64, values: Values) {
+    self.rrd.update(time, &values);
+    self.latest = Some(Row { time, values });
+  }
+
+  fn fetch (&self, start: Option<u64>, end: Option<u64>, step: Option<u64>) -> Result<Stats, String> {
+    let end = end.unwrap_or_else(now);
+    let start = start.unwrap_or_else(|| end.saturating_sub(DEFAULT_RANGE));
+    match self.rrd.fetch(start, end, step) {
+      Some(fetch) => Ok(Stats { step: fetch.step, rows: fetch.rows, latest: self.latest.clone() }),
+      None => Err(format!("no archive with a step of {} seconds", step.unwrap_or_default()))
+    }
+  }
+}
+
+// Usage of a CPU, from the rate of its time (busy or idle), which is a bit
+// off with the jitter of the samples.
+fn usage (rate: f64) -> f64 {
+  rate.max(0.0).min(1.0)
+}
+
+// Average of the `cpu<N>` values in `cpu`.
+fn average_cpu (values: &mut Values) {
+  let usages: Vec<f64> = values.iter()
+    .filter(|(name, _)| name.strip_prefix("cpu").map_or(false, |id| id.parse::<u32>().is_ok()))
+    .map(|(_, value)| *value)
+    .collect();
+  if !usages.is_empty() {
+    values.insert(String::from("cpu"), usages.iter().sum::<f64>() / usages.len() as f64);
+  }
+}
+
+// The rates of the CPU times in usages, from the idle times if `idle`.
+fn cpu_usages (values: &mut Values, idle: bool) {
+  for (_, value) in values.iter_mut().filter(|(name, _)| name.starts_with("cpu")) {
+    *value = usage(if idle { 1.0 - *value } else { *value });
+  }
+  average_cpu(values);
+}
+
+// -----------------------------------------------------------------------------
+
+struct DomainSeries {
+  uuid: String,
+  state: DomainState,
+  series: Series
+}
+
+pub struct Collector {
+  // Counters of blkback and netback.
+  sysfs: PathBuf,
+  host: Mutex<Series>,
+  domains: Mutex<HashMap<u32, DomainSeries>>
+}
+
+impl Default for Collector {
+  fn default () -> Self {
+    Self::new("/sys")
+  }
+}
+
+impl Collector {
+  pub fn new<P: AsRef<Path>> (sysfs: P) -> Self {
+    Self {
+      sysfs: sysfs.as_ref().to_path_buf(),
+      host: Mutex::new(Series::default()),
+      domains: Mutex::new(HashMap::new())
+    }
+  }
+
+  // Sample the host and its domains. The series of the domains which are not
+  // listed anymore are dropped, the ones of the domains which fail to be
+  // sampled are kept.
+  pub fn sample (&self, xc: &Xenctrl, store: &dyn Store) -> xenctrl::Result<()> {
+    let time = now();
+    let host = self.sample_host(xc)?;
+    let now = Instant::now();
+    let mut series = self.host.lock().unwrap();
+    let mut values = series.rates(now, host);
+    cpu_usages(&mut values, true);
+    series.record(time, values);
+    drop(series);
+
+    let domains = xc.get_domain_info_list()?;
+    let samples: Vec<(u32, String, DomainState, Sample)> = domains.iter().filter_map(|info| {
+      let dom_id = u32::from(info.domain);
+      // Its series is kept, dropped at the next sample if the domain was
+      // destroyed meanwhile.
+      let sample = match self.sample_domain(xc, store, info) {
+        Ok(sample) => sample,
+        Err(e) => {
+          eprintln!("Failed to sample domain {}: {}", dom_id, e);
+          return None
+        }
+      };
+      Some((dom_id, xenctrl::get_uuid_from_domain_handle(&info.handle), DomainState::from_domain_info(info), sample))
+    }).collect();
+
+    let now = Instant::now();
+    let mut series = self.domains.lock().unwrap();
+    series.retain(|dom_id, _| domains.iter().any(|info| u32::from(info.domain) == *dom_id));
+    for (dom_id, uuid, state, sample) in samples {
+      let domain = series.entry(dom_id).or_insert_with(|| DomainSeries {
+        uuid: uuid.clone(), state, series: Series::default()
+      });
+      if domain.uuid != uuid {
+        *domain = DomainSeries { uuid, state, series: Series::default() };
+      }
+      domain.state = state;
+      let mut values = domain.series.rates(now, sample);
+      cpu_usages(&mut values, false);
+      domain.series.record(time, values);
+    }
+    Ok(())
+  }
+
+  // The CPU counters are the idle times.
+  fn sample_host (&self, xc: &Xenctrl) -> xenctrl::Result<Sample> {
+    let info = xc.get_physinfo()?;
+    let mut sample = Sample::default();
+    sample.gauge(String::from("memory_total"), (info.total_pages * PAGE_SIZE as u64) as f64);
+    sample.gauge(String::from("memory_free"), (info.free_pages * PAGE_SIZE as u64) as f64);
+    for (cpu, idle_time) in xc.get_cpu_idle_times(info.max_cpu_id + 1)?.into_iter().enumerate() {
+      sample.counter(format!("cpu{}", cpu), idle_time, 1e-9);
+    }
+    Ok(sample)
+  }
+
+  fn sample_domain (&self, xc: &Xenctrl, store: &dyn Store, info: &DomainInfo) -> xenctrl::Result<Sample> {
+    let dom_id = u32::from(info.domain);
+    let mut sample = Sample::default();
+    sample.gauge(String::from("memory"), (info.tot_pages * PAGE_SIZE as u64) as f64);
+    sample.gauge(String::from("memory_max"), (info.max_pages * PAGE_SIZE as u64) as f64);
+    for vcpu in 0..=info.max_vcpu_id {
+      let vcpu_info = xc.get_vcpu_info(dom_id, vcpu)?;
+      if vcpu_info.online != 0 {
+        sample.counter(format!("cpu{}", vcpu), vcpu_info.cpu_time, 1e-9);
+      }
+    }
+
+    for disk in vbd::list(store, dom_id) {
+      if let Some(statistics) = vbd::statistics(store, &self.sysfs, dom_id, disk.devid) {
+        let name = if disk.vdev.is_empty() { disk.devid.to_string() } else { disk.vdev };
+        sample.counter(format!("vbd_{}_read", name), statistics.read_sectors, SECTOR_SIZE);
+        sample.counter(format!("vbd_{}_write", name), statistics.write_sectors, SECTOR_SIZE);
+        sample.counter(format!("vbd_{}_read_reqs", name), statistics.read_requests, 1.0);
+        sample.counter(format!("vbd_{}_write_reqs", name), statistics.write_requests, 1.0);
+      }
+    }
+    for interface in vif::list(store, dom_id) {
+      if let Some(statistics) = vif::statistics(&self.sysfs, dom_id, interface.devid) {
+        let devid = interface.devid;
+        sample.counter(format!("vif_{}_rx", devid), statistics.rx_bytes, 1.0);
+        sample.counter(format!("vif_{}_tx", devid), statistics.tx_bytes, 1.0);
+        sample.counter(format!("vif_{}_rx_packets", devid), statistics.rx_packets, 1.0);
+        sample.counter(format!("vif_{}_tx_packets", devid), statistics.tx_packets, 1.0);
+      }
+    }
+    Ok(sample)
+  }
+
+  // Rows in [start, end] (seconds since the epoch), the last DEFAULT_RANGE
+  // seconds by default, from the archive of `step` or the finest one.
+  pub fn host_stats (&self, start: Option<u64>, end: Option<u64>, step: Option<u64>) -> Result<Stats, String> {
+    self.host.lock().unwrap().fetch(start, end, step)
+  }
+
+  pub fn domain_stats (
+    &self, dom_id: u32, start: Option<u64>, end: Option<u64>, step: Option<u64>
+  ) -> Result<DomainStats, String> {
+    let domains = self.domains.lock().unwrap();
+    let domain = domains.get(&dom_id).ok_or_else(|| format!("no statistics of domain {}", dom_id))?;
+    Ok(DomainStats { uuid: domain.uuid.clone(), state: domain.state, stats: domain.series.fetch(start, end, step)? })
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn values (pairs: &[(&str, f64)]) -> Values {
+    pairs.iter().map(|(name, value)| (name.to_string(), *value)).collect()
+  }
+
+  fn sample (gauges: &[(&str, f64)], counters: &[(&str, u64)]) -> Sample {
+    let mut sample = Sample::default();
+    for (name, value) in gauges {
+      sample.gauge(name.to_string(), *value);
+    }
+    for (name, value) in counters {
+      sample.counter(name.to_string(), *value, 1e-9);
+    }
+    sample
+  }
+
+  #[test]
+  fn rates () {
+    let start = Instant::now();
+    let mut series = Series::default();
+
+    // No rate in the first sample.
+    let first = series.rates(start, sample(&[("memory", 1024.0)], &[("cpu0", 1_000_000_000), ("cpu1", 0)]));
+    assert_eq!(first, values(&[("memory", 1024.0)]));
+
+    let second = series.rates(
+      start + Duration::from_secs(5),
+      sample(&[("memory", 2048.0)], &[("cpu0", 3_000_000_000), ("cpu1", 5_000_000_000)])
+    );
+    assert_eq!(second, values(&[("cpu0", 0.4), ("cpu1", 1.0), ("memory", 2048.0)]));
+
+    // A reset counter, and a new one, have no rate.
+    let third = series.rates(
+      start + Duration::from_secs(10),
+      sample(&[], &[("cpu0", 500_000_000), ("cpu1", 7_500_000_000), ("cpu2", 1_000_000_000)])
+    );
+    assert_eq!(third, values(&[("cpu1", 0.5)]));
+
+    let fourth = series.rates(
+      start + Duration::from_secs(15),
+      sample(&[], &[("cpu0", 1_500_000_000), ("cpu2", 2_000_000_000)])
+    );
+    assert_eq!(fourth, values(&[("cpu0", 0.2), ("cpu2", 0.2)]));
+
+    // No time elapsed.
+    let fifth = series.rates(start + Duration::from_secs(15), sample(&[], &[("cpu0", 2_000_000_000)]));
+    assert!(fifth.is_empty());
+  }
+
+  #[test]
+  fn cpu_average () {
+    let mut cpus = values(&[("cpu0", 0.2), ("cpu1", 0.6), ("cpux", 1.0), ("memory", 1024.0)]);
+    average_cpu(&mut cpus);
+    assert_eq!(cpus["cpu"], 0.4);
+
+    let mut memory = values(&[("memory", 1024.0)]);
+    average_cpu(&mut memory);
+    assert_eq!(memory, values(&[("memory", 1024.0)]));
+  }
+
+  #[test]
+  fn cpu_usage () {
+    // Idle times of the host, a bit off with the jitter.
+    let mut host = values(&[("cpu0", 0.25), ("cpu1", 1.02), ("cpu2", -0.01), ("memory_free", 1024.0)]);
+    cpu_usages(&mut host, true);
+    assert_eq!(host, values(&[("cpu", 1.75 / 3.0), ("cpu0", 0.75), ("cpu1", 0.0), ("cpu2", 1.0), ("memory_free", 1024.0)]));
+
+    let mut domain = values(&[("cpu0", 0.25), ("cpu1", 1.02)]);
+    cpu_usages(&mut domain, false);
+    assert_eq!(domain, values(&[("cpu", 0.625), ("cpu0", 0.25), ("cpu1", 1.0)]));
+  }
+}
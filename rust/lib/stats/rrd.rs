@@ -0,0 +1,207 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+
+// =============================================================================
+// Round-robin archives of samples, like the RRAs of rrdtool.
+//
+// An archive keeps the averages of the values over its last `rows` periods of
+// `step` seconds: a row is added when a sample starts a new period, and the
+// oldest one is dropped. The periods without samples have no row. The values
+// are gauges, the rates of the counters are computed before the update.
+// =============================================================================
+
+#[derive(Clone, Copy)]
+pub struct ArchiveConfig {
+  // Seconds.
+  pub step: u64,
+  pub rows: usize
+}
+
+// 5 seconds over an hour, a minute over a day, an hour over 30 days.
+pub const ARCHIVES: [ArchiveConfig; 3] = [
+  ArchiveConfig { step: 5, rows: 720 },
+  ArchiveConfig { step: 60, rows: 1440 },
+  ArchiveConfig { step: 3600, rows: 720 }
+];
+
+pub type Values = BTreeMap<String, f64>;
+
+#[derive(Clone, Serialize)]
+pub struct Row {
+  // Start of the period, or time of the sample, in seconds since the epoch.
+  pub time: u64,
+  pub values: Values
+}
+
+// Rows of an archive in a time range.
+#[derive(Serialize)]
+pub struct Fetch {
+  pub step: u64,
+  pub rows: Vec<Row>
+}
+
+// -----------------------------------------------------------------------------
+
+// Sums and counts of the values of the current period.
+struct Period {
+  time: u64,
+  sums: BTreeMap<String, (f64, u32)>
+}
+
+impl Period {
+  fn average (self) -> Row {
+    let values = self.sums.into_iter().map(|(name, (sum, count))| (name, sum / f64::from(count))).collect();
+    Row { time: self.time, values }
+  }
+}
+
+struct Archive {
+  config: ArchiveConfig,
+  rows: VecDeque<Row>,
+  current: Option<Period>
+}
+
+impl Archive {
+  fn new (config: ArchiveConfig) -> Self {
+    Self { config, rows: VecDeque::with_capacity(config.rows), current: None }
+  }
+
+  fn update (&mut self, time: u64, values: &Values) {
+    let start = time - time % self.config.step;
+    match &self.current {
+      // The clock went back: ignored until it reaches the current period.
+      Some(period) if period.time > start => return,
+      Some(period) if period.time < start => {
+        let row = self.current.take().unwrap().average();
+        if self.rows.len() == self.config.rows {
+          self.rows.pop_front();
+        }
+        self.rows.push_back(row);
+      },
+      _ => ()
+    }
+
+    let period = self.current.get_or_insert_with(|| Period { time: start, sums: BTreeMap::new() });
+    for (name, value) in values.iter().filter(|(_, value)| value.is_finite()) {
+      let sum = period.sums.entry(name.clone()).or_insert((0.0, 0));
+      sum.0 += value;
+      sum.1 += 1;
+    }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+pub struct Rrd {
+  archives: Vec<Archive>,
+  // Time of the last update.
+  last: Option<u64>
+}
+
+impl Default for Rrd {
+  fn default () -> Self {
+    Self::new(&ARCHIVES)
+  }
+}
+
+impl Rrd {
+  // The archives are sorted by step.
+  pub fn new (configs: &[ArchiveConfig]) -> Self {
+    let mut archives: Vec<Archive> = configs.iter().copied().map(Archive::new).collect();
+    archives.sort_by_key(|archive| archive.config.step);
+    Self { archives, last: None }
+  }
+
+  pub fn update (&mut self, time: u64, values: &Values) {
+    for archive in &mut self.archives {
+      archive.update(time, values);
+    }
+    self.last = Some(self.last.map_or(time, |last| last.max(time)));
+  }
+
+  // The rows of the periods which started in [start, end], from the archive
+  // with the given step, or from the finest one which can hold `start`.
+  // None if no archive has this step.
+  pub fn fetch (&self, start: u64, end: u64, step: Option<u64>) -> Option<Fetch> {
+    let last = self.last.unwrap_or(end);
+    let archive = match step {
+      Some(step) => self.archives.iter().find(|archive| archive.config.step == step)?,
+      None => self.archives.iter()
+        .find(|archive| start >= last.saturating_sub(archive.config.step * archive.config.rows as u64))
+        .or_else(|| self.archives.last())?
+    };
+    Some(Fetch {
+      step: archive.config.step,
+      rows: archive.rows.iter().filter(|row| row.time >= start && row.time <= end).cloned().collect()
+    })
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn values (pairs: &[(&str, f64)]) -> Values {
+    pairs.iter().map(|(name, value)| (name.to_string(), *value)).collect()
+  }
+
+  fn times (rows: &[Row]) -> Vec<u64> {
+    rows.iter().map(|row| row.time).collect()
+  }
+
+  #[test]
+  fn archive () {
+    let mut archive = Archive::new(ArchiveConfig { step: 5, rows: 2 });
+    archive.update(10, &values(&[("a", 1.0), ("b", 4.0)]));
+    archive.update(14, &values(&[("a", 3.0), ("b", f64::NAN)]));
+    assert!(archive.rows.is_empty());
+
+    // A new period: the previous one is averaged, without the NaN.
+    archive.update(15, &values(&[("a", 5.0)]));
+    assert_eq!(times(archive.rows.make_contiguous()), [10]);
+    assert_eq!(archive.rows[0].values, values(&[("a", 2.0), ("b", 4.0)]));
+
+    // The clock went back.
+    archive.update(12, &values(&[("a", 100.0)]));
+    archive.update(17, &values(&[("a", 7.0)]));
+
+    // The periods without samples have no row, the oldest row is dropped.
+    archive.update(30, &values(&[("a", 9.0)]));
+    archive.update(40, &values(&[("a", 11.0)]));
+    assert_eq!(times(archive.rows.make_contiguous()), [15, 30]);
+    assert_eq!(archive.rows[0].values, values(&[("a", 6.0)]));
+    assert_eq!(archive.rows[1].values, values(&[("a", 9.0)]));
+    assert_eq!(archive.current.map(|period| period.average().values), Some(values(&[("a", 11.0)])));
+  }
+
+  #[test]
+  fn fetch () {
+    let mut rrd = Rrd::new(&[ArchiveConfig { step: 60, rows: 2 }, ArchiveConfig { step: 5, rows: 4 }]);
+    // Before the first update, from the end.
+    assert_eq!(rrd.fetch(90, 100, None).map(|fetch| fetch.step), Some(5));
+    assert_eq!(rrd.fetch(0, 100, None).map(|fetch| fetch.step), Some(60));
+    for time in (0..=200).step_by(5) {
+      rrd.update(time, &values(&[("a", time as f64)]));
+    }
+
+    // The finest archive holds the last 20 seconds.
+    let fine = rrd.fetch(190, 200, None).unwrap();
+    assert_eq!(fine.step, 5);
+    assert_eq!(times(&fine.rows), [190, 195]);
+    assert_eq!(fine.rows[1].values, values(&[("a", 195.0)]));
+
+    let coarse = rrd.fetch(100, 200, None).unwrap();
+    assert_eq!(coarse.step, 60);
+    assert_eq!(times(&coarse.rows), [120]);
+    assert_eq!(coarse.rows[0].values, values(&[("a", 147.5)]));
+
+    // Older than all the archives: the coarsest one.
+    assert_eq!(times(&rrd.fetch(0, 200, None).unwrap().rows), [60, 120]);
+
+    assert_eq!(times(&rrd.fetch(0, 200, Some(5)).unwrap().rows), [180, 185, 190, 195]);
+    assert_eq!(rrd.fetch(0, 200, Some(60)).map(|fetch| fetch.step), Some(60));
+    assert!(rrd.fetch(0, 200, Some(10)).is_none());
+  }
+}
@@ -0,0 +1,52 @@
+// =============================================================================
+// Helpers of the unit tests.
+// =============================================================================
+
+use std::fmt::Display;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+// Message of the error of `result`, panics if there is none.
+pub fn error<T, E: Display> (result: Result<T, E>) -> String {
+  match result {
+    Ok(_) => panic!("no error"),
+    Err(e) => e.to_string()
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Empty directory `xenops-<name>-<pid>` of the temp dir, removed with its
+// content on drop. `name` must be unique among the tests.
+pub struct TempDir {
+  path: PathBuf
+}
+
+impl TempDir {
+  pub fn new (name: &str) -> Self {
+    let path = std::env::temp_dir().join(format!("xenops-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    Self { path }
+  }
+}
+
+impl Deref for TempDir {
+  type Target = Path;
+
+  fn deref (&self) -> &Path {
+    &self.path
+  }
+}
+
+impl AsRef<Path> for TempDir {
+  fn as_ref (&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Drop for TempDir {
+  fn drop (&mut self) {
+    let _ = std::fs::remove_dir_all(&self.path);
+  }
+}
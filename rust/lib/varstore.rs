@@ -0,0 +1,773 @@
+use serde::{Serialize, Serializer};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::vm;
+
+// =============================================================================
+// UEFI variables (NVRAM) of the HVM guests.
+//
+// The variables of a VM are kept in `efivars` in its directory, with the
+// layout of the variable flash of OVMF (OVMF_VARS.fd, 128 KiB):
+//
+//   0x00000  firmware volume header (gEfiSystemNvDataFvGuid), variable store
+//            header (gEfiAuthenticatedVariableGuid), then the variables
+//   0x0e000  event log
+//   0x0f000  working block header of the fault-tolerant writes
+//   0x10000  spare area of the fault-tolerant writes
+//
+// A variable is an AUTHENTICATED_VARIABLE_HEADER (start id 0x55aa, state,
+// attributes, monotonic count, timestamp, public key index, name size, data
+// size, vendor GUID), the UTF-16LE name with its NUL, then the data; the next
+// one is aligned to 4 bytes. The firmware appends each new value and marks
+// the previous one as deleted. Erased flash is 0xff.
+//
+// The device model gives the file to OVMF as its variable flash: QEMU writes
+// the changes of the guest in it, so the variables persist across boots. The
+// daemon rewrites the file with the live variables only, while the VM does
+// not run.
+//
+// A store is initialized from a template: a directory of DER certificates in
+// TEMPLATE_DIR, `PK.der` and the ones starting with `KEK`, `db` or `dbx`,
+// which are enrolled as the Secure Boot keys.
+// =============================================================================
+
+pub const TEMPLATE_DIR: &str = "/etc/xenops/uefi-templates";
+
+pub const VARSTORE_FILE: &str = "efivars";
+
+const FLASH_SIZE: usize = 0x20000;
+const VARIABLE_STORE_SIZE: usize = 0xe000;
+const FTW_WORKING_BLOCK_OFFSET: usize = 0xf000;
+
+const FV_HEADER_SIZE: usize = 0x48;
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const FV_ATTRIBUTES: u32 = 0x0004_feff;
+const FV_REVISION: u8 = 2;
+const FV_BLOCK_SIZE: u32 = 0x1000;
+
+const STORE_HEADER_SIZE: usize = 28;
+const VARIABLE_STORE_FORMATTED: u8 = 0x5a;
+const VARIABLE_STORE_HEALTHY: u8 = 0xfe;
+
+const VARIABLE_HEADER_SIZE: usize = 60;
+const VARIABLE_START_ID: u16 = 0x55aa;
+// States of a variable, the firmware clears bits to change them.
+const VAR_ADDED: u8 = 0x3f;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+
+const ERASED: u8 = 0xff;
+
+pub const ATTRIBUTE_NON_VOLATILE: u32 = 0x1;
+pub const ATTRIBUTE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const ATTRIBUTE_RUNTIME_ACCESS: u32 = 0x4;
+pub const ATTRIBUTE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+
+pub const DEFAULT_ATTRIBUTES: u32 = ATTRIBUTE_NON_VOLATILE | ATTRIBUTE_BOOTSERVICE_ACCESS | ATTRIBUTE_RUNTIME_ACCESS;
+
+const SECURE_BOOT_ATTRIBUTES: u32 = DEFAULT_ATTRIBUTES | ATTRIBUTE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+
+// Vendor of the standard variables: PK, KEK, Boot####...
+pub const EFI_GLOBAL_VARIABLE: Guid = Guid([
+  0x61, 0xdf, 0xe4, 0x8b, 0xca, 0x93, 0xd2, 0x11, 0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c
+]);
+
+// Vendor of db and dbx.
+pub const EFI_IMAGE_SECURITY_DATABASE: Guid = Guid([
+  0xcb, 0xb2, 0x19, 0xd7, 0x3a, 0x3d, 0x96, 0x45, 0xa3, 0xbc, 0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f
+]);
+
+const EFI_CERT_X509: Guid = Guid([
+  0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72
+]);
+
+// Owner of the keys enrolled from a template: 3f4a6c1e-5b2d-4e8f-9a7c-0d1e2f3a4b5c.
+const SIGNATURE_OWNER: Guid = Guid([
+  0x1e, 0x6c, 0x4a, 0x3f, 0x2d, 0x5b, 0x8f, 0x4e, 0x9a, 0x7c, 0x0d, 0x1e, 0x2f, 0x3a, 0x4b, 0x5c
+]);
+
+// gEfiSystemNvDataFvGuid: fff12b8d-7696-4c8b-a985-2747075b4f50.
+const EFI_SYSTEM_NV_DATA_FV: Guid = Guid([
+  0x8d, 0x2b, 0xf1, 0xff, 0x96, 0x76, 0x8b, 0x4c, 0xa9, 0x85, 0x27, 0x47, 0x07, 0x5b, 0x4f, 0x50
+]);
+
+// gEfiAuthenticatedVariableGuid: aaf32c78-947b-439a-a180-2e144ec37792.
+const EFI_AUTHENTICATED_VARIABLE: Guid = Guid([
+  0x78, 0x2c, 0xf3, 0xaa, 0x7b, 0x94, 0x9a, 0x43, 0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92
+]);
+
+// Working block header of OVMF: gEdkiiWorkingBlockSignatureGuid, its CRC,
+// the valid flag and the size of the write queue.
+const FTW_WORKING_BLOCK_HEADER: [u8; 32] = [
+  0x2b, 0x29, 0x58, 0x9e, 0x68, 0x7c, 0x7d, 0x49, 0xa0, 0xce, 0x65, 0x00, 0xfd, 0x9f, 0x1b, 0x95,
+  0x2c, 0xaf, 0x2c, 0x64, 0xfe, 0xff, 0xff, 0xff, 0xe0, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+];
+
+pub enum Error {
+  Io(std::io::Error),
+  Invalid(String),
+  InvalidUuid(String),
+  InvalidGuid(String),
+  InvalidTemplate(String),
+  NoVarStore(String),
+  AlreadyExists(String),
+  Full
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Invalid(details) => write!(f, "invalid UEFI variable store: {}", details),
+      Error::InvalidUuid(uuid) => write!(f, "invalid VM UUID: `{}`", uuid),
+      Error::InvalidGuid(guid) => write!(f, "invalid GUID: `{}`", guid),
+      Error::InvalidTemplate(details) => write!(f, "invalid UEFI template: {}", details),
+      Error::NoVarStore(uuid) => write!(f, "no UEFI variables for VM {}", uuid),
+      Error::AlreadyExists(uuid) => write!(f, "UEFI variables of VM {} already exist", uuid),
+      Error::Full => write!(f, "UEFI variable store is full")
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn invalid<T> (details: String) -> Result<T> {
+  Err(Error::Invalid(details))
+}
+
+// -----------------------------------------------------------------------------
+
+fn u16_at (data: &[u8], offset: usize) -> u16 {
+  u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at (data: &[u8], offset: usize) -> u32 {
+  let mut value = [0u8; 4];
+  value.copy_from_slice(&data[offset..offset + 4]);
+  u32::from_le_bytes(value)
+}
+
+fn align (offset: usize) -> usize {
+  (offset + 3) & !3
+}
+
+// Vendor GUID of a variable, with the EFI_GUID layout: the first three fields
+// are little-endian.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Guid(pub [u8; 16]);
+
+impl std::str::FromStr for Guid {
+  type Err = Error;
+
+  fn from_str (value: &str) -> Result<Self> {
+    let fields: Vec<&str> = value.split('-').collect();
+    let sizes = [8, 4, 4, 4, 12];
+    if fields.len() != sizes.len() ||
+      fields.iter().zip(sizes.iter()).any(|(field, size)| field.len() != *size || !field.chars().all(|c| c.is_ascii_hexdigit())) {
+      return Err(Error::InvalidGuid(value.to_string()))
+    }
+
+    let hex: String = fields.concat();
+    let mut bytes = [0u8; 16];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).unwrap();
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Ok(Guid(bytes))
+  }
+}
+
+impl std::fmt::Display for Guid {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let b = &self.0;
+    write!(
+      f,
+      "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+      b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+  }
+}
+
+impl Serialize for Guid {
+  fn serialize<S: Serializer> (&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.to_string())
+  }
+}
+
+// EFI_TIME of an authenticated variable, in UTC. The timezone and the
+// daylight flags are always zero for these.
+#[derive(Clone, Copy, PartialEq)]
+pub struct EfiTime {
+  pub year: u16,
+  pub month: u8,
+  pub day: u8,
+  pub hour: u8,
+  pub minute: u8,
+  pub second: u8,
+  pub nanosecond: u32
+}
+
+impl EfiTime {
+  pub fn from_unix (seconds: u64, nanosecond: u32) -> Self {
+    // Civil date of a day count, see http://howardhinnant.github.io/date_algorithms.html.
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = seconds % 86400;
+    Self {
+      year: year as u16,
+      month: month as u8,
+      day: day as u8,
+      hour: (time / 3600) as u8,
+      minute: (time / 60 % 60) as u8,
+      second: (time % 60) as u8,
+      nanosecond
+    }
+  }
+
+  pub fn now () -> Self {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Self::from_unix(now.as_secs(), 0)
+  }
+
+  // None for a zeroed EFI_TIME, as the firmware writes for the variables
+  // that are not authenticated.
+  fn from_bytes (data: &[u8]) -> Option<Self> {
+    if data.iter().all(|byte| *byte == 0) {
+      return None
+    }
+    Some(Self {
+      year: u16_at(data, 0),
+      month: data[2],
+      day: data[3],
+      hour: data[4],
+      minute: data[5],
+      second: data[6],
+      nanosecond: u32_at(data, 8)
+    })
+  }
+
+  fn to_bytes (time: Option<&Self>) -> [u8; 16] {
+    let mut data = [0u8; 16];
+    if let Some(time) = time {
+      data[0..2].copy_from_slice(&time.year.to_le_bytes());
+      data[2..7].copy_from_slice(&[time.month, time.day, time.hour, time.minute, time.second]);
+      data[8..12].copy_from_slice(&time.nanosecond.to_le_bytes());
+    }
+    data
+  }
+}
+
+impl std::fmt::Display for EfiTime {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(
+      f,
+      "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+      self.year, self.month, self.day, self.hour, self.minute, self.second
+    )
+  }
+}
+
+impl Serialize for EfiTime {
+  fn serialize<S: Serializer> (&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.to_string())
+  }
+}
+
+#[derive(Clone, PartialEq, Serialize)]
+pub struct Variable {
+  pub guid: Guid,
+  pub name: String,
+  pub attributes: u32,
+  // Time of the last authenticated write, set by `VarStore::set` if none.
+  pub timestamp: Option<EfiTime>,
+  // Hexadecimal in JSON.
+  #[serde(serialize_with = "serialize_hex")]
+  pub data: Vec<u8>
+}
+
+fn serialize_hex<S: Serializer> (data: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+  serializer.serialize_str(&data.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+}
+
+// A list of EFI_SIGNATURE_LIST with an X.509 certificate each.
+fn signature_lists (certificates: &[Vec<u8>]) -> Vec<u8> {
+  let mut lists = Vec::new();
+  for certificate in certificates {
+    let signature_size = 16 + certificate.len();
+    lists.extend_from_slice(&EFI_CERT_X509.0);
+    lists.extend_from_slice(&(28 + signature_size as u32).to_le_bytes());
+    lists.extend_from_slice(&0u32.to_le_bytes());
+    lists.extend_from_slice(&(signature_size as u32).to_le_bytes());
+    lists.extend_from_slice(&SIGNATURE_OWNER.0);
+    lists.extend_from_slice(certificate);
+  }
+  lists
+}
+
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Default, PartialEq)]
+pub struct VarStore {
+  variables: Vec<Variable>
+}
+
+impl VarStore {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  // Store with the keys of a template, see the top of the module.
+  pub fn from_template (dir: &Path) -> Result<Self> {
+    let entries = match std::fs::read_dir(dir) {
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        return Err(Error::InvalidTemplate(format!("no template {}", dir.display())))
+      },
+      entries => entries?
+    };
+    let mut entries: Vec<(String, PathBuf)> = entries
+      .filter_map(|entry| entry.ok())
+      .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.path()))
+      .filter(|(name, _)| name.ends_with(".der"))
+      .collect();
+    entries.sort();
+
+    let certificates = |prefix: &str, exclude: Option<&str>| -> Result<Vec<Vec<u8>>> {
+      entries.iter()
+        .filter(|(name, _)| name.starts_with(prefix) && !matches!(exclude, Some(exclude) if name.starts_with(exclude)))
+        .map(|(_, path)| std::fs::read(path).map_err(Error::from))
+        .collect()
+    };
+    let pk = certificates("PK", None)?;
+    let kek = certificates("KEK", None)?;
+    let db = certificates("db", Some("dbx"))?;
+    let dbx = certificates("dbx", None)?;
+    if pk.len() != 1 {
+      return Err(Error::InvalidTemplate(format!("{} PK certificates in {}", pk.len(), dir.display())))
+    }
+
+    let mut store = Self::new();
+    // The PK is enrolled last: before it, the firmware is in setup mode.
+    for (guid, name, certificates) in [
+      (EFI_IMAGE_SECURITY_DATABASE, "db", db),
+      (EFI_IMAGE_SECURITY_DATABASE, "dbx", dbx),
+      (EFI_GLOBAL_VARIABLE, "KEK", kek),
+      (EFI_GLOBAL_VARIABLE, "PK", pk)
+    ].iter() {
+      if !certificates.is_empty() {
+        store.set(Variable {
+          guid: *guid,
+          name: name.to_string(),
+          attributes: SECURE_BOOT_ATTRIBUTES,
+          timestamp: None,
+          data: signature_lists(certificates)
+        });
+      }
+    }
+    Ok(store)
+  }
+
+  // Live variables of a flash image, see the top of the module.
+  pub fn from_bytes (data: &[u8]) -> Result<Self> {
+    if data.len() != FLASH_SIZE {
+      return invalid(format!("size {} instead of {}", data.len(), FLASH_SIZE))
+    }
+    if data[16..32] != EFI_SYSTEM_NV_DATA_FV.0 || &data[40..44] != FV_SIGNATURE {
+      return invalid(String::from("bad firmware volume header"))
+    }
+    let header = &data[FV_HEADER_SIZE..FV_HEADER_SIZE + STORE_HEADER_SIZE];
+    if header[0..16] != EFI_AUTHENTICATED_VARIABLE.0 ||
+      header[20] != VARIABLE_STORE_FORMATTED || header[21] != VARIABLE_STORE_HEALTHY {
+      return invalid(String::from("bad variable store header"))
+    }
+    let end = FV_HEADER_SIZE + u32_at(header, 16) as usize;
+    if end > FTW_WORKING_BLOCK_OFFSET {
+      return invalid(format!("variable store of {} bytes", end - FV_HEADER_SIZE))
+    }
+
+    let mut store = Self::new();
+    // Copies being replaced, used if the firmware did not add the new one.
+    let mut in_transition = Vec::new();
+    let mut offset = align(FV_HEADER_SIZE + STORE_HEADER_SIZE);
+    while offset + VARIABLE_HEADER_SIZE <= end && u16_at(data, offset) == VARIABLE_START_ID {
+      let state = data[offset + 2];
+      let attributes = u32_at(data, offset + 4);
+      let timestamp = EfiTime::from_bytes(&data[offset + 16..offset + 32]);
+      let name_size = u32_at(data, offset + 36) as usize;
+      let data_size = u32_at(data, offset + 40) as usize;
+      let mut guid = [0u8; 16];
+      guid.copy_from_slice(&data[offset + 44..offset + 60]);
+
+      let start = offset + VARIABLE_HEADER_SIZE;
+      if name_size & 1 != 0 || name_size > end || data_size > end || start + name_size + data_size > end {
+        return invalid(format!("truncated variable at {:#x}", offset))
+      }
+      let name: Vec<u16> = data[start..start + name_size].chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect();
+      let name = match String::from_utf16(&name) {
+        Ok(name) if !name.is_empty() => name,
+        _ => return invalid(format!("bad name of variable at {:#x}", offset))
+      };
+      let variable = Variable {
+        guid: Guid(guid),
+        name,
+        attributes,
+        timestamp,
+        data: data[start + name_size..start + name_size + data_size].to_vec()
+      };
+      offset = align(start + name_size + data_size);
+
+      match state {
+        VAR_ADDED => store.insert(variable),
+        state if state == VAR_ADDED & VAR_IN_DELETED_TRANSITION => in_transition.push(variable),
+        // Deleted or partially written.
+        _ => ()
+      }
+    }
+    for variable in in_transition {
+      if store.get(&variable.guid, &variable.name).is_none() {
+        store.insert(variable);
+      }
+    }
+    Ok(store)
+  }
+
+  // Flash image with the variables only, the deleted ones are dropped.
+  pub fn to_bytes (&self) -> Result<Vec<u8>> {
+    let mut data = vec![ERASED; FLASH_SIZE];
+
+    let header = &mut data[0..FV_HEADER_SIZE];
+    header[0..16].copy_from_slice(&[0u8; 16]);
+    header[16..32].copy_from_slice(&EFI_SYSTEM_NV_DATA_FV.0);
+    header[32..40].copy_from_slice(&(FLASH_SIZE as u64).to_le_bytes());
+    header[40..44].copy_from_slice(FV_SIGNATURE);
+    header[44..48].copy_from_slice(&FV_ATTRIBUTES.to_le_bytes());
+    header[48..50].copy_from_slice(&(FV_HEADER_SIZE as u16).to_le_bytes());
+    header[50..55].copy_from_slice(&[0u8; 5]);
+    header[55] = FV_REVISION;
+    header[56..60].copy_from_slice(&(FLASH_SIZE as u32 / FV_BLOCK_SIZE).to_le_bytes());
+    header[60..64].copy_from_slice(&FV_BLOCK_SIZE.to_le_bytes());
+    header[64..72].copy_from_slice(&[0u8; 8]);
+    // The 16-bit words of the header sum to zero.
+    let sum = (0..FV_HEADER_SIZE).step_by(2).fold(0u16, |sum, offset| sum.wrapping_add(u16_at(header, offset)));
+    header[50..52].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    let header = &mut data[FV_HEADER_SIZE..FV_HEADER_SIZE + STORE_HEADER_SIZE];
+    header[0..16].copy_from_slice(&EFI_AUTHENTICATED_VARIABLE.0);
+    header[16..20].copy_from_slice(&((VARIABLE_STORE_SIZE - FV_HEADER_SIZE) as u32).to_le_bytes());
+    header[20] = VARIABLE_STORE_FORMATTED;
+    header[21] = VARIABLE_STORE_HEALTHY;
+    header[22..28].copy_from_slice(&[0u8; 6]);
+
+    let mut offset = align(FV_HEADER_SIZE + STORE_HEADER_SIZE);
+    for variable in &self.variables {
+      let mut name: Vec<u8> = variable.name.encode_utf16().flat_map(|unit| unit.to_le_bytes().to_vec()).collect();
+      name.extend_from_slice(&[0, 0]);
+      let size = VARIABLE_HEADER_SIZE + name.len() + variable.data.len();
+      if offset + size > VARIABLE_STORE_SIZE {
+        return Err(Error::Full)
+      }
+
+      let entry = &mut data[offset..offset + size];
+      entry[0..2].copy_from_slice(&VARIABLE_START_ID.to_le_bytes());
+      entry[2] = VAR_ADDED;
+      entry[3] = 0;
+      entry[4..8].copy_from_slice(&variable.attributes.to_le_bytes());
+      // Monotonic count and public key index, unused with time-based
+      // authentication.
+      entry[8..16].copy_from_slice(&[0u8; 8]);
+      entry[16..32].copy_from_slice(&EfiTime::to_bytes(variable.timestamp.as_ref()));
+      entry[32..36].copy_from_slice(&[0u8; 4]);
+      entry[36..40].copy_from_slice(&(name.len() as u32).to_le_bytes());
+      entry[40..44].copy_from_slice(&(variable.data.len() as u32).to_le_bytes());
+      entry[44..60].copy_from_slice(&variable.guid.0);
+      entry[VARIABLE_HEADER_SIZE..VARIABLE_HEADER_SIZE + name.len()].copy_from_slice(&name);
+      entry[VARIABLE_HEADER_SIZE + name.len()..].copy_from_slice(&variable.data);
+      offset = align(offset + size);
+    }
+
+    data[FTW_WORKING_BLOCK_OFFSET..FTW_WORKING_BLOCK_OFFSET + FTW_WORKING_BLOCK_HEADER.len()]
+      .copy_from_slice(&FTW_WORKING_BLOCK_HEADER);
+    Ok(data)
+  }
+
+  pub fn load (path: &Path) -> Result<Self> {
+    Self::from_bytes(&std::fs::read(path)?)
+  }
+
+  // The file is replaced, never left half written.
+  pub fn save (&self, path: &Path) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, self.to_bytes()?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+  }
+
+  pub fn variables (&self) -> &[Variable] {
+    &self.variables
+  }
+
+  pub fn get (&self, guid: &Guid, name: &str) -> Option<&Variable> {
+    self.variables.iter().find(|variable| variable.guid == *guid && variable.name == name)
+  }
+
+  // Add a variable or replace it. A time-based authenticated variable is
+  // stamped with the current time if it has no timestamp.
+  pub fn set (&mut self, mut variable: Variable) {
+    if variable.attributes & ATTRIBUTE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS != 0 && variable.timestamp.is_none() {
+      variable.timestamp = Some(EfiTime::now());
+    }
+    self.insert(variable);
+  }
+
+  fn insert (&mut self, variable: Variable) {
+    match self.variables.iter_mut().find(|current| current.guid == variable.guid && current.name == variable.name) {
+      Some(current) => *current = variable,
+      None => self.variables.push(variable)
+    }
+  }
+
+  // Returns false if there is no such variable.
+  pub fn delete (&mut self, guid: &Guid, name: &str) -> bool {
+    let count = self.variables.len();
+    self.variables.retain(|variable| !(variable.guid == *guid && variable.name == name));
+    self.variables.len() != count
+  }
+}
+
+// =============================================================================
+
+pub fn path (uuid: &str) -> Result<PathBuf> {
+  vm::vm_dir(uuid).map(|dir| dir.join(VARSTORE_FILE)).ok_or_else(|| Error::InvalidUuid(uuid.to_string()))
+}
+
+// Create the variables of a VM, with the keys of a template if any. Existing
+// variables are kept unless `overwrite`.
+pub fn create (uuid: &str, template: Option<&str>, overwrite: bool) -> Result<()> {
+  let path = path(uuid)?;
+  if path.exists() && !overwrite {
+    return Err(Error::AlreadyExists(uuid.to_string()))
+  }
+  let store = match template {
+    Some(template) if template.is_empty() || template.contains('/') || template.starts_with('.') => {
+      return Err(Error::InvalidTemplate(format!("bad name `{}`", template)))
+    },
+    Some(template) => VarStore::from_template(&Path::new(TEMPLATE_DIR).join(template))?,
+    None => VarStore::new()
+  };
+  std::fs::create_dir_all(path.parent().unwrap())?;
+  store.save(&path)
+}
+
+pub fn load (uuid: &str) -> Result<VarStore> {
+  let path = path(uuid)?;
+  if !path.exists() {
+    return Err(Error::NoVarStore(uuid.to_string()))
+  }
+  VarStore::load(&path)
+}
+
+// Change the variables of a VM while it does not run, they are used at its
+// next boot.
+pub fn update<T> (uuid: &str, f: impl FnOnce(&mut VarStore) -> T) -> Result<T> {
+  let mut store = load(uuid)?;
+  let result = f(&mut store);
+  store.save(&path(uuid)?)?;
+  Ok(result)
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{error, TempDir};
+
+  struct Template {
+    dir: TempDir
+  }
+
+  impl Template {
+    fn new (name: &str, files: &[(&str, &[u8])]) -> Self {
+      let dir = TempDir::new(&format!("varstore-{}", name));
+      for (file, data) in files {
+        std::fs::write(dir.join(file), data).unwrap();
+      }
+      Self { dir }
+    }
+  }
+
+  fn secure_boot_template (name: &str) -> Template {
+    Template::new(name, &[
+      ("PK.der", b"platform key"),
+      ("KEK.der", b"key exchange key"),
+      ("db-1.der", b"first signature"),
+      ("db-2.der", b"second signature"),
+      ("dbx.der", b"forbidden signature"),
+      ("README", b"not a certificate")
+    ])
+  }
+
+  fn variable (name: &str, data: &[u8]) -> Variable {
+    Variable {
+      guid: EFI_GLOBAL_VARIABLE,
+      name: name.to_string(),
+      attributes: DEFAULT_ATTRIBUTES,
+      timestamp: None,
+      data: data.to_vec()
+    }
+  }
+
+  // Append a variable after the last one, like the firmware.
+  fn append (data: &mut [u8], state: u8, variable: &Variable) {
+    let mut offset = align(FV_HEADER_SIZE + STORE_HEADER_SIZE);
+    while u16_at(data, offset) == VARIABLE_START_ID {
+      let size = VARIABLE_HEADER_SIZE + u32_at(data, offset + 36) as usize + u32_at(data, offset + 40) as usize;
+      offset = align(offset + size);
+    }
+    let mut store = VarStore::new();
+    store.insert(variable.clone());
+    let image = store.to_bytes().ok().unwrap();
+    let start = align(FV_HEADER_SIZE + STORE_HEADER_SIZE);
+    let size = VARIABLE_HEADER_SIZE + (variable.name.len() + 1) * 2 + variable.data.len();
+    data[offset..offset + size].copy_from_slice(&image[start..start + size]);
+    data[offset + 2] = state;
+  }
+
+  #[test]
+  fn round_trip () {
+    let template = secure_boot_template("round-trip");
+    let mut store = VarStore::from_template(&template.dir).ok().unwrap();
+    store.set(variable("BootOrder", &[0, 0, 1, 0]));
+    store.set(variable("Boot0000", &[]));
+
+    let names: Vec<&str> = store.variables().iter().map(|variable| variable.name.as_str()).collect();
+    assert_eq!(names, ["db", "dbx", "KEK", "PK", "BootOrder", "Boot0000"]);
+    for name in &["db", "dbx", "KEK", "PK"] {
+      let guid = if name.starts_with("db") { EFI_IMAGE_SECURITY_DATABASE } else { EFI_GLOBAL_VARIABLE };
+      let variable = store.get(&guid, name).unwrap();
+      assert_eq!(variable.attributes, SECURE_BOOT_ATTRIBUTES);
+      assert!(variable.timestamp.is_some());
+    }
+    let db = &store.get(&EFI_IMAGE_SECURITY_DATABASE, "db").unwrap().data;
+    assert_eq!(db.len(), 2 * (28 + 16) + b"first signature".len() + b"second signature".len());
+    assert_eq!(db[0..16], EFI_CERT_X509.0);
+    assert!(store.get(&EFI_GLOBAL_VARIABLE, "BootOrder").unwrap().timestamp.is_none());
+
+    let data = store.to_bytes().ok().unwrap();
+    assert_eq!(data.len(), FLASH_SIZE);
+    assert_eq!((0..FV_HEADER_SIZE).step_by(2).fold(0u16, |sum, offset| sum.wrapping_add(u16_at(&data, offset))), 0);
+    assert_eq!(data[FTW_WORKING_BLOCK_OFFSET..FTW_WORKING_BLOCK_OFFSET + 32], FTW_WORKING_BLOCK_HEADER);
+    assert!(VarStore::from_bytes(&data).ok().unwrap() == store);
+  }
+
+  #[test]
+  fn empty () {
+    let data = VarStore::new().to_bytes().ok().unwrap();
+    assert!(VarStore::from_bytes(&data).ok().unwrap().variables().is_empty());
+  }
+
+  #[test]
+  fn firmware_writes () {
+    let mut store = VarStore::new();
+    store.set(variable("BootOrder", &[0, 0]));
+    store.set(variable("Lang", b"eng"));
+    store.set(variable("Timeout", &[5, 0]));
+    let mut data = store.to_bytes().ok().unwrap();
+
+    // BootOrder replaced, Lang deleted, Timeout being replaced when the
+    // firmware stopped.
+    let mut offset = align(FV_HEADER_SIZE + STORE_HEADER_SIZE);
+    for state in &[VAR_ADDED & VAR_IN_DELETED_TRANSITION & 0xfd, VAR_ADDED & 0xfc, VAR_ADDED & VAR_IN_DELETED_TRANSITION] {
+      data[offset + 2] = *state;
+      let size = VARIABLE_HEADER_SIZE + u32_at(&data, offset + 36) as usize + u32_at(&data, offset + 40) as usize;
+      offset = align(offset + size);
+    }
+    append(&mut data, VAR_ADDED, &variable("BootOrder", &[1, 0, 0, 0]));
+    append(&mut data, 0x7f, &variable("Timeout", &[9, 0]));
+
+    let store = VarStore::from_bytes(&data).ok().unwrap();
+    let names: Vec<&str> = store.variables().iter().map(|variable| variable.name.as_str()).collect();
+    assert_eq!(names, ["BootOrder", "Timeout"]);
+    assert_eq!(store.get(&EFI_GLOBAL_VARIABLE, "BootOrder").unwrap().data, [1, 0, 0, 0]);
+    assert_eq!(store.get(&EFI_GLOBAL_VARIABLE, "Timeout").unwrap().data, [5, 0]);
+  }
+
+  #[test]
+  fn timestamps () {
+    assert_eq!(EfiTime::from_unix(0, 0).to_string(), "1970-01-01T00:00:00Z");
+    assert_eq!(EfiTime::from_unix(951_827_696, 0).to_string(), "2000-02-29T12:34:56Z");
+    assert_eq!(EfiTime::from_unix(1_792_368_000, 0).to_string(), "2026-10-19T00:00:00Z");
+
+    let mut store = VarStore::new();
+    let time = EfiTime::from_unix(951_827_696, 0);
+    store.set(Variable { attributes: SECURE_BOOT_ATTRIBUTES, timestamp: Some(time), ..variable("PK", b"key") });
+    let data = store.to_bytes().ok().unwrap();
+    let store = VarStore::from_bytes(&data).ok().unwrap();
+    assert!(store.get(&EFI_GLOBAL_VARIABLE, "PK").unwrap().timestamp == Some(time));
+  }
+
+  #[test]
+  fn invalid_images () {
+    let data = VarStore::new().to_bytes().ok().unwrap();
+    assert_eq!(error(VarStore::from_bytes(&data[..FLASH_SIZE / 2])), "invalid UEFI variable store: size 65536 instead of 131072");
+
+    let mut bad = data.clone();
+    bad[40] = b'X';
+    assert_eq!(error(VarStore::from_bytes(&bad)), "invalid UEFI variable store: bad firmware volume header");
+
+    let mut bad = data.clone();
+    bad[FV_HEADER_SIZE + 20] = 0;
+    assert_eq!(error(VarStore::from_bytes(&bad)), "invalid UEFI variable store: bad variable store header");
+
+    let mut store = VarStore::new();
+    store.set(variable("BootOrder", &[0, 0]));
+    let mut bad = store.to_bytes().ok().unwrap();
+    let offset = align(FV_HEADER_SIZE + STORE_HEADER_SIZE);
+    bad[offset + 40..offset + 44].copy_from_slice(&0x1_0000u32.to_le_bytes());
+    assert_eq!(error(VarStore::from_bytes(&bad)), "invalid UEFI variable store: truncated variable at 0x64");
+  }
+
+  #[test]
+  fn full () {
+    let mut store = VarStore::new();
+    for index in 0..13 {
+      store.set(variable(&format!("Big{}", index), &[0u8; 4096]));
+    }
+    assert!(store.to_bytes().is_ok());
+    store.set(variable("Big13", &[0u8; 4096]));
+    assert_eq!(error(store.to_bytes()), "UEFI variable store is full");
+  }
+
+  #[test]
+  fn templates () {
+    let template = Template::new("no-pk", &[("KEK.der", b"key exchange key")]);
+    assert!(error(VarStore::from_template(&template.dir)).starts_with("invalid UEFI template: 0 PK certificates in "));
+    assert!(error(VarStore::from_template(Path::new("/nonexistent"))).starts_with("invalid UEFI template: no template "));
+  }
+
+  #[test]
+  fn guids () {
+    let guid: Guid = "8be4df61-93ca-11d2-aa0d-00e098032b8c".parse().ok().unwrap();
+    assert!(guid == EFI_GLOBAL_VARIABLE);
+    assert_eq!(EFI_IMAGE_SECURITY_DATABASE.to_string(), "d719b2cb-3d3a-4596-a3bc-dad00e67656f");
+    assert_eq!(error("8be4df61-93ca-11d2-aa0d".parse::<Guid>()), "invalid GUID: `8be4df61-93ca-11d2-aa0d`");
+  }
+}
@@ -0,0 +1,417 @@
+use serde::{Deserialize, Serialize};
+
+use super::bindings;
+use super::hvm_context::{HvmContext, RecordData};
+use super::xenctrl::{Error, ErrorCode, Result, Xenctrl};
+
+// =============================================================================
+// Typed access to the registers of a vCPU.
+//
+// PV guests use `xc_vcpu_getcontext`/`xc_vcpu_setcontext`, HVM guests use
+// the CPU record of the HVM context.
+// =============================================================================
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct SegmentRegister {
+  pub selector: u16,
+  pub base: u64,
+  pub limit: u32,
+  pub attributes: u32
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct X86Registers {
+  pub rax: u64,
+  pub rbx: u64,
+  pub rcx: u64,
+  pub rdx: u64,
+  pub rsi: u64,
+  pub rdi: u64,
+  pub rbp: u64,
+  pub rsp: u64,
+  pub r8: u64,
+  pub r9: u64,
+  pub r10: u64,
+  pub r11: u64,
+  pub r12: u64,
+  pub r13: u64,
+  pub r14: u64,
+  pub r15: u64,
+  pub rip: u64,
+  pub rflags: u64,
+
+  pub cr0: u64,
+  pub cr2: u64,
+  pub cr3: u64,
+  pub cr4: u64,
+  // Not available for PV guests, always 0.
+  pub efer: u64,
+
+  pub cs: SegmentRegister,
+  pub ds: SegmentRegister,
+  pub es: SegmentRegister,
+  pub fs: SegmentRegister,
+  pub gs: SegmentRegister,
+  pub ss: SegmentRegister
+}
+
+// -----------------------------------------------------------------------------
+
+fn get_domain_flags (xc: &Xenctrl, dom_id: u32) -> Result<u32> {
+  Ok(xc.get_domain_info(dom_id)?.flags)
+}
+
+pub fn is_hvm (xc: &Xenctrl, dom_id: u32) -> Result<bool> {
+  Ok(get_domain_flags(xc, dom_id)? & (1 << bindings::_XEN_DOMINF_hvm_guest) != 0)
+}
+
+pub fn is_paused (xc: &Xenctrl, dom_id: u32) -> Result<bool> {
+  Ok(get_domain_flags(xc, dom_id)? & (1 << bindings::_XEN_DOMINF_paused) != 0)
+}
+
+fn parse_hvm_context (xc: &Xenctrl, dom_id: u32) -> Result<HvmContext> {
+  HvmContext::parse(&xc.get_hvm_context(dom_id)?)
+    .map_err(|e| Error::new(ErrorCode::InternalError, &e.to_string()))
+}
+
+fn missing_vcpu (vcpu: u32) -> Error {
+  Error::new(ErrorCode::InvalidParam, &format!("no context for vCPU {}", vcpu))
+}
+
+// -----------------------------------------------------------------------------
+
+macro_rules! hvm_segment {
+  ($cpu:expr, $sel:ident, $base:ident, $limit:ident, $arbytes:ident) => {
+    SegmentRegister {
+      selector: $cpu.$sel as u16,
+      base: $cpu.$base,
+      limit: $cpu.$limit,
+      attributes: $cpu.$arbytes
+    }
+  }
+}
+
+macro_rules! set_hvm_segment {
+  ($cpu:expr, $segment:expr, $sel:ident, $base:ident, $limit:ident, $arbytes:ident) => {
+    $cpu.$sel = $segment.selector.into();
+    $cpu.$base = $segment.base;
+    $cpu.$limit = $segment.limit;
+    $cpu.$arbytes = $segment.attributes;
+  }
+}
+
+pub fn from_hvm_cpu (cpu: &bindings::HvmSaveTypeCpu) -> X86Registers {
+  X86Registers {
+    rax: cpu.rax, rbx: cpu.rbx, rcx: cpu.rcx, rdx: cpu.rdx,
+    rsi: cpu.rsi, rdi: cpu.rdi, rbp: cpu.rbp, rsp: cpu.rsp,
+    r8: cpu.r8, r9: cpu.r9, r10: cpu.r10, r11: cpu.r11,
+    r12: cpu.r12, r13: cpu.r13, r14: cpu.r14, r15: cpu.r15,
+    rip: cpu.rip,
+    rflags: cpu.rflags,
+    cr0: cpu.cr0,
+    cr2: cpu.cr2,
+    cr3: cpu.cr3,
+    cr4: cpu.cr4,
+    efer: cpu.msr_efer,
+    cs: hvm_segment!(cpu, cs_sel, cs_base, cs_limit, cs_arbytes),
+    ds: hvm_segment!(cpu, ds_sel, ds_base, ds_limit, ds_arbytes),
+    es: hvm_segment!(cpu, es_sel, es_base, es_limit, es_arbytes),
+    fs: hvm_segment!(cpu, fs_sel, fs_base, fs_limit, fs_arbytes),
+    gs: hvm_segment!(cpu, gs_sel, gs_base, gs_limit, gs_arbytes),
+    ss: hvm_segment!(cpu, ss_sel, ss_base, ss_limit, ss_arbytes)
+  }
+}
+
+fn to_hvm_cpu (registers: &X86Registers, cpu: &mut bindings::HvmSaveTypeCpu) {
+  cpu.rax = registers.rax;
+  cpu.rbx = registers.rbx;
+  cpu.rcx = registers.rcx;
+  cpu.rdx = registers.rdx;
+  cpu.rsi = registers.rsi;
+  cpu.rdi = registers.rdi;
+  cpu.rbp = registers.rbp;
+  cpu.rsp = registers.rsp;
+  cpu.r8 = registers.r8;
+  cpu.r9 = registers.r9;
+  cpu.r10 = registers.r10;
+  cpu.r11 = registers.r11;
+  cpu.r12 = registers.r12;
+  cpu.r13 = registers.r13;
+  cpu.r14 = registers.r14;
+  cpu.r15 = registers.r15;
+  cpu.rip = registers.rip;
+  cpu.rflags = registers.rflags;
+  cpu.cr0 = registers.cr0;
+  cpu.cr2 = registers.cr2;
+  cpu.cr3 = registers.cr3;
+  cpu.cr4 = registers.cr4;
+  cpu.msr_efer = registers.efer;
+  set_hvm_segment!(cpu, registers.cs, cs_sel, cs_base, cs_limit, cs_arbytes);
+  set_hvm_segment!(cpu, registers.ds, ds_sel, ds_base, ds_limit, ds_arbytes);
+  set_hvm_segment!(cpu, registers.es, es_sel, es_base, es_limit, es_arbytes);
+  set_hvm_segment!(cpu, registers.fs, fs_sel, fs_base, fs_limit, fs_arbytes);
+  set_hvm_segment!(cpu, registers.gs, gs_sel, gs_base, gs_limit, gs_arbytes);
+  set_hvm_segment!(cpu, registers.ss, ss_sel, ss_base, ss_limit, ss_arbytes);
+}
+
+// -----------------------------------------------------------------------------
+
+fn segment (selector: u16, base: u64) -> SegmentRegister {
+  SegmentRegister { selector, base, ..Default::default() }
+}
+
+fn from_pv_context (context: &bindings::vcpu_guest_context_any_t, guest_width: u32) -> X86Registers {
+  unsafe {
+    if guest_width == 8 {
+      let context = &context.x64;
+      let regs = &context.user_regs;
+      X86Registers {
+        rax: regs.rax, rbx: regs.rbx, rcx: regs.rcx, rdx: regs.rdx,
+        rsi: regs.rsi, rdi: regs.rdi, rbp: regs.rbp, rsp: regs.rsp,
+        r8: regs.r8, r9: regs.r9, r10: regs.r10, r11: regs.r11,
+        r12: regs.r12, r13: regs.r13, r14: regs.r14, r15: regs.r15,
+        rip: regs.rip,
+        rflags: regs.rflags,
+        cr0: context.ctrlreg[0],
+        cr2: context.ctrlreg[2],
+        cr3: context.ctrlreg[3],
+        cr4: context.ctrlreg[4],
+        efer: 0,
+        cs: segment(regs.cs, 0),
+        ds: segment(regs.ds, 0),
+        es: segment(regs.es, 0),
+        fs: segment(regs.fs, context.fs_base),
+        gs: segment(regs.gs, context.gs_base_user),
+        ss: segment(regs.ss, 0)
+      }
+    } else {
+      let context = &context.x32;
+      let regs = &context.user_regs;
+      X86Registers {
+        rax: regs.eax.into(), rbx: regs.ebx.into(), rcx: regs.ecx.into(), rdx: regs.edx.into(),
+        rsi: regs.esi.into(), rdi: regs.edi.into(), rbp: regs.ebp.into(), rsp: regs.esp.into(),
+        rip: regs.eip.into(),
+        rflags: regs.eflags.into(),
+        cr0: context.ctrlreg[0].into(),
+        cr2: context.ctrlreg[2].into(),
+        cr3: context.ctrlreg[3].into(),
+        cr4: context.ctrlreg[4].into(),
+        cs: segment(regs.cs, 0),
+        ds: segment(regs.ds, 0),
+        es: segment(regs.es, 0),
+        fs: segment(regs.fs, 0),
+        gs: segment(regs.gs, 0),
+        ss: segment(regs.ss, 0),
+        ..Default::default()
+      }
+    }
+  }
+}
+
+fn to_pv_context (registers: &X86Registers, context: &mut bindings::vcpu_guest_context_any_t, guest_width: u32) {
+  unsafe {
+    if guest_width == 8 {
+      let context = &mut context.x64;
+      let regs = &mut context.user_regs;
+      regs.rax = registers.rax;
+      regs.rbx = registers.rbx;
+      regs.rcx = registers.rcx;
+      regs.rdx = registers.rdx;
+      regs.rsi = registers.rsi;
+      regs.rdi = registers.rdi;
+      regs.rbp = registers.rbp;
+      regs.rsp = registers.rsp;
+      regs.r8 = registers.r8;
+      regs.r9 = registers.r9;
+      regs.r10 = registers.r10;
+      regs.r11 = registers.r11;
+      regs.r12 = registers.r12;
+      regs.r13 = registers.r13;
+      regs.r14 = registers.r14;
+      regs.r15 = registers.r15;
+      regs.rip = registers.rip;
+      regs.rflags = registers.rflags;
+      regs.cs = registers.cs.selector;
+      regs.ds = registers.ds.selector;
+      regs.es = registers.es.selector;
+      regs.fs = registers.fs.selector;
+      regs.gs = registers.gs.selector;
+      regs.ss = registers.ss.selector;
+      context.ctrlreg[0] = registers.cr0;
+      context.ctrlreg[2] = registers.cr2;
+      context.ctrlreg[3] = registers.cr3;
+      context.ctrlreg[4] = registers.cr4;
+      context.fs_base = registers.fs.base;
+      context.gs_base_user = registers.gs.base;
+    } else {
+      let context = &mut context.x32;
+      let regs = &mut context.user_regs;
+      regs.eax = registers.rax as u32;
+      regs.ebx = registers.rbx as u32;
+      regs.ecx = registers.rcx as u32;
+      regs.edx = registers.rdx as u32;
+      regs.esi = registers.rsi as u32;
+      regs.edi = registers.rdi as u32;
+      regs.ebp = registers.rbp as u32;
+      regs.esp = registers.rsp as u32;
+      regs.eip = registers.rip as u32;
+      regs.eflags = registers.rflags as u32;
+      regs.cs = registers.cs.selector;
+      regs.ds = registers.ds.selector;
+      regs.es = registers.es.selector;
+      regs.fs = registers.fs.selector;
+      regs.gs = registers.gs.selector;
+      regs.ss = registers.ss.selector;
+      context.ctrlreg[0] = registers.cr0 as u32;
+      context.ctrlreg[2] = registers.cr2 as u32;
+      context.ctrlreg[3] = registers.cr3 as u32;
+      context.ctrlreg[4] = registers.cr4 as u32;
+    }
+  }
+}
+
+// =============================================================================
+
+pub fn get (xc: &Xenctrl, dom_id: u32, vcpu: u32) -> Result<X86Registers> {
+  if is_hvm(xc, dom_id)? {
+    let context = parse_hvm_context(xc, dom_id)?;
+    let registers = context.cpus()
+      .find(|(instance, _)| u32::from(*instance) == vcpu)
+      .map(|(_, cpu)| from_hvm_cpu(cpu));
+    return registers.ok_or_else(|| missing_vcpu(vcpu))
+  }
+
+  let guest_width = xc.get_guest_width(dom_id)?;
+  Ok(from_pv_context(&xc.get_vcpu_context(dom_id, vcpu)?, guest_width))
+}
+
+pub fn set (xc: &Xenctrl, dom_id: u32, vcpu: u32, registers: &X86Registers) -> Result<()> {
+  if !is_paused(xc, dom_id)? {
+    return Err(Error::new(
+      ErrorCode::InvalidParam,
+      &format!("domain {} must be paused to modify vCPU registers", dom_id)
+    ))
+  }
+
+  if is_hvm(xc, dom_id)? {
+    let mut context = parse_hvm_context(xc, dom_id)?;
+    let cpu = context.records.iter_mut().find_map(|record| match record.data {
+      RecordData::Cpu(ref mut cpu) if u32::from(record.instance) == vcpu => Some(cpu),
+      _ => None
+    }).ok_or_else(|| missing_vcpu(vcpu))?;
+    to_hvm_cpu(registers, cpu);
+    return xc.set_hvm_context(dom_id, &context.to_bytes())
+  }
+
+  let guest_width = xc.get_guest_width(dom_id)?;
+  let mut context = xc.get_vcpu_context(dom_id, vcpu)?;
+  to_pv_context(registers, &mut context, guest_width);
+  xc.set_vcpu_context(dom_id, vcpu, &context)
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn json (registers: &X86Registers) -> serde_json::Value {
+    serde_json::to_value(registers).unwrap()
+  }
+
+  fn segment_register (selector: u16, base: u64, limit: u32, attributes: u32) -> SegmentRegister {
+    SegmentRegister { selector, base, limit, attributes }
+  }
+
+  // Registers of a 64-bit kernel, each one with a different value.
+  fn registers () -> X86Registers {
+    X86Registers {
+      rax: 0x1111_0000_0000_0001, rbx: 0x1111_0000_0000_0002, rcx: 0x1111_0000_0000_0003, rdx: 0x1111_0000_0000_0004,
+      rsi: 0x1111_0000_0000_0005, rdi: 0x1111_0000_0000_0006, rbp: 0x1111_0000_0000_0007, rsp: 0xffff_c900_0001_3f58,
+      r8: 8, r9: 9, r10: 10, r11: 11, r12: 12, r13: 13, r14: 14, r15: 15,
+      rip: 0xffff_ffff_8100_1234,
+      rflags: 0x246,
+      cr0: 0x8005_0033,
+      cr2: 0x7f00_dead_b000,
+      cr3: 0x1_2345_6000,
+      cr4: 0x0036_06f0,
+      efer: 0xd01,
+      cs: segment_register(0x10, 0, 0xffff_ffff, 0xa09b),
+      ds: segment_register(0x18, 0, 0xffff_ffff, 0xc093),
+      es: segment_register(0x18, 0, 0xffff_ffff, 0xc093),
+      fs: segment_register(0, 0x7f12_3456_7000, 0xffff_ffff, 0x1c000),
+      gs: segment_register(0, 0xffff_8880_7fc0_0000, 0xffff_ffff, 0x1c000),
+      ss: segment_register(0x18, 0, 0xffff_ffff, 0xc093)
+    }
+  }
+
+  #[test]
+  fn hvm_cpu () {
+    let mut cpu: bindings::HvmSaveTypeCpu = unsafe { std::mem::zeroed() };
+    cpu.dr7 = 0x400;
+    cpu.tr_sel = 0x40;
+    to_hvm_cpu(&registers(), &mut cpu);
+
+    assert_eq!(cpu.rip, 0xffff_ffff_8100_1234);
+    assert_eq!(cpu.msr_efer, 0xd01);
+    assert_eq!(cpu.cs_sel, 0x10);
+    assert_eq!(cpu.cs_arbytes, 0xa09b);
+    assert_eq!(cpu.fs_base, 0x7f12_3456_7000);
+    assert_eq!(cpu.gs_base, 0xffff_8880_7fc0_0000);
+    assert_eq!(cpu.ss_limit, 0xffff_ffff);
+    // The registers that aren't exposed are kept.
+    assert_eq!(cpu.dr7, 0x400);
+    assert_eq!(cpu.tr_sel, 0x40);
+
+    assert_eq!(json(&from_hvm_cpu(&cpu)), json(&registers()));
+  }
+
+  #[test]
+  fn pv_context_64 () {
+    let mut context: bindings::vcpu_guest_context_any_t = unsafe { std::mem::zeroed() };
+    to_pv_context(&registers(), &mut context, 8);
+    unsafe {
+      assert_eq!(context.x64.user_regs.rip, 0xffff_ffff_8100_1234);
+      assert_eq!(context.x64.user_regs.r15, 15);
+      assert_eq!(context.x64.user_regs.cs, 0x10);
+      assert_eq!(context.x64.ctrlreg[..5], [0x8005_0033, 0, 0x7f00_dead_b000, 0x1_2345_6000, 0x0036_06f0]);
+      assert_eq!(context.x64.fs_base, 0x7f12_3456_7000);
+      assert_eq!(context.x64.gs_base_user, 0xffff_8880_7fc0_0000);
+    }
+
+    // EFER, the limits, the attributes and the bases other than those of FS
+    // and GS are not in the context of a PV vCPU.
+    let mut expected = registers();
+    expected.efer = 0;
+    for segment in &mut [&mut expected.cs, &mut expected.ds, &mut expected.es, &mut expected.fs, &mut expected.gs, &mut expected.ss] {
+      segment.limit = 0;
+      segment.attributes = 0;
+    }
+    assert_eq!(json(&from_pv_context(&context, 8)), json(&expected));
+  }
+
+  #[test]
+  fn pv_context_32 () {
+    let mut context: bindings::vcpu_guest_context_any_t = unsafe { std::mem::zeroed() };
+    to_pv_context(&registers(), &mut context, 4);
+    unsafe {
+      assert_eq!(context.x32.user_regs.eax, 1);
+      assert_eq!(context.x32.user_regs.eip, 0x8100_1234);
+      assert_eq!(context.x32.user_regs.esp, 0x0001_3f58);
+      assert_eq!(context.x32.user_regs.ss, 0x18);
+      assert_eq!(context.x32.ctrlreg[..5], [0x8005_0033, 0, 0xdead_b000, 0x2345_6000, 0x0036_06f0]);
+    }
+
+    // The registers are truncated to 32 bits, r8-r15 and the bases don't
+    // exist.
+    let registers = from_pv_context(&context, 4);
+    assert_eq!(registers.rbx, 2);
+    assert_eq!(registers.rsp, 0x0001_3f58);
+    assert_eq!(registers.rip, 0x8100_1234);
+    assert_eq!(registers.rflags, 0x246);
+    assert_eq!(registers.cr3, 0x2345_6000);
+    assert_eq!((registers.r8, registers.r15, registers.efer), (0, 0, 0));
+    assert_eq!((registers.cs.selector, registers.ds.selector), (0x10, 0x18));
+    assert_eq!((registers.fs.base, registers.gs.base), (0, 0));
+  }
+}
@@ -1,4 +1,23 @@
-use super::xenstore;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::bindings;
+use super::coredump;
+use super::foreignmemory::ForeignMemory;
+use super::pci;
+use super::xenctrl;
+use super::xenstore::{self, Store};
+
+// Persistent files of the VMs (vTPM state, UEFI variables...), a directory per
+// UUID.
+pub const VM_DIR: &str = "/var/lib/xenops/vms";
+
+// Core files of the crashed domains.
+pub const CORE_DIR: &str = "/var/lib/xenops/cores";
+
+pub const CRASH_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 // =============================================================================
 
@@ -26,6 +45,159 @@ impl std::fmt::Display for ShutdownReason {
   }
 }
 
+impl ShutdownReason {
+  // Reason given by the hypervisor if the domain is shut down.
+  pub fn from_domain_info (info: &xenctrl::DomainInfo) -> Option<Self> {
+    if info.flags & (1 << bindings::_XEN_DOMINF_shutdown) == 0 {
+      return None
+    }
+
+    let code = (info.flags >> bindings::XEN_DOMINF_shutdownshift) & bindings::XEN_DOMINF_shutdownmask;
+    Some(match code {
+      bindings::SHUTDOWN_poweroff => ShutdownReason::PowerOff,
+      bindings::SHUTDOWN_reboot => ShutdownReason::Reboot,
+      bindings::SHUTDOWN_suspend => ShutdownReason::Suspend,
+      bindings::SHUTDOWN_crash => ShutdownReason::Crash,
+      code => ShutdownReason::Unknown(code as i32)
+    })
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Action to apply when a domain crashes (`on_crash` policy). Restarting is
+// left to the toolstack which built the domain: the daemon has no builder.
+// Domains without a valid action, like those not created by this daemon, are
+// preserved to keep the evidence of the crash.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CrashAction {
+  Destroy,
+  Preserve,
+  CoredumpDestroy
+}
+
+impl Default for CrashAction {
+  fn default () -> Self {
+    CrashAction::Preserve
+  }
+}
+
+impl std::str::FromStr for CrashAction {
+  type Err = String;
+
+  fn from_str (value: &str) -> Result<Self, Self::Err> {
+    match value {
+      "destroy" => Ok(CrashAction::Destroy),
+      "preserve" => Ok(CrashAction::Preserve),
+      "coredump-destroy" => Ok(CrashAction::CoredumpDestroy),
+      "restart" | "coredump-restart" => Err(format!("unsupported crash action: `{}`", value)),
+      _ => Err(format!("invalid crash action: `{}`", value))
+    }
+  }
+}
+
+impl CrashAction {
+  pub fn is_coredump (&self) -> bool {
+    *self == CrashAction::CoredumpDestroy
+  }
+
+  // Action to apply once the core is dumped.
+  pub fn after_coredump (&self) -> Self {
+    match *self {
+      CrashAction::CoredumpDestroy => CrashAction::Destroy,
+      action => action
+    }
+  }
+}
+
+impl std::fmt::Display for CrashAction {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match *self {
+      CrashAction::Destroy => write!(f, "destroy"),
+      CrashAction::Preserve => write!(f, "preserve"),
+      CrashAction::CoredumpDestroy => write!(f, "coredump-destroy")
+    }
+  }
+}
+
+fn crash_action_path (dom_id: u32) -> String {
+  format!("{}/xenops/on_crash", xenstore::get_domain_path(dom_id))
+}
+
+// Removed with the domain.
+pub fn set_crash_action (store: &dyn Store, dom_id: u32, action: CrashAction) -> xenstore::Result<()> {
+  store.write(&crash_action_path(dom_id), &action.to_string())
+}
+
+pub fn get_crash_action (store: &dyn Store, dom_id: u32) -> CrashAction {
+  store.read(&crash_action_path(dom_id)).ok().and_then(|action| action.parse().ok()).unwrap_or_default()
+}
+
+// Apply the crash action of a crashed domain: its core is dumped in
+// `directory` if required, then it is destroyed unless preserved. A domain
+// whose core could not be dumped is preserved.
+pub fn apply_crash_action (
+  xc: &xenctrl::Xenctrl,
+  fmem: &ForeignMemory,
+  store: &dyn Store,
+  dom_id: u32,
+  directory: &Path
+) -> coredump::Result<(CrashAction, Option<PathBuf>)> {
+  let action = get_crash_action(store, dom_id);
+  let core = coredump::dump_on_crash(xc, fmem, dom_id, action, directory)?;
+  if action.after_coredump() == CrashAction::Destroy {
+    xc.destroy_domain(dom_id)?;
+    let _ = store.rm(&xenstore::get_domain_path(dom_id));
+    pci::release_domain(store, dom_id);
+  }
+  Ok((action, core))
+}
+
+// Finds the crashed domains and applies their crash action, once per domain:
+// the preserved ones stay crashed.
+#[derive(Default)]
+pub struct CrashHandler {
+  handled: HashSet<u32>
+}
+
+impl CrashHandler {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  pub fn poll (
+    &mut self,
+    xc: &xenctrl::Xenctrl,
+    fmem: &ForeignMemory,
+    store: &dyn Store,
+    directory: &Path
+  ) -> xenctrl::Result<Vec<(u32, coredump::Result<(CrashAction, Option<PathBuf>)>)>> {
+    let domains = xc.get_domain_info_list()?;
+    self.handled.retain(|dom_id| domains.iter().any(|info| u32::from(info.domain) == *dom_id));
+
+    let crashed: Vec<u32> = domains.iter()
+      .filter(|info| info.flags & (1 << bindings::_XEN_DOMINF_dying) == 0)
+      .filter(|info| matches!(ShutdownReason::from_domain_info(info), Some(ShutdownReason::Crash)))
+      .map(|info| u32::from(info.domain))
+      .collect();
+
+    Ok(
+      crashed.into_iter()
+        .filter(|dom_id| self.handled.insert(*dom_id))
+        .map(|dom_id| (dom_id, apply_crash_action(xc, fmem, store, dom_id, directory)))
+        .collect()
+    )
+  }
+}
+
+// =============================================================================
+
+// Directory of a VM, None if `uuid` is not a UUID (it is a path component).
+pub fn vm_dir (uuid: &str) -> Option<PathBuf> {
+  let uuid = Uuid::parse_str(uuid).ok()?;
+  Some(PathBuf::from(VM_DIR).join(uuid.to_string()))
+}
+
 pub fn shutdown (xs: &xenstore::Xenstore, dom_id: u32, reason: ShutdownReason) -> xenstore::Result<()> {
   let domain_path = xs.get_domain_path(dom_id);
   let shutdown_path = domain_path.clone() + "/control/shutdown";
@@ -43,3 +215,45 @@ pub fn shutdown (xs: &xenstore::Xenstore, dom_id: u32, reason: ShutdownReason) -
 pub fn get_name (xs: &xenstore::Xenstore, dom_id: u32) -> xenstore::Result<String> {
   xs.read(&(xs.get_domain_path(dom_id) + "/name"))
 }
+
+pub fn destroy (xc: &xenctrl::Xenctrl, xs: &xenstore::Xenstore, dom_id: u32) -> xenctrl::Result<()> {
+  xc.destroy_domain(dom_id)?;
+  let _ = xs.rm(&xs.get_domain_path(dom_id));
+  pci::release_domain(xs, dom_id);
+  Ok(())
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::xenstore::MemoryStore;
+
+  #[test]
+  fn crash_action () {
+    let store = MemoryStore::new();
+    // Not set, or set by someone else: the crashed domain is kept.
+    assert!(get_crash_action(&store, 5) == CrashAction::Preserve);
+    assert!(store.write("/local/domain/5/xenops/on_crash", "reboot").is_ok());
+    assert!(get_crash_action(&store, 5) == CrashAction::Preserve);
+
+    for action in &[CrashAction::Destroy, CrashAction::Preserve, CrashAction::CoredumpDestroy] {
+      assert!(set_crash_action(&store, 5, *action).is_ok());
+      assert!(get_crash_action(&store, 5) == *action);
+    }
+    assert_eq!(store.read("/local/domain/5/xenops/on_crash").ok().as_deref(), Some("coredump-destroy"));
+    assert!(get_crash_action(&store, 6) == CrashAction::Preserve);
+
+    assert_eq!("restart".parse::<CrashAction>().err().as_deref(), Some("unsupported crash action: `restart`"));
+    assert_eq!("reboot".parse::<CrashAction>().err().as_deref(), Some("invalid crash action: `reboot`"));
+  }
+
+  #[test]
+  fn after_coredump () {
+    assert!(CrashAction::CoredumpDestroy.is_coredump());
+    assert!(!CrashAction::Destroy.is_coredump());
+    assert!(CrashAction::CoredumpDestroy.after_coredump() == CrashAction::Destroy);
+    assert!(CrashAction::Preserve.after_coredump() == CrashAction::Preserve);
+  }
+}
@@ -0,0 +1,371 @@
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use super::vm;
+
+// =============================================================================
+// Virtual TPM of the HVM guests, emulated by swtpm.
+//
+// The state of a TPM is kept in `tpm` in the directory of its VM, it is
+// created once by swtpm_setup. While the VM runs, a swtpm child of the daemon
+// uses it and serves the device model on a socket per domain; swtpm exits
+// when the device model disconnects.
+// =============================================================================
+
+pub const SWTPM_PATH: &str = "/usr/bin/swtpm";
+pub const SWTPM_SETUP_PATH: &str = "/usr/bin/swtpm_setup";
+
+pub const SOCKET_DIR: &str = "/var/run/xenops/vtpm";
+pub const LOG_DIR: &str = "/var/log/xenops/vtpm";
+
+// Written by swtpm in the state directory of a TPM 2.0.
+pub const STATE_FILE: &str = "tpm2-00.permall";
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub enum Error {
+  Io(std::io::Error),
+  InvalidUuid(String),
+  // swtpm_setup failed, with its output.
+  Setup(String),
+  NoState(String),
+  AlreadyExists(String),
+  // Exited before it was ready.
+  Exited(ExitStatus),
+  Timeout
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::InvalidUuid(uuid) => write!(f, "invalid VM UUID: `{}`", uuid),
+      Error::Setup(output) => write!(f, "failed to create TPM state: {}", output),
+      Error::NoState(uuid) => write!(f, "no TPM state for VM {}", uuid),
+      Error::AlreadyExists(uuid) => write!(f, "TPM state of VM {} already exists", uuid),
+      Error::Exited(status) => write!(f, "swtpm exited: {}", status),
+      Error::Timeout => write!(f, "swtpm did not start in time")
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from (e: std::io::Error) -> Self {
+    Error::Io(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// -----------------------------------------------------------------------------
+
+pub fn state_dir (uuid: &str) -> Result<PathBuf> {
+  vm::vm_dir(uuid).map(|dir| dir.join("tpm")).ok_or_else(|| Error::InvalidUuid(uuid.to_string()))
+}
+
+pub fn exists (uuid: &str) -> Result<bool> {
+  Ok(state_dir(uuid)?.join(STATE_FILE).exists())
+}
+
+// Control channel of the swtpm of a domain, given to the device model.
+pub fn socket_path (dom_id: u32) -> PathBuf {
+  PathBuf::from(SOCKET_DIR).join(format!("{}.sock", dom_id))
+}
+
+pub fn log_path (uuid: &str) -> PathBuf {
+  PathBuf::from(LOG_DIR).join(format!("{}.log", uuid))
+}
+
+// Copy the state of a TPM to `path`. swtpm replaces the state file when it
+// writes it, so the copy is consistent while the VM runs.
+pub fn backup (uuid: &str, path: &Path) -> Result<()> {
+  let state = state_dir(uuid)?.join(STATE_FILE);
+  if !state.exists() {
+    return Err(Error::NoState(uuid.to_string()))
+  }
+  std::fs::copy(state, path)?;
+  Ok(())
+}
+
+// Replace the state of a TPM by a backup. Its VM must not run.
+pub fn restore (uuid: &str, path: &Path) -> Result<()> {
+  let dir = state_dir(uuid)?;
+  std::fs::create_dir_all(&dir)?;
+  let tmp = dir.join(format!("{}.tmp", STATE_FILE));
+  std::fs::copy(path, &tmp)?;
+  std::fs::rename(&tmp, dir.join(STATE_FILE))?;
+  Ok(())
+}
+
+// -----------------------------------------------------------------------------
+
+// The binaries of swtpm. The states, the sockets and the logs are under
+// `root`, which can be a fake tree.
+pub struct Swtpm {
+  pub swtpm: String,
+  pub swtpm_setup: String,
+  root: PathBuf
+}
+
+impl Default for Swtpm {
+  fn default () -> Self {
+    Self::new(SWTPM_PATH, SWTPM_SETUP_PATH)
+  }
+}
+
+impl Swtpm {
+  pub fn new (swtpm: &str, swtpm_setup: &str) -> Self {
+    Self { swtpm: swtpm.to_string(), swtpm_setup: swtpm_setup.to_string(), root: PathBuf::from("/") }
+  }
+
+  pub fn with_root<P: AsRef<Path>> (mut self, root: P) -> Self {
+    self.root = root.as_ref().to_path_buf();
+    self
+  }
+
+  fn rooted (&self, path: &Path) -> PathBuf {
+    self.root.join(path.strip_prefix("/").unwrap_or(path))
+  }
+
+  fn state_dir (&self, uuid: &str) -> Result<PathBuf> {
+    Ok(self.rooted(&state_dir(uuid)?))
+  }
+
+  fn exists (&self, uuid: &str) -> Result<bool> {
+    Ok(self.state_dir(uuid)?.join(STATE_FILE).exists())
+  }
+
+  // Create the state of a new TPM 2.0 with an endorsement key. An existing
+  // state is kept unless `overwrite`.
+  pub fn create (&self, uuid: &str, overwrite: bool) -> Result<()> {
+    if self.exists(uuid)? && !overwrite {
+      return Err(Error::AlreadyExists(uuid.to_string()))
+    }
+    let dir = self.state_dir(uuid)?;
+    std::fs::create_dir_all(&dir)?;
+
+    let output = Command::new(&self.swtpm_setup)
+      .arg("--tpm2")
+      .arg("--tpmstate").arg(&dir)
+      .arg("--createek")
+      .arg("--lock-nvram")
+      .arg(if overwrite { "--overwrite" } else { "--not-overwrite" })
+      .stdin(Stdio::null())
+      .output()?;
+    if !output.status.success() {
+      let mut details = String::from_utf8_lossy(&output.stderr).trim().to_string();
+      if details.is_empty() {
+        details = output.status.to_string();
+      }
+      return Err(Error::Setup(details))
+    }
+    if !self.exists(uuid)? {
+      return Err(Error::Setup(format!("no {} written", STATE_FILE)))
+    }
+    Ok(())
+  }
+
+  // Run swtpm for a domain and wait for its socket.
+  pub fn start (&self, uuid: &str, dom_id: u32, timeout: Duration) -> Result<Vtpm> {
+    if !self.exists(uuid)? {
+      return Err(Error::NoState(uuid.to_string()))
+    }
+    let dir = self.state_dir(uuid)?;
+
+    std::fs::create_dir_all(self.rooted(Path::new(SOCKET_DIR)))?;
+    std::fs::create_dir_all(self.rooted(Path::new(LOG_DIR)))?;
+    let socket = self.rooted(&socket_path(dom_id));
+    let _ = std::fs::remove_file(&socket);
+    let log = OpenOptions::new().create(true).append(true).open(self.rooted(&log_path(uuid)))?;
+
+    let child = Command::new(&self.swtpm)
+      .arg("socket")
+      .arg("--tpm2")
+      .arg("--tpmstate").arg(format!("dir={}", dir.display()))
+      .arg("--ctrl").arg(format!("type=unixio,path={}", socket.display()))
+      .arg("--terminate")
+      .stdin(Stdio::null())
+      .stdout(log.try_clone()?)
+      .stderr(log)
+      .spawn()?;
+
+    let mut vtpm = Vtpm { dom_id, uuid: uuid.to_string(), socket, child };
+    let start = Instant::now();
+    while !vtpm.socket.exists() {
+      let error = match vtpm.child.try_wait() {
+        Ok(Some(status)) => Some(Error::Exited(status)),
+        Err(e) => Some(e.into()),
+        Ok(None) if start.elapsed() > timeout => Some(Error::Timeout),
+        Ok(None) => None
+      };
+      if let Some(e) = error {
+        vtpm.kill();
+        return Err(e)
+      }
+      std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(vtpm)
+  }
+}
+
+// A running swtpm.
+pub struct Vtpm {
+  pub dom_id: u32,
+  pub uuid: String,
+  socket: PathBuf,
+  child: Child
+}
+
+impl Vtpm {
+  pub fn socket (&self) -> &Path {
+    &self.socket
+  }
+
+  pub fn pid (&self) -> u32 {
+    self.child.id()
+  }
+
+  pub fn try_wait (&mut self) -> std::io::Result<Option<ExitStatus>> {
+    self.child.try_wait()
+  }
+
+  pub fn kill (&mut self) {
+    if let Ok(None) = self.child.try_wait() {
+      let _ = self.child.kill();
+      let _ = self.child.wait();
+    }
+    let _ = std::fs::remove_file(&self.socket);
+  }
+}
+
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{error, TempDir};
+  use std::os::unix::fs::PermissionsExt;
+
+  const UUID: &str = "3f1a2b4c-0000-4000-8000-123456789abc";
+
+  // Stub binaries of swtpm in a fake root. They write their arguments, one
+  // per line, in `<name>.args` of the root.
+  struct FakeSwtpm {
+    root: TempDir
+  }
+
+  impl FakeSwtpm {
+    fn new (name: &str) -> Self {
+      Self { root: TempDir::new(&format!("vtpm-{}", name)) }
+    }
+
+    fn script (&self, name: &str, body: &str) -> String {
+      let path = self.root.join(name);
+      let args = self.root.join(format!("{}.args", name));
+      std::fs::write(&path, format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > {}\n{}\n", args.display(), body)).unwrap();
+      std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+      path.display().to_string()
+    }
+
+    // swtpm_setup writes the state in the directory after `--tpmstate`.
+    // swtpm creates the file after `path=` in `--ctrl`, then waits to be
+    // killed.
+    fn swtpm (&self, swtpm: &str) -> Swtpm {
+      let swtpm_setup = self.script(
+        "swtpm_setup", "while [ $# -gt 0 ]; do [ \"$1\" = --tpmstate ] && touch \"$2/tpm2-00.permall\"; shift; done"
+      );
+      Swtpm::new(&self.script("swtpm", swtpm), &swtpm_setup).with_root(&self.root)
+    }
+
+    fn args (&self, name: &str) -> Vec<String> {
+      let args = std::fs::read_to_string(self.root.join(format!("{}.args", name))).unwrap();
+      args.lines().map(String::from).collect()
+    }
+
+    fn path (&self, path: &str) -> PathBuf {
+      self.root.join(path.trim_start_matches('/'))
+    }
+  }
+
+  const SERVE: &str = concat!(
+    "for arg; do case \"$arg\" in type=unixio,path=*) touch \"${arg#type=unixio,path=}\";; esac; done\n",
+    "exec sleep 60"
+  );
+
+  #[test]
+  fn create () {
+    let fake = FakeSwtpm::new("create");
+    let swtpm = fake.swtpm(SERVE);
+    let state_dir = fake.path(&format!("/var/lib/xenops/vms/{}/tpm", UUID));
+
+    assert!(swtpm.create(UUID, false).is_ok());
+    assert!(state_dir.join(STATE_FILE).exists());
+    assert_eq!(fake.args("swtpm_setup"), vec![
+      "--tpm2", "--tpmstate", &state_dir.display().to_string(), "--createek", "--lock-nvram", "--not-overwrite"
+    ]);
+
+    assert_eq!(error(swtpm.create(UUID, false)), format!("TPM state of VM {} already exists", UUID));
+    assert!(swtpm.create(UUID, true).is_ok());
+    assert_eq!(fake.args("swtpm_setup").last().unwrap(), "--overwrite");
+
+    assert_eq!(error(swtpm.create("../../etc", false)), "invalid VM UUID: `../../etc`");
+  }
+
+  #[test]
+  fn create_failure () {
+    let fake = FakeSwtpm::new("create-failure");
+    let swtpm_setup = fake.script("swtpm_setup", "echo 'Could not create the EK' >&2\nexit 1");
+    let swtpm = Swtpm::new("/bin/false", &swtpm_setup).with_root(&fake.root);
+    assert_eq!(error(swtpm.create(UUID, false)), "failed to create TPM state: Could not create the EK");
+
+    // Success without a state.
+    let swtpm = Swtpm::new("/bin/false", &fake.script("swtpm_setup", "exit 0")).with_root(&fake.root);
+    assert_eq!(error(swtpm.create(UUID, false)), format!("failed to create TPM state: no {} written", STATE_FILE));
+  }
+
+  #[test]
+  fn start_stop () {
+    let fake = FakeSwtpm::new("start-stop");
+    let swtpm = fake.swtpm(SERVE);
+    assert_eq!(error(swtpm.start(UUID, 5, DEFAULT_TIMEOUT)), format!("no TPM state for VM {}", UUID));
+    assert!(swtpm.create(UUID, false).is_ok());
+
+    let mut vtpm = swtpm.start(UUID, 5, DEFAULT_TIMEOUT).ok().unwrap();
+    let socket = fake.path("/var/run/xenops/vtpm/5.sock");
+    assert_eq!(vtpm.socket(), socket);
+    assert!(socket.exists());
+    assert!(fake.path(&format!("/var/log/xenops/vtpm/{}.log", UUID)).exists());
+    assert_eq!(fake.args("swtpm"), vec![
+      String::from("socket"),
+      String::from("--tpm2"),
+      String::from("--tpmstate"),
+      format!("dir={}", fake.path(&format!("/var/lib/xenops/vms/{}/tpm", UUID)).display()),
+      String::from("--ctrl"),
+      format!("type=unixio,path={}", socket.display()),
+      String::from("--terminate")
+    ]);
+    assert!(vtpm.try_wait().unwrap().is_none());
+
+    vtpm.kill();
+    assert!(vtpm.try_wait().unwrap().is_some());
+    assert!(!socket.exists());
+  }
+
+  #[test]
+  fn start_failure () {
+    let fake = FakeSwtpm::new("start-failure");
+    let swtpm = fake.swtpm("exit 3");
+    assert!(swtpm.create(UUID, false).is_ok());
+    assert_eq!(error(swtpm.start(UUID, 5, DEFAULT_TIMEOUT)), "swtpm exited: exit status: 3");
+
+    // No socket in time: swtpm is killed.
+    let swtpm = fake.swtpm("exec sleep 60");
+    let start = Instant::now();
+    assert_eq!(error(swtpm.start(UUID, 5, Duration::from_millis(100))), "swtpm did not start in time");
+    assert!(start.elapsed() < Duration::from_secs(10));
+  }
+}
@@ -1,4 +1,7 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
 use uuid::Uuid;
 
 use super::bindings;
@@ -58,6 +61,19 @@ impl Error {
       details: String::new()
     }
   }
+
+  pub fn last_os_error () -> Self {
+    let os_error = std::io::Error::last_os_error().raw_os_error().unwrap();
+    Self::new(ErrorCode::OsError(os_error), "")
+  }
+
+  // An interrupted or busy call, it can be retried.
+  pub fn is_transient (&self) -> bool {
+    match self.code {
+      ErrorCode::OsError(code) => code == libc::EINTR || code == libc::EAGAIN,
+      _ => false
+    }
+  }
 }
 
 impl std::fmt::Display for Error {
@@ -94,8 +110,27 @@ pub fn get_uuid_from_domain_handle (dom_handle: &DomainHandle) -> String {
 
 // -----------------------------------------------------------------------------
 
+// Characters read from the console ring of Xen by hypercall.
+const CONSOLE_RING_CHUNK: usize = 1 << 16;
+
 pub type DomainInfo = bindings::xen_domctl_getdomaininfo_t;
 
+pub type VcpuInfo = bindings::xc_vcpuinfo_t;
+
+pub type PhysInfo = bindings::xc_physinfo_t;
+
+pub type VcpuGuestContext = bindings::vcpu_guest_context_any_t;
+
+pub type DomainCreateConfig = bindings::xen_domctl_createdomain;
+
+#[derive(Clone, Copy)]
+pub struct TscInfo {
+  pub mode: u32,
+  pub elapsed_nsec: u64,
+  pub khz: u32,
+  pub incarnation: u32
+}
+
 // =============================================================================
 
 pub struct Xenctrl {
@@ -213,4 +248,510 @@ impl Xenctrl {
       }
     }
   }
+
+  // Resume a suspended domain. With `fast`, the guest sees the suspend as
+  // cancelled and continues with its current state.
+  pub fn resume_domain (&self, dom_id: u32, fast: bool) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_resume(self.xc, dom_id, fast as i32) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn get_hvm_context (&self, dom_id: u32) -> Result<Vec<u8>> {
+    unsafe {
+      // A first call without buffer gives the size to allocate.
+      let size = bindings::xc_domain_hvm_getcontext(self.xc, dom_id, std::ptr::null_mut(), 0);
+      if size <= 0 {
+        return Err(self.get_last_error())
+      }
+
+      let mut buffer = vec![0u8; size as usize];
+      let size = bindings::xc_domain_hvm_getcontext(self.xc, dom_id, buffer.as_mut_ptr(), buffer.len() as u32);
+      if size <= 0 {
+        return Err(self.get_last_error())
+      }
+
+      buffer.truncate(size as usize);
+      Ok(buffer)
+    }
+  }
+
+  pub fn set_hvm_context (&self, dom_id: u32, context: &[u8]) -> Result<()> {
+    unsafe {
+      let mut buffer = context.to_vec();
+      match bindings::xc_domain_hvm_setcontext(self.xc, dom_id, buffer.as_mut_ptr(), buffer.len() as u32) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Returns the size in bytes of the guest words: 4 or 8.
+  pub fn get_guest_width (&self, dom_id: u32) -> Result<u32> {
+    unsafe {
+      let mut guest_width: u32 = 0;
+      match bindings::xc_domain_get_guest_width(self.xc, dom_id, &mut guest_width) {
+        0 => Ok(guest_width),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn get_vcpu_context (&self, dom_id: u32, vcpu: u32) -> Result<VcpuGuestContext> {
+    unsafe {
+      let mut context: VcpuGuestContext = Default::default();
+      match bindings::xc_vcpu_getcontext(self.xc, dom_id, vcpu, &mut context) {
+        0 => Ok(context),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn set_vcpu_context (&self, dom_id: u32, vcpu: u32, context: &VcpuGuestContext) -> Result<()> {
+    unsafe {
+      let mut context = *context;
+      match bindings::xc_vcpu_setcontext(self.xc, dom_id, vcpu, &mut context) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Returns the guest frame mapped at a virtual address, using the page tables of a vCPU.
+  pub fn translate_foreign_address (&self, dom_id: u32, vcpu: u32, addr: u64) -> Result<u64> {
+    unsafe {
+      match bindings::xc_translate_foreign_address(self.xc, dom_id, vcpu as i32, addr) {
+        0 => {
+          let error = self.get_last_error();
+          if error.code == ErrorCode::None {
+            Err(Error::new(ErrorCode::InvalidParam, &format!("address {:#x} is not mapped", addr)))
+          } else {
+            Err(error)
+          }
+        },
+        frame => Ok(frame as u64)
+      }
+    }
+  }
+
+  // When enabled, breakpoints and single steps of the guest pause the domain.
+  pub fn set_domain_debugging (&self, dom_id: u32, enable: bool) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_setdebugging(self.xc, dom_id, enable as u32) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // See XEN_DOMCTL_DEBUG_OP_* for the available operations.
+  pub fn debug_control (&self, dom_id: u32, operation: u32, vcpu: u32) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_debug_control(self.xc, dom_id, operation, vcpu) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn get_maximum_gpfn (&self, dom_id: u32) -> Result<u64> {
+    unsafe {
+      let mut gpfn: bindings::xen_pfn_t = 0;
+      match bindings::xc_domain_maximum_gpfn(self.xc, dom_id, &mut gpfn) {
+        0 => Ok(gpfn),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Log-dirty mode: Xen records the pages written by the domain until the
+  // bitmap is cleaned.
+  pub fn enable_logdirty (&self, dom_id: u32) -> Result<()> {
+    self.shadow_control(dom_id, bindings::XEN_DOMCTL_SHADOW_OP_ENABLE_LOGDIRTY, None, 0).map(|_| ())
+  }
+
+  pub fn disable_logdirty (&self, dom_id: u32) -> Result<()> {
+    self.shadow_control(dom_id, bindings::XEN_DOMCTL_SHADOW_OP_OFF, None, 0).map(|_| ())
+  }
+
+  // Bitmap of the dirty pages since the last clean, the first `pages` pfns
+  // are read: a bit per pfn, in little-endian words of 64 bits.
+  pub fn peek_dirty (&self, dom_id: u32, pages: u64) -> Result<Vec<u8>> {
+    self.read_dirty(dom_id, bindings::XEN_DOMCTL_SHADOW_OP_PEEK, pages)
+  }
+
+  // Same as `peek_dirty` but the bitmap of Xen is reset.
+  pub fn clean_dirty (&self, dom_id: u32, pages: u64) -> Result<Vec<u8>> {
+    self.read_dirty(dom_id, bindings::XEN_DOMCTL_SHADOW_OP_CLEAN, pages)
+  }
+
+  pub fn get_hvm_param (&self, dom_id: u32, param: u32) -> Result<u64> {
+    unsafe {
+      let mut value: u64 = 0;
+      match bindings::xc_hvm_param_get(self.xc, dom_id, param, &mut value) {
+        0 => Ok(value),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn get_tsc_info (&self, dom_id: u32) -> Result<TscInfo> {
+    unsafe {
+      let mut info = TscInfo { mode: 0, elapsed_nsec: 0, khz: 0, incarnation: 0 };
+      match bindings::xc_domain_get_tsc_info(
+        self.xc, dom_id, &mut info.mode, &mut info.elapsed_nsec, &mut info.khz, &mut info.incarnation
+      ) {
+        0 => Ok(info),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn get_vcpu_info (&self, dom_id: u32, vcpu: u32) -> Result<VcpuInfo> {
+    unsafe {
+      let mut info = VcpuInfo::default();
+      match bindings::xc_vcpu_getinfo(self.xc, dom_id, vcpu, &mut info) {
+        0 => Ok(info),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn get_physinfo (&self) -> Result<PhysInfo> {
+    unsafe {
+      let mut info = PhysInfo::default();
+      match bindings::xc_physinfo(self.xc, &mut info) {
+        0 => Ok(info),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Time spent idle by each physical CPU, in nanoseconds.
+  pub fn get_cpu_idle_times (&self, max_cpus: u32) -> Result<Vec<u64>> {
+    let mut info = vec![bindings::xc_cpuinfo_t::default(); max_cpus as usize];
+    let mut count = 0;
+    unsafe {
+      if bindings::xc_getcpuinfo(self.xc, max_cpus as _, info.as_mut_ptr(), &mut count) != 0 {
+        return Err(self.get_last_error())
+      }
+    }
+    info.truncate(count as usize);
+    Ok(info.iter().map(|cpu| cpu.idletime).collect())
+  }
+
+  // Returns the major and minor versions of Xen.
+  pub fn get_xen_version (&self) -> Result<(u32, u32)> {
+    unsafe {
+      match bindings::xc_version(self.xc, bindings::XENVER_version as _, std::ptr::null_mut()) {
+        version if version >= 0 => Ok((version as u32 >> 16, version as u32 & 0xffff)),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Read the console ring of Xen from the character `index`, or from the
+  // oldest one not cleared. With `clear`, the characters read are cleared.
+  // Returns them with the index of the next one.
+  pub fn read_console_ring (&self, index: Option<u32>, clear: bool) -> Result<(Vec<u8>, u32)> {
+    let mut data = Vec::new();
+    let mut buffer = vec![0u8; CONSOLE_RING_CHUNK];
+    let mut incremental = index.is_some();
+    let mut index = index.unwrap_or(0);
+
+    loop {
+      let mut count = buffer.len() as u32;
+      unsafe {
+        if bindings::xc_readconsolering(
+          self.xc, buffer.as_mut_ptr() as _, &mut count, clear as _, incremental as _, &mut index
+        ) != 0 {
+          return Err(self.get_last_error())
+        }
+      }
+      data.extend_from_slice(&buffer[..count as usize]);
+      if (count as usize) < buffer.len() {
+        return Ok((data, index))
+      }
+      incremental = true;
+    }
+  }
+
+  // Trigger the handlers of the given debug keys, their output is written in
+  // the console ring.
+  pub fn send_debug_keys (&self, keys: &str) -> Result<()> {
+    let keys = match CString::new(keys) {
+      Ok(keys) => keys,
+      Err(_) => return Err(Error::new(ErrorCode::InvalidParam, "invalid debug keys"))
+    };
+
+    unsafe {
+      match bindings::xc_send_debug_keys(self.xc, keys.as_ptr() as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn dumpcore (&self, dom_id: u32, path: &Path) -> Result<()> {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+      Ok(path) => path,
+      Err(_) => return Err(Error::new(ErrorCode::InvalidParam, "invalid core file path"))
+    };
+
+    unsafe {
+      match bindings::xc_domain_dumpcore(self.xc, dom_id, path.as_ptr()) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn create_domain (&self, config: &mut DomainCreateConfig) -> Result<u32> {
+    unsafe {
+      // 0 lets the hypervisor choose the domain id.
+      let mut dom_id: u32 = 0;
+      match bindings::xc_domain_create(self.xc, &mut dom_id, config) {
+        0 => Ok(dom_id),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn destroy_domain (&self, dom_id: u32) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_destroy(self.xc, dom_id) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Shut a domain down without the guest, `reason` is a SHUTDOWN_* code.
+  pub fn shutdown_domain (&self, dom_id: u32, reason: u32) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_shutdown(self.xc, dom_id, reason as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn set_max_mem (&self, dom_id: u32, max_memkb: u64) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_setmaxmem(self.xc, dom_id, max_memkb) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Give a PCI device (segment, bus, device and function: SBDF) to a domain.
+  // With `relaxed`, conflicts with reserved memory regions are not errors.
+  pub fn assign_device (&self, dom_id: u32, sbdf: u32, relaxed: bool) -> Result<()> {
+    let flags = if relaxed { bindings::XEN_DOMCTL_DEV_RDM_RELAXED } else { 0 };
+    unsafe {
+      match bindings::xc_assign_device(self.xc, dom_id, sbdf, flags as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn deassign_device (&self, dom_id: u32, sbdf: u32) -> Result<()> {
+    unsafe {
+      match bindings::xc_deassign_device(self.xc, dom_id, sbdf) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Map a physical IRQ in a domain. Returns the pirq.
+  pub fn map_pirq (&self, dom_id: u32, irq: u32) -> Result<u32> {
+    unsafe {
+      let mut pirq: i32 = irq as _;
+      match bindings::xc_physdev_map_pirq(self.xc, dom_id as _, irq as _, &mut pirq) {
+        0 => Ok(pirq as u32),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn unmap_pirq (&self, dom_id: u32, pirq: u32) -> Result<()> {
+    unsafe {
+      match bindings::xc_physdev_unmap_pirq(self.xc, dom_id as _, pirq as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn irq_permission (&self, dom_id: u32, pirq: u32, allow: bool) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_irq_permission(self.xc, dom_id, pirq as _, allow as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Allow or deny the access to `count` machine frames from `first_mfn`.
+  pub fn iomem_permission (&self, dom_id: u32, first_mfn: u64, count: u64, allow: bool) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_iomem_permission(self.xc, dom_id, first_mfn as _, count as _, allow as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  pub fn ioport_permission (&self, dom_id: u32, first_port: u32, count: u32, allow: bool) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_ioport_permission(self.xc, dom_id, first_port, count, allow as _) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Allocate an event channel of `dom_id` which can be bound by `remote_dom_id`.
+  pub fn alloc_unbound_evtchn (&self, dom_id: u32, remote_dom_id: u32) -> Result<u32> {
+    unsafe {
+      match bindings::xc_evtchn_alloc_unbound(self.xc, dom_id, remote_dom_id) {
+        port if port >= 0 => Ok(port as u32),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Write the memory and the state of a domain in `fd` using the migration stream v2.
+  // A checkpointed stream continues with a checkpoint each time the
+  // `checkpoint` callback returns 1.
+  pub fn save_domain (
+    &self,
+    fd: RawFd,
+    dom_id: u32,
+    flags: u32,
+    callbacks: &mut bindings::save_callbacks,
+    hvm: bool,
+    checkpointed: bool
+  ) -> Result<()> {
+    unsafe {
+      match bindings::xc_domain_save(
+        self.xc, fd, dom_id, flags, callbacks, hvm as i32, migration_stream_type(checkpointed), -1
+      ) {
+        0 => Ok(()),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  // Read a migration stream from `fd` in a new domain.
+  // Returns the frames of the xenstore and console rings.
+  // A checkpointed stream is read until its end or an error, the domain is
+  // then restored from the last complete checkpoint. The `checkpoint`
+  // callback is required by libxc for a checkpointed stream, it is called
+  // after each checkpoint.
+  #[allow(clippy::too_many_arguments)]
+  pub fn restore_domain (
+    &self,
+    fd: RawFd,
+    dom_id: u32,
+    store_port: u32,
+    console_port: u32,
+    hvm: bool,
+    checkpointed: bool,
+    callbacks: &mut bindings::restore_callbacks
+  ) -> Result<(u64, u64)> {
+    unsafe {
+      let mut store_mfn = 0;
+      let mut console_mfn = 0;
+      match bindings::xc_domain_restore(
+        self.xc, fd, dom_id,
+        store_port, &mut store_mfn, 0,
+        console_port, &mut console_mfn, 0,
+        hvm as u32, 1,
+        migration_stream_type(checkpointed), callbacks, -1
+      ) {
+        0 => Ok((store_mfn as u64, console_mfn as u64)),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  fn shadow_control (
+    &self,
+    dom_id: u32,
+    operation: u32,
+    bitmap: Option<&mut HypercallBuffer>,
+    pages: u64
+  ) -> Result<u64> {
+    unsafe {
+      let bitmap = match bitmap {
+        Some(bitmap) => &mut bitmap.buffer as *mut bindings::xc_hypercall_buffer_t,
+        None => std::ptr::null_mut()
+      };
+      match bindings::xc_shadow_control(
+        self.xc, dom_id, operation, bitmap, pages as _, std::ptr::null_mut(), 0, std::ptr::null_mut()
+      ) {
+        ret if ret >= 0 => Ok(ret as u64),
+        _ => Err(self.get_last_error())
+      }
+    }
+  }
+
+  fn read_dirty (&self, dom_id: u32, operation: u32, pages: u64) -> Result<Vec<u8>> {
+    let mut buffer = HypercallBuffer::new(self, (((pages + 63) >> 6) << 3) as usize)?;
+    self.shadow_control(dom_id, operation, Some(&mut buffer), pages)?;
+    Ok(buffer.as_slice().to_vec())
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+fn migration_stream_type (checkpointed: bool) -> bindings::xc_migration_stream_t {
+  if checkpointed {
+    bindings::xc_migration_stream_t::XC_MIG_STREAM_REMUS
+  } else {
+    bindings::xc_migration_stream_t::XC_MIG_STREAM_NONE
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Memory locked for the hypercalls, see DECLARE_HYPERCALL_BUFFER.
+struct HypercallBuffer<'a> {
+  xc: &'a Xenctrl,
+  buffer: bindings::xc_hypercall_buffer_t,
+  size: usize,
+  pages: i32
+}
+
+impl<'a> HypercallBuffer<'a> {
+  fn new (xc: &'a Xenctrl, size: usize) -> Result<Self> {
+    let pages = ((size + (1 << bindings::XC_PAGE_SHIFT) - 1) >> bindings::XC_PAGE_SHIFT).max(1) as i32;
+    let mut buffer = bindings::xc_hypercall_buffer_t {
+      ubuf: -1isize as *mut libc::c_void,
+      ..Default::default()
+    };
+    unsafe {
+      if bindings::xc__hypercall_buffer_alloc_pages(xc.xc, &mut buffer, pages).is_null() {
+        return Err(xc.get_last_error())
+      }
+    }
+    Ok(Self { xc, buffer, size, pages })
+  }
+
+  fn as_slice (&self) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(self.buffer.hbuf as *const u8, self.size) }
+  }
+}
+
+impl Drop for HypercallBuffer<'_> {
+  fn drop (&mut self) {
+    unsafe { bindings::xc__hypercall_buffer_free_pages(self.xc.xc, &mut self.buffer, self.pages); }
+  }
 }
@@ -1,5 +1,7 @@
+use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::sync::Mutex;
 
 use super::bindings;
 
@@ -25,6 +27,60 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 // -----------------------------------------------------------------------------
 
+#[derive(Clone, Copy)]
+pub enum PermissionKind {
+  None,
+  Read,
+  Write,
+  ReadWrite
+}
+
+// The first permission of a node gives its owner and the access of the
+// domains which are not listed after.
+#[derive(Clone, Copy)]
+pub struct Permission {
+  pub dom_id: u32,
+  pub kind: PermissionKind
+}
+
+impl Permission {
+  pub fn new (dom_id: u32, kind: PermissionKind) -> Self {
+    Self { dom_id, kind }
+  }
+
+  fn to_c (&self) -> bindings::xs_permissions {
+    let perms = match self.kind {
+      PermissionKind::None => bindings::xs_perm_type::XS_PERM_NONE,
+      PermissionKind::Read => bindings::xs_perm_type::XS_PERM_READ,
+      PermissionKind::Write => bindings::xs_perm_type::XS_PERM_WRITE,
+      PermissionKind::ReadWrite => bindings::xs_perm_type::XS_PERM_READ | bindings::xs_perm_type::XS_PERM_WRITE
+    };
+    bindings::xs_permissions { id: self.dom_id, perms }
+  }
+}
+
+// -----------------------------------------------------------------------------
+
+// Operations on the nodes shared by `Xenstore`, its transactions and
+// `MemoryStore`, to use the same code with a real or an in-memory store.
+pub trait Store {
+  fn read (&self, path: &str) -> Result<String>;
+  fn write (&self, path: &str, value: &str) -> Result<()>;
+  fn rm (&self, path: &str) -> Result<()>;
+  fn mkdir (&self, path: &str) -> Result<()>;
+  fn directory (&self, path: &str) -> Result<Vec<String>>;
+  fn set_permissions (&self, path: &str, permissions: &[Permission]) -> Result<()>;
+
+  // Apply `f` in a transaction when the store has them.
+  fn atomically (&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()>;
+}
+
+pub fn get_domain_path (dom_id: u32) -> String {
+  format!("/local/domain/{}", dom_id)
+}
+
+// -----------------------------------------------------------------------------
+
 pub struct Xenstore {
   xs: *mut bindings::xs_handle
 }
@@ -55,6 +111,95 @@ impl Xenstore {
     self.rm_transaction(bindings::XBT_NULL, &path)
   }
 
+  pub fn mkdir (&self, path: &str) -> Result<()> {
+    self.mkdir_transaction(bindings::XBT_NULL, &path)
+  }
+
+  pub fn directory (&self, path: &str) -> Result<Vec<String>> {
+    self.directory_transaction(bindings::XBT_NULL, &path)
+  }
+
+  pub fn set_permissions (&self, path: &str, permissions: &[Permission]) -> Result<()> {
+    self.set_permissions_transaction(bindings::XBT_NULL, &path, permissions)
+  }
+
+  // Tell xenstored a new domain exists, with the frame and the event channel of its ring.
+  pub fn introduce_domain (&self, dom_id: u32, mfn: u64, port: u32) -> Result<()> {
+    unsafe {
+      if bindings::xs_introduce_domain(self.xs, dom_id, mfn as _, port) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+
+  pub fn release_domain (&self, dom_id: u32) -> Result<()> {
+    unsafe {
+      if bindings::xs_release_domain(self.xs, dom_id) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+
+  // Give a domain (a stubdomain) the privileges of dom0 on the nodes of `target`.
+  pub fn set_target (&self, dom_id: u32, target: u32) -> Result<()> {
+    unsafe {
+      if bindings::xs_set_target(self.xs, dom_id, target) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+
+  // Be notified of the changes of `path` and its children, see `read_watch`.
+  // An event is sent when the watch is registered.
+  pub fn watch (&self, path: &str, token: &str) -> Result<()> {
+    unsafe {
+      let path = CString::new(path).unwrap();
+      let token = CString::new(token).unwrap();
+      if bindings::xs_watch(self.xs, path.as_ptr(), token.as_ptr()) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+
+  pub fn unwatch (&self, path: &str, token: &str) -> Result<()> {
+    unsafe {
+      let path = CString::new(path).unwrap();
+      let token = CString::new(token).unwrap();
+      if bindings::xs_unwatch(self.xs, path.as_ptr(), token.as_ptr()) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+
+  // Wait for the next watch event. Returns the changed path and the token of the watch.
+  pub fn read_watch (&self) -> Result<(String, String)> {
+    unsafe {
+      let mut num: u32 = 0;
+      let event = bindings::xs_read_watch(self.xs, &mut num);
+      if event.is_null() {
+        return Err(Error::new())
+      }
+
+      let event_slice = std::slice::from_raw_parts(event, num as usize);
+      let field = |index: bindings::xs_watch_type| {
+        CStr::from_ptr(event_slice[index as usize]).to_string_lossy().into_owned()
+      };
+      let result = (field(bindings::xs_watch_type::XS_WATCH_PATH), field(bindings::xs_watch_type::XS_WATCH_TOKEN));
+      libc::free(event as *mut libc::c_void);
+      Ok(result)
+    }
+  }
+
   fn read_transaction (&self, tr: bindings::xs_transaction_t, path: &str) -> Result<String> {
     unsafe {
       let mut len: u32 = 0;
@@ -92,6 +237,82 @@ impl Xenstore {
       }
     }
   }
+
+  fn mkdir_transaction (&self, tr: bindings::xs_transaction_t, path: &str) -> Result<()> {
+    unsafe {
+      if bindings::xs_mkdir(self.xs, tr, CString::new(path).unwrap().as_ptr()) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+
+  fn directory_transaction (&self, tr: bindings::xs_transaction_t, path: &str) -> Result<Vec<String>> {
+    unsafe {
+      let mut num: u32 = 0;
+      let entries = bindings::xs_directory(self.xs, tr, CString::new(path).unwrap().as_ptr(), &mut num);
+      if entries.is_null() {
+        return Err(Error::new())
+      }
+
+      let names = std::slice::from_raw_parts(entries, num as usize).iter()
+        .map(|entry| CStr::from_ptr(*entry).to_string_lossy().into_owned())
+        .collect();
+      libc::free(entries as *mut libc::c_void);
+      Ok(names)
+    }
+  }
+
+  fn set_permissions_transaction (
+    &self,
+    tr: bindings::xs_transaction_t,
+    path: &str,
+    permissions: &[Permission]
+  ) -> Result<()> {
+    let mut permissions: Vec<bindings::xs_permissions> = permissions.iter().map(Permission::to_c).collect();
+    unsafe {
+      if bindings::xs_set_permissions(
+        self.xs, tr, CString::new(path).unwrap().as_ptr(), permissions.as_mut_ptr(), permissions.len() as u32
+      ) {
+        Ok(())
+      } else {
+        Err(Error::new())
+      }
+    }
+  }
+}
+
+impl Store for Xenstore {
+  fn read (&self, path: &str) -> Result<String> {
+    Xenstore::read(self, path)
+  }
+
+  fn write (&self, path: &str, value: &str) -> Result<()> {
+    Xenstore::write(self, path, value)
+  }
+
+  fn rm (&self, path: &str) -> Result<()> {
+    Xenstore::rm(self, path)
+  }
+
+  fn mkdir (&self, path: &str) -> Result<()> {
+    Xenstore::mkdir(self, path)
+  }
+
+  fn directory (&self, path: &str) -> Result<Vec<String>> {
+    Xenstore::directory(self, path)
+  }
+
+  fn set_permissions (&self, path: &str, permissions: &[Permission]) -> Result<()> {
+    Xenstore::set_permissions(self, path, permissions)
+  }
+
+  fn atomically (&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+    let transaction = Transaction::new(self)?;
+    f(&transaction)?;
+    transaction.commit()
+  }
 }
 
 impl Drop for Xenstore {
@@ -139,6 +360,18 @@ impl<'a> Transaction<'a> {
     self.store.rm_transaction(self.tr, path)
   }
 
+  pub fn mkdir (&self, path: &str) -> Result<()> {
+    self.store.mkdir_transaction(self.tr, path)
+  }
+
+  pub fn directory (&self, path: &str) -> Result<Vec<String>> {
+    self.store.directory_transaction(self.tr, path)
+  }
+
+  pub fn set_permissions (&self, path: &str, permissions: &[Permission]) -> Result<()> {
+    self.store.set_permissions_transaction(self.tr, path, permissions)
+  }
+
   pub fn commit (&self) -> Result<()> {
     unsafe {
       if bindings::xs_transaction_end(self.store.xs, self.tr, false) {
@@ -163,3 +396,140 @@ impl Drop for Transaction<'_> {
     }
   }
 }
+
+impl Store for Transaction<'_> {
+  fn read (&self, path: &str) -> Result<String> {
+    Transaction::read(self, path)
+  }
+
+  fn write (&self, path: &str, value: &str) -> Result<()> {
+    Transaction::write(self, path, value)
+  }
+
+  fn rm (&self, path: &str) -> Result<()> {
+    Transaction::rm(self, path)
+  }
+
+  fn mkdir (&self, path: &str) -> Result<()> {
+    Transaction::mkdir(self, path)
+  }
+
+  fn directory (&self, path: &str) -> Result<Vec<String>> {
+    Transaction::directory(self, path)
+  }
+
+  fn set_permissions (&self, path: &str, permissions: &[Permission]) -> Result<()> {
+    Transaction::set_permissions(self, path, permissions)
+  }
+
+  // Already in a transaction.
+  fn atomically (&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+    f(self)
+  }
+}
+
+// =============================================================================
+// In-memory store, to use the xenstore protocols without Xen.
+// =============================================================================
+
+struct Node {
+  value: String,
+  permissions: Vec<Permission>
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+  nodes: Mutex<BTreeMap<String, Node>>
+}
+
+impl MemoryStore {
+  pub fn new () -> Self {
+    Self::default()
+  }
+
+  pub fn get_permissions (&self, path: &str) -> Option<Vec<Permission>> {
+    self.nodes.lock().unwrap().get(path).map(|node| node.permissions.clone())
+  }
+
+  // Like xenstored, the missing parents are created with the permissions
+  // of their own parent.
+  fn create (nodes: &mut BTreeMap<String, Node>, path: &str) {
+    let mut permissions = vec![Permission::new(0, PermissionKind::None)];
+    let mut end = 0;
+    while end < path.len() {
+      end = path[end + 1..].find('/').map_or(path.len(), |i| end + 1 + i);
+      let node = nodes.entry(path[..end].to_string()).or_insert_with(|| Node {
+        value: String::new(),
+        permissions: permissions.clone()
+      });
+      permissions = node.permissions.clone();
+    }
+  }
+}
+
+fn is_valid_path (path: &str) -> bool {
+  path.starts_with('/') && path.len() > 1 && !path.ends_with('/') && !path.contains("//")
+}
+
+impl Store for MemoryStore {
+  fn read (&self, path: &str) -> Result<String> {
+    self.nodes.lock().unwrap().get(path).map(|node| node.value.clone()).ok_or_else(Error::new)
+  }
+
+  fn write (&self, path: &str, value: &str) -> Result<()> {
+    if !is_valid_path(path) {
+      return Err(Error::new())
+    }
+    let mut nodes = self.nodes.lock().unwrap();
+    MemoryStore::create(&mut nodes, path);
+    nodes.get_mut(path).unwrap().value = value.to_string();
+    Ok(())
+  }
+
+  fn rm (&self, path: &str) -> Result<()> {
+    let prefix = format!("{}/", path);
+    let mut nodes = self.nodes.lock().unwrap();
+    if nodes.remove(path).is_none() {
+      return Err(Error::new())
+    }
+    nodes.retain(|key, _| !key.starts_with(&prefix));
+    Ok(())
+  }
+
+  fn mkdir (&self, path: &str) -> Result<()> {
+    if !is_valid_path(path) {
+      return Err(Error::new())
+    }
+    MemoryStore::create(&mut self.nodes.lock().unwrap(), path);
+    Ok(())
+  }
+
+  fn directory (&self, path: &str) -> Result<Vec<String>> {
+    let prefix = format!("{}/", path);
+    let nodes = self.nodes.lock().unwrap();
+    if !nodes.contains_key(path) {
+      return Err(Error::new())
+    }
+    Ok(
+      nodes.range(prefix.clone()..)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .filter(|(key, _)| !key[prefix.len()..].contains('/'))
+        .map(|(key, _)| key[prefix.len()..].to_string())
+        .collect()
+    )
+  }
+
+  fn set_permissions (&self, path: &str, permissions: &[Permission]) -> Result<()> {
+    match self.nodes.lock().unwrap().get_mut(path) {
+      Some(node) => {
+        node.permissions = permissions.to_vec();
+        Ok(())
+      },
+      None => Err(Error::new())
+    }
+  }
+
+  fn atomically (&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+    f(self)
+  }
+}
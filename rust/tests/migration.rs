@@ -0,0 +1,56 @@
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+
+// =============================================================================
+// Live migration between two CLI processes, a sender and a receiver, with
+// simulated domains.
+// =============================================================================
+
+const CLI: &str = env!("CARGO_BIN_EXE_xenops-cli");
+
+// Line of `output` after `prefix`.
+fn line_after<'a> (output: &'a str, prefix: &str) -> Option<&'a str> {
+  output.lines().find_map(|line| line.strip_prefix(prefix))
+}
+
+#[test]
+fn simulated_migration () {
+  let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+  let mut receiver = Command::new(CLI)
+    .args(&["migrate-receive", "--listen", &address, "--simulated"])
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  let mut receiver_output = BufReader::new(receiver.stdout.take().unwrap());
+  let mut line = String::new();
+  receiver_output.read_line(&mut line).unwrap();
+  assert!(line.starts_with("Waiting for a migration"), "{}", line);
+
+  let sender = Command::new(CLI).args(&["migrate", "7", &address, "--simulated"]).output().unwrap();
+  let sender_output = String::from_utf8(sender.stdout).unwrap();
+  assert!(sender.status.success());
+
+  let mut output = String::new();
+  while receiver_output.read_line(&mut output).unwrap() > 0 {}
+  assert!(receiver.wait().unwrap().success());
+
+  // Pre-copy iterations, then the stop-and-copy: the memory received is the
+  // one of the suspended domain, with the devices state sent after the stream.
+  assert!(line_after(&sender_output, "iteration 0: ").is_some(), "{}", sender_output);
+  let sent = line_after(&sender_output, "Migrated to domain ").expect(&sender_output);
+  let received = line_after(&output, "Received domain ").expect(&output);
+  assert_eq!(sent, received);
+  assert!(sent.starts_with("1 (checksum "));
+  assert_eq!(line_after(&output, "Devices state of "), Some("16384 bytes."), "{}", output);
+}
+
+#[test]
+fn migration_refused () {
+  // Nobody listens: the sender fails without destroying its domain.
+  let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+  let sender = Command::new(CLI).args(&["migrate", "7", &address, "--simulated"]).output().unwrap();
+  let errors = String::from_utf8(sender.stderr).unwrap();
+  assert!(errors.starts_with("Failed to migrate domain 7:"), "{}", errors);
+}
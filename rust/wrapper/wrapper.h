@@ -1,4 +1,8 @@
 #include <xenctrl.h>
+#include <xenevtchn.h>
+#include <xenforeignmemory.h>
+#include <xengnttab.h>
+#include <xenguest.h>
 #include <xenstore.h>
 
 // Workaround to wrap HvmSaveTypes.